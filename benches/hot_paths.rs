@@ -0,0 +1,170 @@
+//! Micro-benchmarks of the hot paths, as baselines for performance changes:
+//! `cargo bench --features mock`. Each case is timed over several rounds, after a
+//! warm-up one, and the median time per iteration is printed.
+
+use ethers::types::Address;
+use monique::index::{IndexTable, Indexed};
+use monique::indexer::{mock::Fixtures, process};
+use monique::words;
+use serde_json::json;
+use std::hint::black_box;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+const ROUNDS: usize = 7;
+
+/// Prints the median time per iteration of `f` over `ROUNDS` rounds of `iterations`.
+fn bench<R>(name: &str, iterations: u64, mut f: impl FnMut(u64) -> R) {
+    let mut round = || {
+        let start = Instant::now();
+        for i in 0..iterations {
+            black_box(f(i));
+        }
+        start.elapsed() / iterations as u32
+    };
+    round();
+    let mut times: Vec<Duration> = (0..ROUNDS).map(|_| round()).collect();
+    times.sort_unstable();
+    println!(
+        "{:<32} {:>12?}/iter (min {:?}, max {:?})",
+        name,
+        times[ROUNDS / 2],
+        times[0],
+        times[ROUNDS - 1]
+    );
+}
+
+fn address(i: u64) -> Address {
+    Address::from_low_u64_be(i + 1)
+}
+
+fn words() {
+    let checksum = words::checksum(address(0));
+    bench("words::to_words", 100_000, |i| {
+        words::to_words(i * 7_919 % (1 << 40), checksum)
+    });
+    let monics: Vec<String> = (0..1_024)
+        .map(|i| words::to_words(i * 7_919 % (1 << 40), checksum))
+        .collect();
+    bench("words::to_index", 10_000, |i| {
+        words::to_index(monics[i as usize % monics.len()].clone()).unwrap()
+    });
+}
+
+/// Block of `transactions` transfers, each with a transfer log between fresh
+/// addresses.
+fn transfers(transactions: u64) -> Fixtures {
+    let bloom = format!("0x{}", "0".repeat(512));
+    let topic = |i: u64| format!("0x{:064x}", i + 1);
+    let receipts: Vec<_> = (0..transactions)
+        .map(|i| {
+            json!({
+                "transactionHash": topic(i),
+                "transactionIndex": format!("{:#x}", i),
+                "from": address(4 * i),
+                "to": address(4 * i + 1),
+                "cumulativeGasUsed": "0x5208",
+                "logs": [{
+                    "address": address(4 * i + 1),
+                    "topics": [
+                        "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef",
+                        topic(4 * i + 2),
+                        topic(4 * i + 3),
+                    ],
+                    "data": "0x",
+                }],
+                "logsBloom": bloom,
+            })
+        })
+        .collect();
+    let block = json!({
+        "number": "0x1",
+        "miner": address(u64::MAX - 1),
+        "transactions": (0..transactions).map(topic).collect::<Vec<_>>(),
+    });
+    Fixtures::default().with_block(1, block, json!(receipts))
+}
+
+fn blocks(runtime: &Runtime) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/indexer/fixtures");
+    let cases = [
+        (
+            "process/block 46147",
+            Fixtures::load(&dir, &[46147]).unwrap(),
+            46147,
+        ),
+        ("process/200 transfers", transfers(200), 1),
+    ];
+    for (name, fixtures, number) in cases {
+        let provider = fixtures.provider();
+        let block = runtime
+            .block_on(ethers::providers::Middleware::get_block(&provider, number))
+            .unwrap()
+            .unwrap();
+        bench(name, 1_000, |_| {
+            runtime.block_on(process(&provider, &block, false)).unwrap()
+        });
+    }
+}
+
+fn queue(runtime: &Runtime) {
+    let dir = tempfile::tempdir().unwrap();
+    let index = runtime
+        .block_on(IndexTable::<20, Address>::builder(dir.path().join("queue")).build())
+        .unwrap();
+    // blocks of 200 addresses, half of them seen in the previous block
+    let mut number = 0;
+    bench("queue/200 addresses, 50% known", 200, |_| {
+        number += 1;
+        let addresses = (number * 100..number * 100 + 200).map(address).collect();
+        runtime.block_on(index.queue(number, addresses)).unwrap()
+    });
+}
+
+fn lookups(runtime: &Runtime) {
+    const ENTRIES: u64 = 100_000;
+    for (name, cache_size) in [("cached", ENTRIES as usize), ("uncached", 1)] {
+        let dir = tempfile::tempdir().unwrap();
+        let index = runtime
+            .block_on(
+                IndexTable::<20, Address>::builder(dir.path().join(name))
+                    .cache_size(cache_size)
+                    .build(),
+            )
+            .unwrap();
+        runtime.block_on(async {
+            for block in 0..ENTRIES / 1_000 {
+                let addresses = (block * 1_000..(block + 1) * 1_000).map(address).collect();
+                index.queue(block + 1, addresses).await.unwrap();
+            }
+            index.commit(ENTRIES / 1_000).await.unwrap();
+            // warm the caches
+            for i in 0..ENTRIES {
+                index.index(address(i)).await.unwrap();
+            }
+        });
+        let at = |i: u64| address(i * 7_919 % ENTRIES);
+        bench(&format!("index/{} stored", name), 100_000, |i| {
+            runtime.block_on(index.index(at(i))).unwrap()
+        });
+        bench(&format!("index/{} absent", name), 100_000, |i| {
+            runtime.block_on(index.index(address(ENTRIES + i))).unwrap()
+        });
+        bench(&format!("get/{}", name), 100_000, |i| {
+            runtime
+                .block_on(index.get((i * 7_919 % ENTRIES) as usize))
+                .unwrap()
+        });
+    }
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    words();
+    blocks(&runtime);
+    queue(&runtime);
+    lookups(&runtime);
+}
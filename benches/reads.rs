@@ -0,0 +1,91 @@
+//! Time and allocations of the committed reads, with the lookup caches disabled so
+//! that reads go to the database: `cargo bench --bench reads`. The counting allocator
+//! replaces the global one of this binary only.
+
+use ethers::types::Address;
+use monique::index::{IndexTable, Indexed};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::hint::black_box;
+use std::time::Instant;
+use tokio::runtime::Runtime;
+
+const ITERATIONS: u64 = 400_000;
+const BLOCKS: u64 = 100;
+const BLOCK_SIZE: u64 = 1_000;
+
+/// Counts the allocations of each thread.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Prints the time and allocations per iteration of `f`, on this thread.
+fn bench<R>(name: &str, mut f: impl FnMut(u64) -> R) {
+    let (start, allocations) = (Instant::now(), ALLOCATIONS.with(Cell::get));
+    for i in 0..ITERATIONS {
+        black_box(f(i));
+    }
+    println!(
+        "{:<16} {:>8} ns/iter {:>8.2} allocations/iter",
+        name,
+        start.elapsed().as_nanos() / ITERATIONS as u128,
+        (ALLOCATIONS.with(Cell::get) - allocations) as f64 / ITERATIONS as f64
+    );
+}
+
+fn address(i: u64) -> Address {
+    Address::from_low_u64_be(i + 1)
+}
+
+fn main() {
+    let runtime: Runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    // a single cache entry, so that reads go to the database
+    let index = runtime
+        .block_on(
+            IndexTable::<20, Address>::builder(dir.path().join("reads"))
+                .cache_size(1)
+                .build(),
+        )
+        .unwrap();
+    runtime.block_on(async {
+        for block in 0..BLOCKS {
+            let addresses = (block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE)
+                .map(address)
+                .collect();
+            index.queue(block + 1, addresses).await.unwrap();
+        }
+        index.commit(BLOCKS).await.unwrap();
+    });
+    let len = BLOCKS * BLOCK_SIZE;
+
+    bench("get", |i| {
+        runtime
+            .block_on(index.get((i * 7_919 % len) as usize))
+            .unwrap()
+    });
+    bench("index", |i| {
+        runtime
+            .block_on(index.index(address(i * 7_919 % len)))
+            .unwrap()
+    });
+    bench("block_hash", |i| index.block_hash(i % BLOCKS + 1).unwrap());
+}
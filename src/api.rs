@@ -1,28 +1,233 @@
-use crate::index::{Indexed, SharedIndex};
-use crate::words;
-use ethers::types::Address;
+use crate::ens::SharedEns;
+use crate::index::{
+    Appearance, DiskUsage, HistorySample, Indexed, Label, Rollbacks, ScanBudget, SharedIndex,
+    Tombstone,
+};
+use crate::indexer::control::{Command, CommandSender};
+use crate::indexer::sources::SourceStats;
+use crate::indexer::status::{IndexerStatus, StatusReceiver};
+use crate::qr;
+use crate::watchlist::{SharedWatchlists, WatchedEntry};
+use crate::words::{self, PIVOT};
+use crate::MoniqueError;
+use ethers::core::rand;
+use ethers::types::{Address, Bytes, Signature, SignatureError, H256};
+use moka::future::Cache;
 use rocket::{
-    catch, get,
+    catch, delete,
+    fairing::{Fairing, Info, Kind},
+    get,
+    http::{ContentType, Status},
+    post, put,
+    request::{FromRequest, Outcome},
     response::Responder,
-    serde::{json::Json, Serialize},
-    Request, State,
+    serde::{json::Json, Deserialize, Serialize},
+    Build, Data, Request, Response, Rocket, State,
+};
+use std::future::Future;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    cmp,
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    hash::Hash,
+    str::FromStr,
+    sync::Arc,
 };
-use std::{error::Error, str::FromStr};
 
-const PIVOT: usize = 0x40000;
+/// Stable machine-readable code of an error response, for clients to match rather
+/// than the message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// A word of the monic is not in the wordlist, or it has too many words.
+    UnknownWord,
+    WrongChecksum,
+    InvalidAddress,
+    /// Indexes below the pivot are reserved for mutable monics.
+    ReservedRange,
+    /// The entry is still pending, and was looked up with `pending=false`.
+    IndexNotCommitted,
+    InvalidWindow,
+    InvalidPrefix,
+    InvalidLabel,
+    InvalidWatchlist,
+    InvalidRollback,
+    InvalidLogFilter,
+    RollbackNotConfirmed,
+    TooManyBlocks,
+    TooManyIndexes,
+    EntryTooLong,
+    RateLimited,
+    UnknownApiKey,
+    TooManyExpensiveRequests,
+    IndexerUnavailable,
+    LogLevelUnavailable,
+    DatabaseFull,
+    BadRequest,
+    Unauthorized,
+    NotFound,
+    InternalError,
+}
+
+impl ErrorCode {
+    /// Code of the errors only known by their status, answered by the catchers.
+    fn of_status(status: Status) -> Self {
+        match status.code {
+            401 => Self::Unauthorized,
+            404 => Self::NotFound,
+            429 => Self::RateLimited,
+            code if code >= 500 => Self::InternalError,
+            _ => Self::BadRequest,
+        }
+    }
+}
 
-#[derive(Responder, Serialize)]
+#[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct ErrorDescription {
+    code: ErrorCode,
     error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
-#[derive(Serialize)]
+impl ErrorDescription {
+    fn new(code: ErrorCode, error: impl Into<String>) -> Self {
+        Self {
+            code,
+            error: error.into(),
+            request_id: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct AddressInfo {
     address: Address,
     index: usize,
     monic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contract: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<LabelInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ens: Option<String>,
+    /// Transaction the address was first seen in, when recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    appearance: Option<AppearanceInfo>,
+    /// Whether the entry is still in the uncommitted queue, and could be dropped by
+    /// a reorg.
+    pending: bool,
+    /// Chain of the index, once recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chain_id: Option<u64>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AppearanceInfo {
+    transaction: H256,
+    /// Index in the block of the log the address was found in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_index: Option<u64>,
+}
+
+impl From<Appearance> for AppearanceInfo {
+    fn from(value: Appearance) -> Self {
+        Self {
+            transaction: value.transaction,
+            log_index: value.log_index,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LabelInfo {
+    label: String,
+    /// Defaults to `api` when setting a label.
+    #[serde(default)]
+    source: Option<String>,
+}
+
+impl From<Label> for LabelInfo {
+    fn from(value: Label) -> Self {
+        Self {
+            label: value.label,
+            source: Some(value.source),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TxInfo {
+    hash: H256,
+    index: usize,
+    monic: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SignedCheckpoint {
+    block: u64,
+    root: H256,
+    hash: H256,
+    signer: Address,
+    signature: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ProofInfo {
+    index: usize,
+    address: Address,
+    block: u64,
+    root: H256,
+    proof: Vec<Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    appearance: Option<AppearanceInfo>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CheckpointInfo {
+    block: u64,
+    root: H256,
+    hash: H256,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index_root: Option<H256>,
+    /// Hash of the extraction rules of the index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ruleset: Option<H256>,
+    /// Fingerprint of the vocabulary of the monics.
+    wordlist: H256,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CommitInfo {
+    block: u64,
+    committed: usize,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RollbackInfo {
+    block: u64,
+    removed: usize,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WatchlistStatus {
+    name: String,
+    total: usize,
+    indexed: usize,
+    entries: Vec<WatchedEntry>,
 }
 
 #[derive(Serialize)]
@@ -30,106 +235,3123 @@ pub struct AddressInfo {
 pub struct Stats {
     last_block: u64,
     unique_addresses: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index_root: Option<H256>,
+    /// Head of the checkpoint hash chain, at the last committed block.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checkpoint: Option<ChainHead>,
+    disk: DiskInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chain_id: Option<u64>,
+    /// Hash of the extraction rules of the index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ruleset: Option<H256>,
+    /// Fingerprint of the vocabulary of the monics.
+    wordlist: H256,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ChainHead {
+    block: u64,
+    hash: H256,
+}
+
+/// On-disk size of the index, in bytes.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DiskInfo {
+    tables: BTreeMap<&'static str, u64>,
+    used: u64,
+    free: u64,
+    file: u64,
+    max: u64,
+    /// Share of `max` taken by the allocated pages, free ones included.
+    map_usage: f64,
+}
+
+impl From<DiskUsage> for DiskInfo {
+    fn from(usage: DiskUsage) -> Self {
+        let space = usage.space;
+        Self {
+            tables: usage.tables.into_iter().collect(),
+            used: space.used_pages() * space.page_size,
+            free: space.free_pages * space.page_size,
+            file: space.file_size,
+            max: space.max_size,
+            map_usage: space.map_usage(),
+        }
+    }
 }
 
-#[derive(Responder)]
 pub enum ResolveError {
-    #[response(status = 400, content_type = "json")]
     InvalidAlias(Json<ErrorDescription>),
-    #[response(status = 400, content_type = "json")]
     BadAddress(Json<ErrorDescription>),
-    #[response(status = 400, content_type = "json")]
     WrongChecksum(Json<ErrorDescription>),
+    BadRequest(Json<ErrorDescription>),
+    /// Entries that cannot be returned for a known reason, such as a reserved index.
+    NotFound(Json<ErrorDescription>),
+    Internal(Json<ErrorDescription>),
+    Unavailable(Json<ErrorDescription>),
 }
 
-impl From<Box<dyn Error + Send + Sync>> for ResolveError {
-    fn from(value: Box<dyn Error + Send + Sync>) -> Self {
-        Self::InvalidAlias(Json(ErrorDescription {
-            error: value.to_string(),
-        }))
+impl ResolveError {
+    fn description(&self) -> &ErrorDescription {
+        match self {
+            Self::InvalidAlias(e)
+            | Self::BadAddress(e)
+            | Self::WrongChecksum(e)
+            | Self::BadRequest(e)
+            | Self::NotFound(e)
+            | Self::Internal(e)
+            | Self::Unavailable(e) => e,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.description().code
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ResolveError {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let (status, Json(mut description)) = match self {
+            Self::InvalidAlias(e)
+            | Self::BadAddress(e)
+            | Self::WrongChecksum(e)
+            | Self::BadRequest(e) => (Status::BadRequest, e),
+            Self::NotFound(e) => (Status::NotFound, e),
+            Self::Internal(e) => (Status::InternalServerError, e),
+            Self::Unavailable(e) => (Status::ServiceUnavailable, e),
+        };
+        description.request_id = Some(RequestId::of(req).0.clone());
+        (status, Json(description)).respond_to(req)
+    }
+}
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.description().error)
+    }
+}
+
+impl From<MoniqueError> for ResolveError {
+    fn from(value: MoniqueError) -> Self {
+        let error = |code| Json(ErrorDescription::new(code, value.to_string()));
+        match value {
+            MoniqueError::Words(_) => Self::InvalidAlias(error(ErrorCode::UnknownWord)),
+            MoniqueError::Watchlist(_) => Self::BadRequest(error(ErrorCode::InvalidWatchlist)),
+            MoniqueError::Rollback(_) => Self::BadRequest(error(ErrorCode::InvalidRollback)),
+            MoniqueError::DatabaseFull => Self::Internal(error(ErrorCode::DatabaseFull)),
+            _ => Self::Internal(error(ErrorCode::InternalError)),
+        }
     }
 }
 
 impl From<rustc_hex::FromHexError> for ResolveError {
     fn from(value: rustc_hex::FromHexError) -> Self {
-        Self::BadAddress(Json(ErrorDescription {
-            error: value.to_string(),
-        }))
+        Self::BadAddress(Json(ErrorDescription::new(
+            ErrorCode::InvalidAddress,
+            value.to_string(),
+        )))
+    }
+}
+
+impl From<SignatureError> for ResolveError {
+    fn from(value: SignatureError) -> Self {
+        Self::Internal(Json(ErrorDescription::new(
+            ErrorCode::InternalError,
+            value.to_string(),
+        )))
+    }
+}
+
+/// Serialization of the lookup and export responses, negotiated with the `Accept`
+/// header: JSON unless CBOR or MessagePack is preferred.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl Format {
+    fn accepted(req: &Request<'_>) -> Self {
+        let Some(accept) = req.accept() else {
+            return Self::Json;
+        };
+        let media = accept.preferred().media_type();
+        match (media.top().as_str(), media.sub().as_str()) {
+            ("application", "cbor") => Self::Cbor,
+            ("application", "msgpack" | "x-msgpack") => Self::MessagePack,
+            _ => Self::Json,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Format {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, ()> {
+        Outcome::Success(Self::accepted(req))
+    }
+}
+
+/// Response body serialized in the [`Format`] accepted by the client.
+pub struct Negotiated<T>(pub T);
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Negotiated<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let (content_type, body) = match Format::accepted(req) {
+            Format::Json => return Json(self.0).respond_to(req),
+            Format::Cbor => {
+                let mut body = vec![];
+                ciborium::into_writer(&self.0, &mut body)
+                    .map_err(|_| Status::InternalServerError)?;
+                (ContentType::new("application", "cbor"), body)
+            }
+            Format::MessagePack => {
+                let body =
+                    rmp_serde::to_vec_named(&self.0).map_err(|_| Status::InternalServerError)?;
+                (ContentType::MsgPack, body)
+            }
+        };
+        (content_type, body).respond_to(req)
+    }
+}
+
+type ApiResponse = Result<Option<Negotiated<AddressInfo>>, ResolveError>;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum CacheKey {
+    Monic(String),
+    Address(Address),
+}
+
+/// TTL cache of the `/resolve` and `/alias` responses of committed entries, which
+/// are immutable but for their contract flag and label. Misses and pending entries
+/// are not cached.
+pub struct ResponseCache {
+    entries: Cache<CacheKey, AddressInfo>,
+    /// Whether committed entries are classified as contracts after their commit.
+    enriched: bool,
+}
+
+pub type SharedResponseCache = Arc<ResponseCache>;
+
+impl ResponseCache {
+    pub fn new(capacity: u64, ttl: Duration) -> Self {
+        Self {
+            entries: Cache::builder()
+                .max_capacity(capacity)
+                .time_to_live(ttl)
+                .support_invalidation_closures()
+                .build(),
+            enriched: false,
+        }
+    }
+
+    /// Completes the cached responses with the contract flags set by the
+    /// enrichment, which runs after the commit of their entries.
+    pub fn enriched(mut self, enriched: bool) -> Self {
+        self.enriched = enriched;
+        self
+    }
+
+    async fn get_or_lookup(
+        &self,
+        key: CacheKey,
+        set: &SharedIndex<20, Address>,
+        lookup: impl Future<Output = Result<Option<AddressInfo>, ResolveError>>,
+    ) -> Result<Option<AddressInfo>, ResolveError> {
+        if let Some(mut info) = self.entries.get(&key).await {
+            // entries are enriched after their commit, their appearance being
+            // committed with them: the flag is only set once
+            if self.enriched && info.contract.is_none() {
+                info.contract = set.is_contract(info.index - PIVOT)?;
+                if info.contract.is_some() {
+                    self.entries.insert(key, info.clone()).await;
+                }
+            }
+            return Ok(Some(info));
+        }
+        let info = lookup.await?;
+        if let Some(info) = info.as_ref().filter(|info| !info.pending) {
+            self.entries.insert(key, info.clone()).await;
+        }
+        Ok(info)
+    }
+
+    /// Drops the cached responses of an address, e.g. after its label changed.
+    pub fn invalidate(&self, address: Address) {
+        // only fails without `support_invalidation_closures`
+        let _ = self
+            .entries
+            .invalidate_entries_if(move |_, info| info.address == address);
+    }
+
+    /// Drops every cached response on each rollback of the index, whose removed
+    /// entries get other addresses once their blocks are indexed again. Returns once
+    /// the index is dropped.
+    pub async fn follow_rollbacks(self: Arc<Self>, mut rollbacks: Rollbacks) {
+        while rollbacks.changed().await {
+            if let Some(to) = rollbacks.take() {
+                self.entries.invalidate_all();
+                tracing::info!(block = to, "rolled back, response cache cleared");
+            }
+        }
+    }
+}
+
+type TxResponse = Result<Option<Negotiated<TxInfo>>, ResolveError>;
+
+/// Adds the `X-Monique-Block` and `X-Monique-Index-Count` headers to every response:
+/// the last indexed block and the number of entries up to it, read together when the
+/// request is received. The response reflects at least this state, so clients can
+/// tell when the index advanced between two calls.
+pub struct IndexHeight;
+
+#[derive(Clone, Copy)]
+struct Height(Option<(u64, usize)>);
+
+#[rocket::async_trait]
+impl Fairing for IndexHeight {
+    fn info(&self) -> Info {
+        Info {
+            name: "Index height headers",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(set) = req.rocket().state::<SharedIndex<20, Address>>() else {
+            return;
+        };
+        let height = set.height().await;
+        req.local_cache(|| Height(Some(height)));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Height(Some((block, count))) = *req.local_cache(|| Height(None)) else {
+            return;
+        };
+        res.set_raw_header("X-Monique-Block", block.to_string());
+        res.set_raw_header("X-Monique-Index-Count", count.to_string());
+    }
+}
+
+/// Correlation ID of a request: its `X-Request-Id` header when valid, generated
+/// otherwise. It is echoed in the response headers and error bodies, and recorded
+/// in the span of the lookups, so that their logs can be told apart across replicas.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    const HEADER: &'static str = "X-Request-Id";
+
+    fn of<'r>(req: &'r Request<'_>) -> &'r Self {
+        req.local_cache(|| {
+            let valid = |id: &&str| {
+                (1..=128).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic())
+            };
+            match req.headers().get_one(Self::HEADER).filter(valid) {
+                Some(id) => Self(id.to_string()),
+                None => Self(format!("{:032x}", rand::random::<u128>())),
+            }
+        })
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r RequestId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, ()> {
+        Outcome::Success(RequestId::of(req))
+    }
+}
+
+/// Echoes the [`RequestId`] of every request in its response and logs it with the
+/// response status.
+pub struct RequestTracing;
+
+#[rocket::async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let id = RequestId::of(req);
+        let status = res.status();
+        if status.class().is_server_error() {
+            tracing::warn!(request_id = %id, method = %req.method(), uri = %req.uri(), status = status.code, "request failed");
+        } else {
+            tracing::debug!(request_id = %id, method = %req.method(), uri = %req.uri(), status = status.code, "request");
+        }
+        res.set_raw_header(RequestId::HEADER, id.0.clone());
+    }
+}
+
+/// Bodies smaller than this are sent uncompressed.
+const MIN_COMPRESSED_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Preferred encoding of an `Accept-Encoding` header, gzip winning ties.
+    fn accepted(header: &str) -> Option<Self> {
+        let mut best: Option<(Self, f32)> = None;
+        for value in header.split(',') {
+            let mut params = value.split(';');
+            let encoding = match params.next().unwrap_or("").trim() {
+                "gzip" | "x-gzip" => Self::Gzip,
+                "deflate" => Self::Deflate,
+                _ => continue,
+            };
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((encoding, quality));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
     }
 }
 
-type ApiResponse = Result<Option<Json<AddressInfo>>, ResolveError>;
+/// Compresses the response bodies with gzip or deflate, as negotiated with the
+/// `Accept-Encoding` header, as they are streamed. Bodies of a known size under
+/// [`MIN_COMPRESSED_SIZE`] bytes are sent as is: this mostly applies to `/blocks`, the
+/// watchlist status, the metrics and the streamed exports; lookups are smaller.
+pub struct Compress;
+
+#[rocket::async_trait]
+impl Fairing for Compress {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        use async_compression::tokio::bufread::{GzipEncoder, ZlibEncoder};
+        use tokio::io::BufReader;
+
+        let Some(encoding) = req
+            .headers()
+            .get("Accept-Encoding")
+            .find_map(Encoding::accepted)
+        else {
+            return;
+        };
+        if res.headers().contains("Content-Encoding") || res.body().is_none() {
+            return;
+        }
+        if res
+            .body()
+            .preset_size()
+            .is_some_and(|size| size < MIN_COMPRESSED_SIZE)
+        {
+            return;
+        }
+        let body = BufReader::new(res.body_mut().take());
+        match encoding {
+            Encoding::Gzip => res.set_streamed_body(GzipEncoder::new(body)),
+            Encoding::Deflate => res.set_streamed_body(ZlibEncoder::new(body)),
+        }
+        res.set_raw_header("Content-Encoding", encoding.name());
+        res.adjoin_raw_header("Vary", "Accept-Encoding");
+    }
+}
 
 #[catch(404)]
-pub fn not_found(_: &Request) -> Json<ErrorDescription> {
+pub fn not_found(req: &Request) -> Json<ErrorDescription> {
     Json(ErrorDescription {
+        code: ErrorCode::NotFound,
         error: "not found".to_string(),
+        request_id: Some(RequestId::of(req).0.clone()),
+    })
+}
+
+#[catch(401)]
+pub fn unauthorized(req: &Request) -> Json<ErrorDescription> {
+    Json(ErrorDescription {
+        code: ErrorCode::Unauthorized,
+        error: "unauthorized".to_string(),
+        request_id: Some(RequestId::of(req).0.clone()),
     })
 }
 
 #[catch(500)]
-pub fn internal_error(_: &Request) -> Json<ErrorDescription> {
+pub fn internal_error(req: &Request) -> Json<ErrorDescription> {
     Json(ErrorDescription {
+        code: ErrorCode::InternalError,
         error: "internal error".to_string(),
+        request_id: Some(RequestId::of(req).0.clone()),
     })
 }
 
+/// Other statuses, such as request bodies failing to parse.
+#[catch(default)]
+pub fn default_error(status: Status, req: &Request) -> (Status, Json<ErrorDescription>) {
+    let error = status.reason_lossy().to_lowercase();
+    (
+        status,
+        Json(ErrorDescription {
+            code: ErrorCode::of_status(status),
+            error,
+            request_id: Some(RequestId::of(req).0.clone()),
+        }),
+    )
+}
+
 #[get("/")]
-pub async fn stats(set: &State<SharedIndex<20, Address>>) -> Result<Json<Stats>, ResolveError> {
-    Ok(Json(Stats {
-        last_block: set.get_counters().await.last_indexed_block,
+#[tracing::instrument(skip_all, fields(request_id = %request_id))]
+pub async fn stats(
+    request_id: &RequestId,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Json<Stats>, ResolveError> {
+    Ok(Json(get_stats(set).await?))
+}
+
+pub(crate) async fn get_stats(set: &SharedIndex<20, Address>) -> Result<Stats, ResolveError> {
+    let (last_block, last_committed_block) = {
+        let counters = set.get_counters().await;
+        (counters.last_indexed_block, counters.last_committed_block)
+    };
+    Ok(Stats {
+        last_block,
         unique_addresses: set.len().await,
-    }))
+        index_root: set.index_root(last_committed_block)?,
+        checkpoint: set
+            .checkpoint(last_committed_block)?
+            .map(|checkpoint| ChainHead {
+                block: checkpoint.block,
+                hash: checkpoint.hash,
+            }),
+        disk: set.disk_usage()?.into(),
+        chain_id: set.chain_id()?,
+        ruleset: set.ruleset()?,
+        wordlist: words::fingerprint(),
+    })
 }
 
-#[get("/resolve/<alias>")]
-pub async fn resolve(alias: &str, set: &State<SharedIndex<20, Address>>) -> ApiResponse {
-    let (index, checksum) = words::to_index(alias.to_string())?;
-    if index < PIVOT {
-        return Ok(None); // TODO: get mutable monics from the contract
+/// Default window of `/stats/history`.
+const HISTORY_WINDOW: u64 = 24 * 3600;
+
+/// Sample of the indexing progress returned by `/stats/history`.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct HistoryPoint {
+    timestamp: u64,
+    block: u64,
+    addresses: u64,
+    blocks_per_second: f64,
+}
+
+impl From<HistorySample> for HistoryPoint {
+    fn from(sample: HistorySample) -> Self {
+        Self {
+            timestamp: sample.timestamp,
+            block: sample.block,
+            addresses: sample.addresses,
+            blocks_per_second: sample.blocks_per_second,
+        }
     }
-    let stored_index = index - PIVOT;
-    let addr = set.get(stored_index).await?;
-    if let Some(addr) = addr {
-        if words::checksum(addr) == checksum {
-            let res = AddressInfo {
-                address: addr,
-                index,
-                monic: alias.to_string(),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct History {
+    samples: Vec<HistoryPoint>,
+    /// Indexing rate of the samples, one block character each.
+    sparkline: String,
+}
+
+/// Seconds of a window such as `90s`, `30m`, `24h` or `7d`, plain numbers being
+/// seconds.
+fn parse_window(window: &str) -> Result<u64, ResolveError> {
+    let invalid = || {
+        ResolveError::BadRequest(Json(ErrorDescription::new(
+            ErrorCode::InvalidWindow,
+            format!("invalid window: {}", window),
+        )))
+    };
+    let (count, unit) = match window.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&window[..i], c),
+        _ => (window, 's'),
+    };
+    let unit = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 24 * 3600,
+        _ => return Err(invalid()),
+    };
+    let count: u64 = count.parse().map_err(|_| invalid())?;
+    count
+        .checked_mul(unit)
+        .filter(|n| *n > 0)
+        .ok_or_else(invalid)
+}
+
+/// Values scaled to the eight block characters, between the lowest and the highest.
+fn sparkline(values: impl Iterator<Item = f64> + Clone) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let (min, max) = values
+        .clone()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| {
+            (min.min(v), max.max(v))
+        });
+    values
+        .map(|v| {
+            let level = if max > min {
+                (v - min) / (max - min)
+            } else {
+                0.0
             };
-            Ok(Some(Json(res)))
-        } else {
-            Err(ResolveError::WrongChecksum(Json(ErrorDescription {
-                error: "wrong checksum".to_string(),
-            })))
+            BARS[(level * (BARS.len() - 1) as f64).round() as usize]
+        })
+        .collect()
+}
+
+/// Samples of the indexing progress over the last `window` (24h by default), with a
+/// sparkline of the indexing rate.
+#[get("/stats/history?<window>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, window = ?window))]
+pub async fn stats_history(
+    request_id: &RequestId,
+    window: Option<&str>,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Json<History>, ResolveError> {
+    let window = window.map(parse_window).transpose()?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let samples = set.history(now.saturating_sub(window.unwrap_or(HISTORY_WINDOW)))?;
+    Ok(Json(History {
+        sparkline: sparkline(samples.iter().map(|sample| sample.blocks_per_second)),
+        samples: samples.into_iter().map(HistoryPoint::from).collect(),
+    }))
+}
+
+/// Cost limits of the expensive routes, `/search`, `/blocks` and `/proofs`,
+/// independent of the gateway rate limit: each request stops early, with a partial
+/// response, once it read `max_entries` entries or ran for `max_duration`, and
+/// requests over `max_concurrent` running at once get a `429`, so that lookups are
+/// never starved.
+pub struct CostLimits {
+    budget: ScanBudget,
+    running: Arc<tokio::sync::Semaphore>,
+}
+
+pub type SharedCostLimits = Arc<CostLimits>;
+
+impl CostLimits {
+    pub fn new(max_entries: usize, max_duration: Duration, max_concurrent: usize) -> Self {
+        Self {
+            budget: ScanBudget {
+                entries: max_entries,
+                duration: max_duration,
+            },
+            running: Arc::new(tokio::sync::Semaphore::new(max_concurrent)),
         }
-    } else {
-        Ok(None)
     }
 }
 
-#[get("/index/<index>")]
-pub async fn index(index: usize, set: &State<SharedIndex<20, Address>>) -> ApiResponse {
-    if index < PIVOT {
-        return Ok(None);
+impl Default for CostLimits {
+    fn default() -> Self {
+        Self::new(5_000_000, Duration::from_secs(1), 4)
     }
-    let res = set.get(index - PIVOT).await?;
-    let info = res.map(|addr| AddressInfo {
-        address: addr,
-        index,
-        monic: words::to_words(index as u64, words::checksum(addr)),
-    });
-    Ok(info.map(Json))
 }
 
-#[get("/alias/<address>")]
-pub async fn alias(address: String, set: &State<SharedIndex<20, Address>>) -> ApiResponse {
-    let addr = Address::from_str(address.as_str())?;
-    let index = set.index(addr).await?;
-    let res = index.map(|index| AddressInfo {
-        address: addr,
-        index: index + PIVOT,
-        monic: words::to_words((index + PIVOT) as u64, words::checksum(addr)),
-    });
-    Ok(res.map(Json))
+/// Request guard of the expensive routes: a slot among the concurrent expensive
+/// requests, held until the response is sent, with the budget of the request.
+pub struct Expensive {
+    budget: ScanBudget,
+    _permit: tokio::sync::OwnedSemaphorePermit,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Expensive {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, ()> {
+        let Some(limits) = req.rocket().state::<SharedCostLimits>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        match limits.running.clone().try_acquire_owned() {
+            Ok(permit) => Outcome::Success(Self {
+                budget: limits.budget,
+                _permit: permit,
+            }),
+            Err(_) => Outcome::Error((Status::TooManyRequests, ())),
+        }
+    }
+}
+
+/// Response of the expensive routes refused by [`Expensive`].
+#[derive(Responder)]
+#[response(status = 429)]
+pub struct TooManyRequests {
+    body: Json<ErrorDescription>,
+    retry_after: rocket::http::Header<'static>,
+}
+
+#[catch(429)]
+pub fn too_many_requests(req: &Request) -> TooManyRequests {
+    TooManyRequests {
+        body: Json(ErrorDescription {
+            code: ErrorCode::TooManyExpensiveRequests,
+            error: "too many expensive requests, retry later".to_string(),
+            request_id: Some(RequestId::of(req).0.clone()),
+        }),
+        retry_after: rocket::http::Header::new("Retry-After", "1"),
+    }
+}
+
+/// Maximum number of blocks returned by `/blocks`.
+const MAX_BLOCKS: u64 = 10_000;
+
+/// Committed entry returned by `/blocks` in CBOR or MessagePack.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ExportRow {
+    index: usize,
+    address: Address,
+    block: u64,
+}
+
+#[derive(Responder)]
+pub enum BlocksResponse {
+    Dump((ContentType, Vec<u8>)),
+    Rows(Negotiated<Vec<ExportRow>>),
+}
+
+/// Blocks cut short by the cost limits carry an `X-Partial: true` header; clients
+/// resume from the block after the last one returned.
+pub struct Blocks {
+    response: BlocksResponse,
+    partial: bool,
+}
+
+impl<'r> Responder<'r, 'static> for Blocks {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let mut res = self.response.respond_to(req)?;
+        if self.partial {
+            res.set_raw_header("X-Partial", "true");
+        }
+        Ok(res)
+    }
+}
+
+/// Last block of `from..=to` such that the blocks up to it hold at most `entries`
+/// entries, the first block being returned whatever its size.
+fn last_affordable_block(counts: &[(u64, usize)], from: u64, to: u64, entries: usize) -> u64 {
+    let mut total = 0;
+    for (block, count) in counts {
+        total += count;
+        if total > entries && *block > from {
+            return block - 1;
+        }
+    }
+    to
+}
+
+/// Committed blocks in the dump format, for replicas following this instance, or as
+/// rows of entries when CBOR or MessagePack is accepted. Dumps carry the block
+/// timestamps when `timestamps` is set, which replicas of older versions do not.
+#[get("/blocks?<from>&<count>&<timestamps>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, from = from, count = ?count))]
+pub async fn blocks(
+    request_id: &RequestId,
+    from: u64,
+    count: Option<u64>,
+    timestamps: Option<bool>,
+    format: Format,
+    expensive: Expensive,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Option<Blocks>, ResolveError> {
+    let started = Instant::now();
+    if from == 0 {
+        return Ok(None);
+    }
+    // replicas of an index started at a later block begin with its first block
+    let from = from.max(set.start_block());
+    let last_block = set.get_counters().await.last_committed_block;
+    if from > last_block {
+        return Ok(None);
+    }
+    let count = count.unwrap_or(1_000).clamp(1, MAX_BLOCKS);
+    let requested = cmp::min(from + count - 1, last_block);
+    let counts = set.block_counts(from, requested)?;
+    let to = last_affordable_block(&counts, from, requested, expensive.budget.entries);
+    let mut partial = to < requested;
+    if format == Format::Json {
+        let mut dump = vec![];
+        let deadline = started + expensive.budget.duration;
+        let exported = set
+            .export_until(
+                from,
+                to,
+                &mut dump,
+                Some(deadline),
+                timestamps.unwrap_or(false),
+            )
+            .await?;
+        partial |= from + exported - 1 < to;
+        return Ok(Some(Blocks {
+            response: BlocksResponse::Dump((ContentType::Binary, dump)),
+            partial,
+        }));
+    }
+    let mut rows = vec![];
+    for block in from..=to {
+        if block > from && started.elapsed() >= expensive.budget.duration {
+            partial = true;
+            break;
+        }
+        let Some((start, addresses)) = set.block_entries(block)? else {
+            continue;
+        };
+        rows.extend(
+            addresses
+                .into_iter()
+                .enumerate()
+                .map(|(offset, address)| ExportRow {
+                    index: start + offset + PIVOT,
+                    address,
+                    block,
+                }),
+        );
+    }
+    Ok(Some(Blocks {
+        response: BlocksResponse::Rows(Negotiated(rows)),
+        partial,
+    }))
+}
+
+/// Maximum number of blocks covered by `/blocks/counts`.
+const MAX_COUNTED_BLOCKS: u64 = 100_000;
+
+/// Number of new addresses of a committed block.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BlockCount {
+    block: u64,
+    count: usize,
+}
+
+/// Number of new addresses of each committed block of `from..=to`, `to` being the
+/// last committed block by default. Blocks committed before ranges were recorded
+/// are left out.
+#[get("/blocks/counts?<from>&<to>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, from = from, to = ?to))]
+pub async fn block_counts(
+    request_id: &RequestId,
+    from: u64,
+    to: Option<u64>,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Json<Vec<BlockCount>>, ResolveError> {
+    let last_block = set.get_counters().await.last_committed_block;
+    let to = cmp::min(to.unwrap_or(last_block), last_block);
+    if to >= from.saturating_add(MAX_COUNTED_BLOCKS) {
+        return Err(ResolveError::BadRequest(Json(ErrorDescription::new(
+            ErrorCode::TooManyBlocks,
+            format!(
+                "at most {} blocks can be counted at once",
+                MAX_COUNTED_BLOCKS
+            ),
+        ))));
+    }
+    if from > to {
+        return Ok(Json(vec![]));
+    }
+    let counts = set.block_counts(from, to)?;
+    Ok(Json(
+        counts
+            .into_iter()
+            .map(|(block, count)| BlockCount { block, count })
+            .collect(),
+    ))
+}
+
+/// Height of the index as of a time.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct HeightAt {
+    /// Last committed block at the time, and its timestamp.
+    block: u64,
+    timestamp: u64,
+    /// Number of addresses committed up to the block.
+    count: usize,
+    /// Last index assigned, if any.
+    index: Option<usize>,
+}
+
+/// Height of the index as of a unix `timestamp`, from the timestamps recorded for
+/// the committed blocks.
+#[get("/index-at?<timestamp>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, timestamp = timestamp))]
+pub async fn index_at(
+    request_id: &RequestId,
+    timestamp: u64,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Option<Json<HeightAt>>, ResolveError> {
+    Ok(set.height_at(timestamp)?.map(|(block, timestamp, count)| {
+        Json(HeightAt {
+            block,
+            timestamp,
+            count,
+            index: count.checked_sub(1).map(|index| index + PIVOT),
+        })
+    }))
+}
+
+/// Description of a committed entry at a stored (unpivoted) index.
+fn committed_info(
+    index: usize,
+    address: Address,
+    set: &SharedIndex<20, Address>,
+) -> Result<AddressInfo, ResolveError> {
+    Ok(AddressInfo {
+        address,
+        index: index + PIVOT,
+        monic: words::to_words((index + PIVOT) as u64, words::checksum(address)),
+        contract: set.is_contract(index)?,
+        label: set.get_label(address)?.map(LabelInfo::from),
+        ens: None,
+        appearance: set.appearance(index)?.map(AppearanceInfo::from),
+        pending: false,
+        chain_id: set.chain_id()?,
+    })
+}
+
+/// Maximum number of addresses returned by `/search`.
+const MAX_SEARCH_RESULTS: usize = 100;
+
+/// Addresses matching a `/search`, with the (pivoted) index to resume it from.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SearchResult {
+    addresses: Vec<AddressInfo>,
+    next: Option<usize>,
+    /// Whether the scan stopped on its cost limits, before finding `limit` addresses.
+    partial: bool,
+}
+
+/// Nibbles of a hex prefix such as `0xab1`.
+fn parse_prefix(prefix: &str) -> Result<Vec<u8>, ResolveError> {
+    let digits = prefix
+        .strip_prefix("0x")
+        .or_else(|| prefix.strip_prefix("0X"))
+        .unwrap_or(prefix);
+    if digits.is_empty() || digits.len() > 40 {
+        return Err(ResolveError::BadRequest(Json(ErrorDescription::new(
+            ErrorCode::InvalidPrefix,
+            "the prefix must hold 1 to 40 hex digits",
+        ))));
+    }
+    digits
+        .chars()
+        .map(|c| {
+            c.to_digit(16).map(|n| n as u8).ok_or_else(|| {
+                ResolveError::BadRequest(Json(ErrorDescription::new(
+                    ErrorCode::InvalidPrefix,
+                    format!("invalid hex digit: {}", c),
+                )))
+            })
+        })
+        .collect()
+}
+
+fn has_prefix(address: &Address, nibbles: &[u8]) -> bool {
+    nibbles.iter().enumerate().all(|(i, nibble)| {
+        let byte = address.as_bytes()[i / 2];
+        let digit = if i % 2 == 0 { byte >> 4 } else { byte & 0xf };
+        digit == *nibble
+    })
+}
+
+/// Committed addresses starting with a hex prefix. No table is ordered by address,
+/// so this scans the index table from the (pivoted) index `from`, for at most
+/// `limit` results within the [`CostLimits`]; `next` resumes the scan when set.
+#[get("/search?<prefix>&<from>&<limit>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, prefix = prefix, from = ?from, limit = ?limit))]
+pub async fn search(
+    request_id: &RequestId,
+    prefix: &str,
+    from: Option<usize>,
+    limit: Option<usize>,
+    expensive: Expensive,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Json<SearchResult>, ResolveError> {
+    let nibbles = parse_prefix(prefix)?;
+    let from = from.unwrap_or(PIVOT).saturating_sub(PIVOT);
+    let limit = limit.unwrap_or(20).clamp(1, MAX_SEARCH_RESULTS);
+    let (matches, next) = set
+        .scan(from, limit, expensive.budget, |address| {
+            has_prefix(address, &nibbles)
+        })
+        .await?;
+    let partial = next.is_some() && matches.len() < limit;
+    let mut addresses = Vec::with_capacity(matches.len());
+    for (index, address) in matches {
+        addresses.push(committed_info(index, address, set)?);
+    }
+    Ok(Json(SearchResult {
+        addresses,
+        next: next.map(|next| next + PIVOT),
+        partial,
+    }))
+}
+
+/// Maximum number of addresses returned by `/recent`.
+const MAX_RECENT: usize = 100;
+
+/// Committed address returned by `/recent`, with the block that added it.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RecentAddress {
+    #[serde(flatten)]
+    info: AddressInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block: Option<u64>,
+}
+
+/// Most recently committed addresses, newest first.
+#[get("/recent?<limit>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, limit = ?limit))]
+pub async fn recent(
+    request_id: &RequestId,
+    limit: Option<usize>,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Json<Vec<RecentAddress>>, ResolveError> {
+    let limit = limit.unwrap_or(20).clamp(1, MAX_RECENT);
+    let mut addresses = vec![];
+    for (index, address, block) in set.recent(limit).await? {
+        addresses.push(RecentAddress {
+            info: committed_info(index, address, set)?,
+            block,
+        });
+    }
+    Ok(Json(addresses))
+}
+
+#[get("/checkpoint/<block>")]
+pub fn checkpoint(
+    block: u64,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Option<Json<CheckpointInfo>>, ResolveError> {
+    let Some(checkpoint) = set.checkpoint(block)? else {
+        return Ok(None);
+    };
+    Ok(Some(Json(CheckpointInfo {
+        block,
+        root: checkpoint.root,
+        hash: checkpoint.hash,
+        index_root: set.index_root(block)?,
+        ruleset: set.ruleset()?,
+        wordlist: words::fingerprint(),
+    })))
+}
+
+#[get("/checkpoint/<block>/signature")]
+pub fn checkpoint_signature(
+    block: u64,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Option<Json<SignedCheckpoint>>, ResolveError> {
+    let (Some(checkpoint), Some(signature)) = (set.checkpoint(block)?, set.get_signature(block)?)
+    else {
+        return Ok(None);
+    };
+    let signature = Signature::try_from(&signature[..])?;
+    let signer = signature.recover(checkpoint.message().to_vec())?;
+    Ok(Some(Json(SignedCheckpoint {
+        block,
+        root: checkpoint.root,
+        hash: checkpoint.hash,
+        signer,
+        signature: format!("0x{}", signature),
+    })))
+}
+
+/// Proof that the address at a (pivoted) index belongs to the checkpoint of its block.
+#[get("/proof/<index>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, index = index))]
+pub async fn proof(
+    request_id: &RequestId,
+    index: usize,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Option<Json<ProofInfo>>, ResolveError> {
+    if index < PIVOT {
+        return Err(reserved_range(index));
+    }
+    let Some((address, proof)) = set.proof(index - PIVOT).await? else {
+        return Ok(None);
+    };
+    Ok(Some(Json(ProofInfo {
+        index,
+        address,
+        block: proof.block,
+        root: proof.root,
+        proof: proof.nodes.into_iter().map(Bytes::from).collect(),
+        appearance: set.appearance(index - PIVOT)?.map(AppearanceInfo::from),
+    })))
+}
+
+/// Maximum number of indexes proven by a `/proofs` request.
+const MAX_PROOFS: usize = 1_000;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ProofsRequest {
+    indexes: Vec<usize>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ProvenEntry {
+    index: usize,
+    address: Address,
+}
+
+/// Multi-proof of entries of a block: the `nodes` of their proofs, each included once.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MultiProofInfo {
+    block: u64,
+    root: H256,
+    entries: Vec<ProvenEntry>,
+    nodes: Vec<Bytes>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ProofsInfo {
+    proofs: Vec<MultiProofInfo>,
+    /// Requested indexes without a proof.
+    missing: Vec<usize>,
+    /// First requested index left unproven by the [`CostLimits`]: the indexes from it
+    /// on are to be requested again.
+    next: Option<usize>,
+}
+
+/// Proofs that the addresses at (pivoted) indexes belong to the checkpoints of their
+/// blocks, with one multi-proof per block. The tries of the blocks committed without
+/// persisted tries are rebuilt, so this is an expensive route, proving blocks within
+/// the [`CostLimits`].
+#[post("/proofs", data = "<request>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, count = request.indexes.len()))]
+pub async fn proofs(
+    request_id: &RequestId,
+    request: Json<ProofsRequest>,
+    expensive: Expensive,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Json<ProofsInfo>, ResolveError> {
+    if request.indexes.len() > MAX_PROOFS {
+        return Err(ResolveError::BadRequest(Json(ErrorDescription::new(
+            ErrorCode::TooManyIndexes,
+            format!("at most {} indexes can be proven at once", MAX_PROOFS),
+        ))));
+    }
+    let (indexes, mut missing): (Vec<usize>, Vec<usize>) =
+        request.indexes.iter().partition(|index| **index >= PIVOT);
+    let indexes: Vec<usize> = indexes.into_iter().map(|index| index - PIVOT).collect();
+    let (proofs, unproven, next) = set.multi_proof(&indexes, expensive.budget).await?;
+    missing.extend(unproven.into_iter().map(|index| index + PIVOT));
+    missing.sort_unstable();
+    missing.dedup();
+    Ok(Json(ProofsInfo {
+        proofs: proofs
+            .into_iter()
+            .map(|proof| MultiProofInfo {
+                block: proof.block,
+                root: proof.root,
+                entries: proof
+                    .entries
+                    .into_iter()
+                    .map(|(index, address)| ProvenEntry {
+                        index: index + PIVOT,
+                        address,
+                    })
+                    .collect(),
+                nodes: proof.nodes.into_iter().map(Bytes::from).collect(),
+            })
+            .collect(),
+        missing,
+        next: next.map(|next| next + PIVOT),
+    }))
+}
+
+/// Replaces the log filter of the process with the given directives (e.g.
+/// `monique=debug,info`), failing if they cannot be parsed.
+pub type LogFilter = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// Token and command channel of the admin routes, which are only mounted when an
+/// admin token is configured.
+#[derive(Clone)]
+pub struct AdminState {
+    token: String,
+    commands: CommandSender,
+    log_filter: Option<LogFilter>,
+}
+
+impl AdminState {
+    pub fn new(token: String, commands: CommandSender) -> Self {
+        Self {
+            token,
+            commands,
+            log_filter: None,
+        }
+    }
+
+    /// Enables `POST /admin/loglevel`.
+    pub fn with_log_filter(mut self, log_filter: LogFilter) -> Self {
+        self.log_filter = Some(log_filter);
+        self
+    }
+
+    async fn send(&self, command: Command) -> Result<(), ResolveError> {
+        self.commands.send(command).await.map_err(|_| {
+            ResolveError::Unavailable(Json(ErrorDescription::new(
+                ErrorCode::IndexerUnavailable,
+                "indexer is not running",
+            )))
+        })
+    }
+}
+
+/// Request guard checking the `Authorization: Bearer <token>` header.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, ()> {
+        let Some(admin) = req.rocket().state::<AdminState>() else {
+            return Outcome::Error((Status::NotFound, ()));
+        };
+        let token = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if constant_time_eq(token.as_bytes(), admin.token.as_bytes()) => {
+                Outcome::Success(Admin)
+            }
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[post("/admin/pause")]
+pub async fn admin_pause(_admin: Admin, admin: &State<AdminState>) -> Result<Status, ResolveError> {
+    admin.send(Command::Pause).await?;
+    Ok(Status::Accepted)
+}
+
+#[post("/admin/resume")]
+pub async fn admin_resume(
+    _admin: Admin,
+    admin: &State<AdminState>,
+) -> Result<Status, ResolveError> {
+    admin.send(Command::Resume).await?;
+    Ok(Status::Accepted)
+}
+
+/// Promotes a standby instance to active indexer, after a last import from its
+/// primary if it is reachable.
+#[post("/admin/promote")]
+pub async fn admin_promote(
+    _admin: Admin,
+    admin: &State<AdminState>,
+) -> Result<Status, ResolveError> {
+    admin.send(Command::Promote).await?;
+    Ok(Status::Accepted)
+}
+
+/// Commits the pending blocks up to `block` without waiting for it to be safe. The
+/// command is handled between two blocks, or right away while paused.
+#[post("/admin/commit?<block>")]
+pub async fn admin_commit(
+    block: u64,
+    _admin: Admin,
+    admin: &State<AdminState>,
+) -> Result<Json<CommitInfo>, ResolveError> {
+    let (reply, response) = tokio::sync::oneshot::channel();
+    admin.send(Command::Commit { block, reply }).await?;
+    let committed = response.await.map_err(|_| {
+        ResolveError::Unavailable(Json(ErrorDescription::new(
+            ErrorCode::IndexerUnavailable,
+            "indexer stopped before committing",
+        )))
+    })??;
+    Ok(Json(CommitInfo { block, committed }))
+}
+
+/// Unwinds the committed blocks after `block`, e.g. after indexing with a faulty
+/// rule, and restarts the indexer from the block after it. `confirm` must repeat the
+/// block, since the removed entries are lost.
+#[post("/admin/rollback?<block>&<confirm>")]
+pub async fn admin_rollback(
+    block: u64,
+    confirm: Option<u64>,
+    _admin: Admin,
+    admin: &State<AdminState>,
+) -> Result<Json<RollbackInfo>, ResolveError> {
+    if confirm != Some(block) {
+        return Err(ResolveError::BadRequest(Json(ErrorDescription::new(
+            ErrorCode::RollbackNotConfirmed,
+            "confirm the rollback with confirm=<block>",
+        ))));
+    }
+    let (reply, response) = tokio::sync::oneshot::channel();
+    admin.send(Command::Rollback { block, reply }).await?;
+    let removed = response.await.map_err(|_| {
+        ResolveError::Unavailable(Json(ErrorDescription::new(
+            ErrorCode::IndexerUnavailable,
+            "indexer stopped before rolling back",
+        )))
+    })??;
+    Ok(Json(RollbackInfo { block, removed }))
+}
+
+/// Items of a pending block dropped by a reorg or a rollback, returned by
+/// `/admin/tombstones`.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TombstoneInfo {
+    timestamp: u64,
+    block: u64,
+    reason: &'static str,
+    addresses: Vec<Address>,
+}
+
+impl From<Tombstone<Address>> for TombstoneInfo {
+    fn from(tombstone: Tombstone<Address>) -> Self {
+        Self {
+            timestamp: tombstone.timestamp,
+            block: tombstone.block,
+            reason: tombstone.reason.name(),
+            addresses: tombstone.items,
+        }
+    }
+}
+
+/// Pending blocks dropped over the last `window` (all the retained ones by default),
+/// oldest first, e.g. to analyze the fork a provider fed.
+#[get("/admin/tombstones?<window>")]
+pub fn admin_tombstones(
+    window: Option<&str>,
+    _admin: Admin,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Json<Vec<TombstoneInfo>>, ResolveError> {
+    let since = match window.map(parse_window).transpose()? {
+        Some(window) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+            .saturating_sub(window),
+        None => 0,
+    };
+    let tombstones = set.tombstones(since)?;
+    Ok(Json(
+        tombstones.into_iter().map(TombstoneInfo::from).collect(),
+    ))
+}
+
+/// Replaces the log filter, the body holding the new directives.
+#[post("/admin/loglevel", data = "<directives>")]
+pub fn admin_loglevel(
+    directives: &str,
+    _admin: Admin,
+    admin: &State<AdminState>,
+) -> Result<Status, ResolveError> {
+    let Some(log_filter) = &admin.log_filter else {
+        return Err(ResolveError::Unavailable(Json(ErrorDescription::new(
+            ErrorCode::LogLevelUnavailable,
+            "log level cannot be changed",
+        ))));
+    };
+    log_filter(directives.trim()).map_err(|error| {
+        ResolveError::BadRequest(Json(ErrorDescription::new(
+            ErrorCode::InvalidLogFilter,
+            error,
+        )))
+    })?;
+    Ok(Status::NoContent)
+}
+
+/// Which addresses of a watchlist are indexed, and at what index and monic.
+#[get("/watchlist/<name>/status")]
+pub async fn watchlist_status(
+    name: &str,
+    watchlists: &State<SharedWatchlists>,
+    set: &State<SharedIndex<20, Address>>,
+) -> Result<Option<Json<WatchlistStatus>>, ResolveError> {
+    let Some(entries) = watchlists.status(name, set).await? else {
+        return Ok(None);
+    };
+    Ok(Some(Json(WatchlistStatus {
+        name: name.to_string(),
+        total: entries.len(),
+        indexed: entries.iter().filter(|entry| entry.index.is_some()).count(),
+        entries,
+    })))
+}
+
+/// Creates or replaces a watchlist from a JSON array of addresses.
+#[put("/watchlist/<name>", data = "<addresses>")]
+pub async fn watchlist_register(
+    name: &str,
+    addresses: Json<HashSet<Address>>,
+    _admin: Admin,
+    watchlists: &State<SharedWatchlists>,
+) -> Result<Status, ResolveError> {
+    watchlists.register(name, addresses.into_inner()).await?;
+    Ok(Status::NoContent)
+}
+
+#[delete("/watchlist/<name>")]
+pub async fn watchlist_remove(
+    name: &str,
+    _admin: Admin,
+    watchlists: &State<SharedWatchlists>,
+) -> Result<Option<Status>, ResolveError> {
+    Ok(watchlists.remove(name).await?.then_some(Status::NoContent))
+}
+
+/// Sets the label of an address, which does not need to be indexed.
+#[put("/label/<address>", data = "<label>")]
+pub fn label_set(
+    address: &str,
+    label: Json<LabelInfo>,
+    _admin: Admin,
+    set: &State<SharedIndex<20, Address>>,
+    cache: &State<Option<SharedResponseCache>>,
+) -> Result<Status, ResolveError> {
+    let address = Address::from_str(address)?;
+    let LabelInfo { label, source } = label.into_inner();
+    if label.is_empty() || label.len() > u16::MAX as usize {
+        return Err(ResolveError::BadRequest(Json(ErrorDescription::new(
+            ErrorCode::InvalidLabel,
+            "invalid label",
+        ))));
+    }
+    let source = source.unwrap_or_else(|| "api".to_string());
+    set.set_labels(vec![(address, Some(Label { label, source }))])?;
+    if let Some(cache) = cache.inner() {
+        cache.invalidate(address);
+    }
+    Ok(Status::NoContent)
+}
+
+#[delete("/label/<address>")]
+pub fn label_remove(
+    address: &str,
+    _admin: Admin,
+    set: &State<SharedIndex<20, Address>>,
+    cache: &State<Option<SharedResponseCache>>,
+) -> Result<Option<Status>, ResolveError> {
+    let address = Address::from_str(address)?;
+    if set.get_label(address)?.is_none() {
+        return Ok(None);
+    }
+    set.set_labels(vec![(address, None)])?;
+    if let Some(cache) = cache.inner() {
+        cache.invalidate(address);
+    }
+    Ok(Some(Status::NoContent))
+}
+
+#[get("/status")]
+pub fn status(status: &State<StatusReceiver>) -> Json<IndexerStatus> {
+    Json(status.borrow().current())
+}
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Request latency histograms by route and status, shared by the listeners.
+#[derive(Default)]
+pub struct RouteStats {
+    routes: Mutex<BTreeMap<(String, u16), Histogram>>,
+}
+
+pub type SharedRouteStats = Arc<RouteStats>;
+
+impl RouteStats {
+    fn record(&self, route: String, status: u16, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut routes = self.routes.lock().unwrap();
+        let histogram = routes.entry((route, status)).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    fn write(&self, out: &mut String) {
+        let name = "monique_http_request_duration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} API request latency by route and status",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for ((route, status), histogram) in self.routes.lock().unwrap().iter() {
+            let labels = format!("route=\"{}\",status=\"{}\"", route, status);
+            for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name, labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"+Inf\"}} {}",
+                name, labels, histogram.count
+            );
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+        }
+    }
+}
+
+/// Records the latency of every request in [`RouteStats`], by route path (`none` when
+/// no route matched) and status.
+pub struct RouteMetrics(pub SharedRouteStats);
+
+#[rocket::async_trait]
+impl Fairing for RouteMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Route metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(Instant::now);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let elapsed = req.local_cache(Instant::now).elapsed();
+        let route = req.route().map_or_else(
+            || "none".to_string(),
+            |route| route.uri.origin.path().to_string(),
+        );
+        self.0.record(route, res.status().code, elapsed);
+    }
+}
+
+/// Requests a gateway client may make: bursts of up to `per_minute` requests, refilled
+/// continuously, and at most `per_day` requests per UTC day. Daily counts are kept in
+/// memory, and start again from zero when the server restarts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub per_minute: u32,
+    pub per_day: Option<u64>,
+}
+
+/// Rate limiter clients are tracked by: their API key, or their IP address when
+/// anonymous.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Client {
+    Key(String),
+    Ip(std::net::IpAddr),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    day: u64,
+    today: u64,
+}
+
+/// Anonymous clients tracked at once, the least recently seen being forgotten.
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+/// Why a request is refused by the [`RateLimiter`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Rejection {
+    UnknownKey,
+    /// Seconds until the client may retry.
+    Limited(u64),
+}
+
+/// Rate limits of the public gateway: anonymous clients are limited by IP address,
+/// clients sending a known `X-Api-Key` header by the quota of their key.
+pub struct RateLimiter {
+    anonymous: RwLock<Quota>,
+    keys: RwLock<std::collections::HashMap<String, Quota>>,
+    /// Buckets of the API keys, which hold a daily count, one per key of the keys file.
+    key_buckets: Mutex<std::collections::HashMap<String, Bucket>>,
+    /// Buckets of the anonymous clients: a forgotten bucket had time to refill, unless
+    /// more than `MAX_TRACKED_CLIENTS` clients came in between.
+    ip_buckets: Mutex<lru::LruCache<std::net::IpAddr, Bucket>>,
+}
+
+pub type SharedRateLimiter = Arc<RateLimiter>;
+
+impl RateLimiter {
+    const KEY_HEADER: &'static str = "X-Api-Key";
+
+    pub fn new(anonymous: Quota) -> Self {
+        Self {
+            anonymous: RwLock::new(anonymous),
+            keys: Default::default(),
+            key_buckets: Default::default(),
+            ip_buckets: Mutex::new(lru::LruCache::new(
+                std::num::NonZeroUsize::new(MAX_TRACKED_CLIENTS).unwrap(),
+            )),
+        }
+    }
+
+    /// Accepts the API keys of a keys file, one `<key> [<per minute> [<per day>]]` per
+    /// line, keys without limits getting the `default` quota. Blank lines and `#`
+    /// comments are skipped.
+    pub fn with_keys(mut self, keys: &str, default: Quota) -> Result<Self, String> {
+        *self.keys.get_mut().unwrap() = Self::parse_keys(keys, default)?;
+        Ok(self)
+    }
+
+    /// Replaces the anonymous quota and the API keys, as given to `new` and
+    /// `with_keys`. The buckets of the clients are kept, those of removed keys dropped.
+    pub fn reload(&self, anonymous: Quota, keys: &str, default: Quota) -> Result<(), String> {
+        let keys = Self::parse_keys(keys, default)?;
+        self.key_buckets
+            .lock()
+            .unwrap()
+            .retain(|key, _| keys.contains_key(key));
+        *self.keys.write().unwrap() = keys;
+        *self.anonymous.write().unwrap() = anonymous;
+        Ok(())
+    }
+
+    fn parse_keys(
+        keys: &str,
+        default: Quota,
+    ) -> Result<std::collections::HashMap<String, Quota>, String> {
+        let mut quotas = std::collections::HashMap::new();
+        for (number, line) in keys.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut fields = line.split_whitespace();
+            let Some(key) = fields.next() else {
+                continue;
+            };
+            let invalid = |field: &str| format!("line {}: invalid {}", number + 1, field);
+            let mut quota = default;
+            if let Some(per_minute) = fields.next() {
+                quota.per_minute = per_minute.parse().map_err(|_| invalid("rate"))?;
+            }
+            if let Some(per_day) = fields.next() {
+                quota.per_day = Some(per_day.parse().map_err(|_| invalid("daily quota"))?);
+            }
+            if fields.next().is_some() {
+                return Err(invalid("line"));
+            }
+            quotas.insert(key.to_string(), quota);
+        }
+        Ok(quotas)
+    }
+
+    /// Takes a token from the bucket of `client`, or tells when to retry.
+    fn take(&self, client: Client, quota: Quota, now: Instant, day: u64) -> Result<(), Rejection> {
+        let capacity = quota.per_minute as f64;
+        let new = || Bucket {
+            tokens: capacity,
+            updated: now,
+            day,
+            today: 0,
+        };
+        let mut keys;
+        let mut ips;
+        let bucket = match client {
+            Client::Key(key) => {
+                keys = self.key_buckets.lock().unwrap();
+                keys.entry(key).or_insert_with(new)
+            }
+            Client::Ip(ip) => {
+                ips = self.ip_buckets.lock().unwrap();
+                ips.get_or_insert_mut(ip, new)
+            }
+        };
+        if bucket.day != day {
+            bucket.day = day;
+            bucket.today = 0;
+        }
+        if quota.per_day.is_some_and(|per_day| bucket.today >= per_day) {
+            let midnight = (day + 1) * 86400;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            return Err(Rejection::Limited(midnight.saturating_sub(now).max(1)));
+        }
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            if capacity == 0.0 {
+                return Err(Rejection::Limited(60));
+            }
+            let wait = (1.0 - bucket.tokens) * 60.0 / capacity;
+            return Err(Rejection::Limited(wait.ceil().max(1.0) as u64));
+        }
+        bucket.tokens -= 1.0;
+        bucket.today += 1;
+        Ok(())
+    }
+
+    fn check(&self, req: &Request<'_>) -> Result<(), Rejection> {
+        let (client, quota) = match req.headers().get_one(Self::KEY_HEADER) {
+            Some(key) => match self.keys.read().unwrap().get(key) {
+                Some(quota) => (Client::Key(key.to_string()), *quota),
+                None => return Err(Rejection::UnknownKey),
+            },
+            None => match req.client_ip() {
+                Some(ip) => (Client::Ip(ip), *self.anonymous.read().unwrap()),
+                // unix socket clients are local
+                None => return Ok(()),
+            },
+        };
+        let day = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 86400;
+        self.take(client, quota, Instant::now(), day)
+    }
+}
+
+struct Refused(Option<Rejection>);
+
+/// Applies the [`RateLimiter`] of the public gateway: refused requests are rerouted
+/// away from the handlers, and answered with `429 Too Many Requests` and a
+/// `Retry-After` header, or `401 Unauthorized` for unknown API keys.
+pub struct RateLimit(pub SharedRateLimiter);
+
+#[rocket::async_trait]
+impl Fairing for RateLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        if let Err(rejection) = self.0.check(req) {
+            req.local_cache(|| Refused(Some(rejection)));
+            req.set_uri(rocket::http::uri::Origin::parse("/.refused").unwrap());
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Refused(Some(rejection)) = req.local_cache(|| Refused(None)) else {
+            return;
+        };
+        let (status, code, error) = match rejection {
+            Rejection::UnknownKey => (
+                Status::Unauthorized,
+                ErrorCode::UnknownApiKey,
+                "unknown API key".to_string(),
+            ),
+            Rejection::Limited(retry) => {
+                res.set_raw_header("Retry-After", retry.to_string());
+                (
+                    Status::TooManyRequests,
+                    ErrorCode::RateLimited,
+                    format!("rate limit exceeded, retry in {} seconds", retry),
+                )
+            }
+        };
+        let body = rocket::serde::json::to_string(&ErrorDescription {
+            code,
+            error,
+            request_id: Some(RequestId::of(req).0.clone()),
+        })
+        .unwrap_or_default();
+        res.set_status(status);
+        res.set_header(ContentType::JSON);
+        res.set_sized_body(body.len(), std::io::Cursor::new(body));
+    }
+}
+
+#[get("/metrics")]
+pub async fn metrics(
+    sources: &State<Arc<SourceStats>>,
+    set: &State<SharedIndex<20, Address>>,
+    routes: &State<SharedRouteStats>,
+) -> Result<String, ResolveError> {
+    let mut out = String::new();
+    out.push_str("# HELP monique_source_addresses_total New addresses indexed per source\n");
+    out.push_str("# TYPE monique_source_addresses_total counter\n");
+    for (source, count) in sources.snapshot()? {
+        let _ = writeln!(
+            out,
+            "monique_source_addresses_total{{source=\"{}\"}} {}",
+            source.name(),
+            count
+        );
+    }
+    let metrics = set.metrics().await;
+    let seconds = |us: u64| us as f64 / 1e6;
+    for (name, kind, help, value) in [
+        (
+            "monique_queued_blocks_total",
+            "counter",
+            "Blocks queued",
+            metrics.queued_blocks as f64,
+        ),
+        (
+            "monique_queue_seconds_total",
+            "counter",
+            "Time spent queueing blocks",
+            seconds(metrics.queue_us),
+        ),
+        (
+            "monique_queued_items_total",
+            "counter",
+            "Addresses submitted to the queue",
+            metrics.queued_items as f64,
+        ),
+        (
+            "monique_duplicate_items_total",
+            "counter",
+            "Submitted addresses already pending or indexed",
+            metrics.duplicate_items as f64,
+        ),
+        (
+            "monique_commits_total",
+            "counter",
+            "Commits to storage",
+            metrics.commits as f64,
+        ),
+        (
+            "monique_committed_items_total",
+            "counter",
+            "Addresses committed to storage",
+            metrics.committed_items as f64,
+        ),
+        (
+            "monique_commit_prepare_seconds_total",
+            "counter",
+            "Time spent building checkpoint tries",
+            seconds(metrics.commit_prepare_us),
+        ),
+        (
+            "monique_commit_push_seconds_total",
+            "counter",
+            "Time spent writing committed blocks",
+            seconds(metrics.commit_push_us),
+        ),
+        (
+            "monique_pending_blocks",
+            "gauge",
+            "Blocks queued but not committed yet",
+            metrics.pending_blocks as f64,
+        ),
+        (
+            "monique_pending_items",
+            "gauge",
+            "Addresses of the pending blocks",
+            metrics.pending_items as f64,
+        ),
+        (
+            "monique_pending_bytes",
+            "gauge",
+            "Estimated memory held by the pending blocks",
+            metrics.pending_bytes as f64,
+        ),
+        (
+            "monique_spilled_blocks",
+            "gauge",
+            "Pending blocks moved to disk",
+            metrics.spilled_blocks as f64,
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    routes.write(&mut out);
+    Ok(out)
+}
+
+/// Error of the monics and indexes below the pivot.
+fn reserved_range(index: usize) -> ResolveError {
+    ResolveError::NotFound(Json(ErrorDescription::new(
+        ErrorCode::ReservedRange,
+        format!(
+            "index {} is reserved for mutable monics, stored entries start at {}",
+            index, PIVOT
+        ),
+    )))
+}
+
+/// Error of the pending entries looked up with `pending=false`.
+fn not_committed() -> ResolveError {
+    ResolveError::NotFound(Json(ErrorDescription::new(
+        ErrorCode::IndexNotCommitted,
+        "the entry is not committed yet",
+    )))
+}
+
+/// Item at a stored index and whether it is still pending. Pending items are an
+/// error unless `pending` is set: an index missing from the committed entries is then
+/// also looked up in the pending queue, under its lock, to tell the two errors apart.
+async fn get_item<const N: usize, T>(
+    stored_index: usize,
+    set: &SharedIndex<N, T>,
+    pending: bool,
+) -> Result<Option<(T, bool)>, ResolveError>
+where
+    T: AsRef<[u8]> + From<[u8; N]> + PartialEq + Hash + Eq + Copy + Send + Sync + 'static,
+    [u8; N]: From<T>,
+{
+    if let Some(item) = set.get_committed(stored_index).await? {
+        return Ok(Some((item, false)));
+    }
+    match set.get(stored_index).await? {
+        Some(_) if !pending => Err(not_committed()),
+        item => Ok(item.map(|item| (item, true))),
+    }
+}
+
+/// Stored index of an item and whether it is still pending. Pending items are an
+/// error unless `pending` is set: an item missing from the committed entries is then
+/// also looked up in the pending queue, under its lock, to tell the two errors apart.
+async fn index_item<const N: usize, T>(
+    item: T,
+    set: &SharedIndex<N, T>,
+    pending: bool,
+) -> Result<Option<(usize, bool)>, ResolveError>
+where
+    T: AsRef<[u8]> + From<[u8; N]> + PartialEq + Hash + Eq + Copy + Send + Sync + 'static,
+    [u8; N]: From<T>,
+{
+    if let Some(index) = set.index_committed(item).await? {
+        return Ok(Some((index, false)));
+    }
+    match set.index(item).await? {
+        Some(_) if !pending => Err(not_committed()),
+        index => Ok(index.map(|index| (index, true))),
+    }
+}
+
+/// Resolves a monic to its stored index and item, checking the checksum.
+async fn resolve_item<const N: usize, T>(
+    alias: &str,
+    set: &SharedIndex<N, T>,
+    pending: bool,
+) -> Result<Option<(usize, T, bool)>, ResolveError>
+where
+    T: AsRef<[u8]> + From<[u8; N]> + PartialEq + Hash + Eq + Copy + Send + Sync + 'static,
+    [u8; N]: From<T>,
+{
+    let (index, checksum) = words::to_index(alias.to_string())?;
+    if index < PIVOT {
+        return Err(reserved_range(index)); // TODO: get mutable monics from the contract
+    }
+    let stored_index = index - PIVOT;
+    let Some((item, pending)) = get_item(stored_index, set, pending).await? else {
+        return Ok(None);
+    };
+    if words::checksum(item) != checksum {
+        return Err(ResolveError::WrongChecksum(Json(ErrorDescription::new(
+            ErrorCode::WrongChecksum,
+            "wrong checksum",
+        ))));
+    }
+    Ok(Some((stored_index, item, pending)))
+}
+
+/// Resolves a monic to its address, checking the checksum. Pending entries are
+/// only returned when `pending` is set.
+pub async fn lookup_monic(
+    alias: &str,
+    set: &SharedIndex<20, Address>,
+    pending: bool,
+) -> Result<Option<AddressInfo>, ResolveError> {
+    let Some((stored_index, addr, pending)) = resolve_item(alias, set, pending).await? else {
+        return Ok(None);
+    };
+    Ok(Some(AddressInfo {
+        address: addr,
+        index: stored_index + PIVOT,
+        monic: alias.to_string(),
+        contract: set.is_contract(stored_index)?,
+        label: set.get_label(addr)?.map(LabelInfo::from),
+        ens: None,
+        appearance: set.appearance(stored_index)?.map(AppearanceInfo::from),
+        pending,
+        chain_id: set.chain_id()?,
+    }))
+}
+
+/// Looks up the address stored at a (pivoted) index. Pending entries are only
+/// returned when `pending` is set.
+pub async fn lookup_index(
+    index: usize,
+    set: &SharedIndex<20, Address>,
+    pending: bool,
+) -> Result<Option<AddressInfo>, ResolveError> {
+    if index < PIVOT {
+        return Err(reserved_range(index));
+    }
+    let Some((addr, pending)) = get_item(index - PIVOT, set, pending).await? else {
+        return Ok(None);
+    };
+    Ok(Some(AddressInfo {
+        address: addr,
+        index,
+        monic: words::to_words(index as u64, words::checksum(addr)),
+        contract: set.is_contract(index - PIVOT)?,
+        label: set.get_label(addr)?.map(LabelInfo::from),
+        ens: None,
+        appearance: set.appearance(index - PIVOT)?.map(AppearanceInfo::from),
+        pending,
+        chain_id: set.chain_id()?,
+    }))
+}
+
+/// Looks up the index and monic of an address. Pending entries are only returned
+/// when `pending` is set.
+pub async fn lookup_address(
+    address: &str,
+    set: &SharedIndex<20, Address>,
+    pending: bool,
+) -> Result<Option<AddressInfo>, ResolveError> {
+    let addr = Address::from_str(address)?;
+    let Some((index, pending)) = index_item(addr, set, pending).await? else {
+        return Ok(None);
+    };
+    Ok(Some(AddressInfo {
+        address: addr,
+        index: index + PIVOT,
+        monic: words::to_words((index + PIVOT) as u64, words::checksum(addr)),
+        contract: set.is_contract(index)?,
+        label: set.get_label(addr)?.map(LabelInfo::from),
+        ens: None,
+        appearance: set.appearance(index)?.map(AppearanceInfo::from),
+        pending,
+        chain_id: set.chain_id()?,
+    }))
+}
+
+/// Resolves a monic to its transaction hash, checking the checksum.
+pub async fn lookup_tx_monic(
+    alias: &str,
+    set: &SharedIndex<32, H256>,
+) -> Result<Option<TxInfo>, ResolveError> {
+    Ok(resolve_item(alias, set, true)
+        .await?
+        .map(|(stored_index, hash, _)| TxInfo {
+            hash,
+            index: stored_index + PIVOT,
+            monic: alias.to_string(),
+        }))
+}
+
+/// Looks up the transaction hash stored at a (pivoted) index.
+pub async fn lookup_tx_index(
+    index: usize,
+    set: &SharedIndex<32, H256>,
+) -> Result<Option<TxInfo>, ResolveError> {
+    if index < PIVOT {
+        return Err(reserved_range(index));
+    }
+    let res = set.get(index - PIVOT).await?;
+    Ok(res.map(|hash| TxInfo {
+        hash,
+        index,
+        monic: words::to_words(index as u64, words::checksum(hash)),
+    }))
+}
+
+/// Looks up the index and monic of a transaction hash.
+pub async fn lookup_tx_hash(
+    hash: &str,
+    set: &SharedIndex<32, H256>,
+) -> Result<Option<TxInfo>, ResolveError> {
+    let hash = H256::from_str(hash)?;
+    let index = set.index(hash).await?;
+    Ok(index.map(|index| TxInfo {
+        hash,
+        index: index + PIVOT,
+        monic: words::to_words((index + PIVOT) as u64, words::checksum(hash)),
+    }))
+}
+
+/// Adds the primary ENS name of the address, when ENS resolution is enabled.
+async fn with_ens(info: Option<AddressInfo>, ens: Option<&SharedEns>) -> Option<AddressInfo> {
+    let mut info = info?;
+    if let Some(ens) = ens {
+        info.ens = ens.lookup(info.address).await;
+    }
+    Some(info)
+}
+
+/// Resolves a monic through the response cache, with its ENS name.
+pub(crate) async fn resolve_info(
+    alias: &str,
+    pending: bool,
+    set: &SharedIndex<20, Address>,
+    ens: Option<&SharedEns>,
+    cache: Option<&SharedResponseCache>,
+) -> Result<Option<AddressInfo>, ResolveError> {
+    let info = match cache {
+        Some(cache) => {
+            let key = CacheKey::Monic(alias.to_string());
+            cache
+                .get_or_lookup(key, set, lookup_monic(alias, set, pending))
+                .await?
+        }
+        None => lookup_monic(alias, set, pending).await?,
+    };
+    Ok(with_ens(info, ens).await)
+}
+
+/// Looks up an address through the response cache, with its ENS name.
+pub(crate) async fn alias_info(
+    address: &str,
+    pending: bool,
+    set: &SharedIndex<20, Address>,
+    ens: Option<&SharedEns>,
+    cache: Option<&SharedResponseCache>,
+) -> Result<Option<AddressInfo>, ResolveError> {
+    let info = match cache {
+        Some(cache) => {
+            let key = CacheKey::Address(Address::from_str(address)?);
+            cache
+                .get_or_lookup(key, set, lookup_address(address, set, pending))
+                .await?
+        }
+        None => lookup_address(address, set, pending).await?,
+    };
+    Ok(with_ens(info, ens).await)
+}
+
+/// `?pending=false` restricts the lookups to the committed entries, which are never
+/// rolled back. Pending entries are included by default.
+#[get("/resolve/<alias>?<pending>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, alias = alias, pending = ?pending))]
+pub async fn resolve(
+    request_id: &RequestId,
+    alias: &str,
+    pending: Option<bool>,
+    set: &State<SharedIndex<20, Address>>,
+    ens: &State<Option<SharedEns>>,
+    cache: &State<Option<SharedResponseCache>>,
+) -> ApiResponse {
+    let pending = pending.unwrap_or(true);
+    let info = resolve_info(alias, pending, set, ens.as_ref(), cache.as_ref()).await?;
+    Ok(info.map(Negotiated))
+}
+
+/// Pixels per module of the QR codes.
+const QR_SCALE: usize = 8;
+
+/// SVG QR code of an entry, holding the EIP-681 URI of its address with its monic,
+/// e.g. `ethereum:0x5aAe...eAed?monic=abandon+ability`.
+fn qr_code(info: &AddressInfo) -> Result<(ContentType, String), ResolveError> {
+    let text = format!(
+        "ethereum:{}?monic={}",
+        ethers::utils::to_checksum(&info.address, None),
+        info.monic.replace(' ', "+")
+    );
+    let code = qr::QrCode::encode(&text).ok_or_else(|| {
+        ResolveError::Internal(Json(ErrorDescription::new(
+            ErrorCode::EntryTooLong,
+            "entry too long for a QR code",
+        )))
+    })?;
+    Ok((ContentType::SVG, code.to_svg(QR_SCALE)))
+}
+
+#[get("/resolve/<alias>/qr.svg?<pending>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, alias = alias, pending = ?pending))]
+pub async fn resolve_qr(
+    request_id: &RequestId,
+    alias: &str,
+    pending: Option<bool>,
+    set: &State<SharedIndex<20, Address>>,
+    cache: &State<Option<SharedResponseCache>>,
+) -> Result<Option<(ContentType, String)>, ResolveError> {
+    let pending = pending.unwrap_or(true);
+    let info = resolve_info(alias, pending, set, None, cache.as_ref()).await?;
+    info.as_ref().map(qr_code).transpose()
+}
+
+#[get("/alias/<address>/qr.svg?<pending>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, address = %address, pending = ?pending))]
+pub async fn alias_qr(
+    request_id: &RequestId,
+    address: String,
+    pending: Option<bool>,
+    set: &State<SharedIndex<20, Address>>,
+    cache: &State<Option<SharedResponseCache>>,
+) -> Result<Option<(ContentType, String)>, ResolveError> {
+    let pending = pending.unwrap_or(true);
+    let info = alias_info(&address, pending, set, None, cache.as_ref()).await?;
+    info.as_ref().map(qr_code).transpose()
+}
+
+/// Markup of the `/card` pages, whose `{{name}}` placeholders are filled by [`render`].
+const CARD_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8">
+<meta name="viewport" content="width=device-width">
+<title>{{monic}}</title>
+<meta property="og:type" content="website">
+<meta property="og:title" content="{{monic}}">
+<meta property="og:description" content="{{description}}">
+<meta name="twitter:card" content="summary">
+</head><body>
+<h1>{{monic}}</h1>
+<p><code>{{address}}</code></p>
+<p>Index {{index}}, {{status}}</p>
+<img src="/resolve/{{path}}/qr.svg" alt="QR code of {{monic}}" width="256">
+</body></html>
+"#;
+
+/// Fills the `{{name}}` placeholders of `template` with the HTML-escaped values.
+fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut html = template.to_string();
+    for (name, value) in values {
+        let escaped = value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&#39;");
+        html = html.replace(&format!("{{{{{}}}}}", name), &escaped);
+    }
+    html
+}
+
+/// Page of a monic for link previews: its address, index and checkpoint in the
+/// OpenGraph tags, and its QR code.
+#[get("/card/<alias>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, alias = alias))]
+pub async fn card(
+    request_id: &RequestId,
+    alias: &str,
+    set: &State<SharedIndex<20, Address>>,
+    cache: &State<Option<SharedResponseCache>>,
+) -> Result<Option<(ContentType, String)>, ResolveError> {
+    let Some(info) = resolve_info(alias, true, set, None, cache.as_ref()).await? else {
+        return Ok(None);
+    };
+    let checkpoint = match set.committed_block(info.index - PIVOT).await? {
+        Some(block) => set.checkpoint(block)?,
+        None => None,
+    };
+    let address = ethers::utils::to_checksum(&info.address, None);
+    let monic = words::to_words(info.index as u64, words::checksum(info.address));
+    let status = match (&checkpoint, info.pending) {
+        (Some(checkpoint), _) => format!(
+            "committed in block {}, checkpoint {:?}",
+            checkpoint.block, checkpoint.hash
+        ),
+        (None, true) => "pending, not committed yet".to_string(),
+        (None, false) => "committed".to_string(),
+    };
+    let description = format!("{} · index {} · {}", address, info.index, status);
+    let html = render(
+        CARD_TEMPLATE,
+        &[
+            ("monic", monic.as_str()),
+            ("description", description.as_str()),
+            ("address", address.as_str()),
+            ("index", info.index.to_string().as_str()),
+            ("status", status.as_str()),
+            ("path", monic.replace(' ', "%20").as_str()),
+        ],
+    );
+    Ok(Some((ContentType::HTML, html)))
+}
+
+#[get("/index/<index>?<pending>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, index = index, pending = ?pending))]
+pub async fn index(
+    request_id: &RequestId,
+    index: usize,
+    pending: Option<bool>,
+    set: &State<SharedIndex<20, Address>>,
+) -> ApiResponse {
+    let pending = pending.unwrap_or(true);
+    Ok(lookup_index(index, set, pending).await?.map(Negotiated))
+}
+
+#[get("/alias/<address>?<pending>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, address = %address, pending = ?pending))]
+pub async fn alias(
+    request_id: &RequestId,
+    address: String,
+    pending: Option<bool>,
+    set: &State<SharedIndex<20, Address>>,
+    ens: &State<Option<SharedEns>>,
+    cache: &State<Option<SharedResponseCache>>,
+) -> ApiResponse {
+    let pending = pending.unwrap_or(true);
+    let info = alias_info(&address, pending, set, ens.as_ref(), cache.as_ref()).await?;
+    Ok(info.map(Negotiated))
+}
+
+#[get("/tx/resolve/<alias>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, alias = alias))]
+pub async fn tx_resolve(
+    request_id: &RequestId,
+    alias: &str,
+    set: &State<SharedIndex<32, H256>>,
+) -> TxResponse {
+    Ok(lookup_tx_monic(alias, set).await?.map(Negotiated))
+}
+
+#[get("/tx/index/<index>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, index = index))]
+pub async fn tx_index(
+    request_id: &RequestId,
+    index: usize,
+    set: &State<SharedIndex<32, H256>>,
+) -> TxResponse {
+    Ok(lookup_tx_index(index, set).await?.map(Negotiated))
+}
+
+#[get("/tx/alias/<hash>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id, hash = hash))]
+pub async fn tx_alias(
+    request_id: &RequestId,
+    hash: &str,
+    set: &State<SharedIndex<32, H256>>,
+) -> TxResponse {
+    Ok(lookup_tx_hash(hash, set).await?.map(Negotiated))
+}
+
+/// Optional route groups of a server.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RouteSet {
+    /// Public gateway, which serves neither the admin and watchlist routes nor the
+    /// expensive ones, whatever the other fields.
+    pub gateway: bool,
+    pub admin: bool,
+    pub watchlists: bool,
+    pub transactions: bool,
+}
+
+/// Mounts the routes of the API, and the optional ones of `set`. The state they read
+/// is managed by the caller.
+pub fn mount_routes(server: Rocket<Build>, set: RouteSet) -> Rocket<Build> {
+    let mut server = server.mount(
+        "/",
+        rocket::routes![
+            index,
+            resolve,
+            stats,
+            stats_history,
+            alias,
+            resolve_qr,
+            alias_qr,
+            card,
+            status,
+            checkpoint,
+            checkpoint_signature,
+            recent,
+            index_at,
+            proof
+        ],
+    );
+    if set.transactions {
+        server = server.mount("/", rocket::routes![tx_index, tx_resolve, tx_alias]);
+    }
+    if set.gateway {
+        return server;
+    }
+    server = server.mount(
+        "/",
+        rocket::routes![
+            metrics,
+            blocks,
+            block_counts,
+            search,
+            proofs,
+            crate::rpc::rpc
+        ],
+    );
+    if set.admin {
+        server = server.mount(
+            "/",
+            rocket::routes![
+                admin_pause,
+                admin_resume,
+                admin_commit,
+                admin_rollback,
+                admin_promote,
+                admin_loglevel,
+                admin_tombstones,
+                label_set,
+                label_remove
+            ],
+        );
+    }
+    if set.watchlists {
+        server = server.mount(
+            "/",
+            rocket::routes![watchlist_status, watchlist_register, watchlist_remove],
+        );
+    }
+    server
+}
+
+/// Serves `rocket` over a unix domain socket at `path`, replacing any stale socket file.
+///
+/// Rocket 0.5 can only bind TCP listeners, so connections are accepted here and each
+/// request is dispatched to the application through a local client.
+#[cfg(unix)]
+pub async fn serve_unix(rocket: Rocket<Build>, path: &std::path::Path) -> crate::Result<()> {
+    use rocket::http::hyper::{server::conn::Http, service::service_fn};
+    use rocket::local::asynchronous::Client;
+
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    let client = Client::untracked(rocket)
+        .await
+        .map_err(|e| MoniqueError::Server(Box::new(e)))?;
+    let client = Arc::new(client);
+    tracing::info!(path = %path.display(), "API listening on unix socket");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let client = client.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| dispatch(client.clone(), req));
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                tracing::warn!("unix socket connection error: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn dispatch(
+    client: Arc<rocket::local::asynchronous::Client>,
+    req: rocket::http::hyper::Request<rocket::http::hyper::Body>,
+) -> Result<rocket::http::hyper::Response<rocket::http::hyper::Body>, rocket::http::hyper::Error> {
+    use rocket::http::{hyper, Header, Method};
+
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let Ok(method) = Method::from_str(parts.method.as_str()) else {
+        return Ok(hyper::Response::builder()
+            .status(405)
+            .body(hyper::Body::empty())
+            .unwrap());
+    };
+    let uri = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    let mut request = client.req(method, uri).body(body);
+    for (name, value) in parts.headers.iter() {
+        if let Ok(value) = value.to_str() {
+            request.add_header(Header::new(name.as_str().to_string(), value.to_string()));
+        }
+    }
+
+    let response = request.dispatch().await;
+    let mut builder = hyper::Response::builder().status(response.status().code);
+    for header in response.headers().iter() {
+        builder = builder.header(header.name().as_str(), header.value());
+    }
+    let bytes = response.into_bytes().await.unwrap_or_default();
+    Ok(builder.body(hyper::Body::from(bytes)).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepted_encoding() {
+        assert_eq!(
+            Encoding::accepted("gzip, deflate, br"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(Encoding::accepted("deflate, gzip"), Some(Encoding::Deflate));
+        assert_eq!(
+            Encoding::accepted("gzip;q=0.5, deflate;q=0.8"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(Encoding::accepted("gzip;q=0, br"), None);
+        assert_eq!(Encoding::accepted("identity"), None);
+    }
+
+    #[get("/sized/<len>")]
+    fn sized(len: usize) -> String {
+        "a".repeat(len)
+    }
+
+    #[get("/streamed")]
+    fn streamed() -> rocket::response::stream::TextStream![String] {
+        rocket::response::stream::TextStream! {
+            for i in 0..1000 {
+                yield format!("chunk {}\n", i);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn compression() {
+        use rocket::{local::asynchronous::Client, routes};
+        use std::io::Read;
+
+        let rocket = rocket::build()
+            .mount("/", routes![sized, streamed])
+            .attach(Compress);
+        let client = Client::tracked(rocket).await.unwrap();
+        let get = |uri: &'static str| {
+            client
+                .get(uri)
+                .header(rocket::http::Header::new("Accept-Encoding", "gzip"))
+        };
+        let gunzip = |body: Vec<u8>| {
+            let mut decoded = String::new();
+            flate2::read::GzDecoder::new(&body[..])
+                .read_to_string(&mut decoded)
+                .unwrap();
+            decoded
+        };
+
+        // small bodies are sent as is
+        let response = get("/sized/10").dispatch().await;
+        assert_eq!(response.headers().get_one("Content-Encoding"), None);
+        assert_eq!(response.into_string().await.unwrap(), "a".repeat(10));
+
+        let response = get("/sized/4096").dispatch().await;
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+        let body = response.into_bytes().await.unwrap();
+        assert!(body.len() < 4096);
+        assert_eq!(gunzip(body), "a".repeat(4096));
+
+        // streamed bodies, of unknown size, are compressed as they are streamed
+        let response = get("/streamed").dispatch().await;
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+        let expected: String = (0..1000).map(|i| format!("chunk {}\n", i)).collect();
+        assert_eq!(gunzip(response.into_bytes().await.unwrap()), expected);
+
+        // without Accept-Encoding
+        let response = client.get("/streamed").dispatch().await;
+        assert_eq!(response.headers().get_one("Content-Encoding"), None);
+        assert_eq!(response.into_string().await.unwrap(), expected);
+    }
+
+    #[test]
+    fn history_window() {
+        assert_eq!(parse_window("90").ok(), Some(90));
+        assert_eq!(parse_window("90s").ok(), Some(90));
+        assert_eq!(parse_window("30m").ok(), Some(1800));
+        assert_eq!(parse_window("24h").ok(), Some(86400));
+        assert_eq!(parse_window("7d").ok(), Some(604800));
+        for window in ["", "h", "0h", "-1h", "1w", "1.5h", "99999999999999999d"] {
+            assert!(parse_window(window).is_err(), "{}", window);
+        }
+    }
+
+    #[test]
+    fn history_sparkline() {
+        assert_eq!(sparkline([].into_iter()), "");
+        assert_eq!(sparkline([5.0, 5.0].into_iter()), "▁▁");
+        assert_eq!(sparkline([0.0, 1.0, 7.0, 3.5].into_iter()), "▁▂█▅");
+    }
+
+    #[test]
+    fn search_prefix() {
+        let nibbles = parse_prefix("0xAb1").ok().unwrap();
+        assert_eq!(nibbles, vec![0xa, 0xb, 0x1]);
+        let address = Address::from_str("0xab12000000000000000000000000000000000000").unwrap();
+        assert!(has_prefix(&address, &nibbles));
+        assert!(has_prefix(&address, &parse_prefix("ab12").ok().unwrap()));
+        assert!(!has_prefix(&address, &parse_prefix("0xab13").ok().unwrap()));
+        assert!(!has_prefix(&address, &parse_prefix("0xb").ok().unwrap()));
+        assert!(parse_prefix("0x").is_err());
+        assert!(parse_prefix("0xzz").is_err());
+        assert!(parse_prefix(&"a".repeat(41)).is_err());
+    }
+
+    #[test]
+    fn route_histograms() {
+        let stats = RouteStats::default();
+        stats.record("/resolve/<alias>".into(), 200, Duration::from_micros(300));
+        stats.record("/resolve/<alias>".into(), 200, Duration::from_millis(20));
+        stats.record("/alias/<address>".into(), 400, Duration::from_secs(3));
+        let mut out = String::new();
+        stats.write(&mut out);
+        let lines: HashSet<&str> = out.lines().collect();
+        let resolve = "route=\"/resolve/<alias>\",status=\"200\"";
+        for (le, count) in [("0.0005", 1), ("0.025", 2), ("+Inf", 2)] {
+            let line = format!(
+                "monique_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                resolve, le, count
+            );
+            assert!(lines.contains(line.as_str()), "{}", line);
+        }
+        let alias = "route=\"/alias/<address>\",status=\"400\"";
+        for line in [
+            format!(
+                "monique_http_request_duration_seconds_bucket{{{},le=\"2.5\"}} 0",
+                alias
+            ),
+            format!(
+                "monique_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 1",
+                alias
+            ),
+            format!("monique_http_request_duration_seconds_count{{{}}} 1", alias),
+        ] {
+            assert!(lines.contains(line.as_str()), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn request_ids() {
+        use rocket::{catchers, http::Header, local::asynchronous::Client};
+
+        let rocket = rocket::build()
+            .attach(RequestTracing)
+            .register("/", catchers![not_found]);
+        let client = Client::tracked(rocket).await.unwrap();
+        let response = client
+            .get("/missing")
+            .header(Header::new("X-Request-Id", "abc-123"))
+            .dispatch()
+            .await;
+        assert_eq!(response.headers().get_one("X-Request-Id"), Some("abc-123"));
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["request_id"], "abc-123");
+
+        // invalid IDs are replaced
+        let response = client
+            .get("/missing")
+            .header(Header::new("X-Request-Id", "not valid"))
+            .dispatch()
+            .await;
+        let id = response
+            .headers()
+            .get_one("X-Request-Id")
+            .unwrap()
+            .to_string();
+        assert_eq!(id.len(), 32);
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["request_id"], id.as_str());
+    }
+
+    #[test]
+    fn api_keys() {
+        let default = Quota {
+            per_minute: 600,
+            per_day: None,
+        };
+        let limiter = RateLimiter::new(default)
+            .with_keys(
+                "# partners\nalpha\n\nbeta 60 # comment\ngamma 10 1000\n",
+                default,
+            )
+            .unwrap();
+        {
+            let keys = limiter.keys.read().unwrap();
+            assert_eq!(keys["alpha"], default);
+            assert_eq!(keys["beta"].per_minute, 60);
+            assert_eq!(
+                keys["gamma"],
+                Quota {
+                    per_minute: 10,
+                    per_day: Some(1000)
+                }
+            );
+            assert_eq!(keys.len(), 3);
+        }
+        for keys in ["alpha fast", "alpha 1 2 3", "alpha 1 -1"] {
+            assert!(RateLimiter::new(default).with_keys(keys, default).is_err());
+        }
+
+        // a reload replaces the quotas, and keeps them when the keys are invalid
+        let start = Instant::now();
+        let beta = || Client::Key("beta".to_string());
+        let gamma = || Client::Key("gamma".to_string());
+        limiter.take(beta(), default, start, 0).unwrap();
+        limiter.take(gamma(), default, start, 0).unwrap();
+        let slow = Quota {
+            per_minute: 6,
+            per_day: None,
+        };
+        limiter.reload(slow, "alpha\nbeta 30\n", slow).unwrap();
+        assert_eq!(*limiter.anonymous.read().unwrap(), slow);
+        {
+            let keys = limiter.keys.read().unwrap();
+            assert_eq!(keys["alpha"], slow);
+            assert_eq!(keys["beta"].per_minute, 30);
+            assert!(!keys.contains_key("gamma"));
+        }
+        let buckets = limiter.key_buckets.lock().unwrap();
+        assert!(buckets.contains_key("beta") && !buckets.contains_key("gamma"));
+        drop(buckets);
+        assert!(limiter.reload(default, "alpha fast", default).is_err());
+        assert_eq!(limiter.keys.read().unwrap()["beta"].per_minute, 30);
+    }
+
+    #[test]
+    fn token_buckets() {
+        let quota = Quota {
+            per_minute: 6,
+            per_day: Some(8),
+        };
+        let limiter = RateLimiter::new(quota);
+        let client = || Client::Key("alpha".to_string());
+        let start = Instant::now();
+        for _ in 0..6 {
+            assert_eq!(limiter.take(client(), quota, start, 0), Ok(()));
+        }
+        // one token every 10 seconds
+        assert_eq!(
+            limiter.take(client(), quota, start, 0),
+            Err(Rejection::Limited(10))
+        );
+        let later = start + Duration::from_secs(5);
+        assert_eq!(
+            limiter.take(client(), quota, later, 0),
+            Err(Rejection::Limited(5))
+        );
+        let later = start + Duration::from_secs(20);
+        assert_eq!(limiter.take(client(), quota, later, 0), Ok(()));
+        assert_eq!(limiter.take(client(), quota, later, 0), Ok(()));
+        // daily quota reached
+        let later = start + Duration::from_secs(600);
+        assert!(matches!(
+            limiter.take(client(), quota, later, 0),
+            Err(Rejection::Limited(_))
+        ));
+        // and reset the next day
+        assert_eq!(limiter.take(client(), quota, later, 1), Ok(()));
+        // clients have their own buckets
+        let other = Client::Ip(std::net::Ipv4Addr::LOCALHOST.into());
+        assert_eq!(limiter.take(other, quota, start, 0), Ok(()));
+    }
+
+    #[test]
+    fn gateway_routes() {
+        let paths = |set| -> Vec<String> {
+            mount_routes(rocket::build(), set)
+                .routes()
+                .map(|route| route.uri.path().to_string())
+                .collect()
+        };
+        let all = RouteSet {
+            gateway: false,
+            admin: true,
+            watchlists: true,
+            transactions: true,
+        };
+        let full = paths(all);
+        let gateway = paths(RouteSet {
+            gateway: true,
+            ..all
+        });
+        for hidden in [
+            "/rpc",
+            "/search",
+            "/blocks",
+            "/proofs",
+            "/metrics",
+            "/admin/",
+            "/label/",
+            "/watchlist/",
+        ] {
+            assert!(
+                full.iter().any(|path| path.starts_with(hidden)),
+                "{}",
+                hidden
+            );
+            assert!(
+                !gateway.iter().any(|path| path.starts_with(hidden)),
+                "{}",
+                hidden
+            );
+        }
+        for served in [
+            "/resolve/<alias>",
+            "/alias/<address>",
+            "/checkpoint/<block>",
+        ] {
+            assert!(gateway.iter().any(|path| path == served), "{}", served);
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        use rocket::{catchers, http::Header, local::asynchronous::Client};
+
+        let anonymous = Quota {
+            per_minute: 2,
+            per_day: None,
+        };
+        let limiter = RateLimiter::new(anonymous)
+            .with_keys("alpha 100 1", anonymous)
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(RateLimit(Arc::new(limiter)))
+            .register("/", catchers![not_found]);
+        let client = Client::tracked(rocket).await.unwrap();
+        let remote: std::net::SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        for _ in 0..2 {
+            let response = client.get("/missing").remote(remote).dispatch().await;
+            assert_eq!(response.status(), Status::NotFound);
+        }
+        let response = client.get("/missing").remote(remote).dispatch().await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let retry: u64 = response
+            .headers()
+            .get_one("Retry-After")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=30).contains(&retry));
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert!(body["error"].as_str().unwrap().starts_with("rate limit"));
+
+        // other addresses and API keys have their own limits
+        let other: std::net::SocketAddr = "192.0.2.2:4000".parse().unwrap();
+        let response = client.get("/missing").remote(other).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        let key = || Header::new("X-Api-Key", "alpha");
+        let response = client
+            .get("/missing")
+            .remote(remote)
+            .header(key())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .get("/missing")
+            .remote(remote)
+            .header(key())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+
+        let response = client
+            .get("/missing")
+            .header(Header::new("X-Api-Key", "beta"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn affordable_blocks() {
+        let counts = [(10, 4), (11, 0), (12, 3), (14, 5)];
+        assert_eq!(last_affordable_block(&counts, 10, 15, 100), 15);
+        assert_eq!(last_affordable_block(&counts, 10, 15, 7), 13);
+        assert_eq!(last_affordable_block(&counts, 10, 15, 6), 11);
+        // the first block is returned whatever its size
+        assert_eq!(last_affordable_block(&counts, 10, 15, 1), 10);
+        assert_eq!(last_affordable_block(&[], 10, 15, 1), 15);
+    }
+
+    #[get("/expensive")]
+    fn expensive(_expensive: Expensive) -> &'static str {
+        "done"
+    }
+
+    #[tokio::test]
+    async fn expensive_requests() {
+        use rocket::{catchers, local::asynchronous::Client, routes};
+
+        let build = |max_concurrent| {
+            let limits = CostLimits::new(10, Duration::from_secs(1), max_concurrent);
+            rocket::build()
+                .manage(Arc::new(limits))
+                .mount("/", routes![expensive])
+                .register("/", catchers![too_many_requests])
+        };
+        let client = Client::tracked(build(1)).await.unwrap();
+        // the slot is released with the response
+        for _ in 0..2 {
+            let response = client.get("/expensive").dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+        let client = Client::tracked(build(0)).await.unwrap();
+        let response = client.get("/expensive").dispatch().await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(response.headers().get_one("Retry-After"), Some("1"));
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert!(body["error"].as_str().unwrap().starts_with("too many"));
+    }
+
+    /// Label of the cached response of `address`.
+    async fn cached_label(
+        address: Address,
+        set: &SharedIndex<20, Address>,
+        cache: &SharedResponseCache,
+    ) -> Option<Option<String>> {
+        let address = format!("{:#x}", address);
+        let info = alias_info(&address, true, set, None, Some(cache))
+            .await
+            .ok()
+            .unwrap();
+        info.map(|info| info.label.map(|label| label.label))
+    }
+
+    #[tokio::test]
+    async fn response_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let set: SharedIndex<20, Address> = Arc::new(
+            crate::index::IndexTable::builder(dir.path())
+                .build()
+                .await
+                .unwrap(),
+        );
+        let (committed, pending) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        set.queue(1, vec![committed]).await.unwrap();
+        set.commit(1).await.unwrap();
+        set.queue(2, vec![pending]).await.unwrap();
+        let label = |address: Address, text: &str| {
+            let label = Label {
+                label: text.to_string(),
+                source: "test".to_string(),
+            };
+            set.set_labels(vec![(address, Some(label))]).unwrap();
+        };
+        let cache = Arc::new(ResponseCache::new(100, Duration::from_millis(200)));
+        tokio::spawn(cache.clone().follow_rollbacks(set.subscribe_rollbacks()));
+
+        // hit: the label set after the first lookup is not read
+        label(committed, "first");
+        assert_eq!(
+            cached_label(committed, &set, &cache).await,
+            Some(Some("first".into()))
+        );
+        label(committed, "second");
+        assert_eq!(
+            cached_label(committed, &set, &cache).await,
+            Some(Some("first".into()))
+        );
+        cache.invalidate(committed);
+        assert_eq!(
+            cached_label(committed, &set, &cache).await,
+            Some(Some("second".into()))
+        );
+
+        // pending entries are looked up every time
+        label(pending, "first");
+        assert_eq!(
+            cached_label(pending, &set, &cache).await,
+            Some(Some("first".into()))
+        );
+        label(pending, "second");
+        assert_eq!(
+            cached_label(pending, &set, &cache).await,
+            Some(Some("second".into()))
+        );
+
+        // expired responses are looked up again
+        label(committed, "third");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(
+            cached_label(committed, &set, &cache).await,
+            Some(Some("third".into()))
+        );
+
+        // a rollback drops the responses of the removed entries
+        set.rollback(0).await.unwrap();
+        while cache.entries.contains_key(&CacheKey::Address(committed)) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(cached_label(committed, &set, &cache).await, None);
+    }
+
+    /// Client of the default routes, serving an index whose first entry,
+    /// `Address::repeat_byte(1)`, is committed. The optional state is managed as
+    /// `None`, as by `monique serve` without ENS resolution nor response cache.
+    async fn client(dir: &std::path::Path) -> rocket::local::asynchronous::Client {
+        let set: SharedIndex<20, Address> = Arc::new(
+            crate::index::IndexTable::builder(dir)
+                .build()
+                .await
+                .unwrap(),
+        );
+        set.queue(1, vec![Address::repeat_byte(1)]).await.unwrap();
+        set.commit(1).await.unwrap();
+        let cost_limits: SharedCostLimits =
+            Arc::new(CostLimits::new(1_000, Duration::from_secs(1), 1));
+        let rocket = mount_routes(rocket::build(), RouteSet::default())
+            .manage(None::<SharedEns>)
+            .manage(None::<SharedResponseCache>)
+            .manage(Arc::new(SourceStats::new(set.clone())))
+            .manage(set)
+            .manage(crate::indexer::status::channel().1)
+            .manage(SharedRouteStats::default())
+            .manage(cost_limits);
+        rocket::local::asynchronous::Client::tracked(rocket)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn launch() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client(dir.path()).await;
+        let address = format!("{:#x}", Address::repeat_byte(1));
+        let response = client.get(format!("/alias/{}", address)).dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::Ok);
+    }
+
+    #[tokio::test]
+    async fn qr_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client(dir.path()).await;
+        let address = Address::repeat_byte(1);
+        let monic = words::to_words(PIVOT as u64, words::checksum(address));
+        let text = format!(
+            "ethereum:{}?monic={}",
+            ethers::utils::to_checksum(&address, None),
+            monic.replace(' ', "+")
+        );
+        let expected = qr::QrCode::encode(&text).unwrap().to_svg(QR_SCALE);
+
+        for path in [
+            format!("/alias/{:#x}/qr.svg", address),
+            format!("/resolve/{}/qr.svg", monic.replace(' ', "%20")),
+        ] {
+            let response = client.get(&path).dispatch().await;
+            assert_eq!(response.status(), Status::Ok, "{}", path);
+            assert_eq!(response.content_type(), Some(ContentType::SVG), "{}", path);
+            assert_eq!(response.into_string().await.unwrap(), expected, "{}", path);
+        }
+
+        let unknown = format!("/alias/{:#x}/qr.svg", Address::repeat_byte(2));
+        let response = client.get(unknown).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.get("/alias/0x12/qr.svg").dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn card_route() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client(dir.path()).await;
+        let address = Address::repeat_byte(1);
+        let monic = words::to_words(PIVOT as u64, words::checksum(address));
+        let response = client
+            .get(format!("/card/{}", monic.replace(' ', "%20")))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let html = response.into_string().await.unwrap();
+        assert!(html.contains(r#"<meta property="og:type" content="website">"#));
+        let title = format!(r#"<meta property="og:title" content="{}">"#, monic);
+        assert!(html.contains(&title), "{}", html);
+        let description = format!(
+            r#"<meta property="og:description" content="{} · index {} · committed in block 1, checkpoint 0x"#,
+            ethers::utils::to_checksum(&address, None),
+            PIVOT
+        );
+        assert!(html.contains(&description), "{}", html);
+        assert!(!html.contains("{{"), "{}", html);
+
+        // values are escaped
+        assert_eq!(
+            render("<p title=\"{{x}}\">{{x}}</p>", &[("x", "<a href='#'>&\"")]),
+            "<p title=\"&lt;a href=&#39;#&#39;&gt;&amp;&quot;\">&lt;a href=&#39;#&#39;&gt;&amp;&quot;</p>"
+        );
+    }
+
+    #[post("/proofs", data = "<request>")]
+    fn proofs(request: Json<ProofsRequest>) -> String {
+        request.indexes.len().to_string()
+    }
+
+    #[tokio::test]
+    async fn error_codes() {
+        use rocket::{catchers, local::asynchronous::Client, routes};
+
+        let code = |code: ErrorCode| serde_json::to_value(code).unwrap();
+        assert_eq!(code(ErrorCode::WrongChecksum), "WRONG_CHECKSUM");
+        assert_eq!(code(ErrorCode::IndexNotCommitted), "INDEX_NOT_COMMITTED");
+        assert_eq!(code(ErrorCode::ReservedRange), "RESERVED_RANGE");
+        let error = ResolveError::from(MoniqueError::Words(words::WordError));
+        assert_eq!(error.code(), ErrorCode::UnknownWord);
+        let error = ResolveError::from(Address::from_str("0x12").unwrap_err());
+        assert_eq!(error.code(), ErrorCode::InvalidAddress);
+        assert_eq!(reserved_range(1).code(), ErrorCode::ReservedRange);
+        let dir = tempfile::tempdir().unwrap();
+        let transactions: SharedIndex<32, H256> = Arc::new(
+            crate::index::IndexTable::builder(dir.path().join("tx"))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let Err(error) = lookup_tx_index(1, &transactions).await else {
+            panic!("a reserved index was looked up");
+        };
+        assert_eq!(error.code(), ErrorCode::ReservedRange);
+
+        let rocket = rocket::build()
+            .mount("/", routes![proofs])
+            .register("/", catchers![not_found, default_error]);
+        let client = Client::tracked(rocket).await.unwrap();
+        let response = client.get("/missing").dispatch().await;
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["code"], "NOT_FOUND");
+        // bodies failing to parse are answered by the default catcher
+        let response = client.post("/proofs").body("{").dispatch().await;
+        assert!(response.status().class().is_client_error());
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["code"], "BAD_REQUEST");
+        assert!(body["request_id"].is_string());
+    }
 }
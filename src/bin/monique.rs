@@ -1,118 +1,2339 @@
-use clap::{arg, command, Command};
+use clap::{arg, command, ArgAction, ArgMatches, Command};
 use ethers::{
-    providers::{Provider, Ws},
-    types::Address,
+    providers::{Http, Middleware, Provider},
+    signers::{LocalWallet, Signer},
+    types::{Address, Bytes, Signature, H256},
 };
-use log::{error, warn};
-use monique::index::SharedIndex;
-use monique::indexer::Indexer;
-use monique::Result;
-use monique::{api, index::IndexTable};
-use rocket::{catchers, routes, Config};
+use monique::api::{
+    CostLimits, Quota, RateLimiter, ResponseCache, SharedCostLimits, SharedRateLimiter,
+    SharedResponseCache, SharedRouteStats,
+};
+#[cfg(feature = "encryption")]
+use monique::encryption;
+use monique::ens::{EnsResolver, SharedEns};
+use monique::follower::Follower;
+use monique::index::{
+    BenchOptions, BucketReport, Checkpoint, DiskUsage, Envelope, HistorySample, Indexed, Label,
+    Plain, SharedIndex, Space, MAP_USAGE_WARNING,
+};
+use monique::indexer::{
+    align_tables, check_rollback, control,
+    network::{Genesis, Network},
+    providers, ruleset,
+    sources::SourceStats,
+    status::{self, IndexerState, IndexerStatus, StatusReceiver},
+    Indexer, PendingPolicy,
+};
+use monique::watchlist::{SharedWatchlists, Watchlists};
+use monique::webhooks::{self, WebhookConfig};
+use monique::{api, index::IndexTable, verify, words, MoniqueError};
+use rocket::{catchers, Config};
+use serde::Deserialize;
 use std::{
     clone::Clone,
     env,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
     net::{IpAddr, Ipv4Addr},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry,
 };
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+fn init_logging(format: &str, otlp_endpoint: Option<&String>) -> Result<LogHandle> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = reload::Layer::new(filter);
+    let fmt = match format {
+        "json" => tracing_subscriber::fmt::layer().json().boxed(),
+        _ => tracing_subscriber::fmt::layer().boxed(),
+    };
+    let registry = tracing_subscriber::registry().with(filter).with(fmt);
+
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = otlp_endpoint {
+        use opentelemetry_otlp::WithExportConfig;
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+        registry
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .init();
+        return Ok(handle);
+    }
+    #[cfg(not(feature = "otlp"))]
+    let _ = otlp_endpoint;
+
+    registry.init();
+    Ok(handle)
+}
 
+fn set_log_filter(handle: &LogHandle, directives: &str) -> std::result::Result<(), String> {
+    let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+    handle.reload(filter).map_err(|e| e.to_string())?;
+    info!(directives, "log filter changed");
+    Ok(())
+}
+
+/// Settings of `monique run` that can change without a restart, read from the
+/// `--config` file at startup and on SIGHUP. Missing settings are left unchanged.
+#[derive(Deserialize)]
+struct Settings {
+    log: Option<String>,
+    rpc_url: Option<String>,
+    cache_size: Option<usize>,
+    index_cache_size: Option<usize>,
+    gateway_rate: Option<u32>,
+    api_key_rate: Option<u32>,
+}
+
+impl Settings {
+    fn read(path: &PathBuf) -> Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+}
+
+async fn apply_settings(
+    path: &PathBuf,
+    log: &LogHandle,
+    db: &SharedIndex<20, Address>,
+    provider_urls: &std::sync::RwLock<Vec<String>>,
+) -> Result<()> {
+    let settings = Settings::read(path)?;
+    if let Some(directives) = &settings.log {
+        set_log_filter(log, directives)?;
+    }
+    if let Some(urls) = settings.rpc_url {
+        let urls: Vec<String> = urls.split(',').map(|url| url.trim().to_string()).collect();
+        let mut current = provider_urls.write().unwrap();
+        if *current != urls {
+            info!("provider URLs changed, effective from the next connection");
+            *current = urls;
+        }
+    }
+    if settings.cache_size.is_some() || settings.index_cache_size.is_some() {
+        let (cache_size, index_cache_size) = db.cache_sizes().await;
+        let cache_size = settings.cache_size.unwrap_or(cache_size);
+        let index_cache_size = settings.index_cache_size.unwrap_or(index_cache_size);
+        db.resize_caches(cache_size, index_cache_size).await?;
+        info!(cache_size, index_cache_size, "caches resized");
+    }
+    Ok(())
+}
+
+/// Applies the gateway rate limits of the `--config` file, reading the API keys file
+/// again. The rates missing from the file are those of the command line.
+fn apply_rate_limits(path: &PathBuf, matches: &ArgMatches, limiter: &RateLimiter) -> Result<()> {
+    let settings = Settings::read(path)?;
+    let quota = |rate: Option<u32>, name: &str| Quota {
+        per_minute: rate.unwrap_or(*matches.get_one::<u32>(name).unwrap()),
+        per_day: None,
+    };
+    let keys = match matches.get_one::<PathBuf>("api-keys") {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => String::new(),
+    };
+    limiter
+        .reload(
+            quota(settings.gateway_rate, "gateway-rate"),
+            &keys,
+            quota(settings.api_key_rate, "api-key-rate"),
+        )
+        .map_err(|e| format!("api keys: {}", e))?;
+    Ok(())
+}
+
+async fn lookup(command: &str, matches: &ArgMatches) -> Result<()> {
+    let datadir = matches.get_one::<PathBuf>("datadir").unwrap();
+    let index_table = IndexTable::<20, Address>::builder(datadir)
+        .cache_size(1_000)
+        .read_only(true)
+        .build()
+        .await?;
+    let db = SharedIndex::<20, Address>::new(index_table);
+    let res = match command {
+        "resolve" => {
+            let monic = matches.get_one::<String>("MONIC").unwrap();
+            api::lookup_monic(monic, &db, true).await
+        }
+        _ => {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            api::lookup_address(address, &db, true).await
+        }
+    };
+    match res.map_err(|e| e.to_string())? {
+        Some(info) => println!("{}", serde_json::to_string_pretty(&info)?),
+        None => Err("not found")?,
+    }
+    Ok(())
+}
+
+async fn dump(command: &str, matches: &ArgMatches) -> Result<()> {
+    let datadir = matches.get_one::<PathBuf>("datadir").unwrap().to_path_buf();
+    let file = matches.get_one::<PathBuf>("FILE").unwrap();
+    if command == "export" {
+        let db = IndexTable::<20, Address>::builder(datadir)
+            .cache_size(1_000)
+            .read_only(true)
+            .build()
+            .await?;
+        let from = match matches.get_one::<u64>("from") {
+            Some(from) => *from,
+            None => db.start_block(),
+        };
+        let to = match matches.get_one::<u64>("to") {
+            Some(to) => *to,
+            None => db.get_counters().await.last_committed_block,
+        };
+        if let Some(entries) = matches.get_one::<u64>("segment-size") {
+            let segments = db
+                .export_segments(file, to, *entries, &*envelope(matches)?)
+                .await?;
+            info!("exported {} segments to {}", segments.len(), file.display());
+            return Ok(());
+        }
+        let mut writer = envelope(matches)?.writer(File::create(file)?)?;
+        let blocks = db.export(from, to, &mut writer).await?;
+        writer.finish()?;
+        info!("exported {} blocks to {}", blocks, file.display());
+        #[cfg(feature = "s3")]
+        if let Some(bucket) = matches.get_one::<String>("s3-bucket") {
+            let key = match matches.get_one::<String>("s3-key") {
+                Some(key) => key.clone(),
+                None => file
+                    .file_name()
+                    .ok_or("no file name to use as S3 key")?
+                    .to_string_lossy()
+                    .to_string(),
+            };
+            let target = monique::s3::S3Target {
+                bucket: bucket.clone(),
+                key,
+                region: matches.get_one::<String>("s3-region").unwrap().clone(),
+                endpoint: matches.get_one::<String>("s3-endpoint").cloned(),
+            };
+            monique::s3::upload(file, &target).await?;
+        }
+    } else {
+        let db = IndexTable::<20, Address>::builder(&datadir)
+            .cache_size(1_000)
+            .build()
+            .await?;
+        let url = file
+            .to_str()
+            .filter(|file| file.starts_with("http://") || file.starts_with("https://"));
+        let blocks = match url {
+            Some(url) => {
+                let path = datadir.join("download.dump");
+                download(url, &path).await?;
+                let blocks = import_file(&db, &path, matches).await;
+                std::fs::remove_file(&path)?;
+                blocks?
+            }
+            None if file.is_dir() => db.import_segments(file, &*envelope(matches)?).await?,
+            None => import_file(&db, file, matches).await?,
+        };
+        info!("imported {} blocks from {}", blocks, file.display());
+    }
+    Ok(())
+}
+
+/// Encoding of the dumps and segments: encrypted with the key given with
+/// `--encryption-key-file` or `--encryption-key`, if any.
+fn envelope(matches: &ArgMatches) -> Result<Box<dyn Envelope>> {
+    #[cfg(feature = "encryption")]
+    if let Some(key) = encryption_key(matches)? {
+        return Ok(Box::new(key));
+    }
+    let _ = matches;
+    Ok(Box::new(Plain))
+}
+
+/// Imports the dump file at `path`, which must be given a key if encrypted.
+async fn import_file(
+    db: &IndexTable<20, Address>,
+    path: &Path,
+    matches: &ArgMatches,
+) -> Result<u64> {
+    #[cfg(feature = "encryption")]
+    if encryption::is_encrypted(path)? && encryption_key(matches)?.is_none() {
+        Err(format!(
+            "{} is encrypted, but no key was given",
+            path.display()
+        ))?
+    }
+    let reader = envelope(matches)?.reader(File::open(path)?)?;
+    Ok(db.import(reader).await?)
+}
+
+#[cfg(feature = "encryption")]
+fn encryption_key(matches: &ArgMatches) -> monique::Result<Option<encryption::Key>> {
+    if let Ok(Some(path)) = matches.try_get_one::<PathBuf>("encryption-key-file") {
+        return Ok(Some(encryption::Key::from_file(path)?));
+    }
+    if let Ok(Some(hex)) = matches.try_get_one::<String>("encryption-key") {
+        return Ok(Some(encryption::Key::from_hex(hex)?));
+    }
+    Ok(None)
+}
+
+/// Rotates the key of an encrypted dump file, or of the segments of a directory.
+#[cfg(feature = "encryption")]
+fn rekey(matches: &ArgMatches) -> Result<()> {
+    let file = matches.get_one::<PathBuf>("FILE").unwrap();
+    let old = encryption::Key::from_file(matches.get_one::<PathBuf>("old-key-file").unwrap())?;
+    let new = encryption::Key::from_file(matches.get_one::<PathBuf>("new-key-file").unwrap())?;
+    if file.is_dir() {
+        let rewritten = encryption::rekey_segments(file, &old, &new)?;
+        info!(
+            "encrypted {} segments again in {}",
+            rewritten,
+            file.display()
+        );
+    } else {
+        encryption::rekey(file, &old, &new)?;
+        info!("encrypted {} again", file.display());
+    }
+    Ok(())
+}
+
+/// Downloads a dump (e.g. from a presigned URL) to `path`, through a temporary file
+/// renamed once complete, so that a failed download leaves nothing behind.
+async fn download(url: &str, path: &Path) -> Result<()> {
+    let partial = path.with_extension("part");
+    match download_to(url, &partial).await {
+        Ok(bytes) => {
+            std::fs::rename(&partial, path)?;
+            info!("downloaded {} bytes", bytes);
+            Ok(())
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Writes the body of `url` to `path`, returning its size.
+async fn download_to(url: &str, path: &Path) -> Result<usize> {
+    let mut response = reqwest::get(url).await?.error_for_status()?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut bytes = 0;
+    while let Some(chunk) = response.chunk().await? {
+        writer.write_all(&chunk)?;
+        bytes += chunk.len();
+    }
+    writer.flush()?;
+    Ok(bytes)
+}
+
+/// Splits a CSV line into its fields, which may be double-quoted to hold commas.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+        .into_iter()
+        .map(|field| field.trim().to_string())
+        .collect()
+}
+
+/// Imports `address,label[,source]` lines, the source defaulting to `csv`.
+async fn labels(matches: &ArgMatches) -> Result<()> {
+    let datadir = matches.get_one::<PathBuf>("datadir").unwrap();
+    let file = matches.get_one::<PathBuf>("FILE").unwrap();
+    let mut labels = vec![];
+    for (n, line) in BufReader::new(File::open(file)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = csv_fields(&line);
+        let Ok(address) = Address::from_str(&fields[0]) else {
+            // header line
+            if n == 0 {
+                continue;
+            }
+            Err(format!("line {}: invalid address {:?}", n + 1, fields[0]))?
+        };
+        let label = match fields.get(1) {
+            Some(label) if !label.is_empty() && label.len() <= u16::MAX as usize => label,
+            _ => Err(format!("line {}: invalid label", n + 1))?,
+        };
+        let source = fields.get(2).filter(|source| !source.is_empty());
+        labels.push((
+            address,
+            Some(Label {
+                label: label.clone(),
+                source: source.cloned().unwrap_or_else(|| "csv".to_string()),
+            }),
+        ));
+    }
+    let db = IndexTable::<20, Address>::builder(datadir)
+        .cache_size(1_000)
+        .build()
+        .await?;
+    let count = labels.len();
+    db.set_labels(labels)?;
+    info!("imported {} labels from {}", count, file.display());
+    Ok(())
+}
+
+async fn rollback(matches: &ArgMatches) -> Result<()> {
+    let datadir = matches.get_one::<PathBuf>("datadir").unwrap();
+    let block = *matches.get_one::<u64>("to-block").unwrap();
+    let db = IndexTable::<20, Address>::builder(datadir)
+        .cache_size(1_000)
+        .build()
+        .await?;
+    let tx_dir = datadir.join("tx");
+    let transactions = match tx_dir.exists() {
+        true => Some(
+            IndexTable::<32, H256>::builder(tx_dir)
+                .cache_size(1_000)
+                .build()
+                .await?,
+        ),
+        false => None,
+    };
+    check_rollback(&db, transactions.as_ref(), block).await?;
+    let removed = db.rollback(block).await?;
+    if let Some(transactions) = &transactions {
+        transactions.rollback(block).await?;
+    }
+    info!(
+        "rolled back to block {}, removing {} addresses",
+        block, removed
+    );
+    Ok(())
+}
+
+/// Free pages above which the maintenance task suggests `monique compact`.
+const MAX_FRAGMENTATION: f64 = 0.25;
+
+/// Logs the page usage of a database file.
+fn report_space(name: &str, space: &Space) {
+    let mb = |bytes: u64| bytes / 1_000_000;
+    let fragmentation = (space.fragmentation() * 1000.0).round() / 10.0;
+    info!(
+        used_mb = mb(space.used_pages() * space.page_size),
+        file_mb = mb(space.file_size),
+        max_mb = mb(space.max_size),
+        free_mb = mb(space.free_pages * space.page_size),
+        fragmentation,
+        "{} space",
+        name
+    );
+    if space.map_usage() > MAP_USAGE_WARNING {
+        warn!(
+            "{} uses {:.1}% of its maximum size",
+            name,
+            space.map_usage() * 100.0
+        );
+    }
+    if space.fragmentation() > MAX_FRAGMENTATION {
+        warn!(
+            "{} is {}% free pages, `monique compact` can reclaim them",
+            name, fragmentation
+        );
+    }
+}
+
+/// Disk usage of an index as JSON, in bytes.
+fn disk_usage(usage: &DiskUsage) -> serde_json::Value {
+    let space = &usage.space;
+    let tables: serde_json::Map<String, serde_json::Value> = usage
+        .tables
+        .iter()
+        .map(|(table, size)| (table.to_string(), (*size).into()))
+        .collect();
+    serde_json::json!({
+        "tables": tables,
+        "used": space.used_pages() * space.page_size,
+        "free": space.free_pages * space.page_size,
+        "file": space.file_size,
+        "max": space.max_size,
+        "map_usage": space.map_usage(),
+    })
+}
+
+/// Reports the page usage of the index every `--maintenance-interval` seconds.
+fn report_space_periodically(db: SharedIndex<20, Address>, matches: &ArgMatches) {
+    if let Some(interval) = matches.get_one::<u64>("maintenance-interval") {
+        let interval = std::time::Duration::from_secs(*interval);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                match db.space() {
+                    Ok(space) => report_space("index", &space),
+                    Err(e) => error!("failed to read the index space: {}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Built-in network profile, or a custom one read from a JSON file.
+fn parse_network(value: &str) -> std::result::Result<Network, String> {
+    if let Some(network) = Network::named(value) {
+        return Ok(network);
+    }
+    let file = File::open(value).map_err(|e| {
+        format!(
+            "not one of {} nor a profile file: {}",
+            Network::NAMES.join(", "),
+            e
+        )
+    })?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| e.to_string())
+}
+
+/// Allocations read from a genesis file of the chain.
+fn parse_genesis(value: &str) -> std::result::Result<Genesis, String> {
+    let file = File::open(value).map_err(|e| e.to_string())?;
+    Genesis::read(BufReader::new(file))
+}
+
+/// Rejects the providers of another chain than the one of the index, before anything
+/// starts. The providers unreachable for now are checked by the indexer once connected.
+async fn check_chain(db: &SharedIndex<20, Address>, urls: &[String]) -> Result<()> {
+    let connected = match providers::connect(urls).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            warn!("cannot check the chain of the providers yet: {}", e);
+            return Ok(());
+        }
+    };
+    for (provider, probe) in connected {
+        let chain_id = provider.get_chainid().await?.as_u64();
+        if let Err(e) = db.check_chain_id(chain_id) {
+            error!(url = probe.url, "{}", e);
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+/// Records a sample of the progress of the index every `--history-interval`
+/// seconds, for `/stats/history`, keeping those of the last `--history-retention`
+/// seconds.
+fn record_history_periodically(db: SharedIndex<20, Address>, matches: &ArgMatches) {
+    let interval = *matches.get_one::<u64>("history-interval").unwrap();
+    let retention = *matches.get_one::<u64>("history-retention").unwrap();
+    let capacity = monique::index::history_capacity(interval, retention);
+    let interval = std::time::Duration::from_secs(interval);
+    tokio::spawn(async move {
+        let mut previous: Option<(std::time::Instant, u64)> = None;
+        loop {
+            tokio::time::sleep(interval).await;
+            let block = db.get_counters().await.last_indexed_block;
+            let now = std::time::Instant::now();
+            // the rate needs a previous sample
+            let Some((then, then_block)) = previous.replace((now, block)) else {
+                continue;
+            };
+            let elapsed = now.duration_since(then).as_secs_f64();
+            let sample = HistorySample {
+                timestamp: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                block,
+                addresses: db.len().await as u64,
+                blocks_per_second: block.saturating_sub(then_block) as f64 / elapsed,
+            };
+            if let Err(e) = db.record_history(sample, capacity) {
+                error!("failed to record the history of the index: {}", e);
+            }
+        }
+    });
+}
+
+/// Follows the commits of the indexer process writing to the datadir, every
+/// `--refresh-interval` milliseconds.
+fn refresh_periodically(
+    db: SharedIndex<20, Address>,
+    transactions: Option<SharedIndex<32, H256>>,
+    matches: &ArgMatches,
+) {
+    let interval = *matches.get_one::<u64>("refresh-interval").unwrap();
+    let interval = std::time::Duration::from_millis(interval);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = db.refresh().await {
+                error!("failed to refresh the index: {}", e);
+            }
+            if let Some(transactions) = &transactions {
+                if let Err(e) = transactions.refresh().await {
+                    error!("failed to refresh the transaction index: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Reports the page usage of the index (and transaction index) files, and writes
+/// compacted copies of them to `--output`.
+async fn compact(matches: &ArgMatches) -> Result<()> {
+    let datadir = matches.get_one::<PathBuf>("datadir").unwrap();
+    let output = matches.get_one::<PathBuf>("output");
+    if let Some(output) = output {
+        if output.exists() {
+            Err(format!("{} already exists", output.display()))?
+        }
+    }
+    let db = IndexTable::<20, Address>::builder(datadir)
+        .cache_size(1_000)
+        .read_only(true)
+        .build()
+        .await?;
+    report_space("index", &db.space()?);
+    if let Some(output) = output {
+        db.compact_to(output)?;
+    }
+    let tx_dir = datadir.join("tx");
+    if tx_dir.exists() {
+        let transactions = IndexTable::<32, H256>::builder(tx_dir)
+            .cache_size(1_000)
+            .read_only(true)
+            .build()
+            .await?;
+        report_space("transaction index", &transactions.space()?);
+        if let Some(output) = output {
+            transactions.compact_to(&output.join("tx"))?;
+        }
+    }
+    if let Some(output) = output {
+        let compacted = IndexTable::<20, Address>::builder(output)
+            .cache_size(1_000)
+            .read_only(true)
+            .build()
+            .await?;
+        report_space("compacted index", &compacted.space()?);
+        info!(
+            "compacted copy written to {}, to replace the database files of {}",
+            output.display(),
+            datadir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Prints the bucket sizes of the dedup tables of the index (and transaction index),
+/// with the lookup costs they imply and those of 32 and 64-bit keys.
+async fn analyze(matches: &ArgMatches) -> Result<()> {
+    let datadir = matches.get_one::<PathBuf>("datadir").unwrap();
+    let hot_spots = *matches.get_one::<usize>("hot-spots").unwrap();
+    let json = |report: BucketReport| {
+        let hot_spots: Vec<_> = report
+            .hot_spots
+            .iter()
+            .map(|(key, size)| serde_json::json!({ "key": format!("{:#010x}", key), "size": size }))
+            .collect();
+        serde_json::json!({
+            "entries": report.entries,
+            "buckets": report.buckets,
+            "sizes": report.sizes,
+            "hot_spots": hot_spots,
+            "colliding_pairs": report.colliding_pairs(),
+            "expected_pairs_32": report.expected_pairs(32),
+            "expected_pairs_64": report.expected_pairs(64),
+            "hit_cost": report.hit_cost(),
+            "miss_cost": report.miss_cost(),
+        })
+    };
+    let db = IndexTable::<20, Address>::builder(datadir)
+        .cache_size(1_000)
+        .read_only(true)
+        .build()
+        .await?;
+    let mut report = serde_json::json!({ "index": json(db.analyze_buckets(hot_spots)?) });
+    let tx_dir = datadir.join("tx");
+    if tx_dir.exists() {
+        let transactions = IndexTable::<32, H256>::builder(tx_dir)
+            .cache_size(1_000)
+            .read_only(true)
+            .build()
+            .await?;
+        report["transactions"] = json(transactions.analyze_buckets(hot_spots)?);
+    }
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+async fn bench(matches: &ArgMatches) -> Result<()> {
+    let mut options = BenchOptions::default();
+    let count = |name: &str, default: usize| *matches.get_one::<usize>(name).unwrap_or(&default);
+    options.entries = count("entries", options.entries);
+    options.block_size = count("block-size", options.block_size);
+    options.commit_blocks = count("commit-blocks", options.commit_blocks);
+    options.lookups = count("lookups", options.lookups);
+    options.cache_size = count("cache-size", options.cache_size);
+    options.seed = *matches.get_one::<u64>("seed").unwrap_or(&options.seed);
+    let (dir, temporary) = match matches.get_one::<PathBuf>("datadir") {
+        Some(dir) if dir.exists() => Err(format!("{} already exists", dir.display()))?,
+        Some(dir) => (dir.clone(), false),
+        None => (
+            env::temp_dir().join(format!("monique-bench-{}", std::process::id())),
+            true,
+        ),
+    };
+    info!("benchmarking {:?} in {}", options, dir.display());
+    let result = monique::index::bench(&dir, &options).await;
+    if temporary {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    let micros = |duration: std::time::Duration| duration.as_nanos() as f64 / 1e3;
+    let scenarios: serde_json::Map<String, serde_json::Value> = result?
+        .iter()
+        .map(|scenario| {
+            let report = serde_json::json!({
+                "operations": scenario.operations,
+                "seconds": scenario.elapsed.as_secs_f64(),
+                "per_second": scenario.per_second().round(),
+                "p50_us": micros(scenario.quantile(0.5)),
+                "p99_us": micros(scenario.quantile(0.99)),
+                "max_us": micros(scenario.quantile(1.0)),
+            });
+            (scenario.name.to_string(), report)
+        })
+        .collect();
+    let report = serde_json::json!({
+        "options": {
+            "entries": options.entries,
+            "block_size": options.block_size,
+            "commit_blocks": options.commit_blocks,
+            "lookups": options.lookups,
+            "cache_size": options.cache_size,
+            "seed": options.seed,
+        },
+        "scenarios": scenarios,
+    });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+async fn top(matches: &ArgMatches) -> Result<()> {
+    let url = matches.get_one::<String>("url").unwrap();
+    let interval = *matches.get_one::<u64>("interval").unwrap();
+    let client = reqwest::Client::new();
+    let mut previous: Option<IndexerStatus> = None;
+    let mut addresses_per_second = 0.0;
+    loop {
+        let status: IndexerStatus = client
+            .get(format!("{}/status", url.trim_end_matches('/')))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if let Some(previous) = &previous {
+            let elapsed = status.updated_at.saturating_sub(previous.updated_at);
+            if elapsed > 0 {
+                addresses_per_second =
+                    status.addresses.saturating_sub(previous.addresses) as f64 / elapsed as f64;
+            }
+        }
+        let eta = status
+            .eta_seconds
+            .map(format_duration)
+            .unwrap_or("-".to_string());
+        let commit = status
+            .commit_ms
+            .map(|ms| format!("{} ms", ms))
+            .unwrap_or("-".to_string());
+
+        // clear the screen and move the cursor home
+        print!("\x1b[2J\x1b[H");
+        println!("monique - {}", url);
+        println!();
+        println!("state     {:?}", status.state);
+        println!(
+            "block     {} / {} (lag {})",
+            status.current_block,
+            status.head_block,
+            status.head_block.saturating_sub(status.current_block)
+        );
+        println!(
+            "speed     {:.1} blk/s  {:.1} addr/s  eta {}",
+            status.blocks_per_second, addresses_per_second, eta
+        );
+        println!("index     {} addresses", status.addresses);
+        println!("commit    {}", commit);
+        println!("cache     {:.1}% hit rate", status.cache_hit_rate * 100.0);
+        let provider = &status.provider;
+        println!(
+            "provider  {:?}  {} reconnects  subscribed {}",
+            provider.state,
+            provider.reconnects,
+            provider
+                .subscription_age_seconds
+                .map(|age| format!("{} ago", format_duration(age)))
+                .unwrap_or("-".to_string())
+        );
+        if let Some(error) = &provider.last_error {
+            println!("error     {}", error);
+        }
+
+        previous = Some(status);
+        tokio::time::sleep(tokio::time::Duration::from_secs(interval)).await;
+    }
+}
+
+async fn health(matches: &ArgMatches) -> Result<()> {
+    let url = matches.get_one::<String>("url").unwrap();
+    let max_lag = *matches.get_one::<u64>("max-lag").unwrap();
+    let timeout = *matches.get_one::<u64>("timeout").unwrap();
+    let status: IndexerStatus = reqwest::Client::new()
+        .get(format!("{}/status", url.trim_end_matches('/')))
+        .timeout(std::time::Duration::from_secs(timeout))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if status.state == IndexerState::Stalled {
+        Err("indexer is stalled")?;
+    }
+    let lag = status.head_block.saturating_sub(status.current_block);
+    if lag > max_lag {
+        Err(format!(
+            "indexer lags {} blocks behind (max {})",
+            lag, max_lag
+        ))?;
+    }
+    println!("ok: {:?}, lag {} blocks", status.state, lag);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ProofInfo {
+    address: Address,
+    block: u64,
+    root: H256,
+    proof: Vec<Bytes>,
+}
+
+#[derive(Deserialize)]
+struct SignedCheckpoint {
+    root: H256,
+    hash: H256,
+    signature: String,
+}
+
+/// Verifies a monic against a proof from the API, trusting either a given checkpoint
+/// root or the checkpoint signature of a given operator.
+async fn verify_monic(matches: &ArgMatches) -> Result<()> {
+    let monic = matches.get_one::<String>("MONIC").unwrap();
+    let url = matches
+        .get_one::<String>("url")
+        .unwrap()
+        .trim_end_matches('/');
+    let (index, _) = words::to_index(monic.to_string())?;
+    let client = reqwest::Client::new();
+    let proof: ProofInfo = client
+        .get(format!("{}/proof/{}", url, index))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let root = match (
+        matches.get_one::<H256>("root"),
+        matches.get_one::<Address>("signer"),
+    ) {
+        (Some(root), _) => *root,
+        (None, Some(signer)) => {
+            let checkpoint: SignedCheckpoint = client
+                .get(format!("{}/checkpoint/{}/signature", url, proof.block))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let message = Checkpoint {
+                block: proof.block,
+                root: checkpoint.root,
+                hash: checkpoint.hash,
+            }
+            .message();
+            let signature = Signature::from_str(checkpoint.signature.trim_start_matches("0x"))?;
+            if signature.recover(message.to_vec())? != *signer {
+                Err(format!(
+                    "checkpoint {} is not signed by {:?}",
+                    proof.block, signer
+                ))?;
+            }
+            checkpoint.root
+        }
+        (None, None) => Err("either --root or --signer is required")?,
+    };
+    if proof.root != root {
+        Err(format!(
+            "block {} has root {:?}, trusted root is {:?}",
+            proof.block, proof.root, root
+        ))?;
+    }
+    let nodes = proof.proof.into_iter().map(|node| node.to_vec()).collect();
+    if !verify::verify_resolution(monic, proof.address, root, nodes)? {
+        Err(format!("invalid proof for {}", monic))?;
+    }
+    println!(
+        "{}",
+        serde_json::json!({
+            "monic": monic,
+            "address": proof.address,
+            "index": index,
+            "block": proof.block,
+            "root": root,
+        })
+    );
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct PeerCheckpoint {
+    hash: H256,
+}
+
+/// Hash of the checkpoint of a block of the peer, `None` until it commits the block.
+async fn peer_checkpoint(client: &reqwest::Client, url: &str, block: u64) -> Result<Option<H256>> {
+    let res = client
+        .get(format!("{}/checkpoint/{}", url, block))
+        .send()
+        .await?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let checkpoint: PeerCheckpoint = res.error_for_status()?.json().await?;
+    Ok(Some(checkpoint.hash))
+}
+
+/// Compares the checkpoint hashes of every `--every` committed block with those of a
+/// peer. As the hashes chain, the blocks match up to the last matching sample, and
+/// the first divergent block is found by bisecting up to the next sample.
+async fn compare(matches: &ArgMatches) -> Result<()> {
+    let datadir = matches.get_one::<PathBuf>("datadir").unwrap();
+    let url = matches
+        .get_one::<String>("peer")
+        .unwrap()
+        .trim_end_matches('/');
+    let every = *matches.get_one::<u64>("every").unwrap();
+    let db = IndexTable::<20, Address>::builder(datadir)
+        .read_only(true)
+        .build()
+        .await?;
+    let (first, last) = (
+        db.start_block(),
+        db.get_counters().await.last_committed_block,
+    );
+    if last < first {
+        Err("the index has no committed block")?
+    }
+    let local = |block: u64| -> Result<H256> {
+        match db.checkpoint(block)? {
+            Some(checkpoint) => Ok(checkpoint.hash),
+            None => Err(format!("no local checkpoint for block {}", block).into()),
+        }
+    };
+    let client = reqwest::Client::new();
+    let (client, local) = (&client, &local);
+    let comparison = verify::bisect_checkpoints(first, last, every, move |block| async move {
+        let matched: Result<Option<bool>> = match peer_checkpoint(client, url, block).await? {
+            Some(hash) => Ok(Some(hash == local(block)?)),
+            None => Ok(None),
+        };
+        matched
+    })
+    .await?;
+    let Some(divergent) = comparison.first_divergent else {
+        println!(
+            "{}",
+            serde_json::json!({
+                "peer": url,
+                "compared": comparison.compared,
+                "last_matching_block": comparison.last_matching,
+            })
+        );
+        return Ok(());
+    };
+    let peer_hash = peer_checkpoint(client, url, divergent).await?;
+    println!(
+        "{}",
+        serde_json::json!({
+            "peer": url,
+            "compared": comparison.compared,
+            "last_matching_block": comparison.last_matching,
+            "first_divergent_block": divergent,
+            "local_hash": local(divergent)?,
+            "peer_hash": peer_hash,
+        })
+    );
+    Err(format!("the checkpoints diverge from block {}", divergent))?
+}
+
+fn format_duration(seconds: u64) -> String {
+    match seconds {
+        s if s >= 86_400 => format!("{}d {}h", s / 86_400, (s % 86_400) / 3600),
+        s if s >= 3600 => format!("{}h {}m", s / 3600, (s % 3600) / 60),
+        s => format!("{}m {}s", s / 60, s % 60),
+    }
+}
+
+fn encoding(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("encode", matches)) => {
+            let index = *matches.get_one::<u64>("INDEX").unwrap();
+            let address = *matches.get_one::<Address>("ADDRESS").unwrap();
+            if index >= 1 << 62 {
+                Err("index must be lower than 2^62")?
+            }
+            println!("{}", words::to_words(index, words::checksum(address)));
+        }
+        Some(("decode", matches)) => {
+            let monic = matches.get_one::<String>("MONIC").unwrap();
+            let (index, checksum) = words::to_index(monic.to_string())?;
+            println!(
+                "{}",
+                serde_json::json!({ "index": index, "checksum": checksum })
+            );
+        }
+        _ => unreachable!("subcommand required"),
+    }
+    Ok(())
+}
+
+/// Refuses to start on an index whose counters drifted from its tables, unless
+/// `--force` is given, then repeats the check every `--check-interval` seconds. With
+/// `--recover`, the index is truncated back to its last consistent block, which is
+/// returned, and checked again.
+async fn check_index<F, R>(check: F, recover: R, matches: &ArgMatches) -> Result<Option<u64>>
+where
+    F: Fn() -> monique::Result<()> + Send + 'static,
+    R: std::future::Future<Output = monique::Result<Option<u64>>>,
+{
+    let mut recovered = None;
+    if let Err(e) = check() {
+        if matches.get_flag("recover") {
+            warn!("{}, recovering", e);
+            recovered = recover.await?;
+            check()?;
+        } else if !matches.get_flag("force") {
+            return Err(e.into());
+        } else {
+            warn!("{}, starting anyway", e);
+        }
+    }
+    if let Some(interval) = matches.get_one::<u64>("check-interval") {
+        let interval = std::time::Duration::from_secs(*interval);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = check() {
+                    error!("{}", e);
+                }
+            }
+        });
+    }
+    Ok(recovered)
+}
+
+/// Command line of the `monique` binary: the flags shared by several subcommands are
+/// defined once here, and added to each of them.
+fn cli() -> Command {
+    let datadir_arg = arg!(-d --datadir <DATADIR> "Data directory")
+        .env("MONIQUE_DATADIR")
+        .required(true)
+        .value_parser(clap::value_parser!(PathBuf));
+    let persist_tries_arg =
+        arg!(--"persist-tries" "Store checkpoint tries to serve proofs without rebuilding them")
+            .env("MONIQUE_PERSIST_TRIES");
+    let check_args = [
+        arg!(--force "Start even if the integrity check of the index fails").env("MONIQUE_FORCE"),
+        arg!(--recover "Truncate the index back to its last consistent block if the integrity check fails")
+            .env("MONIQUE_RECOVER")
+            .conflicts_with("force"),
+        arg!(--"migrate-ruleset" "Continue an index built with other extraction rules, recording the current ones")
+            .env("MONIQUE_MIGRATE_RULESET"),
+        arg!(--"check-interval" <SECONDS> "Also check the integrity of the index periodically")
+            .env("MONIQUE_CHECK_INTERVAL")
+            .value_parser(clap::value_parser!(u64).range(1..)),
+        arg!(--"maintenance-interval" <SECONDS> "Report the free pages of the database periodically")
+            .env("MONIQUE_MAINTENANCE_INTERVAL")
+            .value_parser(clap::value_parser!(u64).range(1..)),
+        arg!(--"history-interval" <SECONDS> "Interval of the samples served by /stats/history")
+            .env("MONIQUE_HISTORY_INTERVAL")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("60"),
+        arg!(--"history-retention" <SECONDS> "Keep the samples served by /stats/history for this long")
+            .env("MONIQUE_HISTORY_RETENTION")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("604800"),
+    ];
+    let network_arg = arg!(--network <NETWORK> "Network profile: mainnet, sepolia, holesky, or a JSON profile file")
+        .env("MONIQUE_NETWORK")
+        .value_parser(parse_network);
     let common_args = [
-        arg!(-r --"rpc-url" <PROVIDER> "JSON-RPC Provider"),
-        arg!(-d --datadir <DATADIR> "Data directory")
-            .required(true)
+        arg!(-r --"rpc-url" <PROVIDER> "JSON-RPC Provider, repeated or comma-separated to select among several")
+            .env("MONIQUE_RPC_URL")
+            .value_delimiter(',')
+            .action(ArgAction::Append),
+        datadir_arg.clone(),
+    ];
+    let api_args = [
+        arg!(-p --port <PORT> "API server port")
+            .env("MONIQUE_PORT")
+            .value_parser(clap::value_parser!(u16)),
+        arg!(--address <ADDRESS> "API server address (repeat or comma-separate to bind several)")
+            .env("MONIQUE_ADDRESS")
+            .action(ArgAction::Append)
+            .value_delimiter(',')
+            .value_parser(clap::value_parser!(IpAddr)),
+        arg!(--"listen-unix" <PATH> "Serve the API on a unix domain socket")
+            .env("MONIQUE_LISTEN_UNIX")
+            .value_parser(clap::value_parser!(PathBuf)),
+        arg!(--"ens-rpc-url" <URL> "Add reverse ENS names to /alias and /resolve, from this HTTP provider")
+            .env("MONIQUE_ENS_RPC_URL"),
+        arg!(--"ens-ttl" <SECONDS> "How long reverse ENS names are cached")
+            .env("MONIQUE_ENS_TTL")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("3600"),
+        arg!(--"response-cache-size" <ENTRIES> "Responses of /resolve and /alias cached (0 to disable)")
+            .env("MONIQUE_RESPONSE_CACHE_SIZE")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("100000"),
+        arg!(--"response-cache-ttl" <SECONDS> "How long responses of /resolve and /alias are cached")
+            .env("MONIQUE_RESPONSE_CACHE_TTL")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("300"),
+        arg!(--"max-scanned-entries" <ENTRIES> "Entries read by a /search or /blocks request before it returns a partial response")
+            .env("MONIQUE_MAX_SCANNED_ENTRIES")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("5000000"),
+        arg!(--"max-scan-duration" <MILLISECONDS> "Time spent by a /search or /blocks request before it returns a partial response")
+            .env("MONIQUE_MAX_SCAN_DURATION")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("1000"),
+        arg!(--"max-expensive-requests" <COUNT> "Concurrent /search and /blocks requests, the others getting a 429")
+            .env("MONIQUE_MAX_EXPENSIVE_REQUESTS")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("4"),
+        arg!(--"public-gateway" "Serve a rate-limited public gateway, without the admin and expensive routes")
+            .env("MONIQUE_PUBLIC_GATEWAY"),
+        arg!(--"gateway-rate" <PER_MINUTE> "Requests per minute of each anonymous gateway client IP")
+            .env("MONIQUE_GATEWAY_RATE")
+            .value_parser(clap::value_parser!(u32))
+            .default_value("60"),
+        arg!(--"api-keys" <FILE> "Gateway API keys, one `<key> [<per minute> [<per day>]]` per line")
+            .env("MONIQUE_API_KEYS")
+            .value_parser(clap::value_parser!(PathBuf))
+            .requires("public-gateway"),
+        arg!(--"api-key-rate" <PER_MINUTE> "Requests per minute of the API keys without their own rate")
+            .env("MONIQUE_API_KEY_RATE")
+            .value_parser(clap::value_parser!(u32))
+            .default_value("600"),
+        arg!(--"ip-header" <HEADER> "Header holding the client IP set by a trusted reverse proxy, such as X-Real-IP")
+            .env("MONIQUE_IP_HEADER"),
+    ];
+    #[cfg(feature = "nats")]
+    let nats_args = [
+        arg!(--"nats-url" <URL> "Publish indexed addresses to this NATS server")
+            .env("MONIQUE_NATS_URL"),
+        arg!(--"nats-subject" <SUBJECT> "NATS subject of the indexed addresses")
+            .env("MONIQUE_NATS_SUBJECT")
+            .default_value("monique.addresses"),
+    ];
+    #[cfg(not(feature = "nats"))]
+    let nats_args: [clap::Arg; 0] = [];
+    #[cfg(feature = "ipfs")]
+    let ipfs_args = [
+        arg!(--"ipfs-api" <URL> "Pin checkpoint manifests to the IPFS node with this RPC API")
+            .env("MONIQUE_IPFS_API"),
+        arg!(--"ipfs-interval" <BLOCKS> "Blocks between two checkpoint manifests")
+            .env("MONIQUE_IPFS_INTERVAL")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("10000"),
+        arg!(--"ipfs-snapshots" "Also pin a dump of the blocks of each manifest")
+            .env("MONIQUE_IPFS_SNAPSHOTS"),
+    ];
+    #[cfg(not(feature = "ipfs"))]
+    let ipfs_args: [clap::Arg; 0] = [];
+    #[cfg(feature = "s3")]
+    let s3_args = [
+        arg!(--"s3-bucket" <BUCKET> "Upload the dump to this S3 bucket")
+            .env("MONIQUE_S3_BUCKET")
+            .conflicts_with("segment-size"),
+        arg!(--"s3-key" <KEY> "Object key of the dump (default: file name)"),
+        arg!(--"s3-region" <REGION> "S3 region")
+            .env("MONIQUE_S3_REGION")
+            .default_value("us-east-1"),
+        arg!(--"s3-endpoint" <URL> "S3-compatible endpoint (e.g. MinIO)")
+            .env("MONIQUE_S3_ENDPOINT"),
+    ];
+    #[cfg(not(feature = "s3"))]
+    let s3_args: [clap::Arg; 0] = [];
+    #[cfg(feature = "encryption")]
+    let encryption_args = [
+        arg!(--"encryption-key-file" <FILE> "Encrypt (or decrypt) dumps and segments with the hex AES-256 key of this file")
+            .env("MONIQUE_ENCRYPTION_KEY_FILE")
+            .value_parser(clap::value_parser!(PathBuf)),
+        arg!(--"encryption-key" <HEX> "Encrypt (or decrypt) dumps and segments with this hex AES-256 key")
+            .env("MONIQUE_ENCRYPTION_KEY")
+            .hide_env_values(true)
+            .conflicts_with("encryption-key-file"),
+    ];
+    #[cfg(not(feature = "encryption"))]
+    let encryption_args: [clap::Arg; 0] = [];
+    #[cfg(feature = "sqlite")]
+    let sqlite_args = [
+        arg!(--sqlite <FILE> "Mirror committed addresses into this SQLite file")
+            .env("MONIQUE_SQLITE")
             .value_parser(clap::value_parser!(PathBuf)),
+        arg!(--"sqlite-from" <BLOCK> "Mirror again from this block (default: last mirrored block)")
+            .value_parser(clap::value_parser!(u64))
+            .requires("sqlite"),
+    ];
+    #[cfg(not(feature = "sqlite"))]
+    let sqlite_args: [clap::Arg; 0] = [];
+    #[cfg(feature = "postgres")]
+    let postgres_args = [
+        arg!(--"postgres-url" <URL> "Write committed entries and checkpoints to this PostgreSQL database")
+            .env("MONIQUE_POSTGRES_URL"),
+        arg!(--"postgres-entries-table" <TABLE> "PostgreSQL table of the committed entries")
+            .env("MONIQUE_POSTGRES_ENTRIES_TABLE")
+            .default_value("monique_entries"),
+        arg!(--"postgres-checkpoints-table" <TABLE> "PostgreSQL table of the checkpoints")
+            .env("MONIQUE_POSTGRES_CHECKPOINTS_TABLE")
+            .default_value("monique_checkpoints"),
+        arg!(--"postgres-from" <BLOCK> "Write again from this block (default: last written checkpoint)")
+            .value_parser(clap::value_parser!(u64))
+            .requires("postgres-url"),
+    ];
+    #[cfg(not(feature = "postgres"))]
+    let postgres_args: [clap::Arg; 0] = [];
+    #[cfg(feature = "clickhouse")]
+    let clickhouse_args = [
+        arg!(--"clickhouse-url" <URL> "Write committed addresses to this ClickHouse HTTP interface")
+            .env("MONIQUE_CLICKHOUSE_URL"),
+        arg!(--"clickhouse-table" <TABLE> "ClickHouse table of the committed addresses")
+            .env("MONIQUE_CLICKHOUSE_TABLE")
+            .default_value("monique_addresses"),
+        arg!(--"clickhouse-batch" <ROWS> "Rows per ClickHouse insert")
+            .env("MONIQUE_CLICKHOUSE_BATCH")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("50000"),
+        arg!(--"clickhouse-from" <BLOCK> "Write again from this block (default: last written block)")
+            .value_parser(clap::value_parser!(u64))
+            .requires("clickhouse-url"),
+    ];
+    #[cfg(not(feature = "clickhouse"))]
+    let clickhouse_args: [clap::Arg; 0] = [];
+    #[cfg(feature = "dns")]
+    let dns_args = [
+        arg!(--"dns-listen" <ADDRESS> "Answer DNS TXT queries of monics on this UDP address")
+            .env("MONIQUE_DNS_LISTEN")
+            .value_parser(clap::value_parser!(std::net::SocketAddr))
+            .requires("dns-zone"),
+        arg!(--"dns-zone" <ZONE> "DNS zone of the monic names (e.g. monic.example)")
+            .env("MONIQUE_DNS_ZONE"),
+        arg!(--"dns-ttl" <SECONDS> "TTL of the DNS answers")
+            .env("MONIQUE_DNS_TTL")
+            .value_parser(clap::value_parser!(u32))
+            .default_value("3600"),
     ];
+    #[cfg(not(feature = "dns"))]
+    let dns_args: [clap::Arg; 0] = [];
 
     let cmd = Command::new("monique")
         .subcommand_required(true)
+        .arg(
+            arg!(--"log-format" <FORMAT> "Log output format")
+                .env("MONIQUE_LOG_FORMAT")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .global(true),
+        )
         .subcommand(
             command!("run").args(
                 [
                     &common_args[..],
+                    &api_args[..],
+                    &check_args[..],
                     &[
-                        arg!(--api "Enable API server"),
-                        arg!(-p --port <PORT> "API server port")
-                            .value_parser(clap::value_parser!(u16)),
-                        arg!(--address <ADDRESS> "API server address")
-                            .value_parser(clap::value_parser!(Ipv4Addr)),
+                        arg!(--api "Enable API server").env("MONIQUE_API"),
+                        arg!(--enrich "Classify new addresses as contracts or EOAs")
+                            .env("MONIQUE_ENRICH"),
+                        arg!(--"record-appearances" "Record the transaction each new address was first seen in")
+                            .env("MONIQUE_RECORD_APPEARANCES"),
+                        arg!(--traces "Also index the internal calls of the blocks, from the trace API of the node")
+                            .env("MONIQUE_TRACES"),
+                        arg!(--"pending-cap" <MIB> "Stop queueing blocks while the pending queue holds this much memory, until commits make room")
+                            .env("MONIQUE_PENDING_CAP")
+                            .value_parser(clap::value_parser!(u64).range(1..)),
+                        arg!(--"pending-policy" <POLICY> "Over the pending cap, pause queueing or spill the oldest pending blocks to disk")
+                            .env("MONIQUE_PENDING_POLICY")
+                            .value_parser(["pause", "spill"])
+                            .default_value("pause"),
+                        arg!(--"tombstone-retention" <SECONDS> "Keep the pending blocks dropped by a reorg or a rollback for this long (0 to disable)")
+                            .env("MONIQUE_TOMBSTONE_RETENTION")
+                            .value_parser(clap::value_parser!(u64))
+                            .default_value("604800"),
+                        arg!(--"index-transactions" "Also index transaction hashes")
+                            .env("MONIQUE_INDEX_TRANSACTIONS"),
+                        persist_tries_arg.clone(),
+                        network_arg.clone(),
+                        arg!(--"genesis-file" <FILE> "Genesis file of the chain (geth format), whose allocations replace those of the network profile")
+                            .env("MONIQUE_GENESIS_FILE")
+                            .value_parser(parse_genesis),
+                        arg!(--"start-block" <BLOCK> "First block to index, when the index is empty")
+                            .env("MONIQUE_START_BLOCK")
+                            .value_parser(clap::value_parser!(u64).range(1..=u32::MAX as u64)),
+                        arg!(--"signing-key" <KEY> "Private key signing the committed checkpoints")
+                            .env("MONIQUE_SIGNING_KEY")
+                            .hide_env_values(true)
+                            .value_parser(clap::value_parser!(LocalWallet)),
+                        arg!(--"admin-token" <TOKEN> "Bearer token enabling the admin API routes")
+                            .env("MONIQUE_ADMIN_TOKEN")
+                            .hide_env_values(true),
+                        arg!(--config <FILE> "JSON file of settings reloaded on SIGHUP")
+                            .env("MONIQUE_CONFIG")
+                            .value_parser(clap::value_parser!(PathBuf)),
+                        arg!(--watchlists <FILE> "JSON file storing the registered watchlists")
+                            .env("MONIQUE_WATCHLISTS")
+                            .value_parser(clap::value_parser!(PathBuf)),
+                        arg!(--webhooks <FILE> "JSON file listing the webhooks to notify")
+                            .env("MONIQUE_WEBHOOKS")
+                            .value_parser(clap::value_parser!(PathBuf)),
+                        arg!(--"standby-of" <URL> "Follow this primary instance until it fails, then index")
+                            .env("MONIQUE_STANDBY_OF")
+                            .conflicts_with_all(["index-transactions", "start-block"]),
+                        arg!(--"failover-after" <SECONDS> "Promote the standby after its primary is unreachable for this long")
+                            .env("MONIQUE_FAILOVER_AFTER")
+                            .value_parser(clap::value_parser!(u64))
+                            .default_value("60"),
+                        arg!(--"fence-command" <COMMAND> "Shell command stopping the failed primary, which must succeed before an automatic promotion")
+                            .env("MONIQUE_FENCE_COMMAND")
+                            .requires("standby-of"),
                     ][..],
+                    &nats_args[..],
+                    &sqlite_args[..],
+                    &postgres_args[..],
+                    &clickhouse_args[..],
+                    &ipfs_args[..],
+                    &dns_args[..],
                 ]
                 .concat(),
             ),
         )
-        .subcommand(command!("info").args(&common_args));
+        .subcommand(
+            command!("info")
+                .args(&common_args)
+                .arg(network_arg.clone()),
+        )
+        .subcommand(
+            command!("serve")
+                .about("Serve the API from an existing datadir, without indexing")
+                .arg(datadir_arg.clone())
+                .arg(
+                    arg!(--"refresh-interval" <MILLISECONDS> "Interval between reloads of the commits of the indexer process")
+                        .env("MONIQUE_REFRESH_INTERVAL")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("1000"),
+                )
+                .args(&api_args)
+                .args(&dns_args),
+        )
+        .subcommand(
+            command!("follow")
+                .about("Replicate the index of another monique instance through its API")
+                .arg(
+                    arg!(--upstream <URL> "API base URL of the upstream instance")
+                        .env("MONIQUE_UPSTREAM")
+                        .required(true),
+                )
+                .arg(
+                    arg!(--signer <ADDRESS> "Require checkpoints signed by this address")
+                        .env("MONIQUE_UPSTREAM_SIGNER")
+                        .value_parser(clap::value_parser!(Address)),
+                )
+                .arg(
+                    arg!(--interval <SECONDS> "Polling interval once caught up")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("12"),
+                )
+                .arg(arg!(--api "Enable API server").env("MONIQUE_API"))
+                .arg(persist_tries_arg.clone())
+                .arg(network_arg.clone())
+                .arg(datadir_arg.clone())
+                .args(&api_args)
+                .args(&dns_args)
+                .args(&check_args),
+        )
+        .subcommand(
+            command!("resolve")
+                .about("Resolve a monic from a local index")
+                .arg(arg!(<MONIC> "Monic to resolve"))
+                .arg(datadir_arg.clone()),
+        )
+        .subcommand(
+            command!("alias")
+                .about("Get the monic of an address from a local index")
+                .arg(arg!(<ADDRESS> "Address to look up"))
+                .arg(datadir_arg.clone()),
+        )
+        .subcommand(
+            command!("export")
+                .about("Export committed blocks to a dump file")
+                .arg(arg!(<FILE> "Dump file").value_parser(clap::value_parser!(PathBuf)))
+                .arg(datadir_arg.clone())
+                .arg(
+                    arg!(--from <BLOCK> "First block (default: first indexed block)")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--to <BLOCK> "Last block (default: last committed block)")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"segment-size" <ENTRIES> "Export segments of this many entries, listed in a manifest, to the FILE directory")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .conflicts_with("from"),
+                )
+                .args(&s3_args)
+                .args(&encryption_args),
+        )
+        .subcommand(
+            command!("import")
+                .about("Import a dump file, verifying its checkpoints")
+                .arg(
+                    arg!(<FILE> "Dump file, segments directory, or its http(s) URL (e.g. presigned)")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(datadir_arg.clone())
+                .args(&encryption_args),
+        )
+        .subcommand(
+            command!("verify-segments")
+                .about("Verify the checksums and checkpoints of exported segments, in parallel")
+                .arg(
+                    arg!(<DIR> "Segments directory")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .args(&encryption_args),
+        )
+        .subcommand(
+            command!("labels")
+                .about("Import address labels from a CSV file of address,label[,source]")
+                .arg(arg!(<FILE> "CSV file").value_parser(clap::value_parser!(PathBuf)))
+                .arg(datadir_arg.clone()),
+        )
+        .subcommand(
+            command!("rollback")
+                .about("Unwind the committed blocks after a block, e.g. to reindex them")
+                .arg(
+                    arg!(--"to-block" <BLOCK> "Last block to keep")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(datadir_arg.clone()),
+        )
+        .subcommand(
+            command!("compact")
+                .about("Report the free pages of the database, and write a compacted copy of it")
+                .arg(
+                    arg!(-o --output <DIR> "Directory of the compacted copy, which must not exist")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(datadir_arg.clone()),
+        )
+        .subcommand(
+            command!("analyze")
+                .about("Report the bucket sizes of the dedup table, its largest buckets and the lookup costs they imply")
+                .arg(
+                    arg!(--"hot-spots" <COUNT> "Largest buckets to list")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10"),
+                )
+                .arg(datadir_arg.clone()),
+        )
+        .subcommand(
+            command!("bench")
+                .about("Benchmark the storage on a fresh index, and print a report")
+                .arg(
+                    arg!(-d --datadir <DIR> "Directory of the benchmark index, which must not exist (default: a temporary directory, removed afterwards)")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--entries <COUNT> "Entries pushed [default: 1000000]")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--"block-size" <COUNT> "Entries of each block [default: 100]")
+                        .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..)),
+                )
+                .arg(
+                    arg!(--"commit-blocks" <COUNT> "Blocks committed at once [default: 100]")
+                        .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..)),
+                )
+                .arg(
+                    arg!(--lookups <COUNT> "Lookups of each kind [default: 100000]")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--"cache-size" <COUNT> "Capacity of each lookup cache [default: 100000]")
+                        .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..)),
+                )
+                .arg(
+                    arg!(--seed <SEED> "Seed of the random entries [default: 0]")
+                        .value_parser(clap::value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            command!("top")
+                .about("Live dashboard of a running instance")
+                .arg(
+                    arg!(--url <URL> "API base URL")
+                        .env("MONIQUE_URL")
+                        .default_value("http://localhost:8000"),
+                )
+                .arg(
+                    arg!(--interval <SECONDS> "Refresh interval")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("2"),
+                ),
+        )
+        .subcommand(
+            command!("health")
+                .about("Exit non-zero if the API is down or the indexer lags behind")
+                .arg(
+                    arg!(--url <URL> "API base URL")
+                        .env("MONIQUE_URL")
+                        .default_value("http://localhost:8000"),
+                )
+                .arg(
+                    arg!(--"max-lag" <BLOCKS> "Maximum tolerated lag behind the node head")
+                        .env("MONIQUE_MAX_LAG")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("100"),
+                )
+                .arg(
+                    arg!(--timeout <SECONDS> "Request timeout")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("5"),
+                ),
+        )
+        .subcommand(
+            command!("compare")
+                .about("Compare the checkpoints of the index with those of a peer instance")
+                .arg(datadir_arg.clone())
+                .arg(
+                    arg!(--peer <URL> "API base URL of the peer")
+                        .env("MONIQUE_PEER")
+                        .required(true),
+                )
+                .arg(
+                    arg!(--every <BLOCKS> "Interval between the compared blocks")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("10000"),
+                ),
+        )
+        .subcommand(
+            command!("verify")
+                .about("Verify a monic against a trusted checkpoint using the API proof")
+                .arg(arg!(<MONIC> "Monic to verify"))
+                .arg(
+                    arg!(--url <URL> "API base URL")
+                        .env("MONIQUE_URL")
+                        .default_value("http://localhost:8000"),
+                )
+                .arg(
+                    arg!(--root <HASH> "Trusted checkpoint root of the block")
+                        .value_parser(clap::value_parser!(H256)),
+                )
+                .arg(
+                    arg!(--signer <ADDRESS> "Trust checkpoints signed by this address")
+                        .value_parser(clap::value_parser!(Address))
+                        .conflicts_with("root"),
+                ),
+        )
+        .subcommand(
+            command!("words")
+                .about("Encode and decode monics")
+                .subcommand_required(true)
+                .subcommand(
+                    command!("encode")
+                        .about("Encode an index and its address into a monic")
+                        .arg(arg!(<INDEX> "Index").value_parser(clap::value_parser!(u64)))
+                        .arg(
+                            arg!(<ADDRESS> "Address used for the checksum")
+                                .value_parser(clap::value_parser!(Address)),
+                        ),
+                )
+                .subcommand(
+                    command!("decode")
+                        .about("Decode a monic into its index and checksum")
+                        .arg(arg!(<MONIC> "Monic to decode")),
+                ),
+        );
 
-    let matches = cmd.get_matches();
+    #[cfg(feature = "encryption")]
+    let cmd = cmd.subcommand(
+        command!("rekey")
+            .about("Encrypt again a dump file or the segments of a directory with a new key")
+            .arg(
+                arg!(<FILE> "Encrypted dump file or segments directory")
+                    .value_parser(clap::value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"old-key-file" <FILE> "File of the current hex key")
+                    .required(true)
+                    .value_parser(clap::value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"new-key-file" <FILE> "File of the new hex key")
+                    .required(true)
+                    .value_parser(clap::value_parser!(PathBuf)),
+            ),
+    );
+
+    #[cfg(feature = "otlp")]
+    let cmd = cmd.arg(
+        arg!(--"otlp-endpoint" <URL> "Export tracing spans to an OTLP collector")
+            .env("MONIQUE_OTLP_ENDPOINT")
+            .global(true),
+    );
+    cmd
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let matches = cli().get_matches();
+    let log = init_logging(
+        matches.get_one::<String>("log-format").unwrap(),
+        matches
+            .try_get_one::<String>("otlp-endpoint")
+            .unwrap_or_default(),
+    )?;
     let (command, matches) = matches.subcommand().expect("no subcommand");
 
-    let default_provider = "ws://localhost:8546".to_string();
-    let provider_url = matches
-        .get_one::<String>("rpc-url")
-        .unwrap_or(&default_provider);
+    if command == "resolve" || command == "alias" {
+        return lookup(command, matches).await;
+    }
+    if command == "words" {
+        return encoding(matches);
+    }
+    if command == "export" || command == "import" {
+        return dump(command, matches).await;
+    }
+    if command == "verify-segments" {
+        let dir = matches.get_one::<PathBuf>("DIR").unwrap();
+        let blocks = monique::index::verify_segments::<20, Address>(dir, &*envelope(matches)?)?;
+        info!("verified {} blocks in {}", blocks, dir.display());
+        if let Some(wordlist) = monique::index::manifest_wordlist(dir)? {
+            if wordlist != words::fingerprint() {
+                warn!(
+                    wordlist = ?wordlist,
+                    "the segments were exported by a resolver with another vocabulary"
+                );
+            }
+        }
+        return Ok(());
+    }
+    #[cfg(feature = "encryption")]
+    if command == "rekey" {
+        return rekey(matches);
+    }
+    if command == "labels" {
+        return labels(matches).await;
+    }
+    if command == "rollback" {
+        return rollback(matches).await;
+    }
+    if command == "compact" {
+        return compact(matches).await;
+    }
+    if command == "analyze" {
+        return analyze(matches).await;
+    }
+    if command == "bench" {
+        return bench(matches).await;
+    }
+    if command == "top" {
+        return top(matches).await;
+    }
+    if command == "health" {
+        return health(matches).await;
+    }
+    if command == "compare" {
+        return compare(matches).await;
+    }
+    if command == "verify" {
+        return verify_monic(matches).await;
+    }
+    if command == "serve" {
+        let datadir = matches.get_one::<PathBuf>("datadir").unwrap();
+        let index_table = IndexTable::<20, Address>::builder(datadir)
+            .read_only(true)
+            .build()
+            .await?;
+        let db = SharedIndex::<20, Address>::new(index_table);
+        let sources = Arc::new(SourceStats::new(db.clone()));
+        let tx_dir = datadir.join("tx");
+        let transactions = if tx_dir.exists() {
+            let tx_table = IndexTable::<32, H256>::builder(tx_dir)
+                .read_only(true)
+                .build()
+                .await?;
+            Some(SharedIndex::<32, H256>::new(tx_table))
+        } else {
+            None
+        };
+        refresh_periodically(db.clone(), transactions.clone(), matches);
+        return serve(
+            matches,
+            db,
+            transactions,
+            status::channel().1,
+            sources,
+            None,
+            None,
+        )
+        .await;
+    }
+    if command == "follow" {
+        return follow(matches).await;
+    }
+
+    let provider_urls: Vec<String> = match matches.get_many::<String>("rpc-url") {
+        Some(urls) => urls.cloned().collect(),
+        None => vec!["ws://localhost:8546".to_string()],
+    };
     let datadir = matches.get_one::<PathBuf>("datadir").unwrap();
 
-    let index_table = IndexTable::<20, Address>::new(datadir.to_path_buf(), 1_000_000).await;
+    let mut builder = IndexTable::<20, Address>::builder(datadir)
+        .persist_tries(command == "run" && matches.get_flag("persist-tries"));
+    if command == "run" {
+        builder =
+            builder.tombstone_retention(*matches.get_one::<u64>("tombstone-retention").unwrap());
+    }
+    let index_table = builder.build().await?;
     let db = SharedIndex::<20, Address>::new(index_table);
+    let sources = Arc::new(SourceStats::new(db.clone()));
+    let network = matches.get_one::<Network>("network");
 
     if command == "info" {
-        let provider = Provider::<Ws>::connect(provider_url).await?;
-        let indexer = Indexer::new(db, provider);
+        // only checked: `info` leaves the datadir as it is
+        if let Some(network) = network {
+            db.verify_chain_id(network.chain_id)?;
+        }
+        let (provider, _) = providers::connect(&provider_urls).await?.remove(0);
+        db.verify_chain_id(provider.get_chainid().await?.as_u64())?;
+        let (status_tx, _) = status::channel();
+        status_tx.send_modify(|status| status.provider.connected());
+        let indexer = Indexer::new(db.clone(), provider).with_status(status_tx);
         indexer.info().await?;
+        let sources: serde_json::Map<String, serde_json::Value> = sources
+            .snapshot()?
+            .into_iter()
+            .map(|(source, count)| (source.name().to_string(), count.into()))
+            .collect();
+        let last_committed_block = db.get_counters().await.last_committed_block;
+        let checkpoint = db.checkpoint(last_committed_block)?.map(
+            |checkpoint| serde_json::json!({ "block": checkpoint.block, "hash": checkpoint.hash }),
+        );
+        let mut disk = serde_json::json!({ "index": disk_usage(&db.disk_usage()?) });
+        let tx_dir = datadir.join("tx");
+        if tx_dir.exists() {
+            let transactions = IndexTable::<32, H256>::builder(tx_dir)
+                .cache_size(1_000)
+                .read_only(true)
+                .build()
+                .await?;
+            disk["transactions"] = disk_usage(&transactions.disk_usage()?);
+        }
+        let info = serde_json::json!({
+            "status": indexer.status(),
+            "checkpoint": checkpoint,
+            "sources": sources,
+            "disk": disk,
+        });
+        println!("{}", serde_json::to_string_pretty(&info)?);
         return Ok(());
     }
 
+    if let Some(network) = network {
+        db.check_chain_id(network.chain_id)?;
+    }
     let api = matches.get_flag("api");
-    let port = *matches.get_one::<u16>("port").unwrap_or(&8000);
-    let default_address = Ipv4Addr::LOCALHOST;
-    let address = matches
-        .get_one::<Ipv4Addr>("address")
-        .unwrap_or(&default_address);
+    let enrich = matches.get_flag("enrich");
+    let record_appearances = matches.get_flag("record-appearances");
+    let traces = matches.get_flag("traces");
+    let pending_cap = matches
+        .get_one::<u64>("pending-cap")
+        .map(|mib| (mib * 1024 * 1024) as usize);
+    let pending_policy = match matches
+        .get_one::<String>("pending-policy")
+        .unwrap()
+        .as_str()
+    {
+        "spill" => PendingPolicy::Spill,
+        _ => PendingPolicy::Pause,
+    };
+    let check = db.clone();
+    let recovered = check_index(move || check.check(), db.recover(), matches).await?;
+    check_chain(&db, &provider_urls).await?;
+    report_space_periodically(db.clone(), matches);
+    record_history_periodically(db.clone(), matches);
+    let start_block = matches.get_one::<u64>("start-block").copied().or(network
+        .filter(|_| !matches.contains_id("standby-of"))
+        .map(|network| network.start_block));
+    if let Some(start) = start_block {
+        db.set_start_block(start).await?;
+    }
+    let genesis = match matches.get_one::<Genesis>("genesis-file") {
+        Some(file) => {
+            if let (Some(network), Some(chain_id)) = (network, file.chain_id) {
+                if network.chain_id != chain_id {
+                    Err(format!(
+                        "the genesis file is for chain {}, the network profile for chain {}",
+                        chain_id, network.chain_id
+                    ))?
+                }
+            }
+            file.allocations.clone()
+        }
+        None => network
+            .map(|network| network.genesis.clone())
+            .unwrap_or_default(),
+    };
+    db.check_ruleset(
+        ruleset(&genesis, traces),
+        matches.get_flag("migrate-ruleset"),
+    )?;
+    let transactions = if matches.get_flag("index-transactions") {
+        let tx_table = IndexTable::<32, H256>::builder(datadir.join("tx"))
+            .build()
+            .await?;
+        tx_table.set_start_block(db.start_block()).await?;
+        let tx_table = SharedIndex::<32, H256>::new(tx_table);
+        let check = tx_table.clone();
+        let tx_recovered = check_index(move || check.check(), tx_table.recover(), matches).await?;
+        if recovered.is_some() || tx_recovered.is_some() {
+            align_tables(&db, &tx_table).await?;
+        }
+        Some(tx_table)
+    } else {
+        None
+    };
+    let _transactions = transactions.clone();
+    let signer = matches.get_one::<LocalWallet>("signing-key").cloned();
+    if let Some(signer) = &signer {
+        info!("signing checkpoints as {:?}", signer.address());
+    }
+    let watchlists: SharedWatchlists = match matches.get_one::<PathBuf>("watchlists") {
+        Some(path) => Arc::new(Watchlists::load(path)?),
+        None => Arc::new(Watchlists::default()),
+    };
+    watchlists.spawn(db.clone());
+    if let Some(path) = matches.get_one::<PathBuf>("webhooks") {
+        let hooks: Vec<WebhookConfig> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        webhooks::spawn(db.clone(), hooks, Some(watchlists.clone()));
+    }
+    #[cfg(feature = "nats")]
+    if let Some(url) = matches.get_one::<String>("nats-url") {
+        let subject = matches.get_one::<String>("nats-subject").unwrap().clone();
+        let (db, url, watchlists) = (db.clone(), url.clone(), watchlists.clone());
+        tokio::spawn(async move {
+            if let Err(e) = monique::nats::publish(db, &url, subject, Some(watchlists)).await {
+                error!("NATS publisher failed: {}", e);
+            }
+        });
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = matches.get_one::<PathBuf>("sqlite").cloned() {
+        let from = matches.get_one::<u64>("sqlite-from").copied();
+        let db = db.clone();
+        tokio::spawn(async move {
+            if let Err(e) = monique::sqlite::mirror(db, &path, from).await {
+                error!("SQLite mirror failed: {}", e);
+            }
+        });
+    }
+    #[cfg(feature = "postgres")]
+    if let Some(url) = matches.get_one::<String>("postgres-url").cloned() {
+        let tables = monique::postgres::Tables {
+            entries: matches
+                .get_one::<String>("postgres-entries-table")
+                .unwrap()
+                .clone(),
+            checkpoints: matches
+                .get_one::<String>("postgres-checkpoints-table")
+                .unwrap()
+                .clone(),
+        };
+        let from = matches.get_one::<u64>("postgres-from").copied();
+        let db = db.clone();
+        tokio::spawn(async move {
+            if let Err(e) = monique::postgres::stream(db, &url, tables, from).await {
+                error!("PostgreSQL sink failed: {}", e);
+            }
+        });
+    }
+    #[cfg(feature = "clickhouse")]
+    if let Some(url) = matches.get_one::<String>("clickhouse-url").cloned() {
+        let table = matches
+            .get_one::<String>("clickhouse-table")
+            .unwrap()
+            .clone();
+        let batch_rows = *matches.get_one::<usize>("clickhouse-batch").unwrap();
+        let from = matches.get_one::<u64>("clickhouse-from").copied();
+        let db = db.clone();
+        tokio::spawn(async move {
+            if let Err(e) = monique::clickhouse::stream(db, &url, &table, batch_rows, from).await {
+                error!("ClickHouse sink failed: {}", e);
+            }
+        });
+    }
+    #[cfg(feature = "ipfs")]
+    if let Some(api) = matches.get_one::<String>("ipfs-api") {
+        let publisher = monique::ipfs::IpfsPublisher::new(
+            api,
+            *matches.get_one::<u64>("ipfs-interval").unwrap(),
+            matches.get_flag("ipfs-snapshots"),
+        );
+        let db = db.clone();
+        tokio::spawn(async move {
+            if let Err(e) = monique::ipfs::run(db, publisher).await {
+                error!("IPFS publisher failed: {}", e);
+            }
+        });
+    }
+    let provider_urls = Arc::new(std::sync::RwLock::new(provider_urls));
+    if let Some(path) = matches.get_one::<PathBuf>("config").cloned() {
+        apply_settings(&path, &log, &db, &provider_urls).await?;
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = signal(SignalKind::hangup())?;
+            let (log, db, provider_urls) = (log.clone(), db.clone(), provider_urls.clone());
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    match apply_settings(&path, &log, &db, &provider_urls).await {
+                        Ok(()) => info!("settings reloaded from {}", path.display()),
+                        Err(e) => error!("failed to reload settings: {}", e),
+                    }
+                }
+            });
+        }
+    }
 
+    let (status_tx, status_rx) = status::channel();
+    let (commands_tx, commands_rx) = control::channel();
+    let standby = matches.get_one::<String>("standby-of").map(|upstream| {
+        let follower = Follower::new(db.clone(), upstream).with_status(status_tx.clone());
+        match matches.get_one::<String>("fence-command") {
+            Some(fence) => follower.with_fence(fence.clone()),
+            None => follower,
+        }
+    });
+    let failover_after =
+        std::time::Duration::from_secs(*matches.get_one::<u64>("failover-after").unwrap());
     let _db = db.clone();
-    let _provider_url = provider_url.clone();
+    let _provider_urls = provider_urls.clone();
+    let current_url = Arc::new(std::sync::RwLock::new(None::<String>));
+    let _current_url = current_url.clone();
     let indexing_loop = tokio::spawn({
         async move {
+            if let Some(follower) = standby {
+                loop {
+                    match follower.standby(failover_after, &commands_rx).await {
+                        Ok(block) => {
+                            info!(block, "promoted to active indexer");
+                            break;
+                        }
+                        Err(
+                            e @ (MoniqueError::Diverged(_)
+                            | MoniqueError::ChainMismatch { .. }
+                            | MoniqueError::RulesetMismatch { .. }),
+                        ) => {
+                            // neither following nor replacing this primary is safe
+                            error!("Standby halted: {}", e);
+                            status_tx.send_modify(|status| status.state = IndexerState::Stalled);
+                            return;
+                        }
+                        Err(e) => error!("Standby failed with error: {}", e),
+                    }
+                    status_tx.send_modify(|status| status.state = IndexerState::Stalled);
+                    warn!("Standby will restart in 5 seconds...");
+                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                }
+            }
             loop {
-                match Provider::<Ws>::connect(_provider_url.clone()).await {
-                    Ok(provider) => {
-                        let mut indexer = Indexer::new(_db.clone(), provider);
-                        if let Err(e) = indexer.run().await {
-                            error!("Indexer failed with error: {}", e);
+                let urls = _provider_urls.read().unwrap().clone();
+                status_tx.send_modify(|status| status.provider.connecting());
+                match providers::connect(&urls).await {
+                    Ok(mut connected) => {
+                        let (provider, best) = connected.remove(0);
+                        info!(url = best.url, head = best.head, "provider selected");
+                        *_current_url.write().unwrap() = Some(best.url.clone());
+                        let backfill = connected
+                            .into_iter()
+                            .filter(|(_, probe)| {
+                                probe.head + providers::HEAD_TOLERANCE >= best.head
+                            })
+                            .map(|(provider, probe)| (provider, probe.head))
+                            .collect();
+                        status_tx.send_modify(|status| status.provider.connected());
+                        let mut indexer = Indexer::new(_db.clone(), provider)
+                            .with_backfill(backfill)
+                            .with_enrichment(enrich)
+                            .with_appearances(record_appearances)
+                            .with_pending_cap(pending_cap)
+                            .with_pending_policy(pending_policy)
+                            .with_status(status_tx.clone())
+                            .with_traces(traces)
+                            .with_commands(commands_rx.clone())
+                            .with_genesis(genesis.clone());
+                        if let Some(transactions) = &_transactions {
+                            indexer = indexer.with_transactions(transactions.clone());
+                        }
+                        if let Some(signer) = &signer {
+                            indexer = indexer.with_signer(signer.clone());
+                        }
+                        match indexer.run().await {
+                            Err(MoniqueError::RolledBack(block)) => {
+                                info!("Indexer restarting after block {}", block);
+                                continue;
+                            }
+                            Err(MoniqueError::Reconnect) => continue,
+                            Err(e) => {
+                                error!("Indexer failed with error: {}", e);
+                                status_tx.send_modify(|status| status.provider.failed(&e));
+                            }
+                            Ok(()) => {}
                         }
                     }
                     Err(e) => {
                         error!("Failed to connect to provider with error: {}", e);
+                        status_tx.send_modify(|status| status.provider.failed(&e));
                     }
                 }
+                status_tx.send_modify(|status| status.state = IndexerState::Stalled);
                 warn!("Indexer will restart in 5 seconds...");
                 tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
             }
         }
     });
 
+    if provider_urls.read().unwrap().len() > 1 {
+        // switch to a healthier provider between two blocks
+        let commands_tx = commands_tx.clone();
+        tokio::spawn(async move {
+            let mut health = providers::Health::default();
+            loop {
+                tokio::time::sleep(providers::PROBE_INTERVAL).await;
+                let urls = provider_urls.read().unwrap().clone();
+                let Ok(connected) = providers::connect(&urls).await else {
+                    continue;
+                };
+                let ranked: Vec<_> = connected.into_iter().map(|(_, probe)| probe).collect();
+                let current = current_url.read().unwrap().clone();
+                if let Some(current) = current {
+                    if health.record(&current, &ranked) {
+                        info!(from = current, to = ranked[0].url, "switching provider");
+                        let _ = commands_tx.send(control::Command::Reconnect).await;
+                    }
+                }
+            }
+        });
+    }
+
     if !api {
         indexing_loop.await?;
         return Ok(());
     }
 
-    let config = Config {
-        port,
-        address: IpAddr::V4(*address),
-        ..Default::default()
-    };
+    let admin = matches.get_one::<String>("admin-token").map(|token| {
+        let log = log.clone();
+        api::AdminState::new(token.clone(), commands_tx)
+            .with_log_filter(Arc::new(move |directives| set_log_filter(&log, directives)))
+    });
+    let served = serve(
+        matches,
+        db,
+        transactions,
+        status_rx,
+        sources,
+        admin,
+        Some(watchlists),
+    )
+    .await;
+    // stops the indexer, with its block fetches, once the server is shut down
+    indexing_loop.abort();
+    served
+}
 
-    rocket::custom(config)
-        .manage(db)
-        .mount(
-            "/",
-            routes![api::index, api::resolve, api::stats, api::alias],
-        )
-        .register("/", catchers![api::not_found, api::internal_error])
-        .launch()
+async fn follow(matches: &ArgMatches) -> Result<()> {
+    let datadir = matches.get_one::<PathBuf>("datadir").unwrap();
+    let upstream = matches.get_one::<String>("upstream").unwrap();
+    let interval = *matches.get_one::<u64>("interval").unwrap();
+    let index_table = IndexTable::<20, Address>::builder(datadir)
+        .persist_tries(matches.get_flag("persist-tries"))
+        .build()
         .await?;
+    let db = SharedIndex::<20, Address>::new(index_table);
+    if let Some(network) = matches.get_one::<Network>("network") {
+        db.check_chain_id(network.chain_id)?;
+    }
+    let check = db.clone();
+    check_index(move || check.check(), db.recover(), matches).await?;
+    report_space_periodically(db.clone(), matches);
+    record_history_periodically(db.clone(), matches);
+    let (status_tx, status_rx) = status::channel();
+    let mut follower = Follower::new(db.clone(), upstream)
+        .with_status(status_tx.clone())
+        .with_interval(std::time::Duration::from_secs(interval))
+        .with_ruleset_migration(matches.get_flag("migrate-ruleset"));
+    if let Some(signer) = matches.get_one::<Address>("signer") {
+        follower = follower.with_signer(*signer);
+    }
+    let following = tokio::spawn(async move {
+        loop {
+            if let Err(e) = follower.run().await {
+                error!("Follower failed with error: {}", e);
+            }
+            status_tx.send_modify(|status| status.state = IndexerState::Stalled);
+            warn!("Follower will restart in 5 seconds...");
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+        }
+    });
+
+    if !matches.get_flag("api") {
+        following.await?;
+        return Ok(());
+    }
+    let sources = Arc::new(SourceStats::new(db.clone()));
+    serve(matches, db, None, status_rx, sources, None, None).await
+}
+
+async fn serve(
+    matches: &ArgMatches,
+    db: SharedIndex<20, Address>,
+    transactions: Option<SharedIndex<32, H256>>,
+    status_rx: StatusReceiver,
+    sources: Arc<SourceStats>,
+    admin: Option<api::AdminState>,
+    watchlists: Option<SharedWatchlists>,
+) -> Result<()> {
+    let port = *matches.get_one::<u16>("port").unwrap_or(&8000);
+    let unix = matches.get_one::<PathBuf>("listen-unix");
+    // without an explicit address, a unix socket replaces the default TCP listener
+    let addresses: Vec<IpAddr> = match (matches.get_many::<IpAddr>("address"), unix) {
+        (Some(addresses), _) => addresses.copied().collect(),
+        (None, Some(_)) => vec![],
+        (None, None) => vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+    };
+    let ens: Option<SharedEns> = match matches.get_one::<String>("ens-rpc-url") {
+        Some(url) => {
+            let ttl = *matches.get_one::<u64>("ens-ttl").unwrap();
+            let provider = Provider::<Http>::try_from(url.as_str())?;
+            Some(Arc::new(EnsResolver::new(
+                provider,
+                std::time::Duration::from_secs(ttl),
+            )))
+        }
+        None => None,
+    };
+    let gateway: Option<SharedRateLimiter> = if matches.get_flag("public-gateway") {
+        let quota = |name: &str| Quota {
+            per_minute: *matches.get_one::<u32>(name).unwrap(),
+            per_day: None,
+        };
+        let mut limiter = RateLimiter::new(quota("gateway-rate"));
+        if let Some(path) = matches.get_one::<PathBuf>("api-keys") {
+            let keys = std::fs::read_to_string(path)?;
+            limiter = limiter
+                .with_keys(&keys, quota("api-key-rate"))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        let limiter = Arc::new(limiter);
+        // the rate limits of `monique run` are reloaded with its settings
+        if let Ok(Some(path)) = matches.try_get_one::<PathBuf>("config") {
+            apply_rate_limits(path, matches, &limiter)?;
+            #[cfg(unix)]
+            {
+                use tokio::signal::unix::{signal, SignalKind};
+                let mut hangup = signal(SignalKind::hangup())?;
+                let (path, matches, limiter) = (path.clone(), matches.clone(), limiter.clone());
+                tokio::spawn(async move {
+                    while hangup.recv().await.is_some() {
+                        if let Err(e) = apply_rate_limits(&path, &matches, &limiter) {
+                            error!("failed to reload the rate limits: {}", e);
+                        }
+                    }
+                });
+            }
+        }
+        Some(limiter)
+    } else {
+        None
+    };
+    let (admin, watchlists) = match gateway {
+        Some(_) => {
+            if admin.is_some() {
+                warn!("the public gateway does not serve the admin routes");
+            }
+            (None, None)
+        }
+        None => (admin, watchlists),
+    };
+    let cache_size = match *matches.get_one::<u64>("response-cache-size").unwrap() {
+        0 if gateway.is_some() => {
+            warn!("the public gateway always caches responses, using the default cache size");
+            100_000
+        }
+        size => size,
+    };
+    let cache: Option<SharedResponseCache> = match cache_size {
+        0 => None,
+        size => {
+            let ttl = *matches.get_one::<u64>("response-cache-ttl").unwrap();
+            // only the indexer of `monique run` classifies the entries
+            let enriched = matches!(matches.try_get_one::<bool>("enrich"), Ok(Some(true)));
+            let cache = Arc::new(
+                ResponseCache::new(size, std::time::Duration::from_secs(ttl)).enriched(enriched),
+            );
+            tokio::spawn(cache.clone().follow_rollbacks(db.subscribe_rollbacks()));
+            Some(cache)
+        }
+    };
+    let cost_limits: SharedCostLimits = Arc::new(CostLimits::new(
+        *matches.get_one::<u64>("max-scanned-entries").unwrap() as usize,
+        std::time::Duration::from_millis(*matches.get_one::<u64>("max-scan-duration").unwrap()),
+        *matches.get_one::<u64>("max-expensive-requests").unwrap() as usize,
+    ));
+    let route_stats = SharedRouteStats::default();
+    let build = |config: Config| {
+        let mut server = rocket::custom(config);
+        if let Some(transactions) = &transactions {
+            server = server.manage(transactions.clone());
+        }
+        if let Some(admin) = &admin {
+            server = server.manage(admin.clone());
+        }
+        if let Some(watchlists) = &watchlists {
+            server = server.manage(watchlists.clone());
+        }
+        // the gateway attaches its rate limit first, so that refused requests are
+        // answered before the other fairings see their responses
+        if let Some(limiter) = &gateway {
+            server = server.attach(api::RateLimit(limiter.clone()));
+        }
+        let routes = api::RouteSet {
+            gateway: gateway.is_some(),
+            admin: admin.is_some(),
+            watchlists: watchlists.is_some(),
+            transactions: transactions.is_some(),
+        };
+        api::mount_routes(server, routes)
+            .manage(ens.clone())
+            .manage(cache.clone())
+            .manage(db.clone())
+            .manage(status_rx.clone())
+            .manage(sources.clone())
+            .manage(route_stats.clone())
+            .manage(cost_limits.clone())
+            .attach(api::IndexHeight)
+            .attach(api::Compress)
+            .attach(api::RequestTracing)
+            .attach(api::RouteMetrics(route_stats.clone()))
+            .register(
+                "/",
+                catchers![
+                    api::not_found,
+                    api::unauthorized,
+                    api::too_many_requests,
+                    api::internal_error,
+                    api::default_error
+                ],
+            )
+    };
+
+    // the client IP is the peer address, unless a trusted proxy sets it in a header:
+    // otherwise clients could pick their own and bypass the gateway rate limit
+    let ip_header = matches
+        .get_one::<String>("ip-header")
+        .map(|header| header.clone().into());
+    // one server per listener, sharing the same state
+    let mut servers: JoinSet<Result<()>> = JoinSet::new();
+    for address in addresses {
+        let server = build(Config {
+            port,
+            address,
+            ip_header: ip_header.clone(),
+            ..Default::default()
+        });
+        servers.spawn(async move {
+            server.launch().await?;
+            Ok(())
+        });
+    }
+    #[cfg(feature = "dns")]
+    if let Some(address) = matches.get_one::<std::net::SocketAddr>("dns-listen") {
+        let zone = matches.get_one::<String>("dns-zone").unwrap();
+        let ttl = *matches.get_one::<u32>("dns-ttl").unwrap();
+        let socket = tokio::net::UdpSocket::bind(address).await?;
+        let server = monique::dns::DnsServer::new(db.clone(), zone, ttl);
+        servers.spawn(async move { Ok(server.serve(socket).await?) });
+    }
+    #[cfg(unix)]
+    if let Some(path) = unix.cloned() {
+        let server = build(Config {
+            ip_header,
+            ..Default::default()
+        });
+        servers.spawn(async move { Ok(api::serve_unix(server, &path).await?) });
+    }
+    #[cfg(not(unix))]
+    if unix.is_some() {
+        Err("unix sockets are not supported on this platform")?;
+    }
+    while let Some(res) = servers.join_next().await {
+        res??;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn cli() {
+        super::cli().debug_assert();
+    }
+}
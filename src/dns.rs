@@ -0,0 +1,278 @@
+//! Minimal authoritative DNS frontend answering the TXT queries of
+//! `<monic>.<zone>` names with the resolved address, for environments where only
+//! DNS egress is allowed.
+//!
+//! The words of the monic are separated by `-` or by dots, e.g.
+//! `source-avoid-abandon.monic.example`. Only committed entries are answered, since
+//! resolvers cache the responses.
+
+use crate::index::SharedIndex;
+use crate::words::{self, PIVOT};
+use crate::Result;
+use ethers::types::Address;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+const HEADER_LEN: usize = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+const RCODE_FORMERR: u16 = 1;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+
+pub struct DnsServer {
+    db: SharedIndex<20, Address>,
+    /// Lowercase labels of the zone.
+    zone: Vec<String>,
+    ttl: u32,
+}
+
+/// Question of a query, with its raw bytes to be echoed in the response.
+struct Question<'a> {
+    labels: Vec<String>,
+    qtype: u16,
+    qclass: u16,
+    raw: &'a [u8],
+}
+
+impl DnsServer {
+    /// Answers the names under `zone` (e.g. `monic.example`), with responses cached
+    /// for `ttl` seconds.
+    pub fn new(db: SharedIndex<20, Address>, zone: &str, ttl: u32) -> Self {
+        Self {
+            db,
+            zone: zone
+                .trim_end_matches('.')
+                .split('.')
+                .filter(|label| !label.is_empty())
+                .map(|label| label.to_ascii_lowercase())
+                .collect(),
+            ttl,
+        }
+    }
+
+    /// Serves the queries received on `socket` until it fails.
+    pub async fn serve(self, socket: UdpSocket) -> Result<()> {
+        info!(address = %socket.local_addr()?, zone = %self.zone.join("."), "DNS frontend listening");
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await?;
+            let Some(response) = self.handle(&buf[..len]).await else {
+                continue;
+            };
+            if let Err(e) = socket.send_to(&response, peer).await {
+                warn!("DNS: failed to reply to {}: {}", peer, e);
+            }
+        }
+    }
+
+    /// Response to a query packet, if it is a query at all.
+    async fn handle(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() < HEADER_LEN {
+            return None;
+        }
+        let flags = u16::from_be_bytes([packet[2], packet[3]]);
+        if flags & 0x8000 != 0 {
+            return None; // a response
+        }
+        let opcode = (flags >> 11) & 0xf;
+        let qdcount = u16::from_be_bytes([packet[4], packet[5]]);
+        if opcode != 0 {
+            return Some(response(packet, flags, None, RCODE_NOTIMP, &[]));
+        }
+        let question = match parse_question(&packet[HEADER_LEN..]) {
+            Some(question) if qdcount == 1 => question,
+            _ => return Some(response(packet, flags, None, RCODE_FORMERR, &[])),
+        };
+        let (rcode, answers) = self.answer(&question).await;
+        debug!(name = %question.labels.join("."), qtype = question.qtype, rcode, "DNS query");
+        Some(response(packet, flags, Some(question.raw), rcode, &answers))
+    }
+
+    /// Response code and TXT records of a question.
+    async fn answer(&self, question: &Question<'_>) -> (u16, Vec<Vec<u8>>) {
+        if question.qclass != CLASS_IN && question.qclass != CLASS_ANY {
+            return (RCODE_REFUSED, vec![]);
+        }
+        let labels = &question.labels;
+        if labels.len() < self.zone.len() || !labels.ends_with(&self.zone) {
+            return (RCODE_REFUSED, vec![]);
+        }
+        let name = &labels[..labels.len() - self.zone.len()];
+        if name.is_empty() {
+            return (0, vec![]); // the zone apex
+        }
+        let monic = name.join("-").replace('-', " ");
+        let address = match self.resolve(monic).await {
+            Ok(Some(address)) => address,
+            Ok(None) => return (RCODE_NXDOMAIN, vec![]),
+            Err(e) => {
+                warn!("DNS: lookup failed: {}", e);
+                return (RCODE_SERVFAIL, vec![]);
+            }
+        };
+        if question.qtype != TYPE_TXT && question.qtype != TYPE_ANY {
+            return (0, vec![]); // the name exists, without records of this type
+        }
+        (0, vec![txt_record(&format!("{:#x}", address), self.ttl)])
+    }
+
+    /// Committed address of a monic, `None` if it is invalid or not indexed yet.
+    async fn resolve(&self, monic: String) -> Result<Option<Address>> {
+        let Ok((index, checksum)) = words::to_index(monic) else {
+            return Ok(None);
+        };
+        if index < PIVOT {
+            return Ok(None);
+        }
+        let address = self.db.get_committed(index - PIVOT).await?;
+        Ok(address.filter(|address| words::checksum(address) == checksum))
+    }
+}
+
+/// Parses the first question of a query, rejecting compressed names.
+fn parse_question(data: &[u8]) -> Option<Question<'_>> {
+    let mut labels = vec![];
+    let mut offset = 0;
+    loop {
+        let len = *data.get(offset)? as usize;
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            return None;
+        }
+        let label = data.get(offset..offset + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        offset += len;
+    }
+    let fields = data.get(offset..offset + 4)?;
+    Some(Question {
+        labels,
+        qtype: u16::from_be_bytes([fields[0], fields[1]]),
+        qclass: u16::from_be_bytes([fields[2], fields[3]]),
+        raw: &data[..offset + 4],
+    })
+}
+
+/// TXT record of the question name, which the answer points to.
+fn txt_record(text: &str, ttl: u32) -> Vec<u8> {
+    let mut record = vec![0xc0, HEADER_LEN as u8];
+    record.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    record.extend_from_slice(&CLASS_IN.to_be_bytes());
+    record.extend_from_slice(&ttl.to_be_bytes());
+    record.extend_from_slice(&(text.len() as u16 + 1).to_be_bytes());
+    record.push(text.len() as u8);
+    record.extend_from_slice(text.as_bytes());
+    record
+}
+
+fn response(
+    query: &[u8],
+    flags: u16,
+    question: Option<&[u8]>,
+    rcode: u16,
+    answers: &[Vec<u8>],
+) -> Vec<u8> {
+    // QR and AA, keeping the opcode and RD of the query
+    let flags = 0x8000 | 0x0400 | (flags & 0x7900) | rcode;
+    let mut packet = Vec::with_capacity(512);
+    packet.extend_from_slice(&query[..2]);
+    packet.extend_from_slice(&flags.to_be_bytes());
+    packet.extend_from_slice(&(question.is_some() as u16).to_be_bytes());
+    packet.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[0; 4]);
+    if let Some(question) = question {
+        packet.extend_from_slice(question);
+    }
+    for answer in answers {
+        packet.extend_from_slice(answer);
+    }
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexTable;
+    use std::sync::Arc;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    fn rcode(response: &[u8]) -> u16 {
+        u16::from_be_bytes([response[2], response[3]]) & 0xf
+    }
+
+    fn answers(response: &[u8]) -> u16 {
+        u16::from_be_bytes([response[6], response[7]])
+    }
+
+    #[tokio::test]
+    async fn test_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = IndexTable::<20, Address>::builder(dir.path().join("index"))
+            .build()
+            .await
+            .unwrap();
+        let (a, b) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        db.queue(1, vec![a]).await.unwrap();
+        db.commit(1).await.unwrap();
+        db.queue(2, vec![b]).await.unwrap();
+        let server = DnsServer::new(Arc::new(db), "Monic.Example.", 300);
+        let monic = words::to_words(PIVOT as u64, words::checksum(a)).replace(' ', "-");
+
+        let response = server
+            .handle(&query(&format!("{}.monic.example", monic), TYPE_TXT))
+            .await
+            .unwrap();
+        assert_eq!(&response[..2], &[0x12, 0x34]);
+        assert_eq!(rcode(&response), 0);
+        assert_eq!(answers(&response), 1);
+        let text = format!("{:#x}", a);
+        assert!(response.ends_with(text.as_bytes()));
+
+        // dotted words, and no record of other types
+        let dotted = monic.replace('-', ".").to_uppercase();
+        let response = server
+            .handle(&query(&format!("{}.monic.example", dotted), 28))
+            .await
+            .unwrap();
+        assert_eq!((rcode(&response), answers(&response)), (0, 0));
+
+        // pending entries are not answered
+        let pending = words::to_words(PIVOT as u64 + 1, words::checksum(b)).replace(' ', "-");
+        let response = server
+            .handle(&query(&format!("{}.monic.example", pending), TYPE_TXT))
+            .await
+            .unwrap();
+        assert_eq!(rcode(&response), RCODE_NXDOMAIN);
+
+        let response = server
+            .handle(&query(&format!("{}.other.example", monic), TYPE_TXT))
+            .await
+            .unwrap();
+        assert_eq!(rcode(&response), RCODE_REFUSED);
+        let response = server
+            .handle(&query("not-a-monic.monic.example", TYPE_TXT))
+            .await
+            .unwrap();
+        assert_eq!(rcode(&response), RCODE_NXDOMAIN);
+        assert!(server.handle(&[0; 4]).await.is_none());
+    }
+}
@@ -0,0 +1,491 @@
+//! AES-256-GCM encryption of dumps and segment files, for snapshots stored on shared
+//! infrastructure.
+//!
+//! Format: the magic `MONIQUE\xE2`, the key id (first 8 bytes of the SHA-256 of the
+//! key) and a random 32-byte salt, then chunks of up to 64 KiB of plaintext:
+//! `length: u32 (le) | ciphertext | tag`. Chunks are sealed with a key of their own
+//! file, derived from the key and the salt with HKDF-SHA256, so that nonces are never
+//! reused across files. The nonce of a chunk is its number (u64, big-endian, after 4
+//! zero bytes), and its additional data is 1 for the last chunk, 0 otherwise, so that
+//! a truncated or reordered file fails to decrypt.
+//!
+//! Files of the first format (magic `MONIQUE\xE1`), whose chunks were sealed with the
+//! key itself and a random 4-byte nonce prefix instead of the salt, are still read.
+
+use crate::index::{
+    checksum, manifest_item_size, read_manifest, write_manifest, Envelope, SegmentWriter,
+};
+use crate::{MoniqueError, Result};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use ring::digest::{digest, SHA256};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"MONIQUE\xE2";
+/// Magic of the files whose chunks were sealed with the key itself.
+const MAGIC_PREFIXED: &[u8; 8] = b"MONIQUE\xE1";
+const SALT_SIZE: usize = 32;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+/// A 256-bit encryption key.
+#[derive(Clone)]
+pub struct Key {
+    key: [u8; 32],
+}
+
+impl Key {
+    /// Parses a key given as 64 hex characters, e.g. from the environment.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let invalid = || MoniqueError::Encryption("the key must be 64 hex characters".to_string());
+        let hex = hex.trim().trim_start_matches("0x").as_bytes();
+        if hex.len() != 64 {
+            Err(invalid())?
+        }
+        let digit = |c: u8| (c as char).to_digit(16).ok_or_else(invalid);
+        let mut key = [0u8; 32];
+        for (byte, pair) in key.iter_mut().zip(hex.chunks(2)) {
+            *byte = (digit(pair[0])? * 16 + digit(pair[1])?) as u8;
+        }
+        Ok(Self { key })
+    }
+
+    /// Reads a key file, holding the key in hex (e.g. written by a KMS agent).
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::from_hex(&std::fs::read_to_string(path)?)
+    }
+
+    /// Identifies the key in the encrypted files, without revealing it.
+    pub fn id(&self) -> [u8; 8] {
+        digest(&SHA256, &self.key).as_ref()[..8].try_into().unwrap()
+    }
+
+    fn aead(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.key).unwrap())
+    }
+
+    /// Key of the file with the `salt`.
+    fn file_aead(&self, salt: &[u8; SALT_SIZE]) -> LessSafeKey {
+        let prk = Salt::new(HKDF_SHA256, salt).extract(&self.key);
+        let key = prk
+            .expand(&[b"monique file key"], &AES_256_GCM)
+            .expect("AES-256 key length");
+        LessSafeKey::new(UnboundKey::from(key))
+    }
+}
+
+fn nonce(prefix: [u8; 4], counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&prefix);
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn error(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("encryption: {}", message))
+}
+
+/// Encrypts what is written through it, until `finish` writes the last chunk.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    key: LessSafeKey,
+    prefix: [u8; 4],
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, key: &Key) -> Result<Self> {
+        let mut salt = [0u8; SALT_SIZE];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| MoniqueError::Encryption("no random source".to_string()))?;
+        inner.write_all(MAGIC)?;
+        inner.write_all(&key.id())?;
+        inner.write_all(&salt)?;
+        Ok(Self {
+            inner,
+            key: key.file_aead(&salt),
+            prefix: [0; 4],
+            counter: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+        })
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let nonce = nonce(self.prefix, self.counter);
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::from([last as u8]), &mut self.buffer)
+            .map_err(|_| error("sealing failed"))?;
+        self.inner
+            .write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.counter += 1;
+        Ok(())
+    }
+
+    /// Writes the last chunk, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() == CHUNK_SIZE {
+            self.seal(false)?;
+        }
+        let len = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    /// Flushes the sealed chunks only, the current one being sealed once full.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SegmentWriter for EncryptWriter<BufWriter<File>> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        let file = EncryptWriter::finish(*self)?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Decrypts an encrypted stream, failing if it was truncated.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    key: LessSafeKey,
+    prefix: [u8; 4],
+    counter: u64,
+    chunk: Vec<u8>,
+    position: usize,
+    last: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut inner: R, key: &Key) -> Result<Self> {
+        let mut header = [0u8; 16];
+        inner.read_exact(&mut header)?;
+        if &header[..8] != MAGIC && &header[..8] != MAGIC_PREFIXED {
+            Err(MoniqueError::Encryption(
+                "not an encrypted file".to_string(),
+            ))?
+        }
+        if header[8..16] != key.id() {
+            Err(MoniqueError::Encryption(
+                "the file was encrypted with another key".to_string(),
+            ))?
+        }
+        let (aead, prefix) = match &header[..8] == MAGIC {
+            true => {
+                let mut salt = [0u8; SALT_SIZE];
+                inner.read_exact(&mut salt)?;
+                (key.file_aead(&salt), [0; 4])
+            }
+            false => {
+                let mut prefix = [0u8; 4];
+                inner.read_exact(&mut prefix)?;
+                (key.aead(), prefix)
+            }
+        };
+        Ok(Self {
+            inner,
+            key: aead,
+            prefix,
+            counter: 0,
+            chunk: vec![],
+            position: 0,
+            last: false,
+        })
+    }
+
+    /// Reads and opens the next chunk, `false` at the end of the stream.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        let mut len = [0u8; 4];
+        match self.inner.read_exact(&mut len) {
+            Ok(()) if self.last => return Err(error("data after the last chunk")),
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && self.last => return Ok(false),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(error("truncated file"));
+            }
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if !(TAG_SIZE..=CHUNK_SIZE + TAG_SIZE).contains(&len) {
+            return Err(error("invalid chunk length"));
+        }
+        self.chunk.resize(len, 0);
+        self.inner.read_exact(&mut self.chunk)?;
+        // the last chunk is told apart by its additional data
+        for last in [false, true] {
+            let mut chunk = self.chunk.clone();
+            let nonce = nonce(self.prefix, self.counter);
+            if let Ok(plaintext) =
+                self.key
+                    .open_in_place(nonce, Aad::from([last as u8]), &mut chunk)
+            {
+                let len = plaintext.len();
+                chunk.truncate(len);
+                self.chunk = chunk;
+                self.position = 0;
+                self.counter += 1;
+                self.last = last;
+                return Ok(true);
+            }
+        }
+        Err(error("authentication failed"))
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Id of the key the file was encrypted with, `None` if it is not encrypted.
+pub fn key_id(path: &Path) -> Result<Option<[u8; 8]>> {
+    let mut header = [0u8; 16];
+    match File::open(path)?.read_exact(&mut header) {
+        Ok(()) if &header[..8] == MAGIC || &header[..8] == MAGIC_PREFIXED => {
+            Ok(Some(header[8..].try_into().unwrap()))
+        }
+        Ok(()) => Ok(None),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn is_encrypted(path: &Path) -> Result<bool> {
+    Ok(key_id(path)?.is_some())
+}
+
+/// Segment files encrypted with the key.
+impl Envelope for Key {
+    fn writer(&self, file: File) -> Result<Box<dyn SegmentWriter>> {
+        Ok(Box::new(EncryptWriter::new(BufWriter::new(file), self)?))
+    }
+
+    fn reader(&self, file: File) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(DecryptReader::new(BufReader::new(file), self)?))
+    }
+}
+
+/// Encrypts again the file at `path`, from the `old` key to the `new` one, e.g. to
+/// rotate the key of a snapshot. The file is replaced once rewritten.
+pub fn rekey(path: &Path, old: &Key, new: &Key) -> Result<()> {
+    let mut reader = DecryptReader::new(BufReader::new(File::open(path)?), old)?;
+    let tmp = path.with_extension("rekey");
+    let mut writer = EncryptWriter::new(BufWriter::new(File::create(&tmp)?), new)?;
+    let copied = io::copy(&mut reader, &mut writer).and_then(|_| writer.finish());
+    let file = match copied {
+        Ok(file) => file,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(e)?
+        }
+    };
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Encrypts again the segments listed in the manifest of `dir`, of addresses or of
+/// transactions, updating their checksums. Segments already encrypted with the `new`
+/// key are skipped, so that an interrupted rotation can be resumed. Returns the number
+/// of rewritten segments.
+pub fn rekey_segments(dir: &Path, old: &Key, new: &Key) -> Result<usize> {
+    let Some(item_size) = manifest_item_size(dir)? else {
+        return Ok(0);
+    };
+    let mut segments = read_manifest(dir, item_size)?;
+    let mut rewritten = 0;
+    for i in 0..segments.len() {
+        let path = dir.join(&segments[i].file);
+        if key_id(&path)? != Some(new.id()) {
+            rekey(&path, old, new)?;
+            rewritten += 1;
+        }
+        let checksum = checksum(&path)?;
+        if segments[i].checksum != checksum {
+            segments[i].checksum = checksum;
+            write_manifest(dir, item_size, &segments)?;
+        }
+    }
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let key = Key::from_hex(&"11".repeat(32)).unwrap();
+        let other = Key::from_hex(&format!("0x{}", "22".repeat(32))).unwrap();
+        assert!(Key::from_hex("1234").is_err());
+        // 64 bytes, but not 64 hex digits
+        assert!(Key::from_hex(&format!("{}1€", "11".repeat(30))).is_err());
+        assert!(Key::from_hex(&format!("+1{}", "11".repeat(31))).is_err());
+
+        for len in [0, 10, CHUNK_SIZE, 3 * CHUNK_SIZE + 5] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut writer = EncryptWriter::new(vec![], &key).unwrap();
+            writer.write_all(&plaintext).unwrap();
+            let encrypted = writer.finish().unwrap();
+
+            let mut decrypted = vec![];
+            DecryptReader::new(&encrypted[..], &key)
+                .unwrap()
+                .read_to_end(&mut decrypted)
+                .unwrap();
+            assert_eq!(decrypted, plaintext);
+
+            assert!(DecryptReader::new(&encrypted[..], &other).is_err());
+            // a truncated file is rejected, even at a chunk boundary
+            let truncated = &encrypted[..encrypted.len() - TAG_SIZE - 4 - len % CHUNK_SIZE];
+            if let Ok(mut reader) = DecryptReader::new(truncated, &key) {
+                assert!(reader.read_to_end(&mut vec![]).is_err());
+            }
+            let mut tampered = encrypted.clone();
+            let last = tampered.len() - 1;
+            tampered[last] ^= 1;
+            let mut reader = DecryptReader::new(&tampered[..], &key).unwrap();
+            assert!(reader.read_to_end(&mut vec![]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_prefixed_format() {
+        // a file of the first format, sealed with the key and a nonce prefix
+        let key = Key::from_hex(&"11".repeat(32)).unwrap();
+        let mut chunk = b"snapshot".to_vec();
+        key.aead()
+            .seal_in_place_append_tag(nonce([7; 4], 0), Aad::from([1]), &mut chunk)
+            .unwrap();
+        let mut file = MAGIC_PREFIXED.to_vec();
+        file.extend_from_slice(&key.id());
+        file.extend_from_slice(&[7; 4]);
+        file.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        file.extend_from_slice(&chunk);
+
+        let mut decrypted = vec![];
+        DecryptReader::new(&file[..], &key)
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, b"snapshot");
+    }
+
+    #[test]
+    fn test_file_keys() {
+        // the same plaintext is sealed with another key in each file
+        let key = Key::from_hex(&"11".repeat(32)).unwrap();
+        let encrypt = || {
+            let mut writer = EncryptWriter::new(vec![], &key).unwrap();
+            writer.write_all(b"snapshot").unwrap();
+            writer.finish().unwrap()
+        };
+        let (first, second) = (encrypt(), encrypt());
+        assert_eq!(first[..16], second[..16]);
+        assert_ne!(first[16..], second[16..]);
+    }
+
+    #[tokio::test]
+    async fn test_segments() {
+        use crate::index::{verify_segments, IndexTable};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (key, other) = (
+            Key::from_hex(&"11".repeat(32)).unwrap(),
+            Key::from_hex(&"22".repeat(32)).unwrap(),
+        );
+        // segments of transactions, whose items are 32 bytes
+        let source = IndexTable::<32, [u8; 32]>::builder(dir.path().join("source.db"))
+            .cache_size(16)
+            .build()
+            .await
+            .unwrap();
+        for number in 1..=4u8 {
+            source
+                .queue(number as u64, vec![[number; 32]])
+                .await
+                .unwrap();
+        }
+        source.commit(4).await.unwrap();
+        let segments = dir.path().join("segments");
+        let exported = source.export_segments(&segments, 4, 2, &key).await.unwrap();
+        assert_eq!(exported.len(), 2);
+        assert!(is_encrypted(&segments.join(&exported[0].file)).unwrap());
+        assert_eq!(verify_segments::<32, [u8; 32]>(&segments, &key).unwrap(), 4);
+        assert!(verify_segments::<32, [u8; 32]>(&segments, &other).is_err());
+        let target = IndexTable::<32, [u8; 32]>::builder(dir.path().join("target.db"))
+            .cache_size(16)
+            .build()
+            .await
+            .unwrap();
+        assert!(target.import_segments(&segments, &other).await.is_err());
+        assert_eq!(target.import_segments(&segments, &key).await.unwrap(), 4);
+        assert_eq!(target.block_hash(4).unwrap(), source.block_hash(4).unwrap());
+
+        assert_eq!(rekey_segments(&segments, &key, &other).unwrap(), 2);
+        assert_eq!(rekey_segments(&segments, &key, &other).unwrap(), 0);
+        assert_eq!(
+            verify_segments::<32, [u8; 32]>(&segments, &other).unwrap(),
+            4
+        );
+
+        // a segment truncated within its chunk, or without it, fails to decrypt even
+        // with its checksum updated
+        let mut manifest = read_manifest(&segments, 32).unwrap();
+        let path = segments.join(&manifest[1].file);
+        let bytes = std::fs::read(&path).unwrap();
+        for len in [bytes.len() - 1, 16 + SALT_SIZE] {
+            std::fs::write(&path, &bytes[..len]).unwrap();
+            manifest[1].checksum = checksum(&path).unwrap();
+            write_manifest(&segments, 32, &manifest).unwrap();
+            assert!(verify_segments::<32, [u8; 32]>(&segments, &other).is_err());
+        }
+    }
+
+    #[test]
+    fn test_rekey() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump");
+        let (old, new) = (
+            Key::from_hex(&"11".repeat(32)).unwrap(),
+            Key::from_hex(&"22".repeat(32)).unwrap(),
+        );
+        let mut writer = EncryptWriter::new(File::create(&path).unwrap(), &old).unwrap();
+        writer.write_all(b"snapshot").unwrap();
+        writer.finish().unwrap();
+        assert!(is_encrypted(&path).unwrap());
+
+        assert!(rekey(&path, &new, &old).is_err());
+        rekey(&path, &old, &new).unwrap();
+        let mut decrypted = vec![];
+        DecryptReader::new(File::open(&path).unwrap(), &new)
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, b"snapshot");
+    }
+}
@@ -0,0 +1,54 @@
+//! Reverse ENS resolution of the addresses returned by the API, so that wallet UIs
+//! get both names from a single request.
+
+use ethers::providers::{Http, Middleware, Provider, ProviderError};
+use ethers::types::Address;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Names cached, including the addresses without any.
+const CACHE_SIZE: usize = 100_000;
+
+pub struct EnsResolver {
+    provider: Provider<Http>,
+    ttl: Duration,
+    cache: Mutex<LruCache<Address, (Instant, Option<String>)>>,
+}
+
+pub type SharedEns = Arc<EnsResolver>;
+
+impl EnsResolver {
+    pub fn new(provider: Provider<Http>, ttl: Duration) -> Self {
+        Self {
+            provider,
+            ttl,
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap())),
+        }
+    }
+
+    /// Primary ENS name of `address`, checked against its forward resolution. Provider
+    /// failures are not cached and resolve to `None`, so that they never fail a lookup.
+    pub async fn lookup(&self, address: Address) -> Option<String> {
+        if let Some((resolved_at, name)) = self.cache.lock().unwrap().get(&address) {
+            if resolved_at.elapsed() < self.ttl {
+                return name.clone();
+            }
+        }
+        let name = match self.provider.lookup_address(address).await {
+            Ok(name) => Some(name),
+            Err(ProviderError::EnsError(_) | ProviderError::EnsNotOwned(_)) => None,
+            Err(e) => {
+                debug!(%address, "reverse ENS lookup failed: {}", e);
+                return None;
+            }
+        };
+        self.cache
+            .lock()
+            .unwrap()
+            .put(address, (Instant::now(), name.clone()));
+        name
+    }
+}
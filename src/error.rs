@@ -0,0 +1,114 @@
+use thiserror::Error;
+
+/// Errors returned by the index, the indexer and the API lookups.
+#[derive(Debug, Error)]
+pub enum MoniqueError {
+    #[cfg(feature = "index")]
+    #[error("database error: {0}")]
+    Database(libmdbx::Error),
+    #[cfg(feature = "index")]
+    #[error("the database reached its maximum size, see the disk usage in `monique info`")]
+    DatabaseFull,
+    #[cfg(feature = "index")]
+    #[error("storage error: {0}")]
+    Storage(String),
+    #[cfg(feature = "indexer")]
+    #[error("provider error: {0}")]
+    Provider(#[from] ethers::providers::ProviderError),
+    #[cfg(feature = "indexer")]
+    #[error("block {0} not found")]
+    BlockNotFound(u64),
+    #[cfg(feature = "indexer")]
+    #[error("bad block {0}: mismatched number of transactions and receipts")]
+    BadBlock(u64),
+    #[cfg(feature = "indexer")]
+    #[error("transaction index is at block {transactions} but the address index is at block {addresses}")]
+    OutOfSync { addresses: u64, transactions: u64 },
+    #[cfg(feature = "indexer")]
+    #[error("signing error: {0}")]
+    Signing(String),
+    #[cfg(feature = "indexer")]
+    #[error("block subscription ended")]
+    SubscriptionEnded,
+    #[cfg(feature = "index")]
+    #[error("reorg at block {block} is below the last committed block {committed}")]
+    Reorg { block: u64, committed: u64 },
+    #[cfg(feature = "index")]
+    #[error("queuing error: tried to skip block {expected} and queue block {block}")]
+    Queue { expected: u64, block: u64 },
+    #[cfg(feature = "index")]
+    #[error("block {0} is missing from the pending queue")]
+    MissedBlock(u64),
+    #[cfg(feature = "index")]
+    #[error("a commit is already in progress")]
+    Busy(#[from] tokio::sync::TryLockError),
+    #[cfg(feature = "index")]
+    #[error("rollback error: {0}")]
+    Rollback(String),
+    #[cfg(feature = "indexer")]
+    #[error("rolled back to block {0}")]
+    RolledBack(u64),
+    #[cfg(feature = "indexer")]
+    #[error("reconnecting to a healthier provider")]
+    Reconnect,
+    #[cfg(feature = "index")]
+    #[error("checkpoint error: {0}")]
+    Checkpoint(#[from] eth_trie::TrieError),
+    #[cfg(feature = "index")]
+    #[error("dump error: {0}")]
+    Dump(String),
+    #[cfg(feature = "encryption")]
+    #[error("encryption error: {0}")]
+    Encryption(String),
+    #[cfg(feature = "index")]
+    #[error("the datadir indexes chain {datadir}, not chain {provider}")]
+    ChainMismatch { datadir: u64, provider: u64 },
+    #[cfg(feature = "index")]
+    #[error("the datadir was built with the extraction ruleset {datadir:?}, not {current:?}: pass --migrate-ruleset to continue with the current one")]
+    RulesetMismatch {
+        datadir: ethers_core::types::H256,
+        current: ethers_core::types::H256,
+    },
+    #[cfg(feature = "index")]
+    #[error("integrity check failed: {0}")]
+    Integrity(String),
+    #[cfg(feature = "words")]
+    #[error(transparent)]
+    Words(#[from] crate::words::WordError),
+    #[cfg(feature = "follow")]
+    #[error("upstream error: {0}")]
+    Upstream(String),
+    #[cfg(feature = "follow")]
+    #[error("the upstream diverged: {0}")]
+    Diverged(String),
+    #[cfg(any(feature = "nats", feature = "ipfs"))]
+    #[error("publisher error: {0}")]
+    Publisher(String),
+    #[cfg(any(feature = "postgres", feature = "clickhouse"))]
+    #[error("sink error: {0}")]
+    Sink(String),
+    #[cfg(feature = "postgres")]
+    #[error("postgres error: {0}")]
+    Postgres(#[from] sqlx::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("mirror error: {0}")]
+    Mirror(#[from] rusqlite::Error),
+    #[cfg(feature = "watchlist")]
+    #[error("watchlist error: {0}")]
+    Watchlist(String),
+    #[cfg(feature = "api")]
+    #[error("server error: {0}")]
+    Server(Box<rocket::Error>),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[cfg(feature = "index")]
+impl From<libmdbx::Error> for MoniqueError {
+    fn from(e: libmdbx::Error) -> Self {
+        match e {
+            libmdbx::Error::MapFull => MoniqueError::DatabaseFull,
+            e => MoniqueError::Database(e),
+        }
+    }
+}
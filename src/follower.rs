@@ -0,0 +1,647 @@
+//! Replica mode: pulls committed blocks from another monique instance instead of
+//! an Ethereum node, verifying checkpoint roots, the hash chain and optionally
+//! the operator signatures.
+//!
+//! A replica can also be a hot standby of its upstream, promoted to active indexer
+//! on request, or when the upstream fails once a fence command made sure that it
+//! cannot index anymore. The pending queue of the upstream is not
+//! handed over: as indexing is deterministic, the promoted instance indexes again the
+//! blocks after the last committed one, giving their entries the same indexes.
+
+use crate::index::{Checkpoint, SharedIndex};
+use crate::indexer::control::{Command, CommandReceiver};
+use crate::indexer::status::{IndexerState, IndexerStatus, StatusSender};
+use crate::{MoniqueError, Result};
+use ethers::types::{Address, Signature, H256};
+use serde::Deserialize;
+use std::{
+    process::Stdio,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Interval between two requests of a standby to its failing upstream.
+const STANDBY_RETRY: Duration = Duration::from_secs(1);
+
+/// Outcome of a request of the next blocks to the upstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Batch {
+    /// `blocks` were imported, the upstream having more of them when `more`, as when
+    /// it cut the batch short to stay within its cost limits.
+    Imported { blocks: u64, more: bool },
+    /// The upstream is too busy to serve blocks, and asks to be retried after the
+    /// given delay. It is alive: this is not an upstream failure.
+    Busy(Duration),
+}
+
+#[derive(Deserialize)]
+struct SignedCheckpoint {
+    block: u64,
+    hash: H256,
+    signature: String,
+}
+
+/// Part of the upstream `/` stats read by the follower.
+#[derive(Deserialize)]
+struct UpstreamStats {
+    chain_id: Option<u64>,
+    ruleset: Option<H256>,
+}
+
+pub struct Follower {
+    db: SharedIndex<20, Address>,
+    upstream: String,
+    client: reqwest::Client,
+    signer: Option<Address>,
+    batch: u64,
+    interval: Duration,
+    status: Option<StatusSender>,
+    /// Whether the chain of the upstream was checked against the local one.
+    chain_checked: AtomicBool,
+    migrate_ruleset: bool,
+    fence: Option<String>,
+}
+
+impl Follower {
+    pub fn new(db: SharedIndex<20, Address>, upstream: &str) -> Self {
+        Self {
+            db,
+            upstream: upstream.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+            signer: None,
+            batch: 1_000,
+            interval: Duration::from_secs(12),
+            status: None,
+            chain_checked: AtomicBool::new(false),
+            migrate_ruleset: false,
+            fence: None,
+        }
+    }
+
+    /// Only accept batches whose last checkpoint is signed by `signer`.
+    pub fn with_signer(mut self, signer: Address) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Publish progress through the given status channel.
+    pub fn with_status(mut self, status: StatusSender) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Record the extraction rules of the upstream when they differ from the local ones,
+    /// rather than failing.
+    pub fn with_ruleset_migration(mut self, migrate: bool) -> Self {
+        self.migrate_ruleset = migrate;
+        self
+    }
+
+    /// Shell command fencing a failed upstream off, run before an automatic promotion
+    /// with the upstream URL in `MONIQUE_UPSTREAM`: the standby is only promoted when
+    /// it exits successfully. Without one, a standby is only promoted on request.
+    pub fn with_fence(mut self, command: String) -> Self {
+        self.fence = Some(command);
+        self
+    }
+
+    /// Polls the upstream every `interval` once caught up.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        loop {
+            let state = match self.sync_batch().await? {
+                Batch::Imported { more: true, .. } => IndexerState::CatchingUp,
+                Batch::Imported { more: false, .. } => IndexerState::Live,
+                Batch::Busy(retry_after) => {
+                    tokio::time::sleep(retry_after).await;
+                    continue;
+                }
+            };
+            if let Some(status) = &self.status {
+                let block = self.db.get_counters().await.last_committed_block;
+                let mut current = IndexerStatus::new(state, block, block, 0.0);
+                current.addresses = self.db.committed_len().await;
+                status.send_replace(current);
+            }
+            if state == IndexerState::Live {
+                tokio::time::sleep(self.interval).await;
+            }
+        }
+    }
+
+    /// Follows the upstream as a hot standby until it has been unreachable for
+    /// `failover_after` and is fenced off, or until a `Command::Promote`, after which
+    /// the blocks it committed are imported while it is still reachable. Returns the
+    /// last imported block, after which the promoted instance indexes.
+    ///
+    /// An upstream whose chain diverges from the local one stops the standby with
+    /// `MoniqueError::Diverged`: neither following nor replacing it is safe.
+    pub async fn standby(
+        &self,
+        failover_after: Duration,
+        commands: &CommandReceiver,
+    ) -> Result<u64> {
+        let mut failing_since: Option<Instant> = None;
+        loop {
+            let wait = match self.sync_batch().await {
+                Ok(Batch::Imported { more: false, .. }) => {
+                    failing_since = None;
+                    Some(self.interval)
+                }
+                Ok(Batch::Imported { more: true, .. }) => {
+                    failing_since = None;
+                    None
+                }
+                Ok(Batch::Busy(retry_after)) => {
+                    failing_since = None;
+                    Some(retry_after)
+                }
+                Err(MoniqueError::Upstream(e)) => {
+                    let since = *failing_since.get_or_insert_with(Instant::now);
+                    warn!(
+                        failing_s = since.elapsed().as_secs(),
+                        "standby: upstream error: {}", e
+                    );
+                    if since.elapsed() >= failover_after {
+                        match &self.fence {
+                            Some(fence) => match self.run_fence(fence).await {
+                                Ok(()) => {
+                                    warn!("standby: upstream failed and fenced, promoting");
+                                    break;
+                                }
+                                Err(e) => warn!("standby: not promoting: {}", e),
+                            },
+                            None => warn!(
+                                "standby: upstream failed, promote with POST /admin/promote once it is stopped"
+                            ),
+                        }
+                        // tried again after another `failover_after`
+                        failing_since = Some(Instant::now());
+                    }
+                    Some(self.interval.min(STANDBY_RETRY))
+                }
+                Err(e) => Err(e)?,
+            };
+            if let Some(status) = &self.status {
+                let block = self.db.get_counters().await.last_committed_block;
+                let mut current = IndexerStatus::new(IndexerState::Standby, block, block, 0.0);
+                current.addresses = self.db.committed_len().await;
+                status.send_replace(current);
+            }
+            let Some(wait) = wait else {
+                continue;
+            };
+            let command = tokio::select! {
+                command = commands.next(true) => command,
+                _ = tokio::time::sleep(wait) => continue,
+            };
+            match command {
+                Some(Command::Promote) => {
+                    info!("standby: promotion requested");
+                    // the last blocks committed by the upstream, if it still answers
+                    loop {
+                        match self.sync_batch().await {
+                            Ok(Batch::Imported { more: true, .. }) => {}
+                            Ok(Batch::Busy(retry_after)) => tokio::time::sleep(retry_after).await,
+                            Ok(Batch::Imported { more: false, .. }) | Err(_) => break,
+                        }
+                    }
+                    break;
+                }
+                // kept for the promoted indexer
+                Some(Command::Pause) => commands.set_paused(true),
+                Some(Command::Resume) => commands.set_paused(false),
+                Some(Command::Commit { reply, .. }) | Some(Command::Rollback { reply, .. }) => {
+                    let _ = reply.send(Err(MoniqueError::Upstream(
+                        "standby: the blocks are committed by the upstream".to_string(),
+                    )));
+                }
+                Some(Command::Reconnect) => {}
+                None => tokio::time::sleep(wait).await,
+            }
+        }
+        Ok(self.db.get_counters().await.last_committed_block)
+    }
+
+    /// Runs the fence command, failing unless it exits successfully.
+    async fn run_fence(&self, fence: &str) -> Result<()> {
+        let status = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(fence)
+            .env("MONIQUE_UPSTREAM", &self.upstream)
+            .stdin(Stdio::null())
+            .status()
+            .await?;
+        if !status.success() {
+            Err(MoniqueError::Upstream(format!(
+                "fence command failed with {}",
+                status
+            )))?
+        }
+        Ok(())
+    }
+
+    /// Imports the next batch of blocks.
+    pub async fn sync_batch(&self) -> Result<Batch> {
+        if !self.chain_checked.load(Ordering::Relaxed) {
+            self.check_chain().await?;
+            self.chain_checked.store(true, Ordering::Relaxed);
+        }
+        let from = self.db.get_counters().await.last_committed_block + 1;
+        let url = format!(
+            "{}/blocks?from={}&count={}&timestamps=true",
+            self.upstream, from, self.batch
+        );
+        let res = self.client.get(url).send().await.map_err(upstream_error)?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Batch::Imported {
+                blocks: 0,
+                more: false,
+            });
+        }
+        if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = res
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok()?.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(STANDBY_RETRY);
+            info!(
+                retry_after_s = retry_after.as_secs(),
+                "follow: upstream busy"
+            );
+            return Ok(Batch::Busy(retry_after));
+        }
+        let partial = res
+            .headers()
+            .get("X-Partial")
+            .is_some_and(|value| value == "true");
+        let dump = res
+            .error_for_status()
+            .map_err(upstream_error)?
+            .bytes()
+            .await
+            .map_err(upstream_error)?;
+        // the signature of the last block is checked against the chain of the dump
+        // before anything is imported
+        let Some(checkpoint) = self.db.dump_checkpoint(&dump[..]).await? else {
+            return Ok(Batch::Imported {
+                blocks: 0,
+                more: false,
+            });
+        };
+        let signature = match self.signer {
+            Some(signer) => Some(self.verify_signature(checkpoint, signer).await?),
+            None => None,
+        };
+        let imported = self.db.import(&dump[..]).await?;
+        if let Some(signature) = signature {
+            self.db
+                .put_signatures(vec![(checkpoint.block, signature)])?;
+        }
+        if imported > 0 {
+            let last = self.db.get_counters().await.last_committed_block;
+            let addresses = self.db.committed_len().await;
+            info!(
+                block = last,
+                blocks = imported,
+                addresses,
+                "follow progress"
+            );
+        }
+        Ok(Batch::Imported {
+            blocks: imported,
+            more: partial || imported >= self.batch,
+        })
+    }
+
+    /// Records the chain and the extraction rules of the upstream, or checks that they
+    /// match the local ones. Upstreams which do not report them are trusted.
+    async fn check_chain(&self) -> Result<()> {
+        let url = format!("{}/", self.upstream);
+        let stats: UpstreamStats = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(upstream_error)?
+            .json()
+            .await
+            .map_err(upstream_error)?;
+        if let Some(chain_id) = stats.chain_id {
+            self.db.check_chain_id(chain_id)?;
+        }
+        if let Some(ruleset) = stats.ruleset {
+            self.db.check_ruleset(ruleset, self.migrate_ruleset)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the upstream signature of a checkpoint computed from a dump is
+    /// made by `signer`, returning it so that this replica can serve it too.
+    async fn verify_signature(&self, local: Checkpoint, signer: Address) -> Result<[u8; 65]> {
+        let block = local.block;
+        let url = format!("{}/checkpoint/{}/signature", self.upstream, block);
+        let checkpoint: SignedCheckpoint = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(upstream_error)?
+            .json()
+            .await
+            .map_err(upstream_error)?;
+        if checkpoint.block != block || checkpoint.hash != local.hash {
+            Err(MoniqueError::Diverged(format!(
+                "checkpoint mismatch at block {}: signed {}, computed {}",
+                block, checkpoint.hash, local.hash
+            )))?;
+        }
+        let signature = Signature::from_str(checkpoint.signature.trim_start_matches("0x"))
+            .map_err(|e| MoniqueError::Signing(e.to_string()))?;
+        let recovered = signature
+            .recover(local.message().to_vec())
+            .map_err(|e| MoniqueError::Signing(e.to_string()))?;
+        if recovered != signer {
+            Err(MoniqueError::Signing(format!(
+                "checkpoint {} signed by {:?}, expected {:?}",
+                block, recovered, signer
+            )))?;
+        }
+        Ok(signature.into())
+    }
+}
+
+fn upstream_error(e: reqwest::Error) -> MoniqueError {
+    MoniqueError::Upstream(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{IndexTable, Indexed};
+    use ethers::signers::{LocalWallet, Signer};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    type Reply = (u16, Vec<(&'static str, String)>, Vec<u8>);
+
+    /// HTTP upstream answering each request path with `reply`, recording the paths.
+    async fn mock_upstream(
+        reply: impl Fn(&str) -> Reply + Send + Sync + 'static,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = vec![];
+                let mut buffer = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match socket.read(&mut buffer).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split(' ').nth(1).unwrap_or("/").to_string();
+                let (status, headers, body) = reply(&path);
+                recorded.lock().unwrap().push(path);
+                let mut response = format!(
+                    "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    status,
+                    body.len()
+                );
+                for (name, value) in headers {
+                    response.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                response.push_str("\r\n");
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.write_all(&body).await;
+            }
+        });
+        (url, requests)
+    }
+
+    async fn index(path: std::path::PathBuf) -> SharedIndex<20, Address> {
+        Arc::new(
+            IndexTable::<20, Address>::builder(path)
+                .cache_size(16)
+                .build()
+                .await
+                .unwrap(),
+        )
+    }
+
+    /// Upstream index of 3 blocks, with its dump and the checkpoint of its last block.
+    async fn upstream_dump(path: std::path::PathBuf) -> (Vec<u8>, Checkpoint) {
+        let source = index(path).await;
+        let address = Address::repeat_byte;
+        source.queue(1, vec![address(1), address(2)]).await.unwrap();
+        source.queue(2, vec![]).await.unwrap();
+        source.queue(3, vec![address(3)]).await.unwrap();
+        source.commit(3).await.unwrap();
+        let mut dump = vec![];
+        source.export(1, 3, &mut dump).await.unwrap();
+        (dump, source.checkpoint(3).unwrap().unwrap())
+    }
+
+    /// Replies of an upstream serving `dump` from block 1, and `signature` for its
+    /// last block.
+    fn serve(
+        dump: Vec<u8>,
+        signature: Option<String>,
+    ) -> impl Fn(&str) -> Reply + Send + Sync + 'static {
+        move |path| {
+            if path == "/" {
+                (200, vec![], b"{}".to_vec())
+            } else if path.starts_with("/blocks?from=1&") {
+                (200, vec![], dump.clone())
+            } else if path.starts_with("/blocks") {
+                (404, vec![], vec![])
+            } else if let (Some(signature), "/checkpoint/3/signature") = (&signature, path) {
+                (200, vec![], signature.clone().into_bytes())
+            } else {
+                (404, vec![], vec![])
+            }
+        }
+    }
+
+    async fn signed(wallet: &LocalWallet, checkpoint: Checkpoint) -> String {
+        let signature = wallet.sign_message(checkpoint.message()).await.unwrap();
+        serde_json::json!({
+            "block": checkpoint.block,
+            "hash": checkpoint.hash,
+            "signature": signature.to_string(),
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn follows_upstream() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (dump, checkpoint) = upstream_dump(temp_dir.path().join("source.db")).await;
+        let wallet = LocalWallet::new(&mut ethers::core::rand::thread_rng());
+        let signature = signed(&wallet, checkpoint).await;
+        let (url, requests) = mock_upstream(serve(dump, Some(signature))).await;
+
+        let db = index(temp_dir.path().join("replica.db")).await;
+        let follower = Follower::new(db.clone(), &url).with_signer(wallet.address());
+        assert_eq!(
+            follower.sync_batch().await.unwrap(),
+            Batch::Imported {
+                blocks: 3,
+                more: false
+            }
+        );
+        assert_eq!(db.checkpoint(3).unwrap(), Some(checkpoint));
+        assert!(db.get_signature(3).unwrap().is_some());
+        assert_eq!(db.index(Address::repeat_byte(3)).await.unwrap(), Some(2));
+        // caught up
+        assert_eq!(
+            follower.sync_batch().await.unwrap(),
+            Batch::Imported {
+                blocks: 0,
+                more: false
+            }
+        );
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests[0], "/");
+        assert_eq!(requests[2], "/checkpoint/3/signature");
+    }
+
+    #[tokio::test]
+    async fn rejects_forged_upstream() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (dump, checkpoint) = upstream_dump(temp_dir.path().join("source.db")).await;
+        let operator = LocalWallet::new(&mut ethers::core::rand::thread_rng());
+        let forger = LocalWallet::new(&mut ethers::core::rand::thread_rng());
+
+        // signed by another key: nothing is imported
+        let signature = signed(&forger, checkpoint).await;
+        let (url, _) = mock_upstream(serve(dump.clone(), Some(signature))).await;
+        let db = index(temp_dir.path().join("forged.db")).await;
+        let follower = Follower::new(db.clone(), &url).with_signer(operator.address());
+        assert!(matches!(
+            follower.sync_batch().await,
+            Err(MoniqueError::Signing(_))
+        ));
+        assert_eq!(db.committed_len().await, 0);
+        assert_eq!(db.get_counters().await.last_committed_block, 0);
+
+        // a dump whose chain does not match the signed checkpoint
+        let mut tampered = dump;
+        // the root of block 1, after the header and its number
+        tampered[8 + 4 + 32 + 8] ^= 0xff;
+        let signature = signed(&operator, checkpoint).await;
+        let (url, _) = mock_upstream(serve(tampered, Some(signature))).await;
+        let db = index(temp_dir.path().join("tampered.db")).await;
+        let follower = Follower::new(db.clone(), &url).with_signer(operator.address());
+        assert!(follower.sync_batch().await.is_err());
+        assert_eq!(db.committed_len().await, 0);
+    }
+
+    #[tokio::test]
+    async fn honours_busy_and_partial_upstream() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (dump, _) = upstream_dump(temp_dir.path().join("source.db")).await;
+        let busy = Arc::new(AtomicBool::new(true));
+        let reply = {
+            let busy = busy.clone();
+            let serve = serve(dump, None);
+            move |path: &str| {
+                if path.starts_with("/blocks?from=1&") && busy.swap(false, Ordering::Relaxed) {
+                    (429, vec![("Retry-After", "7".to_string())], vec![])
+                } else if path.starts_with("/blocks?from=1&") {
+                    let (status, _, body) = serve(path);
+                    (status, vec![("X-Partial", "true".to_string())], body)
+                } else {
+                    serve(path)
+                }
+            }
+        };
+        let (url, _) = mock_upstream(reply).await;
+        let db = index(temp_dir.path().join("replica.db")).await;
+        let follower = Follower::new(db.clone(), &url);
+        // a busy upstream is retried later, not counted as failing
+        assert_eq!(
+            follower.sync_batch().await.unwrap(),
+            Batch::Busy(Duration::from_secs(7))
+        );
+        assert_eq!(db.get_counters().await.last_committed_block, 0);
+        // a partial batch smaller than the requested one is followed right away
+        assert_eq!(
+            follower.sync_batch().await.unwrap(),
+            Batch::Imported {
+                blocks: 3,
+                more: true
+            }
+        );
+        assert_eq!(db.get_counters().await.last_committed_block, 3);
+    }
+
+    #[tokio::test]
+    async fn halts_on_diverged_upstream() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (dump, checkpoint) = upstream_dump(temp_dir.path().join("source.db")).await;
+        let wallet = LocalWallet::new(&mut ethers::core::rand::thread_rng());
+        // a validly signed checkpoint of another chain
+        let diverged = Checkpoint {
+            hash: H256::repeat_byte(1),
+            ..checkpoint
+        };
+        let signature = signed(&wallet, diverged).await;
+        let (url, _) = mock_upstream(serve(dump, Some(signature))).await;
+        let db = index(temp_dir.path().join("replica.db")).await;
+        let follower = Follower::new(db.clone(), &url)
+            .with_signer(wallet.address())
+            .with_fence("true".to_string());
+        let (_commands, commands_rx) = crate::indexer::control::channel();
+        assert!(matches!(
+            follower.standby(Duration::ZERO, &commands_rx).await,
+            Err(MoniqueError::Diverged(_))
+        ));
+        assert_eq!(db.committed_len().await, 0);
+    }
+
+    #[tokio::test]
+    async fn fences_before_failover() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let db = index(temp_dir.path().join("replica.db")).await;
+        let (_commands, commands_rx) = crate::indexer::control::channel();
+        let failover = |fence: Option<&str>| {
+            let follower = Follower::new(db.clone(), &url).with_interval(Duration::from_millis(10));
+            let follower = match fence {
+                Some(fence) => follower.with_fence(fence.to_string()),
+                None => follower,
+            };
+            let commands_rx = commands_rx.clone();
+            async move {
+                let standby = follower.standby(Duration::ZERO, &commands_rx);
+                tokio::time::timeout(Duration::from_millis(300), standby).await
+            }
+        };
+        // not promoted without a successful fence
+        assert!(failover(None).await.is_err());
+        assert!(failover(Some("exit 1")).await.is_err());
+        assert_eq!(
+            failover(Some("test -n \"$MONIQUE_UPSTREAM\""))
+                .await
+                .unwrap()
+                .unwrap(),
+            0
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Incremental Merkle tree over all the stored entries, in the style of the deposit
+//! contract: only the last left sibling of each level is kept, so appending an entry
+//! and computing the root both take `DEPTH` hashes.
+//!
+//! Leaves are `keccak(item)` at their index, empty leaves are zero, and a node is
+//! `keccak(left | right)`.
+
+use ethers_core::types::H256;
+use tiny_keccak::{Hasher, Keccak};
+
+/// Levels of the tree, enough for the `u32` indexes of the storage.
+pub const DEPTH: usize = 32;
+
+/// Size of the serialized accumulator: count (u64, little endian) | branch.
+pub const ACCUMULATOR_SIZE: usize = 8 + 32 * DEPTH;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Accumulator {
+    branch: [H256; DEPTH],
+    count: u64,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self {
+            branch: [H256::zero(); DEPTH],
+            count: 0,
+        }
+    }
+}
+
+fn hash_pair(left: &H256, right: &H256) -> H256 {
+    let mut hash = [0u8; 32];
+    let mut keccak = Keccak::v256();
+    keccak.update(left.as_bytes());
+    keccak.update(right.as_bytes());
+    keccak.finalize(&mut hash);
+    H256::from(hash)
+}
+
+pub fn leaf(item: &[u8]) -> H256 {
+    let mut hash = [0u8; 32];
+    let mut keccak = Keccak::v256();
+    keccak.update(item);
+    keccak.finalize(&mut hash);
+    H256::from(hash)
+}
+
+impl Accumulator {
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn push(&mut self, item: &[u8]) {
+        let mut node = leaf(item);
+        self.count += 1;
+        let mut size = self.count;
+        for sibling in self.branch.iter_mut() {
+            if size & 1 == 1 {
+                *sibling = node;
+                return;
+            }
+            node = hash_pair(sibling, &node);
+            size >>= 1;
+        }
+        unreachable!("accumulator is full");
+    }
+
+    pub fn root(&self) -> H256 {
+        let mut node = H256::zero();
+        let mut zero = H256::zero();
+        let mut size = self.count;
+        for sibling in self.branch.iter() {
+            node = if size & 1 == 1 {
+                hash_pair(sibling, &node)
+            } else {
+                hash_pair(&node, &zero)
+            };
+            zero = hash_pair(&zero, &zero);
+            size >>= 1;
+        }
+        node
+    }
+
+    pub fn to_bytes(&self) -> [u8; ACCUMULATOR_SIZE] {
+        let mut bytes = [0u8; ACCUMULATOR_SIZE];
+        bytes[..8].copy_from_slice(&self.count.to_le_bytes());
+        for (i, sibling) in self.branch.iter().enumerate() {
+            bytes[8 + 32 * i..8 + 32 * (i + 1)].copy_from_slice(sibling.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; ACCUMULATOR_SIZE]) -> Self {
+        let mut branch = [H256::zero(); DEPTH];
+        for (i, sibling) in branch.iter_mut().enumerate() {
+            *sibling = H256::from_slice(&bytes[8 + 32 * i..8 + 32 * (i + 1)]);
+        }
+        Self {
+            branch,
+            count: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Root of the full tree, computed level by level.
+    fn naive_root(items: &[[u8; 20]]) -> H256 {
+        let mut level: Vec<H256> = items.iter().map(|item| leaf(item)).collect();
+        let mut zero = H256::zero();
+        for _ in 0..DEPTH {
+            if level.len() % 2 == 1 {
+                level.push(zero);
+            }
+            level = level
+                .chunks(2)
+                .map(|pair| hash_pair(&pair[0], &pair[1]))
+                .collect();
+            zero = hash_pair(&zero, &zero);
+        }
+        level.first().copied().unwrap_or(zero)
+    }
+
+    #[test]
+    fn incremental_root() {
+        let mut accumulator = Accumulator::default();
+        assert_eq!(accumulator.root(), naive_root(&[]));
+        let mut items = vec![];
+        for i in 0..20u8 {
+            items.push([i; 20]);
+            accumulator.push(&[i; 20]);
+            assert_eq!(accumulator.root(), naive_root(&items));
+        }
+        assert_eq!(Accumulator::from_bytes(accumulator.to_bytes()), accumulator);
+    }
+}
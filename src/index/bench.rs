@@ -0,0 +1,139 @@
+//! Storage benchmark behind `monique bench`: fills a fresh index with random entries
+//! through queue and commit, then times random lookups both ways. Entries are drawn
+//! from a seeded generator, so that runs with the same options are comparable.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use ethers_core::rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{IndexTable, Indexed};
+use crate::Result;
+
+/// Scale of a benchmark run.
+#[derive(Clone, Debug)]
+pub struct BenchOptions {
+    /// Entries pushed before the lookups.
+    pub entries: usize,
+    /// Entries of each block.
+    pub block_size: usize,
+    /// Blocks committed at once.
+    pub commit_blocks: usize,
+    /// Lookups of each kind.
+    pub lookups: usize,
+    /// Capacity of each lookup cache.
+    pub cache_size: usize,
+    pub seed: u64,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            entries: 1_000_000,
+            block_size: 100,
+            commit_blocks: 100,
+            lookups: 100_000,
+            cache_size: 100_000,
+            seed: 0,
+        }
+    }
+}
+
+/// Timings of one scenario: `operations` in total, over samples timed separately.
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    pub name: &'static str,
+    pub operations: u64,
+    pub elapsed: Duration,
+    /// Durations of the samples, sorted.
+    pub samples: Vec<Duration>,
+}
+
+impl Scenario {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            operations: 0,
+            elapsed: Duration::ZERO,
+            samples: vec![],
+        }
+    }
+
+    fn record(&mut self, operations: u64, elapsed: Duration) {
+        self.operations += operations;
+        self.elapsed += elapsed;
+        self.samples.push(elapsed);
+    }
+
+    pub fn per_second(&self) -> f64 {
+        self.operations as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+
+    /// Sample duration below which `quantile` of the samples fall.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = ((self.samples.len() - 1) as f64 * quantile).round() as usize;
+        self.samples[rank]
+    }
+}
+
+/// Runs the scenarios on a new index in `dir`:
+/// - `push`: entries queued and committed, a sample per commit;
+/// - `commit`: latency of each commit of `commit_blocks` blocks;
+/// - `get`: lookups of random indexes;
+/// - `index`: reverse lookups of random entries.
+pub async fn bench(dir: &Path, options: &BenchOptions) -> Result<Vec<Scenario>> {
+    let index = IndexTable::<20, [u8; 20]>::builder(dir)
+        .cache_size(options.cache_size)
+        .build()
+        .await?;
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let mut items = Vec::with_capacity(options.entries);
+    let (mut push, mut commit) = (Scenario::new("push"), Scenario::new("commit"));
+    let mut number = 0;
+    while items.len() < options.entries {
+        let start = Instant::now();
+        let mut queued = 0;
+        for _ in 0..options.commit_blocks {
+            let count = options.block_size.min(options.entries - items.len());
+            if count == 0 {
+                break;
+            }
+            let block: Vec<[u8; 20]> = (0..count).map(|_| rng.gen()).collect();
+            items.extend_from_slice(&block);
+            number += 1;
+            queued += index.queue(number, block).await?.len();
+        }
+        let committing = Instant::now();
+        index.commit(number).await?;
+        commit.record(1, committing.elapsed());
+        push.record(queued as u64, start.elapsed());
+    }
+
+    let mut get = Scenario::new("get");
+    let mut lookup = Scenario::new("index");
+    if !items.is_empty() {
+        for _ in 0..options.lookups {
+            let position = rng.gen_range(0..items.len());
+            let start = Instant::now();
+            let item = index.get(position).await?;
+            get.record(1, start.elapsed());
+            debug_assert_eq!(item, Some(items[position]));
+        }
+        for _ in 0..options.lookups {
+            let position = rng.gen_range(0..items.len());
+            let start = Instant::now();
+            let found = index.index(items[position]).await?;
+            lookup.record(1, start.elapsed());
+            debug_assert_eq!(found, Some(position));
+        }
+    }
+
+    let mut scenarios = vec![push, commit, get, lookup];
+    for scenario in &mut scenarios {
+        scenario.samples.sort_unstable();
+    }
+    Ok(scenarios)
+}
@@ -1,27 +1,86 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
-use eth_trie::{EthTrie, MemoryDB, Trie};
-use log::trace;
+use eth_trie::{EthTrie, Trie, TrieError, DB};
+use ethers_core::types::H256;
+use tracing::trace;
+
+use crate::{MoniqueError, Result};
+
+/// Nodes written while a trie is built, kept so that they can be persisted.
+#[derive(Default)]
+pub struct MemoryNodes(Mutex<HashMap<Vec<u8>, Vec<u8>>>);
+
+impl DB for MemoryNodes {
+    type Error = std::convert::Infallible;
+
+    fn get(&self, key: &[u8]) -> std::result::Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.0.lock().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> std::result::Result<(), Self::Error> {
+        self.0.lock().unwrap().insert(key.to_vec(), value);
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> std::result::Result<(), Self::Error> {
+        self.0.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn flush(&self) -> std::result::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Read-only view over persisted trie nodes, looked up by hash.
+struct StoredNodes<F>(F);
+
+impl<F> DB for StoredNodes<F>
+where
+    F: Fn(&[u8]) -> Result<Option<Vec<u8>>> + Send + Sync,
+{
+    type Error = MoniqueError;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        (self.0)(key)
+    }
+
+    fn insert(&self, _key: &[u8], _value: Vec<u8>) -> Result<()> {
+        Err(MoniqueError::Storage(
+            "trie nodes are read-only".to_string(),
+        ))
+    }
+
+    fn remove(&self, _key: &[u8]) -> Result<()> {
+        Err(MoniqueError::Storage(
+            "trie nodes are read-only".to_string(),
+        ))
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
 
 pub struct CheckpointTrie {
-    trie: EthTrie<MemoryDB>,
+    trie: EthTrie<MemoryNodes>,
+    nodes: Arc<MemoryNodes>,
     index: u64,
 }
 
 impl CheckpointTrie {
     pub fn new(start_index: u64) -> Self {
-        let mem_db = Arc::new(MemoryDB::new(false));
-        let trie = EthTrie::new(mem_db.clone());
+        let nodes = Arc::new(MemoryNodes::default());
+        let trie = EthTrie::new(nodes.clone());
         Self {
             trie,
+            nodes,
             index: start_index,
         }
     }
 
-    pub fn bulk_insert(
-        &mut self,
-        keys: Vec<&[u8]>,
-    ) -> Result<ethers::types::H256, eth_trie::TrieError> {
+    pub fn bulk_insert(&mut self, keys: Vec<&[u8]>) -> std::result::Result<H256, TrieError> {
         trace!("inserting {} keys for block {}", keys.len(), self.index);
         for key in keys.iter() {
             self.trie
@@ -31,4 +90,34 @@ impl CheckpointTrie {
         }
         self.trie.root_hash()
     }
+
+    /// Nodes on the path from the root to `key`, for a trie already committed with
+    /// `bulk_insert`.
+    pub fn proof(&mut self, key: &[u8]) -> std::result::Result<Vec<Vec<u8>>, TrieError> {
+        self.trie.get_proof(key)
+    }
+
+    /// Hashed nodes of the trie committed with `bulk_insert`.
+    pub fn into_nodes(self) -> Vec<(H256, Vec<u8>)> {
+        drop(self.trie);
+        let mut nodes = self.nodes.0.lock().unwrap();
+        nodes
+            .drain()
+            .map(|(hash, node)| (H256::from_slice(&hash), node))
+            .collect()
+    }
+}
+
+/// Proof of `key` read from persisted nodes, or `None` if the root was not persisted.
+pub fn stored_proof<F>(nodes: F, root: H256, key: &[u8]) -> Result<Option<Vec<Vec<u8>>>>
+where
+    F: Fn(&[u8]) -> Result<Option<Vec<u8>>> + Send + Sync,
+{
+    if nodes(root.as_bytes())?.is_none() {
+        return Ok(None);
+    }
+    let mut trie = EthTrie::new(Arc::new(StoredNodes(nodes))).at_root(root);
+    // loads the root node, `get_proof` cannot encode a root left as a hash
+    trie.root_hash()?;
+    Ok(Some(trie.get_proof(key)?))
 }
@@ -0,0 +1,320 @@
+//! Binary dump of committed blocks, used to seed new deployments from a trusted peer.
+//!
+//! Format (integers are little-endian): the magic `MONIQUE\x01`, the item size
+//! as a u32, then for every block:
+//! `number: u64 | root_hash: [u8; 32] | block_hash: [u8; 32] | count: u32 | items: [[u8; N]; count]`
+//!
+//! Dumps with the magic `MONIQUE\x02` carry the timestamp of each block after its
+//! hash, as a u64 (`u64::MAX` when unknown). Those with the magic `MONIQUE\x03` do
+//! too, and also carry the [`fingerprint`](crate::words::fingerprint) of the
+//! vocabulary of the exporter after the item size, as a `[u8; 32]`.
+
+use super::checkpoint::CheckpointTrie;
+use super::storage::{Block, BlockMeta, Push};
+use super::{Checkpoint, IndexTable, Indexed};
+use crate::{words, MoniqueError, Result};
+use ethers_core::types::H256;
+use std::io::{ErrorKind, Read, Write};
+use std::time::Instant;
+use tracing::{info, warn};
+
+const MAGIC: &[u8; 8] = b"MONIQUE\x01";
+const MAGIC_TIMESTAMPS: &[u8; 8] = b"MONIQUE\x02";
+const MAGIC_WORDLIST: &[u8; 8] = b"MONIQUE\x03";
+const BATCH_SIZE: usize = 100_000;
+
+impl<const N: usize, T> IndexTable<N, T>
+where
+    T: AsRef<[u8]>
+        + From<[u8; N]>
+        + std::cmp::PartialEq
+        + std::hash::Hash
+        + Eq
+        + Copy
+        + Send
+        + Sync
+        + 'static,
+    [u8; N]: From<T>,
+{
+    /// Writes the committed blocks `from..=to` to `writer`, with their timestamps,
+    /// returning the number of blocks.
+    pub async fn export<W: Write>(&self, from: u64, to: u64, writer: W) -> Result<u64> {
+        self.export_until(from, to, writer, None, true).await
+    }
+
+    /// Writes the committed blocks `from..=to` to `writer`, stopping after the block
+    /// during which `deadline` passed, if any. Without `timestamps`, the dump is in
+    /// the first format, which older versions import, and does not carry the
+    /// fingerprint of the vocabulary either. Returns the number of blocks written, at
+    /// least one.
+    pub async fn export_until<W: Write>(
+        &self,
+        from: u64,
+        to: u64,
+        mut writer: W,
+        deadline: Option<Instant>,
+        timestamps: bool,
+    ) -> Result<u64> {
+        let last_block = self.storage.get_counters().await.last_block as u64;
+        if from == 0 || to > last_block {
+            Err(MoniqueError::Dump(format!(
+                "export: invalid range {}..={} (last committed block: {})",
+                from, to, last_block
+            )))?
+        }
+        writer.write_all(if timestamps { MAGIC_WORDLIST } else { MAGIC })?;
+        writer.write_all(&(N as u32).to_le_bytes())?;
+        if timestamps {
+            writer.write_all(words::fingerprint().as_bytes())?;
+        }
+        for number in from..=to {
+            let range = self.storage.get_range(number as u32)?.ok_or_else(|| {
+                MoniqueError::Dump(format!("export: no range recorded for block {}", number))
+            })?;
+            let hash = self.storage.get_block_hash(number as u32)?;
+            writer.write_all(&number.to_le_bytes())?;
+            writer.write_all(range.root_hash.as_bytes())?;
+            writer.write_all(hash.as_bytes())?;
+            if timestamps {
+                let timestamp = self.storage.get_timestamp(number as u32)?;
+                writer.write_all(&timestamp.unwrap_or(u64::MAX).to_le_bytes())?;
+            }
+            writer.write_all(&range.count.to_le_bytes())?;
+            for item in self
+                .storage
+                .get_items(range.start as usize, range.count as usize)?
+            {
+                writer.write_all(item.as_ref())?;
+            }
+            if number % 100_000 == 0 {
+                info!("export: block {}", number);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                writer.flush()?;
+                return Ok(number - from + 1);
+            }
+        }
+        writer.flush()?;
+        Ok(to - from + 1)
+    }
+
+    /// Checkpoint of the last block of a dump following the committed blocks, chained
+    /// from the roots the dump declares and checked against its block hashes, without
+    /// storing anything: a signature of it can be verified before the dump is
+    /// imported, which checks the roots against the entries. `None` for an empty dump.
+    pub async fn dump_checkpoint<R: Read>(&self, mut reader: R) -> Result<Option<Checkpoint>> {
+        let Header { timestamps, .. } = read_header(&mut reader, N)?;
+        let mut previous: Option<(u64, H256)> = None;
+        let mut checkpoint = None;
+        while let Some((block, expected)) = read_block::<N, T, R>(&mut reader, timestamps)? {
+            let previous_hash = match previous {
+                Some((number, hash)) if block.number == number + 1 => hash,
+                Some(_) => Err(MoniqueError::Dump(format!(
+                    "import: unexpected block {}",
+                    block.number
+                )))?,
+                // an empty index starts with the dump
+                None if block.number > self.start_block() && self.is_empty_chain().await => {
+                    H256::zero()
+                }
+                None => {
+                    let last = self.get_counters().await.last_committed_block;
+                    if block.number != last + 1 {
+                        Err(MoniqueError::Dump(format!(
+                            "import: the dump starts at block {}, after block {}",
+                            block.number, last
+                        )))?
+                    }
+                    self.storage.get_block_hash(last as u32)?
+                }
+            };
+            let hash = block.compute_hash(previous_hash);
+            if hash != expected {
+                Err(MoniqueError::Dump(format!(
+                    "import: block hash mismatch at block {}: expected {}, computed {}",
+                    block.number, expected, hash
+                )))?
+            }
+            previous = Some((block.number, hash));
+            checkpoint = Some(Checkpoint {
+                block: block.number,
+                root: block.root_hash,
+                hash,
+            });
+        }
+        Ok(checkpoint)
+    }
+
+    /// Appends the blocks of a dump to the storage, verifying each checkpoint root
+    /// and the chained block hashes. Returns the number of imported blocks.
+    pub async fn import<R: Read>(&self, mut reader: R) -> Result<u64> {
+        let _lock_guard = self.lock.try_lock()?;
+        if !self.pending.read().await.blocks.is_empty() {
+            Err(MoniqueError::Dump(
+                "import: pending queue is not empty".to_string(),
+            ))?
+        }
+        let Header {
+            timestamps,
+            wordlist,
+        } = read_header(&mut reader, N)?;
+        if wordlist.is_some_and(|wordlist| wordlist != words::fingerprint()) {
+            // the entries do not depend on it, but the monics shown by the exporter do
+            warn!(
+                wordlist = ?wordlist,
+                "import: the dump was exported by a resolver with another vocabulary"
+            );
+        }
+
+        let mut index = self.storage.len().await as u64;
+        let mut imported = 0u64;
+        let mut batch: Vec<(Block<T>, H256)> = vec![];
+        let mut batch_items = 0;
+        let mut first = true;
+        while let Some((mut block, hash)) = read_block::<N, T, R>(&mut reader, timestamps)? {
+            // an empty index starts with the dump, e.g. of an instance started at a
+            // later block
+            if std::mem::take(&mut first)
+                && block.number > self.start_block()
+                && self.is_empty_chain().await
+            {
+                self.anchor(block.number).await?;
+            }
+            let mut trie = CheckpointTrie::new(index);
+            let root_hash = trie.bulk_insert(block.items.iter().map(|a| a.as_ref()).collect())?;
+            if root_hash != block.root_hash {
+                Err(MoniqueError::Dump(format!(
+                    "import: root hash mismatch at block {}: expected {}, computed {}",
+                    block.number, block.root_hash, root_hash
+                )))?
+            }
+            if self.storage.persists_tries() {
+                block.nodes = trie.into_nodes();
+            }
+            index += block.items.len() as u64;
+            batch_items += block.items.len();
+            batch.push((block, hash));
+            if batch_items >= BATCH_SIZE {
+                imported += self.import_batch(std::mem::take(&mut batch)).await?;
+                batch_items = 0;
+            }
+        }
+        imported += self.import_batch(batch).await?;
+        Ok(imported)
+    }
+
+    async fn import_batch(&self, batch: Vec<(Block<T>, H256)>) -> Result<u64> {
+        let Some((last, expected)) = batch.last().map(|(b, h)| (b.number, *h)) else {
+            return Ok(0);
+        };
+        let len = batch.len() as u64;
+        // the whole chain is checked before anything is written, so that a tampered
+        // dump leaves the storage as it was
+        let first = batch[0].0.number;
+        if first == 0 {
+            Err(MoniqueError::Dump(
+                "import: unexpected block number 0".to_string(),
+            ))?
+        }
+        let mut previous = self.storage.get_block_hash(first as u32 - 1)?;
+        for (block, expected) in batch.iter() {
+            let hash = block.compute_hash(previous);
+            if hash != *expected {
+                Err(MoniqueError::Dump(format!(
+                    "import: block hash mismatch at block {}: expected {}, computed {}",
+                    block.number, expected, hash
+                )))?
+            }
+            previous = hash;
+        }
+        debug_assert_eq!(previous, expected);
+        self.storage
+            .push(batch.into_iter().map(|(block, _)| block).collect())
+            .await?;
+        let mut counters = self.counters.write().await;
+        counters.last_indexed_block = last;
+        counters.last_committed_block = last;
+        info!("import: block {} [{} addresses]", last, self.len().await);
+        Ok(len)
+    }
+}
+
+/// Header of a dump.
+pub(super) struct Header {
+    /// Whether its blocks carry their timestamp.
+    pub timestamps: bool,
+    /// Fingerprint of the vocabulary of the exporter, if recorded.
+    pub wordlist: Option<H256>,
+}
+
+/// Reads the header of a dump, whose item size must be `item_size`.
+pub(super) fn read_header<R: Read>(reader: &mut R, item_size: usize) -> Result<Header> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    let (timestamps, fingerprint) = match &magic {
+        MAGIC => (false, false),
+        MAGIC_TIMESTAMPS => (true, false),
+        MAGIC_WORDLIST => (true, true),
+        _ => Err(MoniqueError::Dump("import: not a monique dump".to_string()))?,
+    };
+    let mut size = [0u8; 4];
+    reader.read_exact(&mut size)?;
+    if u32::from_le_bytes(size) as usize != item_size {
+        Err(MoniqueError::Dump("import: item size mismatch".to_string()))?
+    }
+    let mut wordlist = None;
+    if fingerprint {
+        let mut hash = [0u8; 32];
+        reader.read_exact(&mut hash)?;
+        wordlist = Some(H256(hash));
+    }
+    Ok(Header {
+        timestamps,
+        wordlist,
+    })
+}
+
+pub(super) fn read_block<const N: usize, T: From<[u8; N]>, R: Read>(
+    reader: &mut R,
+    timestamps: bool,
+) -> Result<Option<(Block<T>, H256)>> {
+    let mut number = [0u8; 8];
+    match reader.read_exact(&mut number) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut root_hash = [0u8; 32];
+    reader.read_exact(&mut root_hash)?;
+    let mut hash = [0u8; 32];
+    reader.read_exact(&mut hash)?;
+    let mut timestamp = None;
+    if timestamps {
+        let mut bytes = [0u8; 8];
+        reader.read_exact(&mut bytes)?;
+        timestamp = Some(u64::from_le_bytes(bytes)).filter(|t| *t != u64::MAX);
+    }
+    let mut count = [0u8; 4];
+    reader.read_exact(&mut count)?;
+    let count = u32::from_le_bytes(count) as usize;
+    // the count is untrusted: a truncated dump fails on the missing items instead
+    let mut items = Vec::with_capacity(count.min(BATCH_SIZE));
+    for _ in 0..count {
+        let mut item = [0u8; N];
+        reader.read_exact(&mut item)?;
+        items.push(T::from(item));
+    }
+    Ok(Some((
+        Block {
+            number: u64::from_le_bytes(number),
+            items,
+            root_hash: root_hash.into(),
+            nodes: vec![],
+            meta: BlockMeta {
+                timestamp,
+                ..Default::default()
+            },
+        },
+        hash.into(),
+    )))
+}
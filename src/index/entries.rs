@@ -0,0 +1,158 @@
+//! Lossless subscription to the committed entries, and to the rollbacks of the index.
+
+use super::IndexTable;
+use crate::Result;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Entries committed after a subscription, in order. A subscriber lagging behind the
+/// commits reads the entries it missed back from the committed blocks, rather than
+/// skipping them.
+pub struct CommittedEntries<const N: usize, T> {
+    index: Arc<IndexTable<N, T>>,
+    receiver: broadcast::Receiver<(usize, T, u64)>,
+    rollbacks: Rollbacks,
+    /// First block whose entries may not all have been delivered.
+    block: u64,
+    /// Index of the next entry, once one was delivered.
+    next: Option<usize>,
+    /// Next block to read back, and the last one, while catching up.
+    replay: Option<(u64, u64)>,
+    /// Entries read back from the current block, delivered first.
+    backlog: VecDeque<(usize, T, u64)>,
+    /// Whether broadcast entries before `next` were read back, and are skipped.
+    deduplicate: bool,
+}
+
+impl<const N: usize, T> CommittedEntries<N, T>
+where
+    T: AsRef<[u8]>
+        + From<[u8; N]>
+        + std::cmp::PartialEq
+        + std::hash::Hash
+        + Eq
+        + Copy
+        + Send
+        + Sync
+        + 'static,
+    [u8; N]: From<T>,
+{
+    pub(super) fn new(index: Arc<IndexTable<N, T>>) -> Self {
+        let receiver = index.committed.subscribe();
+        let rollbacks = index.subscribe_rollbacks();
+        let block = *index.commits.borrow() + 1;
+        Self {
+            index,
+            receiver,
+            rollbacks,
+            block,
+            next: None,
+            replay: None,
+            backlog: VecDeque::new(),
+            deduplicate: false,
+        }
+    }
+
+    /// Next committed `(index, item, block)` entry, `None` once the index is dropped.
+    pub async fn recv(&mut self) -> Result<Option<(usize, T, u64)>> {
+        loop {
+            if let Some(to) = self.rollbacks.take() {
+                self.rewind(to);
+            }
+            if let Some(entry) = self.backlog.pop_front() {
+                return Ok(Some(self.deliver(entry)));
+            }
+            if let Some((number, last)) = self.replay {
+                self.replay = (number < last).then_some((number + 1, last));
+                match self.index.block_entries(number)? {
+                    Some((start, items)) => {
+                        let next = self.next.unwrap_or(0);
+                        self.backlog.extend(
+                            items
+                                .into_iter()
+                                .enumerate()
+                                .map(|(offset, item)| (start + offset, item, number))
+                                .filter(|(index, _, _)| *index >= next),
+                        );
+                    }
+                    None => warn!(block = number, "entries: no range recorded, skipping"),
+                }
+                continue;
+            }
+            match self.receiver.recv().await {
+                Ok(entry) => {
+                    if self.deduplicate {
+                        if entry.0 < self.next.unwrap_or(0) {
+                            continue;
+                        }
+                        self.deduplicate = false;
+                    }
+                    return Ok(Some(self.deliver(entry)));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    let last = *self.index.commits.borrow();
+                    warn!(
+                        skipped,
+                        from = self.block,
+                        to = last,
+                        "entries: lagging, reading the committed blocks back"
+                    );
+                    self.replay = (self.block <= last).then_some((self.block, last));
+                    self.deduplicate = true;
+                }
+                Err(RecvError::Closed) => return Ok(None),
+            }
+        }
+    }
+
+    fn deliver(&mut self, entry: (usize, T, u64)) -> (usize, T, u64) {
+        self.block = entry.2;
+        self.next = Some(entry.0 + 1);
+        entry
+    }
+
+    /// Restarts after a rollback to block `to`: the entries of the blocks after it are
+    /// committed again under the same indexes, and those already broadcast are stale,
+    /// so the committed blocks are read back as after a lag.
+    fn rewind(&mut self, to: u64) {
+        self.receiver = self.index.committed.subscribe();
+        if self.block > to {
+            self.block = to + 1;
+            self.next = None;
+        }
+        self.backlog.clear();
+        let last = *self.index.commits.borrow();
+        self.replay = (self.block <= last).then_some((self.block, last));
+        self.deduplicate = true;
+    }
+}
+
+/// Rollbacks of the index after a subscription.
+pub struct Rollbacks {
+    receiver: watch::Receiver<Vec<u64>>,
+    /// Number of rollbacks already taken.
+    seen: usize,
+}
+
+impl Rollbacks {
+    pub(super) fn new(receiver: watch::Receiver<Vec<u64>>) -> Self {
+        let seen = receiver.borrow().len();
+        Self { receiver, seen }
+    }
+
+    /// Lowest block the index was rolled back to since the last call, if any.
+    pub fn take(&mut self) -> Option<u64> {
+        let log = self.receiver.borrow_and_update();
+        let to = log[self.seen..].iter().min().copied();
+        self.seen = log.len();
+        to
+    }
+
+    /// Waits for a rollback, returning `false` once the index is dropped.
+    pub async fn changed(&mut self) -> bool {
+        self.receiver.changed().await.is_ok()
+    }
+}
@@ -0,0 +1,97 @@
+//! Counters and timers of the queue and commit operations, to be graphed.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters and cumulative timers of the queue and commit operations of an
+/// [`IndexTable`](super::IndexTable) since it was opened.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    pub queued_blocks: u64,
+    /// Time spent queueing blocks, filtering their items included.
+    pub queue_us: u64,
+    /// Items submitted to `queue`.
+    pub queued_items: u64,
+    /// Submitted items dropped as already pending or indexed.
+    pub duplicate_items: u64,
+    pub commits: u64,
+    pub committed_items: u64,
+    /// Time spent building the checkpoint tries of the committed blocks.
+    pub commit_prepare_us: u64,
+    /// Time spent writing the committed blocks to storage.
+    pub commit_push_us: u64,
+    /// Blocks queued but not committed yet.
+    pub pending_blocks: usize,
+    /// Items of the pending blocks.
+    pub pending_items: usize,
+    /// Estimated memory held by the pending blocks, in bytes.
+    pub pending_bytes: usize,
+    /// Pending blocks moved to disk, not counted in the above.
+    pub spilled_blocks: usize,
+}
+
+impl Metrics {
+    /// Ratio of the submitted items filtered out as duplicates.
+    pub fn duplicate_ratio(&self) -> f64 {
+        if self.queued_items == 0 {
+            return 0.0;
+        }
+        self.duplicate_items as f64 / self.queued_items as f64
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Recorder {
+    queued_blocks: AtomicU64,
+    queue_us: AtomicU64,
+    queued_items: AtomicU64,
+    duplicate_items: AtomicU64,
+    commits: AtomicU64,
+    committed_items: AtomicU64,
+    commit_prepare_us: AtomicU64,
+    commit_push_us: AtomicU64,
+}
+
+impl Recorder {
+    pub fn queued(&self, items: usize, duplicates: usize, elapsed: Duration) {
+        self.queued_blocks.fetch_add(1, Ordering::Relaxed);
+        self.queue_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.queued_items.fetch_add(items as u64, Ordering::Relaxed);
+        self.duplicate_items
+            .fetch_add(duplicates as u64, Ordering::Relaxed);
+    }
+
+    pub fn committed(&self, items: usize, prepare_us: u128, push_us: u128) {
+        self.commits.fetch_add(1, Ordering::Relaxed);
+        self.committed_items
+            .fetch_add(items as u64, Ordering::Relaxed);
+        self.commit_prepare_us
+            .fetch_add(prepare_us as u64, Ordering::Relaxed);
+        self.commit_push_us
+            .fetch_add(push_us as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(
+        &self,
+        pending_blocks: usize,
+        pending_items: usize,
+        pending_bytes: usize,
+        spilled_blocks: usize,
+    ) -> Metrics {
+        Metrics {
+            queued_blocks: self.queued_blocks.load(Ordering::Relaxed),
+            queue_us: self.queue_us.load(Ordering::Relaxed),
+            queued_items: self.queued_items.load(Ordering::Relaxed),
+            duplicate_items: self.duplicate_items.load(Ordering::Relaxed),
+            commits: self.commits.load(Ordering::Relaxed),
+            committed_items: self.committed_items.load(Ordering::Relaxed),
+            commit_prepare_us: self.commit_prepare_us.load(Ordering::Relaxed),
+            commit_push_us: self.commit_push_us.load(Ordering::Relaxed),
+            pending_blocks,
+            pending_items,
+            pending_bytes,
+            spilled_blocks,
+        }
+    }
+}
@@ -1,160 +1,1685 @@
+mod accumulator;
+mod bench;
 mod checkpoint;
+mod dump;
+mod entries;
+mod metrics;
+mod segments;
 mod storage;
 #[cfg(test)]
 mod tests;
 
+pub use self::bench::{bench, BenchOptions, Scenario};
 use self::checkpoint::CheckpointTrie;
-use crate::index::storage::{Push, Storage};
-use crate::Result;
+pub use self::entries::{CommittedEntries, Rollbacks};
+pub use self::metrics::Metrics;
+use self::metrics::Recorder;
+pub use self::segments::{
+    checksum, manifest_item_size, manifest_wordlist, read_manifest, verify_segments,
+    write_manifest, Envelope, Plain, Segment, SegmentWriter,
+};
+pub use crate::index::storage::{
+    Appearance, Backend, BucketReport, HistorySample, Label, Space, Tombstone, TombstoneReason,
+};
+use crate::index::storage::{Push, Storage, StorageOptions};
+use crate::{MoniqueError, Result};
 use async_trait::async_trait;
+use ethers_core::types::H256;
 use indexmap::IndexSet;
-use log::{info, trace, warn};
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::Instant;
-use std::{cmp, collections::HashMap};
-use storage::Block;
-use tokio::sync::{Mutex, RwLock, RwLockReadGuard};
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap, HashSet},
+};
+use storage::{Block, BlockMeta, BlockRange};
+use tokio::sync::{broadcast, mpsc, watch, Mutex, RwLock, RwLockReadGuard};
+use tracing::{info, instrument, trace, warn};
 
 #[async_trait]
 pub trait Indexed<T> {
     async fn len(&self) -> usize;
+    async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
     async fn get(&self, index: usize) -> Result<Option<T>>;
     async fn index(&self, item: T) -> Result<Option<usize>>;
 }
 
 pub type SharedIndex<const N: usize, T> = Arc<IndexTable<N, T>>;
 
+/// Share of the maximum database size above which commits log a warning.
+pub const MAP_USAGE_WARNING: f64 = 0.9;
+
+/// Samples of the progress kept by `record_history` to cover `retention` seconds at
+/// one every `interval` seconds.
+pub fn history_capacity(interval: u64, retention: u64) -> u32 {
+    retention
+        .div_ceil(interval.max(1))
+        .clamp(1, u32::MAX as u64) as u32
+}
+
+/// On-disk size of the tables of an index.
+#[derive(Clone, Debug)]
+pub struct DiskUsage {
+    /// Bytes of the pages of each existing table.
+    pub tables: Vec<(&'static str, u64)>,
+    pub space: Space,
+}
+
+/// Name of the database file in the directory of an index.
+const DATA_FILE: &str = "mdbx.dat";
+
+/// Entries buffered for each commit subscriber before it starts lagging.
+const COMMITTED_CAPACITY: usize = 65_536;
+
+/// How far ahead of the next expected block a block can be queued, e.g. by parallel
+/// fetchers.
+const REORDER_WINDOW: u64 = 64;
+
+/// Entries per batch of blocks handed from the prepare stage of a commit to its push
+/// stage, so that building the tries of a batch overlaps writing the previous one.
+#[cfg(not(test))]
+const COMMIT_BATCH_SIZE: usize = 50_000;
+#[cfg(test)]
+const COMMIT_BATCH_SIZE: usize = 100;
+
+/// Entries read per storage pass of a scan, between which other tasks get to run.
+#[cfg(not(test))]
+const SCAN_CHUNK: usize = 10_000;
+#[cfg(test)]
+const SCAN_CHUNK: usize = 16;
+
+/// Cost a scan may incur before it stops early: the number of entries it reads and
+/// the time it runs for, checked between chunks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScanBudget {
+    pub entries: usize,
+    pub duration: Duration,
+}
+
+/// Commitment to the entries of a block: the root of its checkpoint trie and the
+/// hash chaining it to the previous blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub block: u64,
+    pub root: H256,
+    pub hash: H256,
+}
+
+impl Checkpoint {
+    /// Bytes signed by the operator: block number (big endian) | root | hash.
+    pub fn message(&self) -> [u8; 72] {
+        let mut message = [0u8; 72];
+        message[..8].copy_from_slice(&self.block.to_be_bytes());
+        message[8..40].copy_from_slice(self.root.as_bytes());
+        message[40..].copy_from_slice(self.hash.as_bytes());
+        message
+    }
+}
+
+/// Merkle proof that an entry belongs to the checkpoint trie of its block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proof {
+    pub block: u64,
+    pub root: H256,
+    pub nodes: Vec<Vec<u8>>,
+}
+
+/// Merkle multi-proof that entries of a block belong to its checkpoint trie: the
+/// nodes of their individual proofs, each included once.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultiProof<T> {
+    pub block: u64,
+    pub root: H256,
+    pub entries: Vec<(usize, T)>,
+    pub nodes: Vec<Vec<u8>>,
+}
+
 pub struct Counters {
     pub last_indexed_block: u64,
     pub last_committed_block: u64,
 }
 
+/// Data queued with the items of a block, committed along with the entries the
+/// block adds.
+#[derive(Clone, Debug)]
+pub struct BlockData<T> {
+    /// Unix time of the block.
+    pub timestamp: Option<u64>,
+    /// Transaction each item was first seen in, recorded for the items the block
+    /// adds to the index.
+    pub appearances: HashMap<T, Appearance>,
+    /// Stats counter incremented by each item the block adds to the index, e.g. the
+    /// one of its source.
+    pub counters: HashMap<T, &'static str>,
+}
+
+impl<T> Default for BlockData<T> {
+    fn default() -> Self {
+        Self {
+            timestamp: None,
+            appearances: HashMap::new(),
+            counters: HashMap::new(),
+        }
+    }
+}
+
+impl<T: std::hash::Hash + Eq> BlockData<T> {
+    /// Data recorded with the `items` the block adds to the index.
+    fn meta(&self, items: &[T]) -> BlockMeta {
+        let appearances = items
+            .iter()
+            .enumerate()
+            .filter_map(|(offset, item)| Some((offset as u32, *self.appearances.get(item)?)))
+            .collect();
+        let mut counts = BTreeMap::new();
+        for counter in items.iter().filter_map(|item| self.counters.get(item)) {
+            *counts.entry(*counter).or_insert(0) += 1;
+        }
+        BlockMeta {
+            timestamp: self.timestamp,
+            appearances,
+            counts: counts
+                .into_iter()
+                .map(|(counter, count)| (counter.to_string(), count))
+                .collect(),
+        }
+    }
+}
+
+/// Blocks queued but not committed yet, with the set of all their items so that
+/// duplicates can be filtered without scanning every block.
+struct Pending<T> {
+    blocks: BTreeMap<u64, Vec<T>>,
+    items: HashSet<T>,
+    /// Data of the queued blocks, spilled ones included.
+    meta: BTreeMap<u64, BlockMeta>,
+    /// Blocks queued ahead of the next expected one, released in sequence once the
+    /// blocks before them are queued.
+    ahead: BTreeMap<u64, (Vec<T>, BlockData<T>)>,
+    /// Blocks moved to the `spill` table to bound the memory of the queue, with their
+    /// number of items. They are all older than the blocks in memory.
+    spilled: BTreeMap<u64, usize>,
+}
+
+impl<T> Default for Pending<T> {
+    fn default() -> Self {
+        Self {
+            blocks: BTreeMap::new(),
+            items: HashSet::new(),
+            meta: BTreeMap::new(),
+            ahead: BTreeMap::new(),
+            spilled: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T: std::hash::Hash + Eq + Copy> Pending<T> {
+    fn insert(&mut self, number: u64, items: Vec<T>) {
+        self.items.extend(items.iter().copied());
+        self.blocks.insert(number, items);
+    }
+
+    fn remove(&mut self, number: u64) -> Option<Vec<T>> {
+        let items = self.blocks.remove(&number)?;
+        for item in &items {
+            self.items.remove(item);
+        }
+        Some(items)
+    }
+
+    /// Moves a block out of memory, once stored in the `spill` table.
+    fn spill(&mut self, number: u64) {
+        if let Some(items) = self.remove(number) {
+            self.spilled.insert(number, items.len());
+        }
+    }
+
+    /// Items of the blocks in memory after `committed`, in block order: the item at
+    /// `offset` has the global index `stored + spilled_after(committed) + offset`,
+    /// `stored` being the number of entries committed up to block `committed`.
+    fn after(&self, committed: u64) -> impl Iterator<Item = &T> {
+        self.blocks
+            .range(committed + 1..)
+            .flat_map(|(_, items)| items)
+    }
+
+    fn count_after(&self, committed: u64) -> usize {
+        let in_memory: usize = self
+            .blocks
+            .range(committed + 1..)
+            .map(|(_, items)| items.len())
+            .sum();
+        in_memory + self.spilled_after(committed)
+    }
+
+    /// Number of items of the spilled blocks after `committed`.
+    fn spilled_after(&self, committed: u64) -> usize {
+        self.spilled
+            .range(committed + 1..)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Spilled block holding the item at `offset` after `committed`, with its offset
+    /// in the block.
+    fn locate_spilled(&self, committed: u64, mut offset: usize) -> Option<(u64, usize)> {
+        for (number, count) in self.spilled.range(committed + 1..) {
+            if offset < *count {
+                return Some((*number, offset));
+            }
+            offset -= count;
+        }
+        None
+    }
+
+    /// Item at `offset` after `committed`, unless it is in a spilled block.
+    fn get_after(&self, committed: u64, offset: usize) -> Option<T> {
+        let mut offset = offset.checked_sub(self.spilled_after(committed))?;
+        for (_, items) in self.blocks.range(committed + 1..) {
+            if offset < items.len() {
+                return Some(items[offset]);
+            }
+            offset -= items.len();
+        }
+        None
+    }
+
+    /// Estimated memory of a pending item: it is in its block and in the set of all
+    /// of them, whose slots also carry a control byte and spare room.
+    fn item_memory() -> usize {
+        2 * std::mem::size_of::<T>() + 8
+    }
+
+    /// Estimated memory held by the pending items, those held ahead included.
+    fn memory(&self) -> usize {
+        let held: usize = self.ahead.values().map(|(items, _)| items.len()).sum();
+        self.items.len() * Self::item_memory() + held * std::mem::size_of::<T>()
+    }
+
+    /// Drops the blocks up to `number` (included), returning the spilled ones.
+    fn remove_until(&mut self, number: u64) -> Vec<u64> {
+        self.meta = self.meta.split_off(&(number + 1));
+        let kept = self.blocks.split_off(&(number + 1));
+        for items in std::mem::replace(&mut self.blocks, kept).values() {
+            for item in items {
+                self.items.remove(item);
+            }
+        }
+        let kept = self.spilled.split_off(&(number + 1));
+        std::mem::replace(&mut self.spilled, kept)
+            .into_keys()
+            .collect()
+    }
+}
+
 pub struct IndexTable<const N: usize, T> {
     counters: RwLock<Counters>,
-    pending: RwLock<HashMap<u64, Vec<T>>>,
-    storage: Storage<N, T>,
+    pending: RwLock<Pending<T>>,
+    /// Shared with the blocking tasks writing to it.
+    storage: Arc<Storage<N, T>>,
     lock: Mutex<()>,
+    /// Last block of the commit in progress, 0 if none: a reorg of its blocks would be
+    /// lost, as they are written from a snapshot of the pending queue.
+    committing: AtomicU64,
+    committed: broadcast::Sender<(usize, T, u64)>,
+    commits: watch::Sender<u64>,
+    /// Blocks the index was rolled back to, in order, updated before `commits`.
+    rollbacks: watch::Sender<Vec<u64>>,
+    metrics: Recorder,
+    /// Chain id and ruleset recorded in the stats table, cached as they are read with
+    /// every response of the API.
+    chain_id: OnceLock<u64>,
+    ruleset: std::sync::RwLock<Option<H256>>,
+}
+
+/// Configures and opens an [`IndexTable`].
+pub struct IndexTableBuilder<const N: usize, T> {
+    path: PathBuf,
+    options: StorageOptions,
+    _data: std::marker::PhantomData<T>,
+}
+
+impl<const N: usize, T> IndexTableBuilder<N, T>
+where
+    T: AsRef<[u8]>
+        + From<[u8; N]>
+        + cmp::PartialEq
+        + std::hash::Hash
+        + Eq
+        + Copy
+        + Send
+        + Sync
+        + 'static,
+    [u8; N]: From<T>,
+{
+    /// Sets the capacity of both lookup caches.
+    pub fn cache_size(mut self, size: usize) -> Self {
+        self.options.cache_size = size;
+        self.options.index_cache_size = size;
+        self
+    }
+
+    /// Sets the capacity of the index -> item cache only.
+    pub fn index_cache_size(mut self, size: usize) -> Self {
+        self.options.index_cache_size = size;
+        self
+    }
+
+    /// Opens an existing index without write access, e.g. for offline lookups.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// Selects the storage engine, and how its commits are synced to disk.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.options.backend = backend;
+        self
+    }
+
+    /// Sets the mdbx page size, only effective when the database is created.
+    pub fn page_size(mut self, size: usize) -> Self {
+        self.options.page_size = size;
+        self
+    }
+
+    /// Sets the mdbx geometry (lower bound, upper bound and growth step, in bytes).
+    pub fn geometry(
+        mut self,
+        min_size: Option<isize>,
+        max_size: Option<isize>,
+        growth_step: Option<isize>,
+    ) -> Self {
+        self.options.min_size = min_size;
+        self.options.max_size = max_size;
+        self.options.growth_step = growth_step;
+        self
+    }
+
+    /// Stores the checkpoint trie nodes of the blocks committed from now on, so that
+    /// proofs are read instead of rebuilt from all the entries of their block.
+    pub fn persist_tries(mut self, persist: bool) -> Self {
+        self.options.persist_tries = persist;
+        self
+    }
+
+    /// Keeps the pending blocks dropped by a reorg or a rollback for `retention`
+    /// seconds, 0 not recording them.
+    pub fn tombstone_retention(mut self, retention: u64) -> Self {
+        self.options.tombstone_retention = retention;
+        self
+    }
+
+    pub async fn build(self) -> Result<IndexTable<N, T>> {
+        let storage = Storage::open(self.path, &self.options)?;
+        IndexTable::from_storage(storage).await
+    }
 }
 
 impl<const N: usize, T> IndexTable<N, T>
 where
-    T: AsRef<[u8]> + From<[u8; N]> + cmp::PartialEq + std::hash::Hash + Eq + Copy + Send + Sync,
+    T: AsRef<[u8]>
+        + From<[u8; N]>
+        + cmp::PartialEq
+        + std::hash::Hash
+        + Eq
+        + Copy
+        + Send
+        + Sync
+        + 'static,
     [u8; N]: From<T>,
 {
-    pub async fn new(path: PathBuf, cache_size: usize) -> Self {
-        let storage = Storage::new(path, cache_size);
+    /// Starts configuring an index stored in `path`.
+    pub fn builder(path: impl Into<PathBuf>) -> IndexTableBuilder<N, T> {
+        IndexTableBuilder {
+            path: path.into(),
+            options: StorageOptions::default(),
+            _data: std::marker::PhantomData,
+        }
+    }
+
+    async fn from_storage(storage: Storage<N, T>) -> Result<Self> {
+        if !storage.is_read_only() {
+            // left by a previous process, whose pending queue is gone
+            storage.clear_spilled()?;
+        }
         let last_block = storage.get_counters().await.last_block;
+        let chain_id = OnceLock::new();
+        if let Some(id) = storage.get_stat("chain_id")? {
+            let _ = chain_id.set(id);
+        }
+        let ruleset = std::sync::RwLock::new(storage.get_stat_hash("ruleset")?);
         let counters = Counters {
             last_indexed_block: last_block as u64,
             last_committed_block: last_block as u64,
         };
-        Self {
-            pending: RwLock::new(HashMap::new()),
+        Ok(Self {
+            pending: RwLock::new(Pending::default()),
             counters: RwLock::new(counters),
-            storage,
+            storage: Arc::new(storage),
             lock: Mutex::new(()),
-        }
+            committing: AtomicU64::new(0),
+            committed: broadcast::channel(COMMITTED_CAPACITY).0,
+            commits: watch::channel(last_block as u64).0,
+            rollbacks: watch::channel(vec![]).0,
+            metrics: Recorder::default(),
+            chain_id,
+            ruleset,
+        })
     }
 
-    pub async fn get_counters(&self) -> RwLockReadGuard<Counters> {
+    pub async fn get_counters(&self) -> RwLockReadGuard<'_, Counters> {
         self.counters.read().await
     }
 
-    pub async fn queue(&self, block_number: u64, addresses: Vec<T>) -> Result<usize> {
+    /// Checks that the stored counters agree with the tables and that the last block
+    /// chains from its predecessor, so that silent drift is detected before lookups
+    /// fail.
+    pub fn check(&self) -> Result<()> {
+        let errors = self.storage.check()?;
+        if !errors.is_empty() {
+            Err(MoniqueError::Integrity(errors.join("; ")))?
+        }
+        Ok(())
+    }
+
+    /// Truncates the index back to its last consistent block when the integrity check
+    /// fails, so that indexing resumes from the block after it. Returns that block, or
+    /// `None` when the check passed.
+    pub async fn recover(&self) -> Result<Option<u64>> {
+        let _lock_guard = self.lock.try_lock()?;
+        let mut pending = self.pending.write().await;
+        let Some(to) = self.storage.recover().await? else {
+            return Ok(None);
+        };
+        let to = to as u64;
+        *pending = Pending::default();
+        self.storage.clear_spilled()?;
+        {
+            let mut counters = self.counters.write().await;
+            counters.last_indexed_block = to;
+            counters.last_committed_block = to;
+        }
+        self.rollbacks.send_modify(|log| log.push(to));
+        self.commits.send_replace(to);
+        warn!(block = to, "recovered");
+        Ok(Some(to))
+    }
+
+    /// Page usage of the database file.
+    pub fn space(&self) -> Result<Space> {
+        self.storage.space()
+    }
+
+    /// Size of the pages of each table, with the page usage of the file.
+    pub fn disk_usage(&self) -> Result<DiskUsage> {
+        Ok(DiskUsage {
+            tables: self.storage.table_sizes()?,
+            space: self.storage.space()?,
+        })
+    }
+
+    /// Bucket sizes of the dedup table, with its `hot_spots` largest buckets.
+    pub fn analyze_buckets(&self, hot_spots: usize) -> Result<BucketReport> {
+        self.storage.analyze_buckets(hot_spots)
+    }
+
+    /// Writes a compacted copy of the database to the `dir` directory, without its
+    /// free pages. The copy is consistent even while the index is written to.
+    pub fn compact_to(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        self.storage.copy_compact(&dir.join(DATA_FILE))
+    }
+
+    /// First block of the index, 1 unless it was started at a later block.
+    pub fn start_block(&self) -> u64 {
+        self.storage.start_block() as u64
+    }
+
+    /// Starts an empty index at block `start`, e.g. for an L2 or a pruned node: the
+    /// checkpoint of block `start` chains to the zero hash, as block 1 does by default.
+    pub async fn set_start_block(&self, start: u64) -> Result<()> {
+        if start == self.start_block() {
+            return Ok(());
+        }
+        let _lock_guard = self.lock.try_lock()?;
+        self.anchor(start).await
+    }
+
+    /// Whether no block was committed or queued yet.
+    async fn is_empty_chain(&self) -> bool {
+        let counters = self.get_counters().await;
+        counters.last_indexed_block + 1 == self.start_block()
+    }
+
+    /// Moves the start of an empty index to block `start`, the commit lock being held.
+    async fn anchor(&self, start: u64) -> Result<()> {
+        if !self.is_empty_chain().await {
+            Err(MoniqueError::Storage(format!(
+                "cannot start at block {}: the index holds blocks from block {}",
+                start,
+                self.start_block()
+            )))?
+        }
+        if start > u32::MAX as u64 {
+            Err(MoniqueError::Storage(format!(
+                "invalid start block {}",
+                start
+            )))?
+        }
+        let _pending = self.pending.write().await;
+        self.storage.set_start_block(start as u32).await?;
+        let mut counters = self.counters.write().await;
+        counters.last_indexed_block = start - 1;
+        counters.last_committed_block = start - 1;
+        self.commits.send_replace(start - 1);
+        Ok(())
+    }
+
+    /// Unwinds the index to block `to`: the blocks committed after it are removed
+    /// from the storage and the pending queue is dropped, so that indexing resumes
+    /// from block `to + 1`. Returns the number of committed entries removed.
+    pub async fn rollback(&self, to: u64) -> Result<usize> {
+        let _lock_guard = self.lock.try_lock()?;
+        if to > u32::MAX as u64 {
+            Err(MoniqueError::Rollback(format!("invalid block {}", to)))?
+        }
+        let committed = self.get_counters().await.last_committed_block;
+        if to > committed {
+            Err(MoniqueError::Rollback(format!(
+                "block {} is not committed yet, the last committed block is {}",
+                to, committed
+            )))?
+        }
+        let (removed, dropped) = {
+            let mut pending = self.pending.write().await;
+            let removed = self.storage.truncate(to as u32).await?;
+            let mut dropped = std::mem::take(&mut *pending);
+            for number in dropped.spilled.keys() {
+                if let Some(items) = self.storage.get_spilled(*number)? {
+                    dropped.blocks.insert(*number, items);
+                }
+            }
+            for (number, (items, _)) in dropped.ahead {
+                dropped.blocks.insert(number, items);
+            }
+            self.storage.clear_spilled()?;
+            let mut counters = self.counters.write().await;
+            counters.last_indexed_block = to;
+            counters.last_committed_block = to;
+            (removed, dropped.blocks)
+        };
+        self.bury(dropped, TombstoneReason::Rollback).await;
+        self.rollbacks.send_modify(|log| log.push(to));
+        self.commits.send_replace(to);
+        warn!(block = to, removed, "rolled back");
+        Ok(removed)
+    }
+
+    /// Catches up with the blocks committed (or rolled back) by the writer process of
+    /// a read-only index, notifying the subscribers as a commit would. Returns the last
+    /// committed block.
+    pub async fn refresh(&self) -> Result<u64> {
+        if !self.storage.is_read_only() {
+            Err(MoniqueError::Storage(
+                "refresh: only read-only indexes follow the writer process".to_string(),
+            ))?
+        }
+        // the height readers see the stored counters and the last block change together
+        let pending = self.pending.write().await;
+        let Some((previous, truncated)) = self.storage.refresh().await? else {
+            return Ok(self.get_counters().await.last_committed_block);
+        };
+        let current = self.storage.get_counters().await.clone();
+        let last = current.last_block as u64;
+        // the writer may have recorded or migrated its ruleset
+        *self.ruleset.write().unwrap() = self.storage.get_stat_hash("ruleset")?;
+        {
+            let mut counters = self.counters.write().await;
+            counters.last_indexed_block = last;
+            counters.last_committed_block = last;
+        }
+        drop(pending);
+        if let Some(to) = truncated {
+            self.rollbacks.send_modify(|log| log.push(to as u64));
+        }
+        self.commits.send_replace(last);
+        if current.counter > previous.counter && self.committed.receiver_count() > 0 {
+            let from = previous.last_block.min(current.last_block) + 1;
+            for (number, range) in self.storage.get_ranges(from, current.last_block)? {
+                let start = range.start.max(previous.counter) as usize;
+                let end = (range.start + range.count) as usize;
+                if start >= end {
+                    continue;
+                }
+                let items = self.storage.get_items(start, end - start)?;
+                for (index, item) in (start..).zip(items) {
+                    let _ = self.committed.send((index, item, number as u64));
+                }
+            }
+        }
+        Ok(last)
+    }
+
+    /// Queue and commit metrics since the index was opened.
+    pub async fn metrics(&self) -> Metrics {
+        let pending = self.pending.read().await;
+        self.metrics.snapshot(
+            pending.blocks.len(),
+            pending.items.len(),
+            pending.memory(),
+            pending.spilled.len(),
+        )
+    }
+
+    /// Estimated memory held by the pending blocks, in bytes.
+    pub async fn pending_memory(&self) -> usize {
+        self.pending.read().await.memory()
+    }
+
+    /// Moves the oldest pending blocks to the `spill` table until the blocks left in
+    /// memory hold at most `cap` bytes, e.g. to keep queueing blocks through a long
+    /// finality stall. Returns the number of blocks moved.
+    pub async fn spill(&self, cap: usize) -> Result<usize> {
+        let _lock_guard = self.lock.lock().await;
+        let mut pending = self.pending.write().await;
+        let excess = pending.memory().saturating_sub(cap);
+        let mut blocks = vec![];
+        let mut freed = 0;
+        for (number, items) in &pending.blocks {
+            if freed >= excess {
+                break;
+            }
+            freed += items.len() * Pending::<T>::item_memory();
+            blocks.push((*number, items.clone()));
+        }
+        if blocks.is_empty() {
+            return Ok(0);
+        }
+        self.storage.put_spilled(&blocks)?;
+        for (number, _) in &blocks {
+            pending.spill(*number);
+        }
+        trace!(blocks = blocks.len(), "spilled pending blocks");
+        Ok(blocks.len())
+    }
+
+    /// Index of an item of a spilled block.
+    async fn index_spilled(&self, pending: &Pending<T>, item: T) -> Result<Option<usize>> {
+        if pending.spilled.is_empty() {
+            return Ok(None);
+        }
+        let Some(block) = self.storage.get_spilled_blocks(&[item])?[0] else {
+            return Ok(None);
+        };
+        let (stored_count, last_block) = self.stored().await;
+        if block <= last_block || !pending.spilled.contains_key(&block) {
+            return Ok(None);
+        }
+        let Some(items) = self.storage.get_spilled(block)? else {
+            return Ok(None);
+        };
+        let before: usize = pending
+            .spilled
+            .range(last_block + 1..block)
+            .map(|(_, count)| count)
+            .sum();
+        Ok(items
+            .iter()
+            .position(|i| *i == item)
+            .map(|offset| stored_count + before + offset))
+    }
+
+    /// Number of stored entries and last stored block, read atomically. Callers
+    /// hold the `pending` lock so that committed blocks can be told apart.
+    async fn stored(&self) -> (usize, u64) {
+        let counters = self.storage.get_counters().await;
+        (counters.counter as usize, counters.last_block as u64)
+    }
+
+    /// Last indexed block and number of entries up to it, pending ones included,
+    /// read together: both locks are held, as by the queues, commits and refreshes
+    /// that update them.
+    pub async fn height(&self) -> (u64, usize) {
+        let pending = self.pending.read().await;
+        let counters = self.counters.read().await;
+        let (stored_count, last_block) = self.stored().await;
+        (
+            counters.last_indexed_block,
+            stored_count + pending.count_after(last_block),
+        )
+    }
+
+    /// Subscribes to the `(index, item, block)` entries written by each commit.
+    /// Slow receivers skip entries (`RecvError::Lagged`) rather than slowing down commits.
+    pub fn subscribe(&self) -> broadcast::Receiver<(usize, T, u64)> {
+        self.committed.subscribe()
+    }
+
+    /// Subscribes to the entries written by each commit, slow receivers reading the
+    /// ones they missed back from the committed blocks.
+    pub fn subscribe_entries(self: &Arc<Self>) -> CommittedEntries<N, T> {
+        CommittedEntries::new(self.clone())
+    }
+
+    /// Watches the last committed block.
+    pub fn subscribe_commits(&self) -> watch::Receiver<u64> {
+        self.commits.subscribe()
+    }
+
+    /// Subscribes to the rollbacks (and recoveries) of the index, for the consumers
+    /// that must remove what they derived from the blocks rolled back.
+    pub fn subscribe_rollbacks(&self) -> Rollbacks {
+        Rollbacks::new(self.rollbacks.subscribe())
+    }
+
+    /// Chained hash of the checkpoints up to a committed block.
+    pub fn block_hash(&self, number: u64) -> Result<H256> {
+        self.storage.get_block_hash(number as u32)
+    }
+
+    /// Checkpoint of a committed block, if its range was recorded.
+    pub fn checkpoint(&self, number: u64) -> Result<Option<Checkpoint>> {
+        let Some(range) = self.storage.get_range(number as u32)? else {
+            return Ok(None);
+        };
+        Ok(Some(Checkpoint {
+            block: number,
+            root: range.root_hash,
+            hash: self.storage.get_block_hash(number as u32)?,
+        }))
+    }
+
+    /// Stores the operator signatures of checkpoints, by block number.
+    pub fn put_signatures(&self, signatures: Vec<(u64, [u8; 65])>) -> Result<()> {
+        self.storage.put_signatures(
+            signatures
+                .into_iter()
+                .map(|(number, signature)| (number as u32, signature))
+                .collect(),
+        )
+    }
+
+    pub fn get_signature(&self, number: u64) -> Result<Option<[u8; 65]>> {
+        self.storage.get_signature(number as u32)
+    }
+
+    /// Records the timestamps of committed blocks.
+    pub fn put_timestamps(&self, timestamps: Vec<(u64, u64)>) -> Result<()> {
+        self.storage.put_timestamps(
+            timestamps
+                .into_iter()
+                .map(|(number, timestamp)| (number as u32, timestamp))
+                .collect(),
+        )
+    }
+
+    /// Timestamp of a committed block, if it was recorded.
+    pub fn timestamp(&self, number: u64) -> Result<Option<u64>> {
+        self.storage.get_timestamp(number as u32)
+    }
+
+    /// Last committed block with a recorded timestamp not after `timestamp`, with its
+    /// timestamp and the number of entries committed up to it.
+    pub fn height_at(&self, timestamp: u64) -> Result<Option<(u64, u64, usize)>> {
+        let Some((number, time)) = self.storage.get_block_at(timestamp)? else {
+            return Ok(None);
+        };
+        Ok(self
+            .storage
+            .get_range(number)?
+            .map(|range| (number as u64, time, (range.start + range.count) as usize)))
+    }
+
+    /// Index of the first entry added by a committed block, and its entries, if its
+    /// range was recorded.
+    pub fn block_entries(&self, number: u64) -> Result<Option<(usize, Vec<T>)>> {
+        let Some(range) = self.storage.get_range(number as u32)? else {
+            return Ok(None);
+        };
+        let items = self
+            .storage
+            .get_items(range.start as usize, range.count as usize)?;
+        Ok(Some((range.start as usize, items)))
+    }
+
+    /// Number of entries added by each committed block of `from..=to`. Blocks
+    /// committed before ranges were recorded are left out.
+    pub fn block_counts(&self, from: u64, to: u64) -> Result<Vec<(u64, usize)>> {
+        Ok(self
+            .storage
+            .get_ranges(from as u32, to as u32)?
+            .into_iter()
+            .map(|(number, range)| (number as u64, range.count as usize))
+            .collect())
+    }
+
+    /// Root of the checkpoint trie of a committed block, if it was recorded.
+    pub fn checkpoint_root(&self, number: u64) -> Result<Option<H256>> {
+        Ok(self
+            .storage
+            .get_range(number as u32)?
+            .map(|range| range.root_hash))
+    }
+
+    /// Root of the Merkle tree over all the entries committed up to a block, if it
+    /// was recorded.
+    pub fn index_root(&self, number: u64) -> Result<Option<H256>> {
+        self.storage.get_index_root(number as u32)
+    }
+
+    /// Committed block that added the entry at `index`, unless it was committed
+    /// before ranges were recorded.
+    pub async fn committed_block(&self, index: usize) -> Result<Option<u64>> {
+        let (len, last_block) = self.stored().await;
+        if index >= len {
+            return Ok(None);
+        }
+        Ok(self.block_of(index, last_block)?.map(|(block, _)| block))
+    }
+
+    /// Committed block that added the entry at `index`, with its range, searched up
+    /// to `last_block`. Entries of blocks committed before ranges were recorded have
+    /// none.
+    fn block_of(&self, index: usize, last_block: u64) -> Result<Option<(u64, BlockRange)>> {
+        // ranges are contiguous: find the first block ending after `index`
+        let (mut low, mut high) = (1, last_block);
+        let mut found = None;
+        while low <= high {
+            let middle = low + (high - low) / 2;
+            match self.storage.get_range(middle as u32)? {
+                Some(range) if (range.start + range.count) as usize > index => {
+                    found = Some((middle, range));
+                    high = middle - 1;
+                }
+                // blocks without a range all precede the recorded ones
+                _ => low = middle + 1,
+            }
+        }
+        Ok(found.filter(|(_, range)| range.start as usize <= index))
+    }
+
+    /// Last `limit` committed entries, newest first, with the block that added them
+    /// (`None` for blocks committed before ranges were recorded).
+    pub async fn recent(&self, limit: usize) -> Result<Vec<(usize, T, Option<u64>)>> {
+        let (len, last_block) = self.stored().await;
+        let first = len.saturating_sub(limit);
+        let mut entries = Vec::with_capacity(len - first);
+        let mut end = len;
+        // one range read per block, walking back from the tail of the index
+        while end > first {
+            let Some((block, range)) = self.block_of(end - 1, last_block)? else {
+                break;
+            };
+            let start = cmp::max(range.start as usize, first);
+            let items = self.storage.get_items(start, end - start)?;
+            entries.extend(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(offset, item)| (start + offset, item, Some(block))),
+            );
+            end = start;
+        }
+        if end > first {
+            let items = self.storage.get_items(first, end - first)?;
+            entries.extend(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(offset, item)| (first + offset, item, None)),
+            );
+        }
+        Ok(entries)
+    }
+
+    /// Item stored at `index` with the proof of its inclusion in the checkpoint of
+    /// its block, read from the persisted trie if any, rebuilt from the block entries
+    /// otherwise. Entries of blocks committed before ranges were recorded have no proof.
+    pub async fn proof(&self, index: usize) -> Result<Option<(T, Proof)>> {
+        let (len, last_block) = self.stored().await;
+        if index >= len {
+            return Ok(None);
+        }
+        let Some((block, range)) = self.block_of(index, last_block)? else {
+            return Ok(None);
+        };
+        if let Some(item) = self.storage.get(index).await? {
+            let stored = checkpoint::stored_proof(
+                |hash| self.storage.get_trie_node(hash),
+                range.root_hash,
+                item.as_ref(),
+            )?;
+            if let Some(nodes) = stored {
+                return Ok(Some((
+                    item,
+                    Proof {
+                        block,
+                        root: range.root_hash,
+                        nodes,
+                    },
+                )));
+            }
+        }
+        // blocks committed without persisted tries: rebuild the trie of the block
+        let items = self
+            .storage
+            .get_items(range.start as usize, range.count as usize)?;
+        let item = items[index - range.start as usize];
+        let mut trie = CheckpointTrie::new(range.start as u64);
+        trie.bulk_insert(items.iter().map(|a| a.as_ref()).collect())?;
+        let nodes = trie.proof(item.as_ref())?;
+        Ok(Some((
+            item,
+            Proof {
+                block,
+                root: range.root_hash,
+                nodes,
+            },
+        )))
+    }
+
+    /// Multi-proofs of the entries at `indexes`, one per block, in index order, with
+    /// the indexes that have no proof (not committed, or committed before ranges
+    /// were recorded). The proofs stop once the entries of the blocks read, whose
+    /// tries may have to be rebuilt, or the time spent exceed the `budget`, after the
+    /// first block; the index to resume from is then returned.
+    pub async fn multi_proof(
+        &self,
+        indexes: &[usize],
+        budget: ScanBudget,
+    ) -> Result<(Vec<MultiProof<T>>, Vec<usize>, Option<usize>)> {
+        let started = Instant::now();
+        let (len, last_block) = self.stored().await;
+        let mut indexes = indexes.to_vec();
+        indexes.sort_unstable();
+        indexes.dedup();
+        let (mut proofs, mut missing) = (vec![], vec![]);
+        let mut read = 0;
+        let mut remaining = indexes.into_iter().peekable();
+        while let Some(index) = remaining.next() {
+            if !proofs.is_empty()
+                && (read >= budget.entries || started.elapsed() >= budget.duration)
+            {
+                return Ok((proofs, missing, Some(index)));
+            }
+            if index >= len {
+                missing.push(index);
+                continue;
+            }
+            let Some((block, range)) = self.block_of(index, last_block)? else {
+                missing.push(index);
+                continue;
+            };
+            let (start, end) = (range.start as usize, (range.start + range.count) as usize);
+            let mut group = vec![index];
+            while let Some(index) = remaining.next_if(|index| *index < end) {
+                group.push(index);
+            }
+            let items = self.storage.get_items(start, end - start)?;
+            read += items.len();
+            let entries: Vec<(usize, T)> = group
+                .into_iter()
+                .map(|index| (index, items[index - start]))
+                .collect();
+            let mut nodes = IndexSet::new();
+            let mut trie = None;
+            for (_, item) in entries.iter() {
+                let stored = checkpoint::stored_proof(
+                    |hash| self.storage.get_trie_node(hash),
+                    range.root_hash,
+                    item.as_ref(),
+                )?;
+                let proof = match stored {
+                    Some(proof) => proof,
+                    // blocks committed without persisted tries: rebuild the trie once
+                    None => {
+                        let trie = match &mut trie {
+                            Some(trie) => trie,
+                            None => {
+                                let mut rebuilt = CheckpointTrie::new(range.start as u64);
+                                rebuilt.bulk_insert(items.iter().map(|a| a.as_ref()).collect())?;
+                                trie.insert(rebuilt)
+                            }
+                        };
+                        trie.proof(item.as_ref())?
+                    }
+                };
+                nodes.extend(proof);
+            }
+            proofs.push(MultiProof {
+                block,
+                root: range.root_hash,
+                entries,
+                nodes: nodes.into_iter().collect(),
+            });
+            // let the other requests run between blocks
+            tokio::task::yield_now().await;
+        }
+        Ok((proofs, missing, None))
+    }
+
+    /// Number of entries already committed to the storage.
+    pub async fn committed_len(&self) -> usize {
+        self.storage.len().await
+    }
+
+    /// Committed entries matching `filter`, scanned in index order from `from` until
+    /// `limit` of them are found or the `budget` is spent. Returns them with the index
+    /// to resume the scan from, `None` once all the committed entries were scanned.
+    pub async fn scan(
+        &self,
+        from: usize,
+        limit: usize,
+        budget: ScanBudget,
+        filter: impl Fn(&T) -> bool,
+    ) -> Result<(Vec<(usize, T)>, Option<usize>)> {
+        let started = Instant::now();
+        let last = self.committed_len().await;
+        let end = cmp::min(last, from.saturating_add(budget.entries));
+        let mut matches = vec![];
+        let mut position = from;
+        while position < end {
+            let count = cmp::min(SCAN_CHUNK, end - position);
+            for (index, item) in self.storage.scan(position, count, &filter)? {
+                matches.push((index, item));
+                if matches.len() == limit {
+                    return Ok((matches, Some(index + 1).filter(|next| *next < last)));
+                }
+            }
+            position += count;
+            if started.elapsed() >= budget.duration {
+                break;
+            }
+            // let the other requests run between chunks
+            tokio::task::yield_now().await;
+        }
+        Ok((matches, Some(position).filter(|next| *next < last)))
+    }
+
+    /// Item committed at `index`, ignoring the pending blocks.
+    pub async fn get_committed(&self, index: usize) -> Result<Option<T>> {
+        self.storage.get(index).await
+    }
+
+    /// Index of a committed item, ignoring the pending blocks.
+    pub async fn index_committed(&self, item: T) -> Result<Option<usize>> {
+        self.storage.index(item).await
+    }
+
+    /// Capacities of the item and index caches.
+    pub async fn cache_sizes(&self) -> (usize, usize) {
+        self.storage.cache_sizes().await
+    }
+
+    /// Resizes the item -> index and index -> item caches of a running index.
+    pub async fn resize_caches(&self, cache_size: usize, index_cache_size: usize) -> Result<()> {
+        self.storage
+            .resize_caches(cache_size, index_cache_size)
+            .await
+    }
+
+    /// Ratio of reverse lookups answered by the storage cache.
+    pub fn cache_hit_rate(&self) -> f64 {
+        let (hits, misses) = self.storage.cache_stats();
+        if hits + misses == 0 {
+            return 0.0;
+        }
+        hits as f64 / (hits + misses) as f64
+    }
+
+    /// Records whether the committed entries hold code (contract) or not (EOA).
+    pub fn set_contract_flags(&self, flags: Vec<(usize, bool)>) -> Result<()> {
+        self.storage.put_contract_flags(flags)
+    }
+
+    /// Returns the contract flag of a committed entry, if it has been enriched.
+    pub fn is_contract(&self, index: usize) -> Result<Option<bool>> {
+        self.storage.get_contract_flag(index)
+    }
+
+    /// Records the transactions the committed entries were first seen in.
+    pub fn set_appearances(&self, appearances: Vec<(usize, Appearance)>) -> Result<()> {
+        self.storage.put_appearances(appearances)
+    }
+
+    /// Returns the transaction a committed entry was first seen in, if it was recorded.
+    pub fn appearance(&self, index: usize) -> Result<Option<Appearance>> {
+        self.storage.get_appearance(index)
+    }
+
+    /// Sets the labels of items, which do not need to be indexed yet. A `None` label
+    /// removes the current one.
+    pub fn set_labels(&self, labels: Vec<(T, Option<Label>)>) -> Result<()> {
+        self.storage.put_labels(labels)
+    }
+
+    pub fn get_label(&self, item: T) -> Result<Option<Label>> {
+        self.storage.get_label(item)
+    }
+
+    /// Records a sample of the progress, keeping the last `capacity` of them.
+    pub fn record_history(&self, sample: HistorySample, capacity: u32) -> Result<()> {
+        self.storage.put_history_sample(sample, capacity)
+    }
+
+    /// Samples of the progress taken at or after `since` (unix time), oldest first.
+    pub fn history(&self, since: u64) -> Result<Vec<HistorySample>> {
+        self.storage.get_history(since)
+    }
+
+    /// Id of the chain indexed, once recorded by `check_chain_id`. It is read from
+    /// the stats table until recorded (by the writer process, for a read-only index),
+    /// and cached from then on, as it never changes.
+    pub fn chain_id(&self) -> Result<Option<u64>> {
+        if let Some(chain_id) = self.chain_id.get() {
+            return Ok(Some(*chain_id));
+        }
+        let chain_id = self.storage.get_stat("chain_id")?;
+        if let Some(id) = chain_id {
+            let _ = self.chain_id.set(id);
+        }
+        Ok(chain_id)
+    }
+
+    /// Records the id of the chain indexed, or checks that it matches the one
+    /// recorded, so that the blocks of another chain are never mixed in.
+    pub fn check_chain_id(&self, chain_id: u64) -> Result<()> {
+        self.verify_chain_id(chain_id)?;
+        if self.chain_id()?.is_none() {
+            self.put_stats(vec![("chain_id".to_string(), chain_id)])?;
+            let _ = self.chain_id.set(chain_id);
+        }
+        Ok(())
+    }
+
+    /// Checks that `chain_id` matches the one recorded, if any, without recording it.
+    pub fn verify_chain_id(&self, chain_id: u64) -> Result<()> {
+        match self.chain_id()? {
+            Some(datadir) if datadir != chain_id => Err(MoniqueError::ChainMismatch {
+                datadir,
+                provider: chain_id,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Hash of the extraction rules the index is built with, once recorded by
+    /// `check_ruleset`. It is cached, and reloaded by the refreshes of a read-only
+    /// index.
+    pub fn ruleset(&self) -> Result<Option<H256>> {
+        Ok(*self.ruleset.read().unwrap())
+    }
+
+    /// Records the extraction rules the index is built with, or checks that they did
+    /// not change, as other rules give other indexes to the same addresses. With
+    /// `migrate`, different rules are recorded instead, the committed entries staying
+    /// as they were indexed.
+    pub fn check_ruleset(&self, ruleset: H256, migrate: bool) -> Result<()> {
+        match self.ruleset()? {
+            Some(datadir) if datadir == ruleset => Ok(()),
+            Some(datadir) if !migrate => Err(MoniqueError::RulesetMismatch {
+                datadir,
+                current: ruleset,
+            }),
+            Some(datadir) => {
+                warn!(from = %datadir, to = %ruleset, "migrating the extraction ruleset");
+                self.record_ruleset(ruleset)
+            }
+            None => self.record_ruleset(ruleset),
+        }
+    }
+
+    fn record_ruleset(&self, ruleset: H256) -> Result<()> {
+        self.storage.put_stat_hash("ruleset", ruleset)?;
+        *self.ruleset.write().unwrap() = Some(ruleset);
+        Ok(())
+    }
+
+    /// Reads a 64-bit counter persisted in the stats table.
+    pub fn get_stat(&self, key: &str) -> Result<Option<u64>> {
+        self.storage.get_stat(key)
+    }
+
+    /// Persists 64-bit counters in the stats table.
+    pub fn put_stats(&self, values: Vec<(String, u64)>) -> Result<()> {
+        self.storage.put_stats(values)
+    }
+
+    /// Reads a text persisted in the stats table.
+    pub fn get_stat_text(&self, key: &str) -> Result<Option<String>> {
+        self.storage.get_stat_text(key)
+    }
+
+    /// Persists a text in the stats table.
+    pub fn put_stat_text(&self, key: &str, value: &str) -> Result<()> {
+        self.storage.put_stat_text(key, value)
+    }
+
+    /// Queues the items referenced in a block, returning the ones that were not
+    /// indexed yet. A block slightly ahead of the next expected one is held back
+    /// until the blocks before it are queued, the items of the blocks it releases
+    /// being returned by the call that fills the gap.
+    pub async fn queue(&self, block_number: u64, addresses: Vec<T>) -> Result<Vec<T>> {
+        self.queue_with(block_number, addresses, BlockData::default())
+            .await
+    }
+
+    /// Queues the items referenced in a block as `queue` does, with the data to
+    /// commit along with them.
+    #[instrument(skip_all, fields(block = block_number, addresses = addresses.len()))]
+    pub async fn queue_with(
+        &self,
+        block_number: u64,
+        addresses: Vec<T>,
+        data: BlockData<T>,
+    ) -> Result<Vec<T>> {
         trace!(
             "queueing {} addresses for block {}",
             addresses.len(),
             block_number
         );
-        // TODO: if storage lookup gets too slow and blocks other operations, consider unblocking `pending` and `counters`
-        // watch out for concurrency
+        let mut dropped = BTreeMap::new();
+        let result = self
+            .queue_locked(block_number, addresses, data, &mut dropped)
+            .await;
+        // once the pending queue is unlocked
+        self.bury(dropped, TombstoneReason::Reorg).await;
+        result
+    }
+
+    /// Queues a block under the locks of the pending queue and counters, moving the
+    /// blocks a reorg drops into `dropped`.
+    async fn queue_locked(
+        &self,
+        block_number: u64,
+        addresses: Vec<T>,
+        data: BlockData<T>,
+        dropped: &mut BTreeMap<u64, Vec<T>>,
+    ) -> Result<Vec<T>> {
         let mut pending = self.pending.write().await;
         let mut counters = self.counters.write().await;
+        let committed = cmp::max(
+            counters.last_committed_block,
+            self.committing.load(Ordering::Acquire),
+        );
+        if block_number <= committed {
+            Err(MoniqueError::Reorg {
+                block: block_number,
+                committed,
+            })?;
+        }
         if block_number <= counters.last_indexed_block {
             warn!(
                 "possible reorg detected: {} <= {} -- rolling back index",
                 block_number, counters.last_indexed_block
             );
+            let spilled: Vec<u64> = pending
+                .spilled
+                .range(block_number..)
+                .map(|(number, _)| *number)
+                .collect();
+            for number in &spilled {
+                pending.spilled.remove(number);
+                if let Some(items) = self.storage.get_spilled(*number)? {
+                    pending.insert(*number, items);
+                }
+            }
+            self.storage.remove_spilled(&spilled)?;
             for n in block_number..=counters.last_indexed_block {
-                match pending.remove(&n) {
+                match pending.remove(n) {
                     Some(a) => {
                         info!("removing {} addresses from block {}", a.len(), n);
+                        dropped.insert(n, a);
                     }
                     None => {
                         info!("no addresses to remove from block {}", n);
                     }
                 }
             }
-        } else if block_number != counters.last_indexed_block + 1 {
-            Err(format!(
-                "queuing error: tried to skip block {} and queue block {}",
-                counters.last_indexed_block + 1,
-                block_number
-            ))?;
-        }
-        let queue: Vec<&T> = pending.values().flatten().collect();
-        let mut new_queue = IndexSet::with_capacity(addresses.len());
-        for address in addresses {
-            if queue.contains(&&address) {
-                continue;
+            pending.meta.retain(|number, _| *number < block_number);
+            if !pending.ahead.is_empty() {
+                info!("dropping {} blocks queued ahead", pending.ahead.len());
+                for (number, (items, _)) in std::mem::take(&mut pending.ahead) {
+                    dropped.insert(number, items);
+                }
             }
-            if self.storage.index(address.into()).await?.is_some() {
-                continue;
+        } else if block_number > counters.last_indexed_block + 1 {
+            if block_number > counters.last_indexed_block + REORDER_WINDOW {
+                Err(MoniqueError::Queue {
+                    expected: counters.last_indexed_block + 1,
+                    block: block_number,
+                })?;
             }
-            new_queue.insert(address);
+            trace!(
+                "holding block {} until block {} is queued",
+                block_number,
+                counters.last_indexed_block + 1
+            );
+            pending.ahead.insert(block_number, (addresses, data));
+            return Ok(vec![]);
         }
-        let len = new_queue.len();
-        pending.insert(block_number, new_queue.into_iter().collect());
+        let mut new_items = self
+            .insert_block(&mut pending, &mut counters, block_number, addresses, data)
+            .await?;
+        let mut next = block_number + 1;
+        while let Some((addresses, data)) = pending.ahead.remove(&next) {
+            let released = self
+                .insert_block(&mut pending, &mut counters, next, addresses, data)
+                .await?;
+            new_items.extend(released);
+            next += 1;
+        }
+        Ok(new_items)
+    }
+
+    /// Records the items of dropped pending blocks as tombstones, from a blocking task,
+    /// once the pending queue is unlocked. A failure is only logged, the blocks being
+    /// dropped anyway.
+    async fn bury(&self, blocks: BTreeMap<u64, Vec<T>>, reason: TombstoneReason) {
+        if blocks.is_empty() {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let tombstones = blocks
+            .into_iter()
+            .map(|(block, items)| Tombstone {
+                timestamp,
+                block,
+                reason,
+                items,
+            })
+            .collect();
+        let storage = self.storage.clone();
+        let result = tokio::task::spawn_blocking(move || storage.put_tombstones(tombstones))
+            .await
+            .unwrap_or_else(|e| Err(MoniqueError::Storage(e.to_string())));
+        if let Err(e) = result {
+            warn!(
+                reason = reason.name(),
+                "failed to record the dropped blocks: {}", e
+            );
+        }
+    }
+
+    /// Pending blocks dropped at or after `since` (unix time), in the order they were
+    /// dropped, while their retention lasts.
+    pub fn tombstones(&self, since: u64) -> Result<Vec<Tombstone<T>>> {
+        self.storage.get_tombstones(since)
+    }
+
+    /// Adds the next block to the pending queue, without the items already pending
+    /// or indexed, which are returned.
+    async fn insert_block(
+        &self,
+        pending: &mut Pending<T>,
+        counters: &mut Counters,
+        block_number: u64,
+        addresses: Vec<T>,
+        data: BlockData<T>,
+    ) -> Result<Vec<T>> {
+        let start = Instant::now();
+        let submitted = addresses.len();
+        let candidates: Vec<T> = addresses
+            .into_iter()
+            .filter(|address| !pending.items.contains(address))
+            .collect::<IndexSet<T>>()
+            .into_iter()
+            .collect();
+        let candidates = if pending.spilled.is_empty() {
+            candidates
+        } else {
+            let spilled = self.storage.get_spilled_blocks(&candidates)?;
+            candidates
+                .into_iter()
+                .zip(spilled)
+                .filter_map(|(address, block)| {
+                    let spilled = block.is_some_and(|block| pending.spilled.contains_key(&block));
+                    (!spilled).then_some(address)
+                })
+                .collect()
+        };
+        let stored = self.storage.index_many(&candidates).await?;
+        let new_items: Vec<T> = candidates
+            .into_iter()
+            .zip(stored)
+            .filter_map(|(address, index)| index.is_none().then_some(address))
+            .collect();
+        pending.meta.insert(block_number, data.meta(&new_items));
+        pending.insert(block_number, new_items.clone());
         counters.last_indexed_block = block_number;
-        Ok(len)
+        self.metrics
+            .queued(submitted, submitted - new_items.len(), start.elapsed());
+        Ok(new_items)
     }
 
+    #[instrument(skip(self))]
     pub async fn commit(&self, safe_block: u64) -> Result<usize> {
         trace!("committing up to block {}", safe_block);
         let _lock_guard = self.lock.try_lock()?; // Do not allow concurrent commits for now
-        let start = Instant::now();
-        let mut index = self.storage.len().await as u64;
-        let start_index = index as usize;
-        let (blocks, target) = {
-            let mut blocks: Vec<Block<T>> = vec![];
-            let mut pending_blocks = self.pending.write().await;
-            let counters = self.get_counters().await;
-            let last_block = pending_blocks.keys().max().cloned().unwrap_or(0);
-            let target = cmp::min(safe_block, last_block);
-            for number in counters.last_committed_block + 1..=target {
-                if let Some(items) = pending_blocks.remove(&number) {
-                    let mut checkpoint = CheckpointTrie::new(index);
-                    let root_hash =
-                        checkpoint.bulk_insert(items.iter().map(|a| a.as_ref()).collect())?;
-                    index += items.len() as u64;
-                    blocks.push(Block {
-                        items,
-                        root_hash,
-                        number,
-                    });
-                } else {
-                    panic!("commit: missed block {}", number);
+
+        // snapshot the blocks to commit, so that queueing and reads are not
+        // blocked while the checkpoint tries are built and the storage is written
+        let (snapshot, target) = {
+            let pending_blocks = self.pending.read().await;
+            let first = self.get_counters().await.last_committed_block + 1;
+            let last_block = pending_blocks
+                .blocks
+                .keys()
+                .chain(pending_blocks.spilled.keys())
+                .max()
+                .cloned()
+                .unwrap_or(0);
+            // never below the last committed block, e.g. with nothing pending
+            let target = cmp::min(safe_block, last_block).max(first - 1);
+            let mut snapshot = Vec::new();
+            for number in first..=target {
+                let meta = pending_blocks
+                    .meta
+                    .get(&number)
+                    .cloned()
+                    .unwrap_or_default();
+                match pending_blocks.blocks.get(&number) {
+                    Some(items) => snapshot.push((number, items.clone(), meta)),
+                    None if pending_blocks.spilled.contains_key(&number) => {
+                        let items = self
+                            .storage
+                            .get_spilled(number)?
+                            .ok_or(MoniqueError::MissedBlock(number))?;
+                        snapshot.push((number, items, meta));
+                    }
+                    None => Err(MoniqueError::MissedBlock(number))?,
                 }
             }
-            (blocks, target)
+            // the queue holds the pending lock: no reorg can come in between
+            self.committing.store(target, Ordering::Release);
+            (snapshot, target)
         };
+        let written = self.write_snapshot(snapshot, target).await;
+        if written.is_err() {
+            // the blocks left in the queue can be reorganized again
+            self.committing.store(0, Ordering::Release);
+        }
+        written
+    }
 
-        let prep_time = start.elapsed().as_micros();
-
+    /// Writes the snapshot of the pending `blocks` up to `target` to the storage, in
+    /// batches, removing them from the queue. Returns the number of committed entries.
+    async fn write_snapshot(
+        &self,
+        snapshot: Vec<(u64, Vec<T>, BlockMeta)>,
+        target: u64,
+    ) -> Result<usize> {
+        let start_index = self.storage.len().await;
+        // the tries only depend on the start index of their block: split the snapshot
+        // into batches of whole blocks, each with the start index of its entries
+        let mut index = start_index as u64;
+        let mut batches: Vec<Vec<_>> = vec![];
+        let mut batch_len = 0;
+        for (number, items, meta) in snapshot {
+            if batches.is_empty() || batch_len >= COMMIT_BATCH_SIZE {
+                batches.push(vec![]);
+                batch_len = 0;
+            }
+            batch_len += items.len();
+            let start = index;
+            index += items.len() as u64;
+            batches
+                .last_mut()
+                .unwrap()
+                .push((number, start, items, meta));
+        }
         let len = index as usize - start_index;
-        let start = Instant::now();
-        self.storage.push(blocks).await?;
-        self.counters.write().await.last_committed_block = target;
-        let push_time = start.elapsed().as_micros();
+        let batch_count = batches.len();
+
+        // prepare stage: the tries of the next batch are built in parallel while the
+        // previous one is pushed, the storage chaining the block hashes in order
+        let persist_tries = self.storage.persists_tries();
+        let (sender, mut receiver) = mpsc::channel(1);
+        let prepare = tokio::task::spawn_blocking(move || {
+            for batch in batches {
+                let start = Instant::now();
+                let blocks = prepare_blocks(batch, persist_tries);
+                if sender.blocking_send((blocks, start.elapsed())).is_err() {
+                    break; // the push stage failed
+                }
+            }
+        });
+
+        // push stage
+        let (mut prep_time, mut push_time) = (0, 0);
+        let (mut pushed, mut pushed_batches) = (0, 0);
+        while let Some((blocks, elapsed)) = receiver.recv().await {
+            let blocks = blocks?;
+            prep_time += elapsed.as_micros();
+            let start = Instant::now();
+            let last = blocks.last().map(|block| block.number).unwrap_or(target);
+            let entries: Vec<(usize, T, u64)> = if self.committed.receiver_count() > 0 {
+                let mut index = self.storage.len().await;
+                let mut entries = vec![];
+                for block in &blocks {
+                    for item in &block.items {
+                        entries.push((index, *item, block.number));
+                        index += 1;
+                    }
+                }
+                entries
+            } else {
+                vec![]
+            };
+            pushed += blocks.iter().map(|block| block.items.len()).sum::<usize>();
+            self.storage.push(blocks).await?;
+            self.mark_committed(last).await;
+            pushed_batches += 1;
+            if batch_count > 1 {
+                // e.g. the first commit after a long downtime
+                info!(
+                    block = last,
+                    batch = pushed_batches,
+                    batches = batch_count,
+                    addresses_added = pushed,
+                    total = len,
+                    "commit progress"
+                );
+            }
+            for entry in entries {
+                // no receiver left is not an error
+                let _ = self.committed.send(entry);
+            }
+            push_time += start.elapsed().as_micros();
+        }
+        if let Err(e) = prepare.await {
+            if let Ok(panic) = e.try_into_panic() {
+                std::panic::resume_unwind(panic);
+            }
+            Err(MoniqueError::Storage(
+                "commit: prepare stage cancelled".to_string(),
+            ))?
+        }
+        self.mark_committed(target).await;
+        self.metrics.committed(len, prep_time, push_time);
         if len > 0 {
+            // the head of the checkpoint chain, to compare instances
+            let hash = self.storage.get_block_hash(target as u32)?;
             info!(
-                "Commit: addresses={len} prepare={prep_time}us push={push_time}us average={}",
-                push_time / len as u128
+                block = target,
+                %hash,
+                addresses_added = len,
+                prepare_us = prep_time as u64,
+                push_us = push_time as u64,
+                average_us = (push_time / len as u128) as u64,
+                "commit"
             );
+            let space = self.storage.space()?;
+            if space.map_usage() > MAP_USAGE_WARNING {
+                warn!(
+                    max_size = space.max_size,
+                    "the database uses {:.1}% of its maximum size",
+                    space.map_usage() * 100.0
+                );
+            }
         }
         Ok(len)
     }
+
+    /// Drops the pending blocks up to `block`, now in storage, and notifies the
+    /// commit subscribers.
+    async fn mark_committed(&self, block: u64) {
+        {
+            // readers skip the pending blocks already in storage until they are removed
+            let mut pending_blocks = self.pending.write().await;
+            let spilled = pending_blocks.remove_until(block);
+            self.counters.write().await.last_committed_block = block;
+            if self.committing.load(Ordering::Acquire) <= block {
+                self.committing.store(0, Ordering::Release);
+            }
+            // the blocks are not read anymore: leftovers are only cleared on restart
+            if let Err(e) = self.storage.remove_spilled(&spilled) {
+                warn!("failed to remove the spilled blocks: {}", e);
+            }
+        }
+        self.commits.send_replace(block);
+    }
+}
+
+/// Builds the checkpoint tries of a batch of `(number, start index, items, meta)`
+/// blocks, in parallel.
+fn prepare_blocks<T>(
+    batch: Vec<(u64, u64, Vec<T>, BlockMeta)>,
+    persist_tries: bool,
+) -> Result<Vec<Block<T>>>
+where
+    T: AsRef<[u8]> + Send,
+{
+    batch
+        .into_par_iter()
+        .map(|(number, start, items, meta)| {
+            let mut checkpoint = CheckpointTrie::new(start);
+            let root_hash = checkpoint.bulk_insert(items.iter().map(|a| a.as_ref()).collect())?;
+            let nodes = if persist_tries {
+                checkpoint.into_nodes()
+            } else {
+                vec![]
+            };
+            Ok(Block {
+                items,
+                root_hash,
+                number,
+                nodes,
+                meta,
+            })
+        })
+        .collect()
 }
 
 #[async_trait]
@@ -167,48 +1692,56 @@ where
         + Copy
         + std::convert::From<[u8; N]>
         + Send
-        + Sync,
+        + Sync
+        + 'static,
     [u8; N]: From<T>,
 {
     async fn len(&self) -> usize {
-        let stored_count = self.storage.len().await;
-        let pending_count = self.pending.read().await.values().flatten().count();
-        stored_count + pending_count
+        let pending = self.pending.read().await;
+        let (stored_count, last_block) = self.stored().await;
+        stored_count + pending.count_after(last_block)
     }
 
+    #[instrument(level = "trace", skip(self))]
     async fn get(&self, index: usize) -> Result<Option<T>> {
-        trace!(
-            "get index={}, storage.len={}",
-            index,
-            self.storage.len().await
-        );
-        if index > self.storage.len().await {
-            // if the index is in the pending queue
-            let pending = self.pending.read().await;
-            let mut offset = self.storage.len().await;
-            for (_, items) in pending.iter() {
-                if index < offset + items.len() {
-                    return Ok(Some(items[index - offset]));
-                }
-                offset += items.len();
-            }
-        } else {
-            return Ok(Some(self.storage.get(index).await?.unwrap().into()));
-        };
-        Ok(None)
+        let pending = self.pending.read().await;
+        let (stored_count, last_block) = self.stored().await;
+        trace!("get index={}, storage.len={}", index, stored_count);
+        if index < stored_count {
+            drop(pending);
+            return self.storage.get(index).await;
+        }
+        if let Some((block, offset)) = pending.locate_spilled(last_block, index - stored_count) {
+            return Ok(self
+                .storage
+                .get_spilled(block)?
+                .and_then(|items| items.get(offset).copied()));
+        }
+        Ok(pending.get_after(last_block, index - stored_count))
     }
 
+    #[instrument(level = "trace", skip_all)]
     async fn index(&self, item: T) -> Result<Option<usize>> {
         // Check the pending queue
-        let mut index = self.storage.len().await;
-        for pending in self.pending.read().await.values().flatten() {
-            if *pending == item {
-                return Ok(Some(index));
+        {
+            let pending = self.pending.read().await;
+            if !pending.items.contains(&item) {
+                if let Some(index) = self.index_spilled(&pending, item).await? {
+                    return Ok(Some(index));
+                }
+                drop(pending);
+                return self.storage.index(item).await;
+            }
+            let (stored_count, last_block) = self.stored().await;
+            let offset = pending.after(last_block).position(|i| *i == item);
+            if let Some(offset) = offset {
+                return Ok(Some(
+                    stored_count + pending.spilled_after(last_block) + offset,
+                ));
             }
-            index += 1;
         }
         // Get from the storage
-        match self.storage.index(item.into()).await? {
+        match self.storage.index(item).await? {
             Some(v) => Ok(Some(v)),
             None => Ok(None),
         }
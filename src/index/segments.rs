@@ -0,0 +1,415 @@
+//! Segmented dumps: the committed blocks split into dump files of about the same
+//! number of entries, listed with their checksums in a `MANIFEST` file.
+//!
+//! Segments are immutable once written, so that a backup only copies the new ones,
+//! and each one can be verified on its own, in parallel: the manifest records the
+//! hash of the block before a segment, from which its blocks are chained.
+//!
+//! Manifest format: the header `monique-segments 2 <item size> <wordlist>`, where
+//! `wordlist` is the [`fingerprint`](crate::words::fingerprint) of the vocabulary of
+//! the exporter (absent from the headers `monique-segments 1 <item size>` of older
+//! manifests), then a line per segment:
+//! `file from to start count previous_hash last_hash xxh3`.
+
+use super::checkpoint::CheckpointTrie;
+use super::dump::{read_block, read_header, Header};
+use super::IndexTable;
+use crate::{words, MoniqueError, Result};
+use ethers_core::types::H256;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use tracing::info;
+use xxhash_rust::xxh3::Xxh3;
+
+pub const MANIFEST: &str = "MANIFEST";
+const HEADER: &str = "monique-segments 2";
+const HEADER_V1: &str = "monique-segments 1";
+
+/// Blocks whose ranges are read at once when splitting the segments.
+const RANGE_WINDOW: u64 = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// File name, in the directory of the manifest.
+    pub file: String,
+    pub from: u64,
+    pub to: u64,
+    /// Index of the first entry of the segment.
+    pub start: u64,
+    pub count: u64,
+    /// Hash of the block before the segment.
+    pub previous_hash: H256,
+    /// Hash of the last block of the segment.
+    pub last_hash: H256,
+    /// XXH3 hash of the file.
+    pub checksum: u64,
+}
+
+/// Item size and wordlist fingerprint of a manifest header.
+fn parse_header(header: &str) -> Result<(usize, Option<H256>)> {
+    let unsupported = || MoniqueError::Dump(format!("manifest: unsupported header {:?}", header));
+    if let Some(size) = header.strip_prefix(HEADER_V1) {
+        return Ok((size.trim().parse().map_err(|_| unsupported())?, None));
+    }
+    let fields: Vec<&str> = header
+        .strip_prefix(HEADER)
+        .ok_or_else(unsupported)?
+        .split_whitespace()
+        .collect();
+    let [size, wordlist] = fields[..] else {
+        Err(unsupported())?
+    };
+    Ok((
+        size.parse().map_err(|_| unsupported())?,
+        Some(wordlist.parse().map_err(|_| unsupported())?),
+    ))
+}
+
+/// Header of the manifest of `dir`, if there is one.
+fn read_manifest_header(dir: &Path) -> Result<Option<(usize, Option<H256>)>> {
+    let path = dir.join(MANIFEST);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let header = BufReader::new(File::open(path)?)
+        .lines()
+        .next()
+        .transpose()?
+        .unwrap_or_default();
+    parse_header(&header).map(Some)
+}
+
+/// Size of the items of the segments in `dir`, from its manifest, if there is one.
+pub fn manifest_item_size(dir: &Path) -> Result<Option<usize>> {
+    Ok(read_manifest_header(dir)?.map(|(item_size, _)| item_size))
+}
+
+/// Fingerprint of the vocabulary of the exporter of the segments in `dir`, from its
+/// manifest, if recorded.
+pub fn manifest_wordlist(dir: &Path) -> Result<Option<H256>> {
+    Ok(read_manifest_header(dir)?.and_then(|(_, wordlist)| wordlist))
+}
+
+/// Reads the manifest of the segments in `dir`, empty if there is none.
+pub fn read_manifest(dir: &Path, item_size: usize) -> Result<Vec<Segment>> {
+    let path = dir.join(MANIFEST);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let invalid = |line: &str| MoniqueError::Dump(format!("manifest: invalid line {:?}", line));
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    if parse_header(&header)?.0 != item_size {
+        Err(MoniqueError::Dump(format!(
+            "manifest: unsupported header {:?}",
+            header
+        )))?
+    }
+    let mut segments = vec![];
+    for line in lines {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [file, from, to, start, count, previous_hash, last_hash, checksum] = fields[..] else {
+            Err(invalid(&line))?
+        };
+        let number = |value: &str| value.parse::<u64>().map_err(|_| invalid(&line));
+        let hash = |value: &str| value.parse::<H256>().map_err(|_| invalid(&line));
+        segments.push(Segment {
+            file: file.to_string(),
+            from: number(from)?,
+            to: number(to)?,
+            start: number(start)?,
+            count: number(count)?,
+            previous_hash: hash(previous_hash)?,
+            last_hash: hash(last_hash)?,
+            checksum: u64::from_str_radix(checksum, 16).map_err(|_| invalid(&line))?,
+        });
+    }
+    Ok(segments)
+}
+
+pub fn write_manifest(dir: &Path, item_size: usize, segments: &[Segment]) -> Result<()> {
+    // written aside and renamed, so that the manifest is never partial
+    let path = dir.join(format!("{}.tmp", MANIFEST));
+    let mut writer = BufWriter::new(File::create(&path)?);
+    writeln!(
+        writer,
+        "{} {} {:?}",
+        HEADER,
+        item_size,
+        words::fingerprint()
+    )?;
+    for segment in segments {
+        writeln!(
+            writer,
+            "{} {} {} {} {} {:?} {:?} {:016x}",
+            segment.file,
+            segment.from,
+            segment.to,
+            segment.start,
+            segment.count,
+            segment.previous_hash,
+            segment.last_hash,
+            segment.checksum
+        )?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    std::fs::rename(path, dir.join(MANIFEST))?;
+    Ok(())
+}
+
+/// Hashes what is read through it.
+struct Checksum<S> {
+    inner: S,
+    hasher: Xxh3,
+}
+
+impl<S> Checksum<S> {
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            hasher: Xxh3::new(),
+        }
+    }
+
+    fn digest(&self) -> u64 {
+        self.hasher.digest()
+    }
+}
+
+impl<R: Read> Read for Checksum<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// XXH3 hash of a file.
+pub fn checksum(path: &Path) -> Result<u64> {
+    let mut reader = Checksum::new(BufReader::new(File::open(path)?));
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    Ok(reader.digest())
+}
+
+/// Encoding of the segment files, e.g. their encryption.
+pub trait Envelope: Sync {
+    /// Writer of a new segment file, completed by `SegmentWriter::finish`.
+    fn writer(&self, file: File) -> Result<Box<dyn SegmentWriter>>;
+    /// Reader of the dump in a segment file.
+    fn reader(&self, file: File) -> Result<Box<dyn Read + Send>>;
+}
+
+pub trait SegmentWriter: Write + Send {
+    /// Completes the file and syncs it to disk.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Segment files holding the dumps as they are.
+pub struct Plain;
+
+impl Envelope for Plain {
+    fn writer(&self, file: File) -> Result<Box<dyn SegmentWriter>> {
+        Ok(Box::new(BufWriter::new(file)))
+    }
+
+    fn reader(&self, file: File) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+impl SegmentWriter for BufWriter<File> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Verifies the segment files in `dir` in parallel: their checksums, the checkpoint
+/// roots of their blocks and the chain of block hashes, between segments too.
+/// Returns the number of verified blocks.
+pub fn verify_segments<const N: usize, T>(dir: &Path, envelope: &dyn Envelope) -> Result<u64>
+where
+    T: AsRef<[u8]> + From<[u8; N]> + Send,
+{
+    let segments = read_manifest(dir, N)?;
+    for pair in segments.windows(2) {
+        if pair[1].from != pair[0].to + 1
+            || pair[1].start != pair[0].start + pair[0].count
+            || pair[1].previous_hash != pair[0].last_hash
+        {
+            Err(MoniqueError::Dump(format!(
+                "segments: {} does not follow {}",
+                pair[1].file, pair[0].file
+            )))?
+        }
+    }
+    segments
+        .par_iter()
+        .map(|segment| verify_segment::<N, T>(dir, segment, envelope))
+        .sum()
+}
+
+fn verify_segment<const N: usize, T>(
+    dir: &Path,
+    segment: &Segment,
+    envelope: &dyn Envelope,
+) -> Result<u64>
+where
+    T: AsRef<[u8]> + From<[u8; N]>,
+{
+    let mismatch =
+        |what: &str| MoniqueError::Dump(format!("segment {}: {} mismatch", segment.file, what));
+    let path = dir.join(&segment.file);
+    if checksum(&path)? != segment.checksum {
+        Err(mismatch("checksum"))?
+    }
+    let mut reader = envelope.reader(File::open(path)?)?;
+    let Header { timestamps, .. } = read_header(&mut reader, N)?;
+    let (mut index, mut hash, mut next) = (segment.start, segment.previous_hash, segment.from);
+    while let Some((block, block_hash)) = read_block::<N, T, _>(&mut reader, timestamps)? {
+        if block.number != next {
+            Err(mismatch("block number"))?
+        }
+        let mut trie = CheckpointTrie::new(index);
+        let root_hash = trie.bulk_insert(block.items.iter().map(|a| a.as_ref()).collect())?;
+        if root_hash != block.root_hash {
+            Err(mismatch(&format!("root hash of block {}", block.number)))?
+        }
+        hash = block.compute_hash(hash);
+        if hash != block_hash {
+            Err(mismatch(&format!("block hash of block {}", block.number)))?
+        }
+        index += block.items.len() as u64;
+        next += 1;
+    }
+    if next != segment.to + 1 || index != segment.start + segment.count {
+        Err(mismatch("length"))?
+    }
+    if hash != segment.last_hash {
+        Err(mismatch("last hash"))?
+    }
+    Ok(segment.to - segment.from + 1)
+}
+
+impl<const N: usize, T> IndexTable<N, T>
+where
+    T: AsRef<[u8]>
+        + From<[u8; N]>
+        + std::cmp::PartialEq
+        + std::hash::Hash
+        + Eq
+        + Copy
+        + Send
+        + Sync
+        + 'static,
+    [u8; N]: From<T>,
+{
+    /// Exports the committed blocks up to `to` as segments of at least
+    /// `segment_entries` entries (but the last one) in `dir`. The segments already
+    /// listed in its manifest are kept, and only the following blocks are exported.
+    /// Returns the new segments.
+    pub async fn export_segments(
+        &self,
+        dir: &Path,
+        to: u64,
+        segment_entries: u64,
+        envelope: &dyn Envelope,
+    ) -> Result<Vec<Segment>> {
+        std::fs::create_dir_all(dir)?;
+        let mut segments = read_manifest(dir, N)?;
+        let (mut from, mut start) = match segments.last() {
+            Some(last) => (last.to + 1, last.start + last.count),
+            None => {
+                let from = self.start_block();
+                let start = self
+                    .storage
+                    .get_range(from as u32)?
+                    .map(|range| range.start);
+                (from, start.unwrap_or(0) as u64)
+            }
+        };
+        let first_new = segments.len();
+        while from <= to {
+            // the blocks of the next segment
+            let (mut last, mut count) = (from, 0u64);
+            'split: for window in (from..=to).step_by(RANGE_WINDOW as usize) {
+                let end = (window + RANGE_WINDOW - 1).min(to);
+                for (number, range) in self.storage.get_ranges(window as u32, end as u32)? {
+                    last = number as u64;
+                    count += range.count as u64;
+                    if count >= segment_entries {
+                        break 'split;
+                    }
+                }
+            }
+            let file = format!("segment-{:05}.dump", segments.len());
+            let path = dir.join(&file);
+            let mut writer = envelope.writer(File::create(&path)?)?;
+            self.export(from, last, &mut writer).await?;
+            writer.finish()?;
+            let segment = Segment {
+                file,
+                from,
+                to: last,
+                start,
+                count,
+                previous_hash: self.storage.get_block_hash(from as u32 - 1)?,
+                last_hash: self.storage.get_block_hash(last as u32)?,
+                checksum: checksum(&path)?,
+            };
+            info!(
+                from,
+                to = last,
+                count,
+                "segments: exported {}",
+                segment.file
+            );
+            segments.push(segment);
+            write_manifest(dir, N, &segments)?;
+            from = last + 1;
+            start += count;
+        }
+        Ok(segments.split_off(first_new))
+    }
+
+    /// Imports the segments of `dir` following the committed blocks, after checking
+    /// their checksums. Returns the number of imported blocks.
+    pub async fn import_segments(&self, dir: &Path, envelope: &dyn Envelope) -> Result<u64> {
+        let segments = read_manifest(dir, N)?;
+        if segments.is_empty() {
+            Err(MoniqueError::Dump(format!(
+                "segments: no {} in {}",
+                MANIFEST,
+                dir.display()
+            )))?
+        }
+        let mut imported = 0;
+        for segment in segments {
+            let last = self.get_counters().await.last_committed_block;
+            if segment.to <= last {
+                continue;
+            }
+            if segment.from != last + 1 && !self.is_empty_chain().await {
+                Err(MoniqueError::Dump(format!(
+                    "segments: {} starts at block {}, after block {}",
+                    segment.file, segment.from, last
+                )))?
+            }
+            let path = dir.join(&segment.file);
+            if checksum(&path)? != segment.checksum {
+                Err(MoniqueError::Dump(format!(
+                    "segment {}: checksum mismatch",
+                    segment.file
+                )))?
+            }
+            imported += self.import(envelope.reader(File::open(path)?)?).await?;
+        }
+        Ok(imported)
+    }
+}
@@ -1,18 +1,30 @@
 use async_trait::async_trait;
+use fs2::FileExt;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::path::Path;
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
 use std::{cmp, hash::Hash, num::NonZeroUsize, path::PathBuf};
 use tiny_keccak::{Hasher, Keccak};
 use xxhash_rust::xxh3::xxh3_64;
 
-use ethers::types::H256;
+use ethers_core::types::H256;
 use libmdbx::{
     Database, DatabaseOptions, Mode, NoWriteMap, PageSize, ReadWriteOptions, TableFlags, WriteFlags,
 };
-use log::{info, trace, warn};
 use lru::LruCache;
+use mdbx_sys as ffi;
 use tokio::sync::{RwLock, RwLockReadGuard};
+use tracing::{info, instrument, trace, warn};
 
-use crate::Result;
+use crate::{MoniqueError, Result};
 
+use super::accumulator::{Accumulator, ACCUMULATOR_SIZE};
 use super::Indexed;
 
 #[derive(Clone)]
@@ -21,115 +33,1969 @@ pub struct Counters {
     pub last_block: u32,
 }
 
+/// Lock file of the writer process, in the database directory.
+pub const WRITER_LOCK: &str = "writer.lock";
+
+/// Idle read transactions kept by the pool, each holding a reader slot of the
+/// database.
+const MAX_IDLE_READS: usize = 16;
+
+/// Read-only transaction of the lookups, reset between them.
+struct ReadTxn(NonNull<ffi::MDBX_txn>);
+
+// SAFETY: libmdbx opens its databases with `MDBX_NOTLS`, which ties the reader slot
+// of a read transaction to the transaction instead of its thread, and a `ReadTxn` is
+// only used by the thread that owns it
+unsafe impl Send for ReadTxn {}
+
+impl ReadTxn {
+    fn begin(db: &Database<NoWriteMap>) -> Result<Self> {
+        let mut txn = ptr::null_mut();
+        mdbx_result(unsafe {
+            ffi::mdbx_txn_begin_ex(
+                db.ptr().0,
+                ptr::null_mut(),
+                ffi::MDBX_TXN_RDONLY,
+                &mut txn,
+                ptr::null_mut(),
+            )
+        })?;
+        NonNull::new(txn)
+            .map(Self)
+            .ok_or_else(|| libmdbx::Error::Invalid.into())
+    }
+
+    /// Handle of the table `name`, `None` if it does not exist in this snapshot.
+    /// mdbx shares the handles of existing tables with the other transactions.
+    fn open(&self, handle: &OnceLock<ffi::MDBX_dbi>, name: &CStr) -> Result<Option<ffi::MDBX_dbi>> {
+        if let Some(dbi) = handle.get() {
+            return Ok(Some(*dbi));
+        }
+        let mut dbi = 0;
+        match unsafe { ffi::mdbx_dbi_open(self.0.as_ptr(), name.as_ptr(), 0, &mut dbi) } {
+            ffi::MDBX_NOTFOUND => Ok(None),
+            code => mdbx_result(code).map(|()| Some(*handle.get_or_init(|| dbi))),
+        }
+    }
+
+    /// Value of `key` in the table `dbi`.
+    fn get<const LEN: usize>(&self, dbi: ffi::MDBX_dbi, key: &[u8]) -> Result<Option<[u8; LEN]>> {
+        let key = ffi::MDBX_val {
+            iov_base: key.as_ptr() as *mut _,
+            iov_len: key.len(),
+        };
+        let mut data = ffi::MDBX_val {
+            iov_base: ptr::null_mut(),
+            iov_len: 0,
+        };
+        match unsafe { ffi::mdbx_get(self.0.as_ptr(), dbi, &key, &mut data) } {
+            // SAFETY: `data` points into the snapshot of the transaction, still live
+            ffi::MDBX_SUCCESS => unsafe { value(&data) }.map(Some),
+            ffi::MDBX_NOTFOUND => Ok(None),
+            code => mdbx_result(code).map(|()| None),
+        }
+    }
+
+    /// First result of `f` over the values of `key` in the duplicate-sorted table
+    /// `dbi`, in order.
+    fn find_duplicate<R>(
+        &self,
+        dbi: ffi::MDBX_dbi,
+        key: &[u8],
+        mut f: impl FnMut(&[u8]) -> Result<Option<R>>,
+    ) -> Result<Option<R>> {
+        let mut cursor = ptr::null_mut();
+        mdbx_result(unsafe { ffi::mdbx_cursor_open(self.0.as_ptr(), dbi, &mut cursor) })?;
+        let mut key = ffi::MDBX_val {
+            iov_base: key.as_ptr() as *mut _,
+            iov_len: key.len(),
+        };
+        let mut data = ffi::MDBX_val {
+            iov_base: ptr::null_mut(),
+            iov_len: 0,
+        };
+        let mut op = ffi::MDBX_SET_KEY;
+        let result = loop {
+            match unsafe { ffi::mdbx_cursor_get(cursor, &mut key, &mut data, op) } {
+                // SAFETY: `data` points to the record of the cursor, valid until the
+                // next cursor operation
+                ffi::MDBX_SUCCESS => {
+                    let value =
+                        unsafe { slice::from_raw_parts(data.iov_base as *const u8, data.iov_len) };
+                    match f(value) {
+                        Ok(None) => op = ffi::MDBX_NEXT_DUP,
+                        found => break found,
+                    }
+                }
+                ffi::MDBX_NOTFOUND => break Ok(None),
+                code => break mdbx_result(code).map(|()| None),
+            }
+        };
+        unsafe { ffi::mdbx_cursor_close(cursor) };
+        result
+    }
+}
+
+impl Drop for ReadTxn {
+    fn drop(&mut self) {
+        // the debug builds of mdbx assert that an aborted read transaction was not
+        // reset, the reset clearing its flags
+        unsafe {
+            ffi::mdbx_txn_renew(self.0.as_ptr());
+            ffi::mdbx_txn_abort(self.0.as_ptr());
+        }
+    }
+}
+
+/// Copies a value of `LEN` bytes out of the database.
+///
+/// # Safety
+///
+/// `data` must point to a live record.
+unsafe fn value<const LEN: usize>(data: &ffi::MDBX_val) -> Result<[u8; LEN]> {
+    slice::from_raw_parts(data.iov_base as *const u8, data.iov_len)
+        .try_into()
+        .map_err(|_| libmdbx::Error::Corrupted.into())
+}
+
+/// Read transactions reused by the lookups, with the handles of the tables they read,
+/// opened once. A lookup renews an idle transaction, which keeps its reader slot and
+/// memory, and resets it when done, releasing its snapshot: an idle transaction
+/// neither hides the later commits nor holds their pages back from reuse.
+#[derive(Default)]
+struct ReadPool {
+    idle: Mutex<Vec<ReadTxn>>,
+    table: OnceLock<ffi::MDBX_dbi>,
+    index: OnceLock<ffi::MDBX_dbi>,
+    ranges: OnceLock<ffi::MDBX_dbi>,
+    blocks: OnceLock<ffi::MDBX_dbi>,
+}
+
+impl ReadPool {
+    /// Runs `f` in a read transaction on the last commit of `db`.
+    fn read<R>(
+        &self,
+        db: &Database<NoWriteMap>,
+        f: impl FnOnce(&ReadTxn) -> Result<R>,
+    ) -> Result<R> {
+        let idle = self.idle.lock().unwrap().pop();
+        let txn = match idle {
+            Some(txn) => {
+                mdbx_result(unsafe { ffi::mdbx_txn_renew(txn.0.as_ptr()) })?;
+                txn
+            }
+            None => ReadTxn::begin(db)?,
+        };
+        let result = f(&txn);
+        if unsafe { ffi::mdbx_txn_reset(txn.0.as_ptr()) } == ffi::MDBX_SUCCESS {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE_READS {
+                idle.push(txn);
+            }
+        }
+        result
+    }
+}
+
+/// State of the index recorded in the stats table.
+struct Stats {
+    counter: u32,
+    last_block: u32,
+    start_block: u32,
+    accumulator: Option<Accumulator>,
+    /// Number of truncations, after which cached lookups may be stale.
+    truncations: u64,
+}
+
+/// Stats key of the block the `n`-th truncation went back to, for reader processes.
+fn truncation_key(n: u64) -> String {
+    format!("truncation:{}", n)
+}
+
+fn read_stats(db: &Database<NoWriteMap>) -> Result<Stats> {
+    let tx = db.begin_ro_txn()?;
+    let Ok(table) = tx.open_table(Some("stats")) else {
+        return Ok(Stats {
+            counter: 0,
+            last_block: 0,
+            start_block: 1,
+            accumulator: None,
+            truncations: 0,
+        });
+    };
+    let counter = tx.get(&table, b"counter")?;
+    let last_block = tx.get(&table, b"last_block")?;
+    let start_block = tx.get(&table, b"start_block")?;
+    let accumulator = tx.get::<[u8; ACCUMULATOR_SIZE]>(&table, b"accumulator")?;
+    let truncations = tx.get(&table, b"truncations")?;
+    Ok(Stats {
+        counter: counter.map(u32::from_le_bytes).unwrap_or(0),
+        last_block: last_block.map(u32::from_le_bytes).unwrap_or(0),
+        start_block: start_block.map(u32::from_le_bytes).unwrap_or(1),
+        accumulator: accumulator.map(Accumulator::from_bytes),
+        truncations: truncations.map(u64::from_le_bytes).unwrap_or(0),
+    })
+}
+
 pub struct Storage<const N: usize, T> {
     _data: std::marker::PhantomData<T>,
+    /// Dropped before the database its transactions read.
+    reads: ReadPool,
     db: Database<NoWriteMap>,
     counters: RwLock<Counters>,
     cache: RwLock<LruCache<T, usize>>,
     index_cache: RwLock<LruCache<usize, T>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    persist_tries: bool,
+    tombstone_retention: u64,
+    read_only: bool,
+    truncations: AtomicU64,
+    /// Held by the writer process, released when the storage is dropped.
+    _writer_lock: Option<File>,
+    accumulator: RwLock<Accumulator>,
+    /// First block of the index, whose previous block anchors the checkpoint chain.
+    start_block: AtomicU32,
+}
+
+/// Storage engine of the index, and how its commits reach the disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Backend {
+    /// mdbx, syncing the data of each commit but not its meta page: a crash reopens
+    /// the database on the previous commit.
+    #[default]
+    Mdbx,
+    /// mdbx, leaving the sync of the commits to the operating system: faster, and a
+    /// system crash loses the last commits but cannot corrupt the database.
+    MdbxNoSync,
+}
+
+/// Database and cache settings used when opening the storage.
+#[derive(Clone, Debug)]
+pub struct StorageOptions {
+    /// Capacity of the item -> index lookup cache.
+    pub cache_size: usize,
+    /// Capacity of the index -> item lookup cache.
+    pub index_cache_size: usize,
+    pub read_only: bool,
+    pub backend: Backend,
+    pub page_size: usize,
+    pub min_size: Option<isize>,
+    pub max_size: Option<isize>,
+    pub growth_step: Option<isize>,
+    /// Stores the checkpoint trie nodes of committed blocks, so that proofs are read
+    /// instead of rebuilt from all the entries of the block.
+    pub persist_tries: bool,
+    /// Seconds for which the pending blocks dropped before their commit are kept, 0
+    /// not recording them.
+    pub tombstone_retention: u64,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        Self {
+            cache_size: 1_000_000,
+            index_cache_size: 1_000_000,
+            read_only: false,
+            backend: Backend::default(),
+            page_size: 16384,
+            min_size: Some(17179869184),
+            max_size: None,
+            growth_step: None,
+            persist_tries: false,
+            tombstone_retention: 7 * 24 * 3600,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Block<T> {
+    pub number: u64,
+    pub items: Vec<T>,
+    pub root_hash: H256,
+    /// Checkpoint trie nodes to persist, empty unless the storage keeps them.
+    pub nodes: Vec<(H256, Vec<u8>)>,
+    /// Data recorded with the block, in the transaction that stores its entries.
+    pub meta: BlockMeta,
+}
+
+/// Data of a block stored along with its entries, so that a crash or a rollback
+/// cannot leave one without the other.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlockMeta {
+    /// Unix time of the block, when known.
+    pub timestamp: Option<u64>,
+    /// Transactions its entries were first seen in, by offset in the block.
+    pub appearances: Vec<(u32, Appearance)>,
+    /// Increments of counters of the stats table, e.g. of the entries per source,
+    /// undone when the block is rolled back.
+    pub counts: Vec<(String, u64)>,
+}
+
+impl BlockMeta {
+    /// (name length: u8 | name | count: u64) for each count
+    fn counts_to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        for (name, count) in &self.counts {
+            bytes.push(name.len() as u8);
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn counts_from_bytes(mut bytes: &[u8]) -> Vec<(String, u64)> {
+        let mut counts = vec![];
+        while let Some((len, rest)) = bytes.split_first() {
+            let len = *len as usize;
+            if rest.len() < len + 8 {
+                break;
+            }
+            let name = String::from_utf8_lossy(&rest[..len]).into_owned();
+            let count = u64::from_le_bytes(rest[len..len + 8].try_into().unwrap());
+            counts.push((name, count));
+            bytes = &rest[len + 8..];
+        }
+        counts
+    }
+}
+
+/// Hash of a block whose checkpoint trie has the root `root_hash`, chained to the
+/// hash of the previous block.
+fn chain_hash(previous_hash: H256, root_hash: H256) -> H256 {
+    let mut hash = [0u8; 32];
+    let mut keccak = Keccak::v256();
+    keccak.update(previous_hash.as_bytes());
+    keccak.update(root_hash.as_ref());
+    keccak.finalize(&mut hash);
+    H256::from(hash)
+}
+
+impl<T> Block<T> {
+    pub fn compute_hash(&self, previous_hash: H256) -> H256 {
+        let res = chain_hash(previous_hash, self.root_hash);
+        trace!(
+            "computed hash for block {}: {} (previous: {}",
+            self.number,
+            res,
+            previous_hash
+        );
+        res
+    }
+}
+
+/// User-supplied label of an item (e.g. "Binance 8" for an address), with where it
+/// comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Label {
+    pub label: String,
+    pub source: String,
+}
+
+impl Label {
+    /// label length (u16, little endian) | label | source
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 + self.label.len() + self.source.len());
+        bytes.extend_from_slice(&(self.label.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(self.label.as_bytes());
+        bytes.extend_from_slice(self.source.as_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let invalid = || MoniqueError::Storage("storage labels: invalid label".to_string());
+        let len = u16::from_le_bytes(bytes.get(..2).ok_or_else(invalid)?.try_into().unwrap());
+        let (label, source) = bytes[2..]
+            .split_at_checked(len as usize)
+            .ok_or_else(invalid)?;
+        Ok(Self {
+            label: String::from_utf8(label.to_vec()).map_err(|_| invalid())?,
+            source: String::from_utf8(source.to_vec()).map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Names of the tables of the database.
+pub const TABLES: [&str; 17] = [
+    "stats",
+    "table",
+    "index",
+    "blocks",
+    "ranges",
+    "contracts",
+    "signatures",
+    "tries",
+    "roots",
+    "labels",
+    "timestamps",
+    "history",
+    "appearances",
+    "tombstones",
+    "spill",
+    "spill_items",
+    "counts",
+];
+
+/// Sample of the indexing progress, kept in the `history` ring buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HistorySample {
+    /// Unix time of the sample, in seconds.
+    pub timestamp: u64,
+    /// Last indexed block.
+    pub block: u64,
+    /// Entries of the index.
+    pub addresses: u64,
+    /// Indexing rate since the previous sample.
+    pub blocks_per_second: f64,
+}
+
+impl HistorySample {
+    /// timestamp | block | addresses | blocks_per_second, little endian
+    fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        bytes[..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.block.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.addresses.to_le_bytes());
+        bytes[24..].copy_from_slice(&self.blocks_per_second.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; 32]) -> Self {
+        let field = |i: usize| bytes[i * 8..(i + 1) * 8].try_into().unwrap();
+        Self {
+            timestamp: u64::from_le_bytes(field(0)),
+            block: u64::from_le_bytes(field(1)),
+            addresses: u64::from_le_bytes(field(2)),
+            blocks_per_second: f64::from_le_bytes(field(3)),
+        }
+    }
+}
+
+/// Transaction an entry was first seen in, with the log of its receipt when found in
+/// one, kept in the `appearances` table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Appearance {
+    pub transaction: H256,
+    /// Index of the log in the block.
+    pub log_index: Option<u64>,
+}
+
+impl Appearance {
+    /// transaction | log_index (`u64::MAX` without a log), little endian
+    fn to_bytes(self) -> [u8; 40] {
+        let mut bytes = [0; 40];
+        bytes[..32].copy_from_slice(self.transaction.as_bytes());
+        bytes[32..].copy_from_slice(&self.log_index.unwrap_or(u64::MAX).to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; 40]) -> Self {
+        let log_index = u64::from_le_bytes(bytes[32..].try_into().unwrap());
+        Self {
+            transaction: H256::from_slice(&bytes[..32]),
+            log_index: (log_index != u64::MAX).then_some(log_index),
+        }
+    }
+}
+
+/// Why the items of a pending block were dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TombstoneReason {
+    /// The block was queued again, from another fork.
+    Reorg,
+    /// The index was rolled back by the operator.
+    Rollback,
+}
+
+impl TombstoneReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TombstoneReason::Reorg => "reorg",
+            TombstoneReason::Rollback => "rollback",
+        }
+    }
+}
+
+/// Items of a pending block dropped before its commit, kept in the `tombstones`
+/// table for post-incident analysis.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tombstone<T> {
+    /// Unix time of the drop, in seconds.
+    pub timestamp: u64,
+    pub block: u64,
+    pub reason: TombstoneReason,
+    pub items: Vec<T>,
+}
+
+/// Page usage of the database file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Space {
+    pub page_size: u64,
+    /// Current size of the file.
+    pub file_size: u64,
+    /// Size up to which the file can grow.
+    pub max_size: u64,
+    /// Pages up to the last allocated one, free ones included.
+    pub allocated_pages: u64,
+    /// Allocated pages on the free list, reused before the file grows.
+    pub free_pages: u64,
+}
+
+impl Space {
+    /// Allocated pages in use, 0 rather than an underflow should the free pages
+    /// counted from the GC table exceed the allocated ones.
+    pub fn used_pages(&self) -> u64 {
+        self.allocated_pages.saturating_sub(self.free_pages)
+    }
+
+    /// Share of the maximum size of the file taken by its allocated pages.
+    pub fn map_usage(&self) -> f64 {
+        if self.max_size == 0 {
+            return 0.0;
+        }
+        (self.allocated_pages * self.page_size) as f64 / self.max_size as f64
+    }
+
+    /// Share of the allocated pages which are free, which only a compacting copy
+    /// gives back.
+    pub fn fragmentation(&self) -> f64 {
+        if self.allocated_pages == 0 {
+            return 0.0;
+        }
+        self.free_pages as f64 / self.allocated_pages as f64
+    }
+}
+
+/// Distribution of the entries of the dedup `table` over their 32-bit hash keys, the
+/// entries of a bucket being read in turn by a lookup.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BucketReport {
+    pub entries: u64,
+    /// Distinct keys.
+    pub buckets: u64,
+    /// Number of buckets of each size.
+    pub sizes: BTreeMap<u64, u64>,
+    /// Largest buckets with their key, largest first.
+    pub hot_spots: Vec<(u32, u64)>,
+}
+
+impl BucketReport {
+    /// Pairs of entries sharing their bucket.
+    pub fn colliding_pairs(&self) -> u64 {
+        self.sizes
+            .iter()
+            .map(|(size, count)| size * (size - 1) / 2 * count)
+            .sum()
+    }
+
+    /// Colliding pairs expected from a uniform hash of `bits` bits over the entries.
+    pub fn expected_pairs(&self, bits: i32) -> f64 {
+        let n = self.entries as f64;
+        n * (n - 1.0) / 2.0 / 2f64.powi(bits)
+    }
+
+    /// Average entries read by the lookup of an indexed item, its bucket being
+    /// scanned up to it.
+    pub fn hit_cost(&self) -> f64 {
+        if self.entries == 0 {
+            return 0.0;
+        }
+        let reads: u64 = self
+            .sizes
+            .iter()
+            .map(|(size, count)| size * (size + 1) / 2 * count)
+            .sum();
+        reads as f64 / self.entries as f64
+    }
+
+    /// Average entries read by the lookup of a new item, the whole bucket it falls
+    /// into, if any.
+    pub fn miss_cost(&self) -> f64 {
+        self.entries as f64 / 2f64.powi(32)
+    }
+}
+
+/// Timestamp of a stored tombstone.
+fn timestamp_of(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+/// Error of a raw MDBX call.
+fn mdbx_result(code: std::os::raw::c_int) -> Result<()> {
+    match code {
+        0 => Ok(()),
+        code => Err(libmdbx::Error::from_err_code(code).into()),
+    }
+}
+
+/// Number of free pages listed in the GC table. Its records are a count of pages
+/// followed by their numbers, both `u32` (which `Database::freelist` reads as `u64`).
+///
+/// # Safety
+///
+/// `txn` must be a live transaction, not used concurrently.
+unsafe fn free_pages(txn: *mut ffi::MDBX_txn) -> Result<u64> {
+    let mut cursor = std::ptr::null_mut();
+    mdbx_result(ffi::mdbx_cursor_open(txn, 0, &mut cursor))?;
+    let mut key = ffi::MDBX_val {
+        iov_base: std::ptr::null_mut(),
+        iov_len: 0,
+    };
+    let mut data = key;
+    let (mut op, mut free) = (ffi::MDBX_FIRST, 0u64);
+    let result = loop {
+        match ffi::mdbx_cursor_get(cursor, &mut key, &mut data, op) {
+            ffi::MDBX_SUCCESS if data.iov_len >= 4 => {
+                // SAFETY: `data` points to the record of the cursor, valid until the
+                // next cursor operation and at least 4 bytes long, maybe unaligned
+                free += std::ptr::read_unaligned(data.iov_base as *const u32) as u64;
+                op = ffi::MDBX_NEXT;
+            }
+            ffi::MDBX_SUCCESS => break Err(libmdbx::Error::Corrupted.into()),
+            ffi::MDBX_NOTFOUND => break Ok(free),
+            code => break mdbx_result(code).map(|_| free),
+        }
+    };
+    ffi::mdbx_cursor_close(cursor);
+    result
+}
+
+/// Entries added by a block, with the root of its checkpoint trie.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockRange {
+    pub start: u32,
+    pub count: u32,
+    pub root_hash: H256,
+}
+
+impl BlockRange {
+    fn to_bytes(self) -> [u8; 40] {
+        let mut bytes = [0u8; 40];
+        bytes[..4].copy_from_slice(&self.start.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.count.to_le_bytes());
+        bytes[8..].copy_from_slice(self.root_hash.as_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; 40]) -> Self {
+        Self {
+            start: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+            count: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            root_hash: H256::from_slice(&bytes[8..]),
+        }
+    }
+}
+
+#[async_trait]
+pub trait Push<T> {
+    async fn push(&self, blocks: Vec<Block<T>>) -> Result<()>;
+}
+
+/// Index of `item`, scanning the entries of the `table` table under its hash for the
+/// one whose item in `index_table` matches.
+fn find<const N: usize, T>(
+    txn: &ReadTxn,
+    table: ffi::MDBX_dbi,
+    index_table: ffi::MDBX_dbi,
+    item: &T,
+) -> Result<Option<usize>>
+where
+    T: AsRef<[u8]> + PartialEq + std::convert::From<[u8; N]>,
+{
+    let hash = (xxh3_64(item.as_ref()) as u32).to_le_bytes();
+    txn.find_duplicate(table, &hash, |v| {
+        let stored: Option<[u8; N]> = txn.get(index_table, v)?;
+        Ok((stored.map(T::from).as_ref() == Some(item))
+            .then(|| u32::from_le_bytes(v.try_into().unwrap()) as usize))
+    })
 }
 
-#[derive(Clone)]
-pub struct Block<T> {
-    pub number: u64,
-    pub items: Vec<T>,
-    pub root_hash: H256,
-}
+impl<const N: usize, T> Storage<N, T>
+where
+    T: Sized + AsRef<[u8]> + PartialEq + Hash + Eq + Copy + std::convert::From<[u8; N]>,
+{
+    #[cfg(test)]
+    pub fn new(path: PathBuf, cache_size: usize) -> Self {
+        let options = StorageOptions {
+            cache_size,
+            index_cache_size: cache_size,
+            ..Default::default()
+        };
+        Self::open(path, &options).unwrap()
+    }
+
+    /// Opens the database, in read-only mode if requested (the database must exist).
+    pub fn open(path: PathBuf, options: &StorageOptions) -> Result<Self> {
+        // table format:
+        // stats: 'counter' -> u32, 'last_block' -> u32, 'accumulator' -> count | branch,
+        //   'truncations' -> u64, 'truncation:<n>' -> u64 (block of the n-th truncation),
+        //   'history_next' -> u64, 'chain_id' -> u64,
+        //   'ruleset' -> hash of the extraction rules, 'tombstones_next' -> u64
+        // table: xxhash32(address) -> [index, ...]
+        // index: index -> address
+        // blocks: block_number -> start_index | count | checkpoint_hash
+        // ranges: block_number -> start_index | count | root_hash
+        // contracts: index -> 1 if the address holds code, 0 otherwise
+        // signatures: block_number -> operator signature of the checkpoint
+        // tries: node_hash -> checkpoint trie node (only with `persist_tries`)
+        // roots: block_number -> root of the accumulator over all the entries
+        // labels: item -> label length | label | source
+        // timestamps: block_number -> block timestamp
+        // history: slot -> timestamp | block | addresses | blocks_per_second
+        // appearances: index -> transaction hash | log index
+        // tombstones: sequence -> timestamp | block | reason | [item, ...]
+        // spill: block_number -> [item, ...] of a pending block moved out of memory
+        // spill_items: item -> block_number of its spilled block
+        // counts: block_number -> [name length | name | count, ...] added to the stats
+        let db = Database::open_with_options(
+            &path,
+            DatabaseOptions {
+                max_tables: Some(TABLES.len() as u64),
+                // readers follow the sync mode of the writer process
+                accede: true,
+                page_size: Some(PageSize::Set(options.page_size)),
+                mode: if options.read_only {
+                    Mode::ReadOnly
+                } else {
+                    Mode::ReadWrite(ReadWriteOptions {
+                        min_size: options.min_size,
+                        max_size: options.max_size,
+                        growth_step: options.growth_step,
+                        sync_mode: match options.backend {
+                            // the meta pages alternate, each with its transaction id: a
+                            // crash before one is synced reopens on the previous commit
+                            Backend::Mdbx => libmdbx::SyncMode::NoMetaSync,
+                            Backend::MdbxNoSync => libmdbx::SyncMode::SafeNoSync,
+                        },
+                        ..Default::default()
+                    })
+                },
+                ..Default::default()
+            },
+        )?;
+        // a single writer process, other processes open the database read-only
+        let writer_lock = if options.read_only {
+            None
+        } else {
+            let lock = File::create(path.join(WRITER_LOCK))?;
+            lock.try_lock_exclusive().map_err(|_| {
+                MoniqueError::Storage(format!(
+                    "storage open: another process writes to {}, open it read-only",
+                    path.display()
+                ))
+            })?;
+            Some(lock)
+        };
+        let Stats {
+            counter,
+            last_block,
+            start_block,
+            accumulator,
+            truncations,
+        } = read_stats(&db)?;
+
+        info!("counter: {}", counter);
+        info!("last_block: {}", last_block);
+
+        // only the writer extends the accumulator, which is missing from databases
+        // filled before it was introduced
+        let accumulator = match accumulator {
+            Some(accumulator) => accumulator,
+            None if options.read_only || counter == 0 => Accumulator::default(),
+            None => {
+                info!("rebuilding the accumulator over {} entries", counter);
+                let mut accumulator = Accumulator::default();
+                let tx = db.begin_ro_txn()?;
+                let table = tx.open_table(Some("index"))?;
+                let mut cursor = tx.cursor(&table)?;
+                for value in cursor.iter_start::<[u8; 4], [u8; N]>() {
+                    accumulator.push(&value?.1);
+                }
+                accumulator
+            }
+        };
+
+        let cache_size = |size| {
+            NonZeroUsize::new(size).ok_or(MoniqueError::Storage(
+                "storage open: cache size must be positive".to_string(),
+            ))
+        };
+        let cache = RwLock::new(LruCache::new(cache_size(options.cache_size)?));
+        let index_cache = RwLock::new(LruCache::new(cache_size(options.index_cache_size)?));
+
+        Ok(Self {
+            _data: std::marker::PhantomData,
+            reads: ReadPool::default(),
+            db,
+            counters: RwLock::new(Counters {
+                counter,
+                last_block,
+            }),
+            cache,
+            index_cache,
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            persist_tries: options.persist_tries,
+            tombstone_retention: options.tombstone_retention,
+            read_only: options.read_only,
+            truncations: AtomicU64::new(truncations),
+            _writer_lock: writer_lock,
+            accumulator: RwLock::new(accumulator),
+            start_block: AtomicU32::new(start_block),
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Reloads the counters, first block and accumulator committed by the writer
+    /// process of the database, in a reader process. Returns the previous counters if
+    /// they changed, with the lowest block the writer truncated to since the last
+    /// refresh, the caches being cleared if entries were rolled back.
+    pub async fn refresh(&self) -> Result<Option<(Counters, Option<u32>)>> {
+        let stats = read_stats(&self.db)?;
+        let mut counters = self.counters.write().await;
+        let seen = self.truncations.swap(stats.truncations, Ordering::Relaxed);
+        let truncated = seen != stats.truncations;
+        if !truncated
+            && counters.counter == stats.counter
+            && counters.last_block == stats.last_block
+            && self.start_block() == stats.start_block
+        {
+            return Ok(None);
+        }
+        if truncated {
+            self.cache.write().await.clear();
+            self.index_cache.write().await.clear();
+        }
+        if let Some(accumulator) = stats.accumulator {
+            *self.accumulator.write().await = accumulator;
+        }
+        self.start_block.store(stats.start_block, Ordering::Relaxed);
+        let previous = counters.clone();
+        counters.counter = stats.counter;
+        counters.last_block = stats.last_block;
+        let mut target = None;
+        if truncated {
+            // a target missing from an older database is taken as a full truncation
+            for n in (seen + 1)..=stats.truncations {
+                let to = self.get_stat(&truncation_key(n))?.unwrap_or(0) as u32;
+                target = Some(target.map_or(to, |target: u32| target.min(to)));
+            }
+        }
+        Ok(Some((previous, target)))
+    }
+
+    pub fn start_block(&self) -> u32 {
+        self.start_block.load(Ordering::Relaxed)
+    }
+
+    /// Starts an empty storage at block `start`, the checkpoint chain being anchored
+    /// to the zero hash at block `start - 1`.
+    pub async fn set_start_block(&self, start: u32) -> Result<()> {
+        let mut counters = self.counters.write().await;
+        if start == 0 {
+            return Err(MoniqueError::Storage(
+                "storage set_start_block: the first block is 1 or more".to_string(),
+            ));
+        }
+        if start == self.start_block() {
+            return Ok(());
+        }
+        if counters.last_block + 1 != self.start_block() {
+            return Err(MoniqueError::Storage(format!(
+                "storage set_start_block: already indexed from block {}",
+                self.start_block()
+            )));
+        }
+        let tx = self.db.begin_rw_txn()?;
+        let table = tx.create_table(Some("stats"), TableFlags::CREATE)?;
+        tx.put(
+            &table,
+            b"start_block",
+            start.to_le_bytes(),
+            WriteFlags::UPSERT,
+        )?;
+        tx.put(
+            &table,
+            b"last_block",
+            (start - 1).to_le_bytes(),
+            WriteFlags::UPSERT,
+        )?;
+        tx.commit()?;
+        self.start_block.store(start, Ordering::Relaxed);
+        counters.last_block = start - 1;
+        Ok(())
+    }
+
+    /// Removes the blocks committed after block `to`, with their entries, from every
+    /// table keyed by block or by index. Returns the number of entries removed. The
+    /// accumulator is rebuilt from the remaining entries, and persisted trie nodes,
+    /// which may be shared between blocks, are kept.
+    pub async fn truncate(&self, to: u32) -> Result<usize> {
+        let mut counters = self.counters.write().await;
+        let last_block = counters.last_block;
+        if to >= last_block {
+            return Ok(0);
+        }
+        if to + 1 < self.start_block() {
+            return Err(MoniqueError::Rollback(format!(
+                "block {} is before the first block {}",
+                to,
+                self.start_block()
+            )));
+        }
+        let counter = match self.get_range(to + 1)? {
+            Some(range) => range.start,
+            None => {
+                return Err(MoniqueError::Rollback(format!(
+                    "block {} was committed before block ranges were recorded",
+                    to + 1
+                )))
+            }
+        };
+
+        self.remove_after(&mut counters, to, counter, false).await
+    }
+
+    /// Removes, in a single transaction, the entries from `counter` on and the blocks
+    /// after `to`, then rebuilds the accumulator and stores the new counters. Entries
+    /// are found by key rather than from the stored counter, so that entries past a
+    /// counter that fell behind are removed too. With `sweep`, the `table` table is
+    /// also scanned for entries that have no counterpart in the `index` table.
+    async fn remove_after(
+        &self,
+        counters: &mut Counters,
+        to: u32,
+        counter: u32,
+        sweep: bool,
+    ) -> Result<usize> {
+        let tx = self.db.begin_rw_txn()?;
+        let index_table = tx.open_table(Some("index"))?;
+        let table = tx.open_table(Some("table"))?;
+        let entries = tx
+            .cursor(&index_table)?
+            .iter_from::<[u8; 4], [u8; N]>(&counter.to_le_bytes())
+            // a seek past the last key leaves the cursor on the last record
+            .filter(|entry| !matches!(entry, Ok((key, _)) if u32::from_le_bytes(*key) < counter))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (key, item) in &entries {
+            let hash = (xxh3_64(&item[..]) as u32).to_le_bytes();
+            tx.del(&table, hash, Some(&key[..]))?;
+            tx.del(&index_table, key, None)?;
+        }
+        if sweep {
+            let strays = tx
+                .cursor(&table)?
+                .iter_start::<[u8; 4], [u8; 4]>()
+                .filter(|entry| {
+                    !matches!(entry, Ok((_, index)) if u32::from_le_bytes(*index) < counter)
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            for (hash, index) in strays {
+                tx.del(&table, hash, Some(&index[..]))?;
+            }
+        }
+        let stats = tx.open_table(Some("stats"))?;
+        // the counts of the removed blocks are taken back from their counters
+        if let Ok(counts) = tx.open_table(Some("counts")) {
+            let from = to + 1;
+            let removed = tx
+                .cursor(&counts)?
+                .iter_from::<[u8; 4], Vec<u8>>(&from.to_le_bytes())
+                .filter(|entry| !matches!(entry, Ok((key, _)) if u32::from_le_bytes(*key) < from))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            for (_, bytes) in removed {
+                for (name, count) in BlockMeta::counts_from_bytes(&bytes) {
+                    let total = tx
+                        .get::<[u8; 8]>(&stats, name.as_bytes())?
+                        .map(u64::from_le_bytes)
+                        .unwrap_or(0);
+                    tx.put(
+                        &stats,
+                        name,
+                        total.saturating_sub(count).to_le_bytes(),
+                        WriteFlags::UPSERT,
+                    )?;
+                }
+            }
+        }
+        let keyed = [
+            ("contracts", counter),
+            ("appearances", counter),
+            ("blocks", to + 1),
+            ("ranges", to + 1),
+            ("roots", to + 1),
+            ("signatures", to + 1),
+            ("timestamps", to + 1),
+            ("counts", to + 1),
+        ];
+        for (name, from) in keyed {
+            let Ok(keyed) = tx.open_table(Some(name)) else {
+                continue;
+            };
+            let keys = tx
+                .cursor(&keyed)?
+                .iter_from::<[u8; 4], ()>(&from.to_le_bytes())
+                .filter(|entry| !matches!(entry, Ok((key, _)) if u32::from_le_bytes(*key) < from))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            for (key, _) in keys {
+                tx.del(&keyed, key, None)?;
+            }
+        }
+
+        info!("rebuilding the accumulator over {} entries", counter);
+        let mut accumulator = Accumulator::default();
+        {
+            let mut cursor = tx.cursor(&index_table)?;
+            for value in cursor.iter_start::<[u8; 4], [u8; N]>() {
+                accumulator.push(&value?.1);
+            }
+        }
+        tx.put(
+            &stats,
+            b"counter",
+            counter.to_le_bytes(),
+            WriteFlags::UPSERT,
+        )?;
+        tx.put(&stats, b"last_block", to.to_le_bytes(), WriteFlags::UPSERT)?;
+        tx.put(
+            &stats,
+            b"accumulator",
+            accumulator.to_bytes(),
+            WriteFlags::UPSERT,
+        )?;
+        let truncations = self.truncations.load(Ordering::Relaxed) + 1;
+        tx.put(
+            &stats,
+            b"truncations",
+            truncations.to_le_bytes(),
+            WriteFlags::UPSERT,
+        )?;
+        tx.put(
+            &stats,
+            truncation_key(truncations),
+            (to as u64).to_le_bytes(),
+            WriteFlags::UPSERT,
+        )?;
+        tx.commit()?;
+        self.truncations.store(truncations, Ordering::Relaxed);
+
+        *self.accumulator.write().await = accumulator;
+        self.cache.write().await.clear();
+        self.index_cache.write().await.clear();
+        counters.counter = counter;
+        counters.last_block = to;
+        Ok(entries.len())
+    }
+
+    /// Brings the database back to a consistent state after the integrity check
+    /// failed: finds the last block whose hash chains from its predecessor and whose
+    /// entries are all stored, and removes everything after it, including entries
+    /// left past the stored counter. Returns that block, or `None` when the check
+    /// passed and nothing was changed.
+    pub async fn recover(&self) -> Result<Option<u32>> {
+        let errors = self.check()?;
+        if errors.is_empty() {
+            return Ok(None);
+        }
+        for error in &errors {
+            warn!("integrity check: {}", error);
+        }
+        let mut counters = self.counters.write().await;
+        let start = self.start_block();
+        let (to, counter) = {
+            let tx = self.db.begin_ro_txn()?;
+            let blocks = tx.open_table(Some("blocks")).ok();
+            let ranges = tx.open_table(Some("ranges")).ok();
+            let index = tx.open_table(Some("index")).ok();
+            let hash = |number: u32| -> Result<Option<H256>> {
+                if number + 1 == start {
+                    return Ok(Some(H256::zero()));
+                }
+                Ok(match &blocks {
+                    Some(table) => tx.get::<[u8; 32]>(table, &number.to_le_bytes())?.map(H256),
+                    None => None,
+                })
+            };
+            let mut valid = None;
+            for number in (start..=counters.last_block.max(start)).rev() {
+                let range = match &ranges {
+                    Some(table) => tx
+                        .get::<[u8; 40]>(table, &number.to_le_bytes())?
+                        .map(BlockRange::from_bytes),
+                    None => None,
+                };
+                let (Some(hash), Some(previous)) = (hash(number)?, hash(number - 1)?) else {
+                    continue;
+                };
+                let Some(range) = range else {
+                    return Err(MoniqueError::Rollback(format!(
+                        "block {} was committed before block ranges were recorded",
+                        number
+                    )));
+                };
+                let end = range.start + range.count;
+                let stored = match (&index, end) {
+                    (_, 0) => true,
+                    (Some(table), end) => tx.get::<()>(table, &(end - 1).to_le_bytes())?.is_some(),
+                    (None, _) => false,
+                };
+                if chain_hash(previous, range.root_hash) == hash && stored {
+                    valid = Some((number, end));
+                    break;
+                }
+            }
+            valid.unwrap_or((start.saturating_sub(1), 0))
+        };
+        let removed = self.remove_after(&mut counters, to, counter, true).await?;
+        info!(
+            "recovered at block {}, removing {} entries past entry {}",
+            to, removed, counter
+        );
+        Ok(Some(to))
+    }
+
+    pub async fn get_counters(&self) -> RwLockReadGuard<'_, Counters> {
+        self.counters.read().await
+    }
+
+    /// Looks up the indexes of several items, sharing a single read transaction
+    /// for the cache misses.
+    pub async fn index_many(&self, items: &[T]) -> Result<Vec<Option<usize>>> {
+        let mut result = vec![None; items.len()];
+        let mut misses = vec![];
+        {
+            let mut cache = self.cache.write().await;
+            for (i, item) in items.iter().enumerate() {
+                match cache.get(item) {
+                    Some(index) => result[i] = Some(*index),
+                    None => misses.push(i),
+                }
+            }
+        }
+        let hits = items.len() - misses.len();
+        self.cache_hits.fetch_add(hits as u64, Ordering::Relaxed);
+        self.cache_misses
+            .fetch_add(misses.len() as u64, Ordering::Relaxed);
+        if misses.is_empty() {
+            return Ok(result);
+        }
+
+        let mut found = vec![];
+        self.reads.read(&self.db, |txn| {
+            let (Some(table), Some(index_table)) = (
+                txn.open(&self.reads.table, c"table")?,
+                txn.open(&self.reads.index, c"index")?,
+            ) else {
+                return Ok(());
+            };
+            for i in misses {
+                if let Some(key) = find(txn, table, index_table, &items[i])? {
+                    result[i] = Some(key);
+                    found.push((items[i], key));
+                }
+            }
+            Ok(())
+        })?;
+        let mut cache = self.cache.write().await;
+        for (item, key) in found {
+            cache.put(item, key);
+        }
+        Ok(result)
+    }
+
+    /// Hits and misses of the reverse lookup cache since startup.
+    pub fn cache_stats(&self) -> (u64, u64) {
+        (
+            self.cache_hits.load(Ordering::Relaxed),
+            self.cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn get_block_hash(&self, number: u32) -> Result<H256> {
+        if number == 0 || number + 1 == self.start_block() {
+            return Ok(H256::zero());
+        }
+        let hash = self.reads.read(&self.db, |txn| {
+            match txn.open(&self.reads.blocks, c"blocks")? {
+                Some(table) => txn.get::<32>(table, &number.to_le_bytes()),
+                None => Ok(None),
+            }
+        })?;
+        match hash {
+            Some(hash) => Ok(H256(hash)),
+            None => Err(MoniqueError::Storage(
+                "storage get_block_hash: block not found".to_string(),
+            )),
+        }
+    }
+
+    /// Checks, in a single snapshot, that the stored counter matches the number of
+    /// entries of the `index` and `table` tables and the range of the last block, and
+    /// that the hash of the last block chains from its predecessor. Returns the
+    /// mismatches found.
+    pub fn check(&self) -> Result<Vec<String>> {
+        let tx = self.db.begin_ro_txn()?;
+        let Ok(stats) = tx.open_table(Some("stats")) else {
+            return Ok(vec![]);
+        };
+        let stat = |key: &[u8]| -> Result<u32> {
+            Ok(tx
+                .get::<[u8; 4]>(&stats, key)?
+                .map(u32::from_le_bytes)
+                .unwrap_or(0))
+        };
+        let (counter, last_block) = (stat(b"counter")?, stat(b"last_block")?);
+        let mut errors = vec![];
+        if let Some(accumulator) = tx.get::<[u8; ACCUMULATOR_SIZE]>(&stats, b"accumulator")? {
+            let count = Accumulator::from_bytes(accumulator).count();
+            if count != counter as u64 {
+                errors.push(format!(
+                    "the accumulator holds {} entries, but the counter is {}",
+                    count, counter
+                ));
+            }
+        }
+        for name in ["index", "table"] {
+            let entries = match tx.open_table(Some(name)) {
+                Ok(table) => tx.table_stat(&table)?.entries(),
+                Err(_) => 0,
+            };
+            if entries != counter as usize {
+                errors.push(format!(
+                    "{} entries in the {} table, but the counter is {}",
+                    entries, name, counter
+                ));
+            }
+        }
+        if last_block < self.start_block() {
+            return Ok(errors);
+        }
+        let block_hash = |number: u32| -> Result<Option<H256>> {
+            if number + 1 == self.start_block() {
+                return Ok(Some(H256::zero()));
+            }
+            Ok(match tx.open_table(Some("blocks")) {
+                Ok(table) => tx.get::<[u8; 32]>(&table, &number.to_le_bytes())?.map(H256),
+                Err(_) => None,
+            })
+        };
+        let range = match tx.open_table(Some("ranges")) {
+            Ok(table) => tx
+                .get::<[u8; 40]>(&table, &last_block.to_le_bytes())?
+                .map(BlockRange::from_bytes),
+            Err(_) => None,
+        };
+        match (block_hash(last_block)?, block_hash(last_block - 1)?, range) {
+            (None, _, _) => errors.push(format!("no hash stored for block {}", last_block)),
+            (_, None, _) => errors.push(format!("no hash stored for block {}", last_block - 1)),
+            (Some(hash), Some(previous), Some(range)) => {
+                if chain_hash(previous, range.root_hash) != hash {
+                    errors.push(format!(
+                        "the hash of block {} does not chain from block {}",
+                        last_block,
+                        last_block - 1
+                    ));
+                }
+                if range.start + range.count != counter {
+                    errors.push(format!(
+                        "block {} ends at entry {}, but the counter is {}",
+                        last_block,
+                        range.start + range.count,
+                        counter
+                    ));
+                }
+            }
+            // committed before ranges were recorded: its root is unknown
+            (Some(_), Some(_), None) => {}
+        }
+        Ok(errors)
+    }
+
+    /// Returns the entries added by a block. Blocks committed before ranges were
+    /// recorded return `None`.
+    pub fn get_range(&self, number: u32) -> Result<Option<BlockRange>> {
+        let range = self.reads.read(&self.db, |txn| {
+            match txn.open(&self.reads.ranges, c"ranges")? {
+                Some(table) => txn.get::<40>(table, &number.to_le_bytes()),
+                None => Ok(None),
+            }
+        })?;
+        Ok(range.map(BlockRange::from_bytes))
+    }
+
+    /// Ranges recorded for the blocks `from..=to`, in block order.
+    pub fn get_ranges(&self, from: u32, to: u32) -> Result<Vec<(u32, BlockRange)>> {
+        let mut ranges = vec![];
+        let tx = self.db.begin_ro_txn()?;
+        let Ok(table) = tx.open_table(Some("ranges")) else {
+            return Ok(ranges);
+        };
+        let mut cursor = tx.cursor(&table)?;
+        for value in cursor.iter_from::<[u8; 4], [u8; 40]>(&from.to_le_bytes()) {
+            let (number, range) = value?;
+            let number = u32::from_le_bytes(number);
+            if number > to {
+                break;
+            }
+            ranges.push((number, BlockRange::from_bytes(range)));
+        }
+        Ok(ranges)
+    }
+
+    /// Reads `count` consecutive entries starting at `start`.
+    pub fn get_items(&self, start: usize, count: usize) -> Result<Vec<T>> {
+        let mut items = Vec::with_capacity(count);
+        if count == 0 {
+            return Ok(items);
+        }
+        let tx = self.db.begin_ro_txn()?;
+        let table = tx.open_table(Some("index")).map_err(|_| {
+            MoniqueError::Storage("storage get_items: range out of bounds".to_string())
+        })?;
+        let mut cursor = tx.cursor(&table)?;
+        for value in cursor
+            .iter_from::<[u8; 4], [u8; N]>(&(start as u32).to_le_bytes())
+            .take(count)
+        {
+            items.push(T::from(value?.1));
+        }
+        if items.len() != count {
+            return Err(MoniqueError::Storage(
+                "storage get_items: range out of bounds".to_string(),
+            ));
+        }
+        Ok(items)
+    }
+
+    /// Entries of the indexes `start..start + count` (fewer past the last entry)
+    /// matching `filter`, read with a single cursor.
+    pub fn scan(
+        &self,
+        start: usize,
+        count: usize,
+        filter: impl Fn(&T) -> bool,
+    ) -> Result<Vec<(usize, T)>> {
+        let mut matches = vec![];
+        let tx = self.db.begin_ro_txn()?;
+        let Ok(table) = tx.open_table(Some("index")) else {
+            return Ok(matches);
+        };
+        let mut cursor = tx.cursor(&table)?;
+        for value in cursor
+            .iter_from::<[u8; 4], [u8; N]>(&(start as u32).to_le_bytes())
+            .take(count)
+        {
+            let (index, item) = value?;
+            let item = T::from(item);
+            if filter(&item) {
+                matches.push((u32::from_le_bytes(index) as usize, item));
+            }
+        }
+        Ok(matches)
+    }
+
+    pub fn get_stat(&self, key: &str) -> Result<Option<u64>> {
+        let tx = self.db.begin_ro_txn()?;
+        if let Ok(table) = tx.open_table(Some("stats")) {
+            return match tx.get::<[u8; 8]>(&table, key.as_bytes())? {
+                Some(v) => Ok(Some(u64::from_le_bytes(v))),
+                None => Ok(None),
+            };
+        }
+        Ok(None)
+    }
+
+    /// Reads a hash persisted in the stats table.
+    pub fn get_stat_hash(&self, key: &str) -> Result<Option<H256>> {
+        let tx = self.db.begin_ro_txn()?;
+        if let Ok(table) = tx.open_table(Some("stats")) {
+            return Ok(tx.get::<[u8; 32]>(&table, key.as_bytes())?.map(H256));
+        }
+        Ok(None)
+    }
+
+    pub fn put_stat_hash(&self, key: &str, value: H256) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        let table = tx.create_table(Some("stats"), TableFlags::CREATE)?;
+        tx.put(&table, key, value.as_bytes(), WriteFlags::UPSERT)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Reads a text persisted in the stats table.
+    pub fn get_stat_text(&self, key: &str) -> Result<Option<String>> {
+        let tx = self.db.begin_ro_txn()?;
+        if let Ok(table) = tx.open_table(Some("stats")) {
+            return match tx.get::<Vec<u8>>(&table, key.as_bytes())? {
+                Some(bytes) => Ok(Some(String::from_utf8(bytes).map_err(|_| {
+                    MoniqueError::Storage(format!("invalid text stat {}", key))
+                })?)),
+                None => Ok(None),
+            };
+        }
+        Ok(None)
+    }
+
+    pub fn put_stat_text(&self, key: &str, value: &str) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        let table = tx.create_table(Some("stats"), TableFlags::CREATE)?;
+        tx.put(&table, key, value, WriteFlags::UPSERT)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn put_stats(&self, values: Vec<(String, u64)>) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        let table = tx.create_table(Some("stats"), TableFlags::CREATE)?;
+        for (key, value) in values {
+            tx.put(&table, key, value.to_le_bytes(), WriteFlags::UPSERT)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn put_contract_flags(&self, flags: Vec<(usize, bool)>) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        let table = tx.create_table(
+            Some("contracts"),
+            TableFlags::CREATE | TableFlags::INTEGER_KEY,
+        )?;
+        for (index, is_contract) in flags {
+            tx.put(
+                &table,
+                (index as u32).to_le_bytes(),
+                [is_contract as u8],
+                WriteFlags::UPSERT,
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
 
-impl<T> Block<T> {
-    pub fn compute_hash(&self, previous_hash: H256) -> H256 {
-        let mut hash = [0u8; 32];
-        let mut keccak = Keccak::v256();
-        keccak.update(previous_hash.as_bytes());
-        keccak.update(self.root_hash.as_ref());
-        keccak.finalize(&mut hash);
-        let res = H256::from(hash);
-        trace!(
-            "computed hash for block {}: {} (previous: {}",
-            self.number,
-            res,
-            previous_hash
-        );
-        res
+    pub fn get_contract_flag(&self, index: usize) -> Result<Option<bool>> {
+        let tx = self.db.begin_ro_txn()?;
+        if let Ok(table) = tx.open_table(Some("contracts")) {
+            return match tx.get::<[u8; 1]>(&table, &(index as u32).to_le_bytes())? {
+                Some(v) => Ok(Some(v[0] == 1)),
+                None => Ok(None),
+            };
+        }
+        Ok(None)
     }
-}
 
-#[async_trait]
-pub trait Push<T> {
-    async fn push(&self, blocks: Vec<Block<T>>) -> Result<()>;
-}
+    pub fn put_appearances(&self, appearances: Vec<(usize, Appearance)>) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        let table = tx.create_table(
+            Some("appearances"),
+            TableFlags::CREATE | TableFlags::INTEGER_KEY,
+        )?;
+        for (index, appearance) in appearances {
+            tx.put(
+                &table,
+                (index as u32).to_le_bytes(),
+                appearance.to_bytes(),
+                WriteFlags::UPSERT,
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
 
-impl<const N: usize, T> Storage<N, T>
-where
-    T: Sized + AsRef<[u8]> + PartialEq + Hash + Eq + Copy + std::convert::From<[u8; N]>,
-{
-    pub fn new(path: PathBuf, cache_size: usize) -> Self {
-        // table format:
-        // stats: 'counter' -> u32, 'last_block' -> u32
-        // table: xxhash32(address) -> [index, ...]
-        // index: index -> address
-        // blocks: block_number -> start_index | count | checkpoint_hash
-        let db = Database::open_with_options(
-            &path,
-            DatabaseOptions {
-                max_tables: Some(4),
-                page_size: Some(PageSize::Set(16384)),
-                mode: Mode::ReadWrite(ReadWriteOptions {
-                    min_size: Some(17179869184),
-                    sync_mode: libmdbx::SyncMode::NoMetaSync,
-                    ..Default::default()
-                }),
-                ..Default::default()
-            },
+    pub fn get_appearance(&self, index: usize) -> Result<Option<Appearance>> {
+        let tx = self.db.begin_ro_txn()?;
+        if let Ok(table) = tx.open_table(Some("appearances")) {
+            return Ok(tx
+                .get::<[u8; 40]>(&table, &(index as u32).to_le_bytes())?
+                .map(Appearance::from_bytes));
+        }
+        Ok(None)
+    }
+
+    /// Sets the labels of items, removing them when `None`.
+    pub fn put_labels(&self, labels: Vec<(T, Option<Label>)>) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        let table = tx.create_table(Some("labels"), TableFlags::CREATE)?;
+        for (item, label) in labels {
+            match label {
+                Some(label) => {
+                    tx.put(&table, item.as_ref(), label.to_bytes(), WriteFlags::UPSERT)?
+                }
+                None => {
+                    tx.del(&table, item.as_ref(), None)?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get_label(&self, item: T) -> Result<Option<Label>> {
+        let tx = self.db.begin_ro_txn()?;
+        if let Ok(table) = tx.open_table(Some("labels")) {
+            return tx
+                .get::<Cow<[u8]>>(&table, item.as_ref())?
+                .map(|bytes| Label::from_bytes(&bytes))
+                .transpose();
+        }
+        Ok(None)
+    }
+
+    pub fn put_signatures(&self, signatures: Vec<(u32, [u8; 65])>) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        let table = tx.create_table(
+            Some("signatures"),
+            TableFlags::CREATE | TableFlags::INTEGER_KEY,
+        )?;
+        for (number, signature) in signatures {
+            tx.put(&table, number.to_le_bytes(), signature, WriteFlags::UPSERT)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn put_timestamps(&self, timestamps: Vec<(u32, u64)>) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        let table = tx.create_table(
+            Some("timestamps"),
+            TableFlags::CREATE | TableFlags::INTEGER_KEY,
+        )?;
+        for (number, timestamp) in timestamps {
+            tx.put(
+                &table,
+                number.to_le_bytes(),
+                timestamp.to_le_bytes(),
+                WriteFlags::UPSERT,
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Stores a sample of the progress, over the oldest one once the `history` table
+    /// holds `capacity` of them.
+    pub fn put_history_sample(&self, sample: HistorySample, capacity: u32) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        let stats = tx.create_table(Some("stats"), TableFlags::CREATE)?;
+        let table = tx.create_table(
+            Some("history"),
+            TableFlags::CREATE | TableFlags::INTEGER_KEY,
+        )?;
+        let next = tx
+            .get::<[u8; 8]>(&stats, b"history_next")?
+            .map_or(0, u64::from_le_bytes);
+        let slot = (next % capacity as u64) as u32;
+        tx.put(
+            &table,
+            slot.to_le_bytes(),
+            sample.to_bytes(),
+            WriteFlags::UPSERT,
+        )?;
+        tx.put(
+            &stats,
+            b"history_next",
+            (next + 1).to_le_bytes(),
+            WriteFlags::UPSERT,
+        )?;
+        // the slots of a larger capacity, set by an earlier process
+        let stale = tx
+            .cursor(&table)?
+            .iter_from::<[u8; 4], ()>(&capacity.to_le_bytes())
+            .filter(|entry| !matches!(entry, Ok((key, _)) if u32::from_le_bytes(*key) < capacity))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (key, _) in stale {
+            tx.del(&table, key, None)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Samples of the progress taken at or after `since`, oldest first.
+    pub fn get_history(&self, since: u64) -> Result<Vec<HistorySample>> {
+        let tx = self.db.begin_ro_txn()?;
+        let Ok(table) = tx.open_table(Some("history")) else {
+            return Ok(vec![]);
+        };
+        let mut samples = vec![];
+        for entry in tx.cursor(&table)?.iter_start::<[u8; 4], [u8; 32]>() {
+            let sample = HistorySample::from_bytes(entry?.1);
+            if sample.timestamp >= since {
+                samples.push(sample);
+            }
+        }
+        samples.sort_by_key(|sample| sample.timestamp);
+        Ok(samples)
+    }
+
+    /// Timestamp of a block, if it was recorded.
+    pub fn get_timestamp(&self, number: u32) -> Result<Option<u64>> {
+        let tx = self.db.begin_ro_txn()?;
+        if let Ok(table) = tx.open_table(Some("timestamps")) {
+            return Ok(tx
+                .get::<[u8; 8]>(&table, &number.to_le_bytes())?
+                .map(u64::from_le_bytes));
+        }
+        Ok(None)
+    }
+
+    /// Last block recorded with a timestamp not after `timestamp`, with its
+    /// timestamp. Block timestamps increase with their number, so this is a binary
+    /// search over the block numbers, tolerating blocks without a timestamp.
+    pub fn get_block_at(&self, timestamp: u64) -> Result<Option<(u32, u64)>> {
+        let tx = self.db.begin_ro_txn()?;
+        let Ok(table) = tx.open_table(Some("timestamps")) else {
+            return Ok(None);
+        };
+        let mut cursor = tx.cursor(&table)?;
+        let decode = |entry: Option<([u8; 4], [u8; 8])>| {
+            entry.map(|(number, time)| (u32::from_le_bytes(number), u64::from_le_bytes(time)))
+        };
+        let Some((first, first_time)) = decode(cursor.first()?) else {
+            return Ok(None);
+        };
+        if first_time > timestamp {
+            return Ok(None);
+        }
+        let (last, last_time) = decode(cursor.last()?).unwrap_or((first, first_time));
+        if last_time <= timestamp {
+            return Ok(Some((last, last_time)));
+        }
+        // the first recorded block from `low` is not after `timestamp`, the one from
+        // `high` is
+        let (mut low, mut high) = (first, last);
+        while high - low > 1 {
+            let middle = low + (high - low) / 2;
+            match decode(cursor.set_range(&middle.to_le_bytes())?) {
+                Some((_, time)) if time <= timestamp => low = middle,
+                _ => high = middle,
+            }
+        }
+        Ok(decode(cursor.set_range(&low.to_le_bytes())?))
+    }
+
+    /// Capacities of the item and index caches.
+    pub async fn cache_sizes(&self) -> (usize, usize) {
+        (
+            self.cache.read().await.cap().get(),
+            self.index_cache.read().await.cap().get(),
         )
-        .unwrap();
-        let (counter, last_block) = {
-            let tx = db.begin_ro_txn().unwrap();
-            if let Ok(table) = tx.open_table(Some("stats")) {
-                let counter = tx.get(&table, b"counter").unwrap();
-                let last_block = tx.get(&table, b"last_block").unwrap();
-                (
-                    u32::from_le_bytes(counter.unwrap()),
-                    u32::from_le_bytes(last_block.unwrap()),
-                )
-            } else {
-                (0, 0)
+    }
+
+    /// Resizes the lookup caches, evicting the least recently used entries if they shrink.
+    pub async fn resize_caches(&self, cache_size: usize, index_cache_size: usize) -> Result<()> {
+        let non_zero = |size| {
+            NonZeroUsize::new(size).ok_or(MoniqueError::Storage(
+                "storage resize: cache size must be positive".to_string(),
+            ))
+        };
+        let (cache_size, index_cache_size) = (non_zero(cache_size)?, non_zero(index_cache_size)?);
+        self.cache.write().await.resize(cache_size);
+        self.index_cache.write().await.resize(index_cache_size);
+        Ok(())
+    }
+
+    /// Page usage of the database file.
+    pub fn space(&self) -> Result<Space> {
+        // the geometry and free pages of the same snapshot, the geometry known to a
+        // reader process being only updated by its transactions
+        let tx = self.db.begin_ro_txn()?;
+        // SAFETY: the transaction lives until the end of the function, and is only
+        // used by this thread
+        let free_pages = unsafe { free_pages(tx.txn().0)? };
+        // SAFETY: the environment outlives `tx`, which is live, and `info` is a
+        // plain C struct of the size given, which mdbx fills
+        let info = unsafe {
+            let mut info: ffi::MDBX_envinfo = std::mem::zeroed();
+            mdbx_result(ffi::mdbx_env_info_ex(
+                self.db.ptr().0,
+                tx.txn().0,
+                &mut info,
+                std::mem::size_of::<ffi::MDBX_envinfo>(),
+            ))?;
+            info
+        };
+        Ok(Space {
+            page_size: self.db.stat()?.page_size() as u64,
+            file_size: info.mi_geo.current,
+            max_size: info.mi_geo.upper,
+            allocated_pages: info.mi_last_pgno + 1,
+            free_pages,
+        })
+    }
+
+    /// Scans the dedup `table`, reporting its bucket sizes and its `hot_spots`
+    /// largest buckets.
+    pub fn analyze_buckets(&self, hot_spots: usize) -> Result<BucketReport> {
+        let tx = self.db.begin_ro_txn()?;
+        let mut report = BucketReport::default();
+        let Ok(table) = tx.open_table(Some("table")) else {
+            return Ok(report);
+        };
+        // smallest of the largest buckets on top
+        let mut largest = BinaryHeap::new();
+        let mut bucket = |report: &mut BucketReport, key: u32, size: u64| {
+            report.buckets += 1;
+            *report.sizes.entry(size).or_default() += 1;
+            largest.push(cmp::Reverse((size, key)));
+            if largest.len() > hot_spots {
+                largest.pop();
             }
         };
+        let mut current: Option<(u32, u64)> = None;
+        for entry in tx.cursor(&table)?.iter_start::<[u8; 4], [u8; 4]>() {
+            let key = u32::from_le_bytes(entry?.0);
+            report.entries += 1;
+            current = match current {
+                Some((k, size)) if k == key => Some((k, size + 1)),
+                Some((k, size)) => {
+                    bucket(&mut report, k, size);
+                    Some((key, 1))
+                }
+                None => Some((key, 1)),
+            };
+        }
+        if let Some((key, size)) = current {
+            bucket(&mut report, key, size);
+        }
+        let mut largest: Vec<_> = largest.into_iter().map(|cmp::Reverse(b)| b).collect();
+        largest.sort_by(|a, b| b.cmp(a));
+        report.hot_spots = largest.into_iter().map(|(size, key)| (key, size)).collect();
+        Ok(report)
+    }
 
-        info!("counter: {}", counter);
-        info!("last_block: {}", last_block);
+    /// Size of the pages of each existing table.
+    pub fn table_sizes(&self) -> Result<Vec<(&'static str, u64)>> {
+        let tx = self.db.begin_ro_txn()?;
+        let mut sizes = vec![];
+        for name in TABLES {
+            if let Ok(table) = tx.open_table(Some(name)) {
+                sizes.push((name, tx.table_stat(&table)?.total_size()));
+            }
+        }
+        Ok(sizes)
+    }
 
-        let cache = RwLock::new(LruCache::new(NonZeroUsize::new(cache_size).unwrap()));
-        let index_cache = RwLock::new(LruCache::new(NonZeroUsize::new(cache_size).unwrap()));
+    /// Writes a compacted copy of the database to the `dest` file, which must not
+    /// exist: the free pages are left out and the tables written back to back.
+    pub fn copy_compact(&self, dest: &Path) -> Result<()> {
+        let dest = CString::new(dest.as_os_str().as_encoded_bytes())
+            .map_err(|e| MoniqueError::Storage(format!("storage copy: {}", e)))?;
+        // SAFETY: the environment is open for the lifetime of `self`, and `dest` is a
+        // NUL-terminated path that outlives the call
+        mdbx_result(unsafe {
+            ffi::mdbx_env_copy(self.db.ptr().0, dest.as_ptr(), ffi::MDBX_CP_COMPACT)
+        })?;
+        Ok(())
+    }
 
-        Self {
-            _data: std::marker::PhantomData,
-            db,
-            counters: RwLock::new(Counters {
-                counter,
-                last_block,
-            }),
-            cache,
-            index_cache,
+    pub fn persists_tries(&self) -> bool {
+        self.persist_tries
+    }
+
+    /// Stores pending blocks moved out of memory, with the block of each of their
+    /// items.
+    pub fn put_spilled(&self, blocks: &[(u64, Vec<T>)]) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        let table = tx.create_table(Some("spill"), TableFlags::CREATE | TableFlags::INTEGER_KEY)?;
+        let items_table = tx.create_table(Some("spill_items"), TableFlags::CREATE)?;
+        for (number, items) in blocks {
+            let number = (*number as u32).to_le_bytes();
+            let bytes: Vec<u8> = items
+                .iter()
+                .flat_map(|item| item.as_ref().to_vec())
+                .collect();
+            tx.put(&table, number, bytes, WriteFlags::UPSERT)?;
+            for item in items {
+                tx.put(&items_table, item.as_ref(), number, WriteFlags::UPSERT)?;
+            }
         }
+        tx.commit()?;
+        Ok(())
     }
 
-    pub async fn get_counters(&self) -> RwLockReadGuard<Counters> {
-        self.counters.read().await
+    /// Items of a spilled block, in order.
+    pub fn get_spilled(&self, number: u64) -> Result<Option<Vec<T>>> {
+        let tx = self.db.begin_ro_txn()?;
+        let Ok(table) = tx.open_table(Some("spill")) else {
+            return Ok(None);
+        };
+        Ok(tx
+            .get::<Cow<[u8]>>(&table, &(number as u32).to_le_bytes())?
+            .map(|bytes| {
+                bytes
+                    .chunks_exact(N)
+                    .map(|item| T::from(item.try_into().unwrap()))
+                    .collect()
+            }))
     }
 
-    fn get_block_hash(&self, number: u32) -> Result<H256> {
-        if number == 0 {
-            return Ok(H256::zero());
+    /// Spilled block of each item, if any.
+    pub fn get_spilled_blocks(&self, items: &[T]) -> Result<Vec<Option<u64>>> {
+        let tx = self.db.begin_ro_txn()?;
+        let Ok(table) = tx.open_table(Some("spill_items")) else {
+            return Ok(vec![None; items.len()]);
+        };
+        items
+            .iter()
+            .map(|item| {
+                Ok(tx
+                    .get::<[u8; 4]>(&table, item.as_ref())?
+                    .map(|number| u32::from_le_bytes(number) as u64))
+            })
+            .collect()
+    }
+
+    /// Removes spilled blocks, with their items.
+    pub fn remove_spilled(&self, numbers: &[u64]) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        let (Ok(table), Ok(items_table)) = (
+            tx.open_table(Some("spill")),
+            tx.open_table(Some("spill_items")),
+        ) else {
+            return Ok(());
+        };
+        for number in numbers {
+            let key = (*number as u32).to_le_bytes();
+            let Some(bytes) = tx.get::<Vec<u8>>(&table, &key)? else {
+                continue;
+            };
+            for item in bytes.chunks_exact(N) {
+                tx.del(&items_table, item, None)?;
+            }
+            tx.del(&table, key, None)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Empties the spill tables, whose blocks are only valid while the pending queue
+    /// that spilled them lives.
+    pub fn clear_spilled(&self) -> Result<()> {
+        let tx = self.db.begin_rw_txn()?;
+        for name in ["spill", "spill_items"] {
+            if let Ok(table) = tx.open_table(Some(name)) {
+                tx.clear_table(&table)?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Stores the tombstones of dropped blocks, removing those older than the
+    /// retention period. Nothing is stored without retention.
+    pub fn put_tombstones(&self, tombstones: Vec<Tombstone<T>>) -> Result<()> {
+        let Some(last) = tombstones.last().map(|tombstone| tombstone.timestamp) else {
+            return Ok(());
+        };
+        if self.tombstone_retention == 0 {
+            return Ok(());
+        }
+        let tx = self.db.begin_rw_txn()?;
+        let stats = tx.create_table(Some("stats"), TableFlags::CREATE)?;
+        let table = tx.create_table(
+            Some("tombstones"),
+            TableFlags::CREATE | TableFlags::INTEGER_KEY,
+        )?;
+        let mut next = tx
+            .get::<[u8; 8]>(&stats, b"tombstones_next")?
+            .map_or(0, u64::from_le_bytes);
+        for tombstone in tombstones {
+            // timestamp | block | reason | [item, ...], little endian
+            let mut bytes = Vec::with_capacity(17 + tombstone.items.len() * N);
+            bytes.extend_from_slice(&tombstone.timestamp.to_le_bytes());
+            bytes.extend_from_slice(&tombstone.block.to_le_bytes());
+            bytes.push(tombstone.reason as u8);
+            for item in &tombstone.items {
+                bytes.extend_from_slice(item.as_ref());
+            }
+            tx.put(&table, next.to_le_bytes(), bytes, WriteFlags::UPSERT)?;
+            next += 1;
+        }
+        tx.put(
+            &stats,
+            b"tombstones_next",
+            next.to_le_bytes(),
+            WriteFlags::UPSERT,
+        )?;
+        // all the tombstones are scanned, the wall clock possibly going backwards
+        let expiry = last.saturating_sub(self.tombstone_retention);
+        let expired = tx
+            .cursor(&table)?
+            .iter_start::<[u8; 8], Cow<[u8]>>()
+            .filter(|entry| !matches!(entry, Ok((_, bytes)) if timestamp_of(bytes) >= expiry))
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for key in expired {
+            tx.del(&table, key, None)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Tombstones of the blocks dropped at or after `since` (unix time), in the
+    /// order they were stored.
+    pub fn get_tombstones(&self, since: u64) -> Result<Vec<Tombstone<T>>> {
+        let tx = self.db.begin_ro_txn()?;
+        let Ok(table) = tx.open_table(Some("tombstones")) else {
+            return Ok(vec![]);
+        };
+        let invalid = || MoniqueError::Storage("invalid tombstone".to_string());
+        let mut tombstones = vec![];
+        for entry in tx.cursor(&table)?.iter_start::<[u8; 8], Cow<[u8]>>() {
+            let (_, bytes) = entry?;
+            if bytes.len() < 17 || (bytes.len() - 17) % N != 0 {
+                Err(invalid())?
+            }
+            if timestamp_of(&bytes) < since {
+                continue;
+            }
+            let reason = match bytes[16] {
+                0 => TombstoneReason::Reorg,
+                1 => TombstoneReason::Rollback,
+                _ => Err(invalid())?,
+            };
+            tombstones.push(Tombstone {
+                timestamp: timestamp_of(&bytes),
+                block: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+                reason,
+                items: bytes[17..]
+                    .chunks_exact(N)
+                    .map(|item| T::from(item.try_into().unwrap()))
+                    .collect(),
+            });
+        }
+        Ok(tombstones)
+    }
+
+    pub fn get_trie_node(&self, hash: &[u8]) -> Result<Option<Vec<u8>>> {
+        let tx = self.db.begin_ro_txn()?;
+        if let Ok(table) = tx.open_table(Some("tries")) {
+            return Ok(tx.get::<Vec<u8>>(&table, hash)?);
+        }
+        Ok(None)
+    }
+
+    /// Root of the accumulator over all the entries up to a block, recorded since the
+    /// accumulator was introduced.
+    pub fn get_index_root(&self, number: u32) -> Result<Option<H256>> {
+        let tx = self.db.begin_ro_txn()?;
+        if let Ok(table) = tx.open_table(Some("roots")) {
+            return Ok(tx
+                .get::<[u8; 32]>(&table, &number.to_le_bytes())?
+                .map(H256::from));
         }
+        Ok(None)
+    }
+
+    pub fn get_signature(&self, number: u32) -> Result<Option<[u8; 65]>> {
         let tx = self.db.begin_ro_txn()?;
-        let blocks_table = tx.open_table(Some("blocks"))?;
-        let key = number.to_le_bytes();
-        match tx.get::<Vec<u8>>(&blocks_table, &key)? {
-            Some(v) => Ok(H256::from_slice(&v)),
-            None => Err("storage get_block_hash: block not found".into()),
+        if let Ok(table) = tx.open_table(Some("signatures")) {
+            return Ok(tx.get::<[u8; 65]>(&table, &number.to_le_bytes())?);
         }
+        Ok(None)
     }
 }
 
@@ -147,11 +2013,14 @@ where
         + Sync,
     [u8; N]: From<T>,
 {
+    #[instrument(skip_all, fields(blocks = blocks.len()))]
     async fn push(&self, blocks: Vec<Block<T>>) -> Result<()> {
         let mut previous_block_hash = match blocks.first() {
             Some(block) => {
                 if block.number == 0 {
-                    return Err("storage push: unexpected block number 0".into());
+                    return Err(MoniqueError::Storage(
+                        "storage push: unexpected block number 0".to_string(),
+                    ));
                 } else {
                     self.get_block_hash(block.number as u32 - 1)?
                 }
@@ -166,6 +2035,13 @@ where
         let blocks_table = tx.create_table(Some("blocks"), flags)?;
         let index_table = tx.create_table(Some("index"), flags)?;
         let stats_table = tx.create_table(Some("stats"), TableFlags::CREATE)?;
+        let ranges_table = tx.create_table(Some("ranges"), flags)?;
+        let tries_table = tx.create_table(Some("tries"), TableFlags::CREATE)?;
+        let roots_table = tx.create_table(Some("roots"), flags)?;
+        let timestamps_table = tx.create_table(Some("timestamps"), flags)?;
+        let appearances_table = tx.create_table(Some("appearances"), flags)?;
+        let counts_table = tx.create_table(Some("counts"), flags)?;
+        let mut accumulator = self.accumulator.read().await.clone();
         let table = tx.create_table(
             Some("table"),
             flags | TableFlags::DUP_SORT | TableFlags::DUP_FIXED | TableFlags::INTEGER_DUP,
@@ -176,7 +2052,9 @@ where
         let mut index = counters.counter;
         for block in blocks.iter() {
             if block.number != last_block as u64 + 1 {
-                return Err("storage push: unexpected block number".into());
+                return Err(MoniqueError::Storage(
+                    "storage push: unexpected block number".to_string(),
+                ));
             }
             last_block = block.number as u32;
             let block_hash = block.compute_hash(previous_block_hash);
@@ -187,11 +2065,56 @@ where
             previous_block_hash = block_hash;
             block_cursor.put(
                 &key,
-                &block_hash.as_bytes(),
+                block_hash.as_bytes(),
                 WriteFlags::APPEND | WriteFlags::NO_OVERWRITE,
             )?;
+            let range = BlockRange {
+                start: index,
+                count: block.items.len() as u32,
+                root_hash: block.root_hash,
+            };
+            tx.put(&ranges_table, key, range.to_bytes(), WriteFlags::UPSERT)?;
+            if let Some(timestamp) = block.meta.timestamp {
+                tx.put(
+                    &timestamps_table,
+                    key,
+                    timestamp.to_le_bytes(),
+                    WriteFlags::UPSERT,
+                )?;
+            }
+            for (offset, appearance) in block.meta.appearances.iter() {
+                tx.put(
+                    &appearances_table,
+                    (index + offset).to_le_bytes(),
+                    appearance.to_bytes(),
+                    WriteFlags::UPSERT,
+                )?;
+            }
+            if !block.meta.counts.is_empty() {
+                tx.put(
+                    &counts_table,
+                    key,
+                    block.meta.counts_to_bytes(),
+                    WriteFlags::UPSERT,
+                )?;
+                for (name, count) in &block.meta.counts {
+                    let total = tx
+                        .get::<[u8; 8]>(&stats_table, name.as_bytes())?
+                        .map(u64::from_le_bytes)
+                        .unwrap_or(0);
+                    tx.put(
+                        &stats_table,
+                        name,
+                        (total + count).to_le_bytes(),
+                        WriteFlags::UPSERT,
+                    )?;
+                }
+            }
+            for (hash, node) in block.nodes.iter() {
+                tx.put(&tries_table, hash.as_bytes(), node, WriteFlags::UPSERT)?;
+            }
             for i in block.items.iter() {
-                let item = <T as Into<[u8; N]>>::into(i.clone());
+                let item = <T as Into<[u8; N]>>::into(*i);
                 let key = index.to_le_bytes();
                 index_cursor.put(&key, &item[..], WriteFlags::APPEND)?;
 
@@ -199,17 +2122,22 @@ where
                 let value = index.to_le_bytes();
                 table_cursor.put(&hash, &value, WriteFlags::APPEND_DUP)?;
 
-                self.cache.write().await.put(*i, index as usize);
-                self.index_cache.write().await.put(index as usize, *i);
+                accumulator.push(&item[..]);
 
                 index += 1;
             }
+            tx.put(
+                &roots_table,
+                key,
+                accumulator.root().as_bytes(),
+                WriteFlags::UPSERT,
+            )?;
         }
 
         tx.put(
             &stats_table,
             b"counter",
-            &index.to_le_bytes(),
+            index.to_le_bytes(),
             WriteFlags::UPSERT,
         )?;
         tx.put(
@@ -219,8 +2147,31 @@ where
             WriteFlags::UPSERT,
         )?;
 
+        tx.put(
+            &stats_table,
+            b"accumulator",
+            accumulator.to_bytes(),
+            WriteFlags::UPSERT,
+        )?;
+
         tx.commit()?;
 
+        // cached once stored, so that a failed push leaves nothing behind
+        let stored =
+            || (counters.counter as usize..).zip(blocks.iter().flat_map(|block| &block.items));
+        {
+            let mut cache = self.cache.write().await;
+            for (index, item) in stored() {
+                cache.put(*item, index);
+            }
+        }
+        let mut index_cache = self.index_cache.write().await;
+        for (index, item) in stored() {
+            index_cache.put(index, *item);
+        }
+        drop(index_cache);
+
+        *self.accumulator.write().await = accumulator;
         let mut counters = self.counters.write().await;
         counters.counter = index;
         counters.last_block = last_block;
@@ -239,56 +2190,45 @@ where
         self.get_counters().await.counter as usize
     }
 
+    #[instrument(level = "trace", skip(self))]
     async fn get(&self, index: usize) -> Result<Option<T>> {
         if let Some(item) = self.index_cache.write().await.get(&index) {
             return Ok(Some(*item));
         }
-        let tx = self.db.begin_ro_txn()?;
-        if let Ok(index_table) = tx.open_table(Some("index")) {
-            return match tx.get(&index_table, &(index as u32).to_le_bytes())? {
-                Some(data) => {
-                    let item = T::from(data);
-                    self.index_cache.write().await.put(index, item);
-                    Ok(Some(item))
-                }
+        let data = self.reads.read(&self.db, |txn| {
+            match txn.open(&self.reads.index, c"index")? {
+                Some(index_table) => txn.get::<N>(index_table, &(index as u32).to_le_bytes()),
                 None => Ok(None),
-            };
-        }
-        Ok(None)
+            }
+        })?;
+        let Some(data) = data else {
+            return Ok(None);
+        };
+        let item = T::from(data);
+        self.index_cache.write().await.put(index, item);
+        Ok(Some(item))
     }
 
+    #[instrument(level = "trace", skip_all)]
     async fn index(&self, item: T) -> Result<Option<usize>> {
         trace!("index: {:?}", item.as_ref());
-        if let Some(index) = self.cache.write().await.get(&item.into()) {
-            trace!("cache hit");
+        if let Some(index) = self.cache.write().await.get(&item) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
             return Ok(Some(*index));
         }
-        let tx = self.db.begin_ro_txn()?;
-        if let Ok(table) = tx.open_table(Some("table")) {
-            let mut cursor = tx.cursor(&table)?;
-            let hash = (xxh3_64(item.as_ref()) as u32).to_le_bytes();
-            for value in cursor.iter_from::<[u8; 4], [u8; 4]>(&hash) {
-                match value {
-                    Ok((k, v)) => {
-                        if k != hash {
-                            break;
-                        }
-                        let key = u32::from_le_bytes(v) as usize;
-                        let item_test = self.get(key).await?;
-                        if item_test == Some(item) {
-                            self.cache.write().await.put(item, key);
-                            return Ok(Some(key));
-                        }
-                    }
-                    Err(e) => {
-                        warn!("error: {:?}", e);
-                        break;
-                    }
-                }
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        let found = self.reads.read(&self.db, |txn| {
+            match (
+                txn.open(&self.reads.table, c"table")?,
+                txn.open(&self.reads.index, c"index")?,
+            ) {
+                (Some(table), Some(index_table)) => find(txn, table, index_table, &item),
+                _ => Ok(None),
             }
-            Ok(None)
-        } else {
-            Ok(None)
+        })?;
+        if let Some(index) = found {
+            self.cache.write().await.put(item, index);
         }
+        Ok(found)
     }
 }
@@ -1,69 +1,1895 @@
-#[cfg(test)]
-mod tests {
-    use ethers::core::rand;
-    use ethers::core::rand::Rng;
-    use tempfile::tempdir;
+use ethers_core::rand::Rng;
+use ethers_core::types::H256;
+use std::path::PathBuf;
+use std::time::Duration;
+use tempfile::tempdir;
 
-    use crate::index::{
-        storage::{Block, Push},
-        Indexed, Storage,
+use crate::MoniqueError;
+
+use crate::index::{
+    accumulator::Accumulator,
+    history_capacity, manifest_wordlist, read_manifest,
+    storage::{Block, BlockMeta, Push, StorageOptions, TABLES},
+    verify_segments, Appearance, Backend, BenchOptions, BlockData, BucketReport, HistorySample,
+    IndexTable, Indexed, Label, Plain, ScanBudget, Space, Storage, Tombstone, TombstoneReason,
+    COMMIT_BATCH_SIZE, REORDER_WINDOW,
+};
+
+#[tokio::test]
+async fn bench() {
+    let temp_dir = tempdir().unwrap();
+    let options = BenchOptions {
+        entries: 1_050,
+        block_size: 10,
+        commit_blocks: 20,
+        lookups: 100,
+        cache_size: 16,
+        seed: 1,
+    };
+    let scenarios = super::bench(&temp_dir.path().join("bench.db"), &options)
+        .await
+        .unwrap();
+    let operations: Vec<_> = scenarios
+        .iter()
+        .map(|scenario| (scenario.name, scenario.operations))
+        .collect();
+    assert_eq!(
+        operations,
+        [("push", 1_050), ("commit", 6), ("get", 100), ("index", 100)]
+    );
+    let get = &scenarios[2];
+    assert_eq!(get.samples.len(), 100);
+    assert!(get.quantile(0.5) <= get.quantile(0.99));
+    assert!(get.per_second() > 0.0);
+}
+
+/// Index of 20-byte items with a small lookup cache, the fixture of most tests.
+async fn open_index(path: impl Into<PathBuf>) -> IndexTable<20, [u8; 20]> {
+    IndexTable::builder(path)
+        .cache_size(16)
+        .build()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn contract_flags() {
+    let temp_dir = tempdir().unwrap();
+    let index = Storage::<20, [u8; 20]>::new(temp_dir.path().join("flags.db"), 16);
+    assert_eq!(index.get_contract_flag(0).unwrap(), None);
+    index
+        .put_contract_flags(vec![(0, true), (1, false)])
+        .unwrap();
+    assert_eq!(index.get_contract_flag(0).unwrap(), Some(true));
+    assert_eq!(index.get_contract_flag(1).unwrap(), Some(false));
+    assert_eq!(index.get_contract_flag(2).unwrap(), None);
+}
+
+#[tokio::test]
+async fn appearances() {
+    let temp_dir = tempdir().unwrap();
+    let index = Storage::<20, [u8; 20]>::new(temp_dir.path().join("appearances.db"), 16);
+    assert_eq!(index.get_appearance(0).unwrap(), None);
+    let sent = Appearance {
+        transaction: H256::repeat_byte(1),
+        log_index: None,
+    };
+    let logged = Appearance {
+        transaction: H256::repeat_byte(2),
+        log_index: Some(3),
+    };
+    index.put_appearances(vec![(0, sent), (1, logged)]).unwrap();
+    assert_eq!(index.get_appearance(0).unwrap(), Some(sent));
+    assert_eq!(index.get_appearance(1).unwrap(), Some(logged));
+    assert_eq!(index.get_appearance(2).unwrap(), None);
+}
+
+#[tokio::test]
+async fn labels() {
+    let temp_dir = tempdir().unwrap();
+    let index = Storage::<20, [u8; 20]>::new(temp_dir.path().join("labels.db"), 16);
+    assert_eq!(index.get_label([1; 20]).unwrap(), None);
+    let label = Label {
+        label: "Binance 8".to_string(),
+        source: "csv".to_string(),
+    };
+    index
+        .put_labels(vec![
+            ([1; 20], Some(label.clone())),
+            ([2; 20], Some(label.clone())),
+        ])
+        .unwrap();
+    assert_eq!(index.get_label([1; 20]).unwrap(), Some(label));
+    index.put_labels(vec![([2; 20], None)]).unwrap();
+    assert_eq!(index.get_label([2; 20]).unwrap(), None);
+}
+
+#[tokio::test]
+async fn history() {
+    let temp_dir = tempdir().unwrap();
+    let index = Storage::<20, [u8; 20]>::new(temp_dir.path().join("history.db"), 16);
+    assert_eq!(index.get_history(0).unwrap(), vec![]);
+    let sample = |timestamp: u64| HistorySample {
+        timestamp,
+        block: timestamp * 2,
+        addresses: timestamp * 3,
+        blocks_per_second: timestamp as f64 / 4.0,
+    };
+    for timestamp in 1..=5 {
+        index.put_history_sample(sample(timestamp), 3).unwrap();
+    }
+    // the oldest samples were overwritten
+    let history = index.get_history(0).unwrap();
+    assert_eq!(history, vec![sample(3), sample(4), sample(5)]);
+    assert_eq!(index.get_history(4).unwrap(), vec![sample(4), sample(5)]);
+    assert_eq!(index.get_history(6).unwrap(), vec![]);
+
+    // a smaller capacity drops the slots beyond it
+    index.put_history_sample(sample(6), 2).unwrap();
+    assert_eq!(index.get_history(0).unwrap().len(), 2);
+
+    // one sample a minute for a day, and at least one
+    assert_eq!(history_capacity(60, 24 * 3600), 1440);
+    assert_eq!(history_capacity(7, 60), 9);
+    assert_eq!(history_capacity(60, 0), 1);
+    assert_eq!(history_capacity(1, u64::MAX), u32::MAX);
+}
+
+#[tokio::test]
+async fn tombstone_retention() {
+    let temp_dir = tempdir().unwrap();
+    let options = StorageOptions {
+        tombstone_retention: 10,
+        ..Default::default()
+    };
+    let index =
+        Storage::<20, [u8; 20]>::open(temp_dir.path().join("tombstones.db"), &options).unwrap();
+    let tombstone = |timestamp: u64| Tombstone {
+        timestamp,
+        block: timestamp * 2,
+        reason: TombstoneReason::Reorg,
+        items: vec![[timestamp as u8; 20], [0; 20]],
+    };
+    index
+        .put_tombstones(vec![tombstone(1), tombstone(5)])
+        .unwrap();
+    assert_eq!(
+        index.get_tombstones(0).unwrap(),
+        vec![tombstone(1), tombstone(5)]
+    );
+    // the tombstones older than 10 seconds were removed
+    index.put_tombstones(vec![tombstone(12)]).unwrap();
+    assert_eq!(
+        index.get_tombstones(0).unwrap(),
+        vec![tombstone(5), tombstone(12)]
+    );
+    assert_eq!(index.get_tombstones(6).unwrap(), vec![tombstone(12)]);
+
+    let options = StorageOptions {
+        tombstone_retention: 0,
+        ..Default::default()
+    };
+    let index = Storage::<20, [u8; 20]>::open(temp_dir.path().join("none.db"), &options).unwrap();
+    index.put_tombstones(vec![tombstone(1)]).unwrap();
+    assert_eq!(index.get_tombstones(0).unwrap(), vec![]);
+}
+
+#[tokio::test]
+async fn reads_see_writes() {
+    let temp_dir = tempdir().unwrap();
+    let index = Storage::<20, [u8; 20]>::new(temp_dir.path().join("reads.db"), 16);
+    // a read before the write
+    assert!(index.get_range(1).unwrap().is_none());
+    let blocks = vec![Block {
+        number: 1,
+        items: vec![[1; 20], [2; 20]],
+        root_hash: [0; 32].into(),
+        nodes: vec![],
+        meta: BlockMeta::default(),
+    }];
+    index.push(blocks).await.unwrap();
+    assert_eq!(index.get_range(1).unwrap().unwrap().count, 2);
+    assert_eq!(index.get_items(0, 2).unwrap(), vec![[1; 20], [2; 20]]);
+
+    // the pooled read transactions move between threads, and see the next writes
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..100 {
+                    assert_eq!(index.get_range(1).unwrap().unwrap().count, 2);
+                    assert!(index.get_range(2).unwrap().is_none());
+                }
+            });
+        }
+    });
+    let blocks = vec![Block {
+        number: 2,
+        items: vec![[3; 20]],
+        root_hash: [0; 32].into(),
+        nodes: vec![],
+        meta: BlockMeta::default(),
+    }];
+    index.push(blocks).await.unwrap();
+    assert_eq!(index.get_range(2).unwrap().unwrap().count, 1);
+    assert_eq!(index.index([3; 20]).await.unwrap(), Some(2));
+}
+
+#[tokio::test]
+async fn atomic_push() {
+    let temp_dir = tempdir().unwrap();
+    let storage = Storage::<20, [u8; 20]>::new(temp_dir.path().join("atomic.db"), 16);
+    let block = |number: u64, items: Vec<[u8; 20]>| Block {
+        number,
+        items,
+        root_hash: [number as u8; 32].into(),
+        nodes: vec![],
+        meta: BlockMeta::default(),
+    };
+    storage.push(vec![block(1, vec![[1; 20]])]).await.unwrap();
+    // block 4 does not follow block 2: nothing of the push is kept, nor cached
+    let gap = vec![block(2, vec![[2; 20], [3; 20]]), block(4, vec![[4; 20]])];
+    assert!(storage.push(gap).await.is_err());
+    assert_eq!(storage.len().await, 1);
+    assert_eq!(storage.get_counters().await.last_block, 1);
+    assert_eq!(storage.index([2; 20]).await.unwrap(), None);
+    assert_eq!(storage.get(1).await.unwrap(), None);
+    storage.push(vec![block(2, vec![[3; 20]])]).await.unwrap();
+    assert_eq!(storage.index([3; 20]).await.unwrap(), Some(1));
+}
+
+#[tokio::test]
+async fn stats() {
+    let temp_dir = tempdir().unwrap();
+    let index = Storage::<20, [u8; 20]>::new(temp_dir.path().join("stats.db"), 16);
+    assert_eq!(index.get_stat("source:miner").unwrap(), None);
+    index
+        .put_stats(vec![("source:miner".to_string(), 42)])
+        .unwrap();
+    assert_eq!(index.get_stat("source:miner").unwrap(), Some(42));
+}
+
+#[tokio::test]
+async fn chain_id() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("chain.db");
+    {
+        let index = IndexTable::<20, [u8; 20]>::builder(path.clone())
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(index.chain_id().unwrap(), None);
+        index.verify_chain_id(1).unwrap();
+        assert_eq!(index.chain_id().unwrap(), None);
+        index.check_chain_id(11155111).unwrap();
+        index.check_chain_id(11155111).unwrap();
+        assert!(index.verify_chain_id(1).is_err());
+    }
+    let index = IndexTable::<20, [u8; 20]>::builder(path)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(index.chain_id().unwrap(), Some(11155111));
+    let error = index.check_chain_id(1).unwrap_err();
+    assert!(matches!(
+        error,
+        MoniqueError::ChainMismatch {
+            datadir: 11155111,
+            provider: 1
+        }
+    ));
+}
+
+#[tokio::test]
+async fn ruleset() {
+    let temp_dir = tempdir().unwrap();
+    let index = IndexTable::<20, [u8; 20]>::builder(temp_dir.path().join("rules.db"))
+        .build()
+        .await
+        .unwrap();
+    let (first, second) = (H256::repeat_byte(1), H256::repeat_byte(2));
+    assert_eq!(index.ruleset().unwrap(), None);
+    index.check_ruleset(first, false).unwrap();
+    index.check_ruleset(first, false).unwrap();
+    let error = index.check_ruleset(second, false).unwrap_err();
+    assert!(matches!(error, MoniqueError::RulesetMismatch { .. }));
+    assert_eq!(index.ruleset().unwrap(), Some(first));
+    index.check_ruleset(second, true).unwrap();
+    assert_eq!(index.ruleset().unwrap(), Some(second));
+    drop(index);
+
+    let index = IndexTable::<20, [u8; 20]>::builder(temp_dir.path().join("rules.db"))
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(index.ruleset().unwrap(), Some(second));
+}
+
+#[tokio::test]
+async fn read_only() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("ro.db");
+    {
+        let index = Storage::<20, [u8; 20]>::new(path.clone(), 16);
+        let blocks = vec![Block {
+            number: 1,
+            items: vec![[1; 20], [2; 20]],
+            root_hash: [0; 32].into(),
+            nodes: vec![],
+            meta: BlockMeta::default(),
+        }];
+        index.push(blocks).await.unwrap();
+    }
+    let options = StorageOptions {
+        cache_size: 16,
+        index_cache_size: 16,
+        read_only: true,
+        ..Default::default()
     };
+    let index = Storage::<20, [u8; 20]>::open(path, &options).unwrap();
+    assert_eq!(index.len().await, 2);
+    assert_eq!(index.get(1).await.unwrap(), Some([2; 20]));
+    assert_eq!(index.index([1; 20]).await.unwrap(), Some(0));
+    assert!(index.put_stats(vec![("key".to_string(), 1)]).is_err());
+}
+
+#[tokio::test]
+async fn backend() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("nosync.db");
+    for backend in [Backend::MdbxNoSync, Backend::Mdbx] {
+        let index = IndexTable::<20, [u8; 20]>::builder(&path)
+            .cache_size(16)
+            .backend(backend)
+            .build()
+            .await
+            .unwrap();
+        let block = index.get_counters().await.last_committed_block + 1;
+        index.queue(block, vec![[block as u8; 20]]).await.unwrap();
+        index.commit(block).await.unwrap();
+    }
+    // committed with either sync mode
+    let index = open_index(&path).await;
+    assert_eq!(index.len().await, 2);
+    assert_eq!(index.index([2; 20]).await.unwrap(), Some(1));
+}
+
+#[tokio::test]
+async fn export_import() {
+    let temp_dir = tempdir().unwrap();
+    let source = open_index(temp_dir.path().join("source.db")).await;
+    let timestamp = |timestamp| BlockData {
+        timestamp: Some(timestamp),
+        ..Default::default()
+    };
+    source
+        .queue_with(1, vec![[1; 20], [2; 20]], timestamp(10))
+        .await
+        .unwrap();
+    source.queue(2, vec![]).await.unwrap();
+    source
+        .queue_with(3, vec![[2; 20], [3; 20]], timestamp(30))
+        .await
+        .unwrap();
+    source.commit(3).await.unwrap();
+
+    let mut dump = vec![];
+    assert_eq!(source.export(1, 3, &mut dump).await.unwrap(), 3);
+    // past its deadline, an export stops after its first block
+    let mut first = vec![];
+    let deadline = Some(std::time::Instant::now());
+    assert_eq!(
+        source
+            .export_until(1, 3, &mut first, deadline, true)
+            .await
+            .unwrap(),
+        1
+    );
+    assert!(dump.starts_with(&first) && first.len() < dump.len());
+    assert!(dump.starts_with(b"MONIQUE\x03"));
+    assert_eq!(&dump[12..44], crate::words::fingerprint().as_bytes());
+
+    let target = open_index(temp_dir.path().join("target.db")).await;
+    assert_eq!(target.import(&dump[..]).await.unwrap(), 3);
+    assert_eq!(target.len().await, 3);
+    assert_eq!(target.index([3; 20]).await.unwrap(), Some(2));
+    assert_eq!(target.get_counters().await.last_committed_block, 3);
+    assert_eq!(target.timestamp(1).unwrap(), Some(10));
+    assert_eq!(target.timestamp(2).unwrap(), None);
+    assert_eq!(target.timestamp(3).unwrap(), Some(30));
+
+    // dumps without timestamps, as read by older versions, import as well
+    let mut legacy = vec![];
+    source
+        .export_until(1, 3, &mut legacy, None, false)
+        .await
+        .unwrap();
+    assert!(legacy.starts_with(b"MONIQUE\x01") && legacy.len() < dump.len());
+    let imported = open_index(temp_dir.path().join("legacy.db")).await;
+    assert_eq!(imported.import(&legacy[..]).await.unwrap(), 3);
+    assert_eq!(imported.len().await, 3);
+    assert_eq!(imported.timestamp(1).unwrap(), None);
+
+    // a tampered dump is rejected
+    let tampered = open_index(temp_dir.path().join("bad.db")).await;
+    let mut broken_chain = dump.clone();
+    let last = dump.len() - 1;
+    dump[last] ^= 0xff;
+    assert!(tampered.import(&dump[..]).await.is_err());
+
+    // so is a broken hash chain, before anything is stored: the hash of block 2
+    // follows the magic, the item size, the wordlist and block 1 (with its 2 items)
+    let offset = 8 + 4 + 32 + (8 + 32 + 32 + 8 + 4 + 2 * 20) + 8 + 32;
+    broken_chain[offset] ^= 0xff;
+    let broken = open_index(temp_dir.path().join("broken.db")).await;
+    let err = broken.import(&broken_chain[..]).await.unwrap_err();
+    assert!(matches!(err, MoniqueError::Dump(_)), "{}", err);
+    assert_eq!(broken.len().await, 0);
+    assert_eq!(broken.committed_len().await, 0);
+    assert_eq!(broken.get_counters().await.last_committed_block, 0);
+    assert_eq!(broken.index([1; 20]).await.unwrap(), None);
+
+    // an item count past the end of a truncated dump fails without allocating it
+    let count = 8 + 4 + 32 + (8 + 32 + 32 + 8);
+    let mut truncated = broken_chain[..count + 4 + 20].to_vec();
+    truncated[count..count + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    let err = broken.import(&truncated[..]).await.unwrap_err();
+    assert!(matches!(err, MoniqueError::Io(_)), "{}", err);
+}
+
+#[tokio::test]
+async fn segments() {
+    let temp_dir = tempdir().unwrap();
+    let source = open_index(temp_dir.path().join("source.db")).await;
+    for number in 1..=6u8 {
+        source
+            .queue(number as u64, vec![[number; 20], [number + 100; 20]])
+            .await
+            .unwrap();
+    }
+    source.commit(4).await.unwrap();
 
-    const TARGET_DB_SIZE: u32 = 1_000_000;
-    const BATCH_SIZE: u32 = 30_000;
-    const GET_ITERATIONS: u32 = 400_000;
+    let dir = temp_dir.path().join("segments");
+    let segments = source.export_segments(&dir, 4, 3, &Plain).await.unwrap();
+    let ranges: Vec<(u64, u64, u64)> = segments.iter().map(|s| (s.from, s.to, s.start)).collect();
+    assert_eq!(ranges, vec![(1, 2, 0), (3, 4, 4)]);
+    assert_eq!(verify_segments::<20, [u8; 20]>(&dir, &Plain).unwrap(), 4);
 
-    #[tokio::test]
-    #[ignore]
-    async fn benchmark() {
+    // only the new blocks are exported
+    source.commit(6).await.unwrap();
+    let segments = source.export_segments(&dir, 6, 3, &Plain).await.unwrap();
+    assert_eq!(segments.len(), 1);
+    assert_eq!((segments[0].from, segments[0].to), (5, 6));
+    assert_eq!(read_manifest(&dir, 20).unwrap().len(), 3);
+    assert_eq!(verify_segments::<20, [u8; 20]>(&dir, &Plain).unwrap(), 6);
+    assert_eq!(
+        manifest_wordlist(&dir).unwrap(),
+        Some(crate::words::fingerprint())
+    );
+
+    // the manifests of older versions, without the wordlist, are still read
+    let manifest = std::fs::read_to_string(dir.join("MANIFEST")).unwrap();
+    let (header, lines) = manifest.split_once('\n').unwrap();
+    assert!(header.starts_with("monique-segments 2 20 0x"));
+    let legacy = temp_dir.path().join("legacy");
+    std::fs::create_dir(&legacy).unwrap();
+    std::fs::write(
+        legacy.join("MANIFEST"),
+        format!("monique-segments 1 20\n{}", lines),
+    )
+    .unwrap();
+    assert_eq!(read_manifest(&legacy, 20).unwrap().len(), 3);
+    assert_eq!(manifest_wordlist(&legacy).unwrap(), None);
+
+    let target = open_index(temp_dir.path().join("target.db")).await;
+    assert_eq!(target.import_segments(&dir, &Plain).await.unwrap(), 6);
+    assert_eq!(target.len().await, 12);
+    assert_eq!(target.block_hash(6).unwrap(), source.block_hash(6).unwrap());
+    // nothing left to import
+    assert_eq!(target.import_segments(&dir, &Plain).await.unwrap(), 0);
+
+    // a tampered segment fails its verification
+    let path = dir.join(&segments[0].file);
+    let mut bytes = std::fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&path, bytes).unwrap();
+    assert!(verify_segments::<20, [u8; 20]>(&dir, &Plain).is_err());
+}
+
+#[tokio::test]
+async fn start_block() {
+    let temp_dir = tempdir().unwrap();
+    let open = |name: &'static str| {
+        IndexTable::<20, [u8; 20]>::builder(temp_dir.path().join(name))
+            .cache_size(16)
+            .build()
+    };
+    let source = open("source.db").await.unwrap();
+    assert_eq!(source.start_block(), 1);
+    source.set_start_block(100).await.unwrap();
+    assert_eq!(source.get_counters().await.last_committed_block, 99);
+    assert!(matches!(
+        source.queue(1, vec![]).await,
+        Err(MoniqueError::Reorg { .. })
+    ));
+    source.queue(100, vec![[1; 20]]).await.unwrap();
+    source.queue(101, vec![[2; 20]]).await.unwrap();
+    source.commit(101).await.unwrap();
+    assert!(source.set_start_block(50).await.is_err());
+    // the chain is anchored to the zero hash, as block 1 of a default index
+    let default = open("default.db").await.unwrap();
+    default.queue(1, vec![[1; 20]]).await.unwrap();
+    default.commit(1).await.unwrap();
+    let first = source.checkpoint(100).unwrap().unwrap();
+    assert_eq!(first.root, default.checkpoint(1).unwrap().unwrap().root);
+    assert_eq!(source.checkpoint(99).unwrap(), None);
+
+    let mut dump = vec![];
+    assert_eq!(source.export(100, 101, &mut dump).await.unwrap(), 2);
+    drop(source);
+    let source = open("source.db").await.unwrap();
+    assert_eq!(source.start_block(), 100);
+    assert_eq!(source.get_counters().await.last_committed_block, 101);
+
+    // an empty index starts with the imported dump
+    let target = open("target.db").await.unwrap();
+    assert_eq!(target.import(&dump[..]).await.unwrap(), 2);
+    assert_eq!(target.start_block(), 100);
+    assert_eq!(
+        target.checkpoint(101).unwrap(),
+        source.checkpoint(101).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn integrity() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("integrity.db");
+    let open = |read_only| {
+        IndexTable::<20, [u8; 20]>::builder(&path)
+            .cache_size(16)
+            .read_only(read_only)
+            .build()
+    };
+    let index = open(false).await.unwrap();
+    index.check().unwrap();
+    index.queue(1, vec![[1; 20], [2; 20]]).await.unwrap();
+    index.queue(2, vec![[3; 20]]).await.unwrap();
+    index.commit(2).await.unwrap();
+    index.check().unwrap();
+    drop(index);
+
+    // drift the counter, then the hash of the last block
+    let tamper = |key: &[u8], table: &str, value: &[u8]| {
+        let db = libmdbx::Database::<libmdbx::NoWriteMap>::open_with_options(
+            &path,
+            libmdbx::DatabaseOptions {
+                max_tables: Some(10),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let tx = db.begin_rw_txn().unwrap();
+        let table = tx.open_table(Some(table)).unwrap();
+        tx.put(&table, key, value, libmdbx::WriteFlags::UPSERT)
+            .unwrap();
+        tx.commit().unwrap();
+    };
+    tamper(b"counter", "stats", &2u32.to_le_bytes());
+    // the writer opens, so that --force and --recover apply
+    let error = open(false).await.unwrap().check().unwrap_err().to_string();
+    assert!(error.contains("the accumulator holds 3 entries, but the counter is 2"));
+    let error = open(true).await.unwrap().check().unwrap_err().to_string();
+    assert!(error.contains("3 entries in the index table, but the counter is 2"));
+    tamper(b"counter", "stats", &3u32.to_le_bytes());
+    tamper(&2u32.to_le_bytes(), "blocks", &[0; 32]);
+    let error = open(true).await.unwrap().check().unwrap_err().to_string();
+    assert!(error.contains("the hash of block 2 does not chain from block 1"));
+}
+
+#[tokio::test]
+async fn recover() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("recover.db");
+    let open = || {
+        IndexTable::<20, [u8; 20]>::builder(&path)
+            .cache_size(16)
+            .build()
+    };
+    let index = open().await.unwrap();
+    assert_eq!(index.recover().await.unwrap(), None);
+    index.queue(1, vec![[1; 20], [2; 20]]).await.unwrap();
+    index.queue(2, vec![[3; 20]]).await.unwrap();
+    index.queue(3, vec![[4; 20]]).await.unwrap();
+    index.commit(3).await.unwrap();
+    drop(index);
+
+    let tamper = |key: &[u8], table: &str, value: &[u8]| {
+        let db = libmdbx::Database::<libmdbx::NoWriteMap>::open_with_options(
+            &path,
+            libmdbx::DatabaseOptions {
+                max_tables: Some(10),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let tx = db.begin_rw_txn().unwrap();
+        let table = tx.open_table(Some(table)).unwrap();
+        tx.put(&table, key, value, libmdbx::WriteFlags::UPSERT)
+            .unwrap();
+        tx.commit().unwrap();
+    };
+
+    // an entry past the counter is removed, the blocks are kept
+    tamper(&4u32.to_le_bytes(), "index", &[5; 20]);
+    let index = open().await.unwrap();
+    assert!(index.check().is_err());
+    assert_eq!(index.recover().await.unwrap(), Some(3));
+    index.check().unwrap();
+    assert_eq!(index.index_committed([4; 20]).await.unwrap(), Some(3));
+    assert_eq!(index.get_committed(4).await.unwrap(), None);
+    drop(index);
+
+    // a block that does not chain is removed with its entries
+    tamper(&3u32.to_le_bytes(), "blocks", &[0; 32]);
+    let index = open().await.unwrap();
+    assert_eq!(index.recover().await.unwrap(), Some(2));
+    index.check().unwrap();
+    assert_eq!(index.committed_len().await, 3);
+    assert_eq!(index.index_committed([4; 20]).await.unwrap(), None);
+
+    // indexing resumes from the block after
+    index.queue(3, vec![[4; 20]]).await.unwrap();
+    assert_eq!(index.commit(3).await.unwrap(), 1);
+    assert_eq!(index.index_committed([4; 20]).await.unwrap(), Some(3));
+    index.check().unwrap();
+}
+
+#[tokio::test]
+async fn missed_block() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("missed.db")).await;
+    index.queue(1, vec![[1; 20]]).await.unwrap();
+    index.queue(2, vec![[2; 20]]).await.unwrap();
+    index.queue(3, vec![[3; 20]]).await.unwrap();
+    index.pending.write().await.remove(2);
+    assert!(matches!(
+        index.commit(3).await,
+        Err(MoniqueError::MissedBlock(2))
+    ));
+    assert_eq!(index.get_counters().await.last_committed_block, 0);
+
+    // queueing the missing range again recovers
+    index.queue(2, vec![[2; 20]]).await.unwrap();
+    index.queue(3, vec![[3; 20]]).await.unwrap();
+    assert_eq!(index.commit(3).await.unwrap(), 3);
+    assert_eq!(index.index([3; 20]).await.unwrap(), Some(2));
+}
+
+#[tokio::test]
+async fn out_of_order() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("ordering.db")).await;
+    index.queue(1, vec![[1; 20]]).await.unwrap();
+    // held back until block 2 is queued
+    assert!(index
+        .queue(4, vec![[4; 20], [1; 20]])
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(index
+        .queue(3, vec![[3; 20], [2; 20]])
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(index.get_counters().await.last_indexed_block, 1);
+    assert_eq!(
+        index.queue(2, vec![[2; 20]]).await.unwrap(),
+        vec![[2; 20], [3; 20], [4; 20]]
+    );
+    assert_eq!(index.get_counters().await.last_indexed_block, 4);
+    index.commit(4).await.unwrap();
+    assert_eq!(index.index([4; 20]).await.unwrap(), Some(3));
+
+    // a rollback drops the blocks held back
+    index.queue(7, vec![[7; 20]]).await.unwrap();
+    index.queue(5, vec![[5; 20]]).await.unwrap();
+    index.queue(5, vec![[6; 20]]).await.unwrap();
+    assert!(index.queue(6, vec![]).await.unwrap().is_empty());
+    assert_eq!(index.get_counters().await.last_indexed_block, 6);
+}
+
+#[tokio::test]
+async fn queue_errors() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("queue.db")).await;
+    index.queue(1, vec![[1; 20]]).await.unwrap();
+    let beyond = 2 + REORDER_WINDOW;
+    assert!(matches!(
+        index.queue(beyond, vec![]).await,
+        Err(MoniqueError::Queue {
+            expected: 2,
+            block
+        }) if block == beyond
+    ));
+    index.queue(2, vec![[2; 20]]).await.unwrap();
+    index.commit(2).await.unwrap();
+    assert!(matches!(
+        index.queue(2, vec![]).await,
+        Err(MoniqueError::Reorg {
+            block: 2,
+            committed: 2
+        })
+    ));
+}
+
+#[tokio::test]
+async fn tombstones() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("reorg.db")).await;
+    index.queue(1, vec![[1; 20]]).await.unwrap();
+    index.queue(2, vec![[2; 20], [1; 20]]).await.unwrap();
+    index.queue(3, vec![[3; 20]]).await.unwrap();
+    // block 2 of another fork drops the blocks from 2 on
+    index.queue(2, vec![[4; 20]]).await.unwrap();
+    let tombstones = index.tombstones(0).unwrap();
+    let dropped: Vec<(u64, TombstoneReason, Vec<[u8; 20]>)> = tombstones
+        .into_iter()
+        .map(|tombstone| (tombstone.block, tombstone.reason, tombstone.items))
+        .collect();
+    assert_eq!(
+        dropped,
+        vec![
+            (2, TombstoneReason::Reorg, vec![[2; 20]]),
+            (3, TombstoneReason::Reorg, vec![[3; 20]]),
+        ]
+    );
+    index.commit(1).await.unwrap();
+    index.rollback(1).await.unwrap();
+    let last = index.tombstones(0).unwrap().pop().unwrap();
+    assert_eq!(
+        (last.block, last.reason, last.items),
+        (2, TombstoneReason::Rollback, vec![[4; 20]])
+    );
+    // a reorg also drops the blocks queued ahead
+    index.queue(2, vec![[6; 20]]).await.unwrap();
+    index.queue(4, vec![[5; 20]]).await.unwrap();
+    index.queue(2, vec![[7; 20]]).await.unwrap();
+    let dropped: Vec<(u64, Vec<[u8; 20]>)> = index
+        .tombstones(0)
+        .unwrap()
+        .into_iter()
+        .skip(3)
+        .map(|tombstone| (tombstone.block, tombstone.items))
+        .collect();
+    assert_eq!(dropped, vec![(2, vec![[6; 20]]), (4, vec![[5; 20]])]);
+}
+
+#[tokio::test]
+async fn hashes() {
+    let temp_dir = tempdir().unwrap();
+    let index = IndexTable::<32, [u8; 32]>::builder(temp_dir.path().join("tx.db"))
+        .cache_size(16)
+        .build()
+        .await
+        .unwrap();
+    index.queue(1, vec![[1; 32], [2; 32]]).await.unwrap();
+    index.queue(2, vec![[3; 32]]).await.unwrap();
+    index.commit(2).await.unwrap();
+    assert_eq!(index.len().await, 3);
+    assert_eq!(index.get(2).await.unwrap(), Some([3; 32]));
+    assert_eq!(index.index([2; 32]).await.unwrap(), Some(1));
+}
+
+#[tokio::test]
+async fn pending_order() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("pending.db")).await;
+    for n in 1..=20u8 {
+        index.queue(n as u64, vec![[n; 20]]).await.unwrap();
+    }
+    for commit in [0, 10, 20] {
+        if commit > 0 {
+            index.commit(commit).await.unwrap();
+        }
+        assert_eq!(index.len().await, 20);
+        for n in 1..=20u8 {
+            assert_eq!(index.get(n as usize - 1).await.unwrap(), Some([n; 20]));
+            assert_eq!(index.index([n; 20]).await.unwrap(), Some(n as usize - 1));
+        }
+        assert_eq!(index.get(20).await.unwrap(), None);
+    }
+}
+
+/// Random queues, rollbacks and commits checked against a model of the global
+/// index assignment: committed entries first, then the pending blocks in order.
+#[tokio::test]
+async fn pending_boundary() {
+    use ethers_core::rand::{rngs::StdRng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    for seed in 0..8 {
+        let mut rng = StdRng::seed_from_u64(seed);
         let temp_dir = tempdir().unwrap();
-        let path = temp_dir.path().join("benchmark-test.db");
-        let index = Storage::<20, [u8; 20]>::new(path, 1_000_000);
-        println!("start: {}", index.len().await);
-        let mut gen = rand::thread_rng();
-        let mut block_num = 1;
-        while index.len().await < TARGET_DB_SIZE as usize {
-            let mut items = Vec::new();
-            let t = std::time::Instant::now();
-            for _ in 0..BATCH_SIZE {
-                let v = gen.gen::<[u8; 20]>();
-                items.push(v);
+        let index = IndexTable::<20, [u8; 20]>::builder(temp_dir.path().join("boundary.db"))
+            .cache_size(8)
+            .build()
+            .await
+            .unwrap();
+        let mut committed: Vec<[u8; 20]> = vec![];
+        let mut pending: BTreeMap<u64, Vec<[u8; 20]>> = BTreeMap::new();
+        let (mut last_committed, mut last_indexed) = (0u64, 0u64);
+
+        for _ in 0..60 {
+            match rng.gen_range(0..10) {
+                0..=6 => {
+                    // next block, or a rollback of some pending blocks
+                    let number = if rng.gen_bool(0.15) && last_indexed > last_committed {
+                        rng.gen_range(last_committed + 1..=last_indexed)
+                    } else {
+                        last_indexed + 1
+                    };
+                    let items: Vec<[u8; 20]> = (0..rng.gen_range(0..6))
+                        .map(|_| [rng.gen_range(1..48u8); 20])
+                        .collect();
+                    pending.split_off(&number);
+                    let mut expected = vec![];
+                    for item in &items {
+                        if !committed.contains(item)
+                            && !pending.values().flatten().any(|i| i == item)
+                            && !expected.contains(item)
+                        {
+                            expected.push(*item);
+                        }
+                    }
+                    let queued = index.queue(number, items).await.unwrap();
+                    assert_eq!(queued, expected, "seed {seed}: block {number}");
+                    pending.insert(number, expected);
+                    last_indexed = number;
+                }
+                _ => {
+                    let safe = rng.gen_range(0..=last_indexed + 2);
+                    let target = safe.min(last_indexed).max(last_committed);
+                    let mut rest = pending.split_off(&(target + 1));
+                    std::mem::swap(&mut pending, &mut rest);
+                    committed.extend(rest.into_values().flatten());
+                    last_committed = target;
+                    index.commit(safe).await.unwrap();
+                    assert_eq!(
+                        index.get_counters().await.last_committed_block,
+                        last_committed,
+                        "seed {seed}: commit {safe}"
+                    );
+                }
+            }
+
+            let model: Vec<_> = committed
+                .iter()
+                .chain(pending.values().flatten())
+                .copied()
+                .collect();
+            assert_eq!(index.committed_len().await, committed.len(), "seed {seed}");
+            assert_eq!(index.len().await, model.len(), "seed {seed}");
+            for (i, item) in model.iter().enumerate() {
+                assert_eq!(
+                    index.get(i).await.unwrap(),
+                    Some(*item),
+                    "seed {seed}: get {i}"
+                );
+                assert_eq!(index.index(*item).await.unwrap(), Some(i), "seed {seed}");
+            }
+            assert_eq!(index.get(model.len()).await.unwrap(), None, "seed {seed}");
+            for n in 1..48u8 {
+                if !model.contains(&[n; 20]) {
+                    assert_eq!(index.index([n; 20]).await.unwrap(), None, "seed {seed}");
+                }
             }
-            let blocks = vec![Block {
-                number: block_num,
-                items,
-                root_hash: [0; 32].into(),
-            }];
-            index.push(blocks).await.expect("push");
-            println!(
-                "items: {} - {} ns",
-                index.len().await,
-                t.elapsed().as_nanos() / (BATCH_SIZE as u128)
-            );
-            block_num += 1;
         }
+    }
+}
+
+#[tokio::test]
+async fn committed_reads() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("committed.db")).await;
+    index.queue(1, vec![[1; 20]]).await.unwrap();
+    index.queue(2, vec![[2; 20]]).await.unwrap();
+    index.commit(1).await.unwrap();
+    assert_eq!(index.get_committed(0).await.unwrap(), Some([1; 20]));
+    assert_eq!(index.get_committed(1).await.unwrap(), None);
+    assert_eq!(index.get(1).await.unwrap(), Some([2; 20]));
+    assert_eq!(index.index_committed([1; 20]).await.unwrap(), Some(0));
+    assert_eq!(index.index_committed([2; 20]).await.unwrap(), None);
+    assert_eq!(index.index([2; 20]).await.unwrap(), Some(1));
+    assert_eq!(index.height().await, (2, 2));
+    index.commit(2).await.unwrap();
+    assert_eq!(index.get_committed(1).await.unwrap(), Some([2; 20]));
+    assert_eq!(index.index_committed([2; 20]).await.unwrap(), Some(1));
+    assert_eq!(index.height().await, (2, 2));
+    index.queue(3, vec![[3; 20], [1; 20]]).await.unwrap();
+    assert_eq!(index.height().await, (3, 3));
+}
+
+#[tokio::test]
+async fn scan() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("scan.db")).await;
+    let items: Vec<[u8; 20]> = (0..50u8).map(|i| [i; 20]).collect();
+    index.queue(1, items).await.unwrap();
+    index.commit(1).await.unwrap();
+    index.queue(2, vec![[200; 20]]).await.unwrap();
+    let even = |item: &[u8; 20]| item[0].is_multiple_of(2);
+    let budget = ScanBudget {
+        entries: usize::MAX,
+        duration: Duration::from_secs(10),
+    };
+
+    let (matches, next) = index.scan(0, 3, budget, even).await.unwrap();
+    assert_eq!(matches, vec![(0, [0; 20]), (2, [2; 20]), (4, [4; 20])]);
+    assert_eq!(next, Some(5));
+    let (matches, next) = index.scan(45, 10, budget, even).await.unwrap();
+    assert_eq!(matches, vec![(46, [46; 20]), (48, [48; 20])]);
+    assert_eq!(next, None);
+    // pending entries are not scanned
+    let (matches, _) = index
+        .scan(0, 10, budget, |item| item[0] == 200)
+        .await
+        .unwrap();
+    assert!(matches.is_empty());
+    // the last committed entry ends the scan
+    let (_, next) = index
+        .scan(0, 1, budget, |item| item[0] == 49)
+        .await
+        .unwrap();
+    assert_eq!(next, None);
+    // out of time after the first chunk
+    let out_of_time = ScanBudget {
+        duration: Duration::ZERO,
+        ..budget
+    };
+    let (matches, next) = index.scan(0, 10, out_of_time, even).await.unwrap();
+    assert_eq!((matches.len(), next), (8, Some(16)));
+    // out of entries within a chunk
+    let few_entries = ScanBudget {
+        entries: 5,
+        ..budget
+    };
+    let (matches, next) = index.scan(2, 10, few_entries, even).await.unwrap();
+    assert_eq!((matches.len(), next), (3, Some(7)));
+}
+
+#[tokio::test]
+async fn recent() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("recent.db")).await;
+    assert!(index.recent(5).await.unwrap().is_empty());
+    index.queue(1, vec![[1; 20], [2; 20]]).await.unwrap();
+    index.queue(2, vec![]).await.unwrap();
+    index.queue(3, vec![[3; 20], [4; 20]]).await.unwrap();
+    index.commit(3).await.unwrap();
+    index.queue(4, vec![[5; 20]]).await.unwrap();
+    // newest first, committed entries only
+    assert_eq!(
+        index.recent(3).await.unwrap(),
+        vec![
+            (3, [4; 20], Some(3)),
+            (2, [3; 20], Some(3)),
+            (1, [2; 20], Some(1))
+        ]
+    );
+    assert_eq!(index.recent(10).await.unwrap().len(), 4);
+}
+
+#[tokio::test]
+async fn block_counts() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("counts.db")).await;
+    index.queue(1, vec![[1; 20], [2; 20]]).await.unwrap();
+    index.queue(2, vec![[1; 20]]).await.unwrap();
+    index.queue(3, vec![[3; 20]]).await.unwrap();
+    index.commit(3).await.unwrap();
+    index.queue(4, vec![[4; 20]]).await.unwrap();
+    assert_eq!(
+        index.block_counts(1, 10).unwrap(),
+        vec![(1, 2), (2, 0), (3, 1)]
+    );
+    assert_eq!(index.block_counts(2, 2).unwrap(), vec![(2, 0)]);
+}
+
+#[tokio::test]
+async fn height_at() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("height.db")).await;
+    assert_eq!(index.height_at(100).unwrap(), None);
+    for block in 1..=20u8 {
+        index.queue(block as u64, vec![[block; 20]]).await.unwrap();
+    }
+    index.commit(20).await.unwrap();
+    // block 5 has no recorded timestamp
+    let timestamps = (1..=20)
+        .filter(|block| *block != 5)
+        .map(|block| (block, block * 10));
+    index.put_timestamps(timestamps.collect()).unwrap();
+    assert_eq!(index.timestamp(4).unwrap(), Some(40));
+    assert_eq!(index.timestamp(5).unwrap(), None);
+
+    assert_eq!(index.height_at(9).unwrap(), None);
+    assert_eq!(index.height_at(10).unwrap(), Some((1, 10, 1)));
+    assert_eq!(index.height_at(55).unwrap(), Some((4, 40, 4)));
+    assert_eq!(index.height_at(60).unwrap(), Some((6, 60, 6)));
+    assert_eq!(index.height_at(139).unwrap(), Some((13, 130, 13)));
+    assert_eq!(index.height_at(1_000).unwrap(), Some((20, 200, 20)));
+    for time in 10..=200 {
+        let (block, timestamp, _) = index.height_at(time).unwrap().unwrap();
+        let expected = if time / 10 == 5 { 4 } else { time / 10 };
+        assert_eq!((block, timestamp), (expected, expected * 10), "{}", time);
+    }
+}
+
+#[tokio::test]
+async fn committed_timestamps() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("times.db")).await;
+    let timestamp = |timestamp| BlockData {
+        timestamp: Some(timestamp),
+        ..Default::default()
+    };
+    index
+        .queue_with(1, vec![[1; 20]], timestamp(10))
+        .await
+        .unwrap();
+    // held ahead, then released by block 2
+    index
+        .queue_with(3, vec![[3; 20]], timestamp(30))
+        .await
+        .unwrap();
+    index
+        .queue_with(2, vec![[2; 20]], timestamp(20))
+        .await
+        .unwrap();
+    // replaced by a reorg
+    index
+        .queue_with(4, vec![[4; 20]], timestamp(40))
+        .await
+        .unwrap();
+    index
+        .queue_with(4, vec![[5; 20]], timestamp(41))
+        .await
+        .unwrap();
+    assert_eq!(index.timestamp(1).unwrap(), None);
+
+    index.spill(0).await.unwrap();
+    index.commit(4).await.unwrap();
+    let timestamps: Vec<_> = (1..=4).map(|n| index.timestamp(n).unwrap()).collect();
+    assert_eq!(timestamps, [Some(10), Some(20), Some(30), Some(41)]);
+
+    // rolled back with their blocks
+    index.rollback(2).await.unwrap();
+    assert_eq!(index.timestamp(3).unwrap(), None);
+    assert_eq!(index.height_at(100).unwrap(), Some((2, 20, 2)));
+}
+
+#[tokio::test]
+async fn committed_appearances() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("seen.db")).await;
+    let seen = |byte| Appearance {
+        transaction: H256::repeat_byte(byte),
+        log_index: None,
+    };
+    let data = |appearances: Vec<([u8; 20], Appearance)>| BlockData {
+        appearances: appearances.into_iter().collect(),
+        ..Default::default()
+    };
+    index
+        .queue_with(1, vec![[1; 20], [2; 20]], data(vec![([2; 20], seen(1))]))
+        .await
+        .unwrap();
+    // [2; 20] is already pending: its first appearance is kept
+    let ahead = data(vec![([2; 20], seen(3)), ([3; 20], seen(3))]);
+    index
+        .queue_with(3, vec![[2; 20], [3; 20]], ahead)
+        .await
+        .unwrap();
+    index.queue(2, vec![[4; 20]]).await.unwrap();
+    assert_eq!(index.appearance(1).unwrap(), None);
+
+    index.commit(3).await.unwrap();
+    let appearances: Vec<_> = (0..4).map(|i| index.appearance(i).unwrap()).collect();
+    assert_eq!(appearances, [None, Some(seen(1)), None, Some(seen(3))]);
+}
+
+#[tokio::test]
+async fn committed_counts() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("counts.db")).await;
+    let data = |counters: Vec<([u8; 20], &'static str)>| BlockData {
+        counters: counters.into_iter().collect(),
+        ..Default::default()
+    };
+    let counts = |index: &IndexTable<20, [u8; 20]>| {
+        ["a", "b"].map(|key| index.get_stat(key).unwrap().unwrap_or(0))
+    };
+    index
+        .queue_with(
+            1,
+            vec![[1; 20], [2; 20]],
+            data(vec![([1; 20], "a"), ([2; 20], "b")]),
+        )
+        .await
+        .unwrap();
+    // held ahead: [1; 20] is not added again
+    let ahead = data(vec![([1; 20], "b"), ([3; 20], "a")]);
+    index
+        .queue_with(3, vec![[1; 20], [3; 20]], ahead)
+        .await
+        .unwrap();
+    index
+        .queue_with(2, vec![[4; 20]], data(vec![([4; 20], "a")]))
+        .await
+        .unwrap();
+    // replaced by a reorg
+    index
+        .queue_with(4, vec![[5; 20]], data(vec![([5; 20], "b")]))
+        .await
+        .unwrap();
+    index
+        .queue_with(4, vec![[6; 20]], data(vec![([6; 20], "a")]))
+        .await
+        .unwrap();
+    // only counted once committed
+    assert_eq!(counts(&index), [0, 0]);
+
+    index.commit(4).await.unwrap();
+    assert_eq!(counts(&index), [4, 1]);
+
+    index.rollback(2).await.unwrap();
+    assert_eq!(counts(&index), [2, 1]);
+    index.rollback(0).await.unwrap();
+    assert_eq!(counts(&index), [0, 0]);
+}
+
+#[tokio::test]
+async fn compact() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("bloated.db")).await;
+    for number in 1..=200u64 {
+        let items = (0..50u32)
+            .map(|i| {
+                let mut item = [0u8; 20];
+                item[..8].copy_from_slice(&number.to_le_bytes());
+                item[8..12].copy_from_slice(&i.to_le_bytes());
+                item
+            })
+            .collect();
+        index.queue(number, items).await.unwrap();
+    }
+    index.commit(200).await.unwrap();
+    index.rollback(10).await.unwrap();
+    let space = index.space().unwrap();
+    assert!(space.page_size > 0 && space.max_size >= space.file_size);
+    assert!(space.free_pages > 0 && space.fragmentation() > 0.0);
+    assert!(space.free_pages < space.allocated_pages);
+    assert_eq!(space.used_pages(), space.allocated_pages - space.free_pages);
+    let overcounted = Space {
+        free_pages: space.allocated_pages + 1,
+        ..space
+    };
+    assert_eq!(overcounted.used_pages(), 0);
+
+    let dir = temp_dir.path().join("compacted.db");
+    index.compact_to(&dir).unwrap();
+    // the copy must not overwrite an existing file
+    assert!(index.compact_to(&dir).is_err());
+    let compacted = open_index(&dir).await;
+    assert_eq!(compacted.len().await, 500);
+    assert_eq!(
+        compacted.block_hash(10).unwrap(),
+        index.block_hash(10).unwrap()
+    );
+    compacted.check().unwrap();
+    let compacted_space = compacted.space().unwrap();
+    assert!(compacted_space.allocated_pages < space.allocated_pages);
+}
+
+#[tokio::test]
+async fn disk_usage() {
+    let temp_dir = tempdir().unwrap();
+    let index = IndexTable::<20, [u8; 20]>::builder(temp_dir.path().join("usage.db"))
+        .cache_size(16)
+        .persist_tries(true)
+        .build()
+        .await
+        .unwrap();
+    let data = BlockData {
+        counters: [([1; 20], "counter")].into_iter().collect(),
+        ..Default::default()
+    };
+    index
+        .queue_with(1, vec![[1; 20], [2; 20]], data)
+        .await
+        .unwrap();
+    index.commit(1).await.unwrap();
+    // every table can be created
+    index.set_contract_flags(vec![(0, true)]).unwrap();
+    index.put_signatures(vec![(1, [1; 65])]).unwrap();
+    index.put_timestamps(vec![(1, 10)]).unwrap();
+    let label = Label {
+        label: "one".to_string(),
+        source: "test".to_string(),
+    };
+    index.set_labels(vec![([1; 20], Some(label))]).unwrap();
+    index.record_history(HistorySample::default(), 10).unwrap();
+    let appearance = Appearance {
+        transaction: H256::repeat_byte(1),
+        log_index: None,
+    };
+    index.set_appearances(vec![(0, appearance)]).unwrap();
+    index.queue(2, vec![[3; 20]]).await.unwrap();
+    index.queue(2, vec![[4; 20]]).await.unwrap();
+    index.spill(0).await.unwrap();
+
+    let usage = index.disk_usage().unwrap();
+    let tables: Vec<&str> = usage.tables.iter().map(|(table, _)| *table).collect();
+    assert_eq!(tables, TABLES.to_vec());
+    assert!(usage.tables.iter().all(|(_, size)| *size > 0));
+    assert!(usage.space.map_usage() > 0.0 && usage.space.map_usage() < 1.0);
+}
+
+#[tokio::test]
+async fn analyze_buckets() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("analyze.db")).await;
+    assert_eq!(index.analyze_buckets(2).unwrap(), BucketReport::default());
+    index
+        .queue(1, vec![[1; 20], [2; 20], [3; 20]])
+        .await
+        .unwrap();
+    index.commit(1).await.unwrap();
+    let report = index.analyze_buckets(2).unwrap();
+    assert_eq!((report.entries, report.buckets), (3, 3));
+    assert_eq!(report.sizes, [(1, 3)].into());
+    assert_eq!(report.hot_spots.len(), 2);
+    assert_eq!(report.hit_cost(), 1.0);
+
+    // two buckets of one entry and one of three
+    let report = BucketReport {
+        entries: 5,
+        buckets: 3,
+        sizes: [(1, 2), (3, 1)].into(),
+        hot_spots: vec![],
+    };
+    assert_eq!(report.colliding_pairs(), 3);
+    assert_eq!(report.hit_cost(), 1.6);
+    assert_eq!(report.expected_pairs(1), 5.0);
+}
+
+#[tokio::test]
+async fn rollback() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("rollback.db");
+    let index = open_index(&path).await;
+    index.queue(1, vec![[1; 20], [2; 20]]).await.unwrap();
+    index.queue(2, vec![[3; 20]]).await.unwrap();
+    index.queue(3, vec![[4; 20], [1; 20]]).await.unwrap();
+    index.commit(3).await.unwrap();
+    index.set_contract_flags(vec![(2, true)]).unwrap();
+    let appearance = Appearance {
+        transaction: H256::repeat_byte(1),
+        log_index: Some(0),
+    };
+    index.set_appearances(vec![(2, appearance)]).unwrap();
+    index.put_timestamps(vec![(2, 20), (3, 30)]).unwrap();
+    index.queue(4, vec![[5; 20]]).await.unwrap();
+    let root = index.index_root(1).unwrap();
+
+    assert!(index.rollback(4).await.is_err());
+    assert_eq!(index.rollback(1).await.unwrap(), 2);
+    assert_eq!(index.len().await, 2);
+    assert_eq!(index.get_counters().await.last_indexed_block, 1);
+    assert_eq!(index.get(2).await.unwrap(), None);
+    assert_eq!(index.index([3; 20]).await.unwrap(), None);
+    assert_eq!(index.index([5; 20]).await.unwrap(), None);
+    assert_eq!(index.is_contract(2).unwrap(), None);
+    assert_eq!(index.appearance(2).unwrap(), None);
+    assert_eq!(index.checkpoint(2).unwrap(), None);
+    assert_eq!(index.timestamp(2).unwrap(), None);
+    assert_eq!(index.height_at(100).unwrap(), None);
+    index.check().unwrap();
+
+    // indexing resumes after the rolled back block, with the same index root as a
+    // fresh index
+    index.queue(2, vec![[6; 20], [3; 20]]).await.unwrap();
+    index.commit(2).await.unwrap();
+    assert_eq!(index.index([3; 20]).await.unwrap(), Some(3));
+    assert_eq!(index.index_root(1).unwrap(), root);
+    let fresh = open_index(temp_dir.path().join("fresh.db")).await;
+    fresh.queue(1, vec![[1; 20], [2; 20]]).await.unwrap();
+    fresh.queue(2, vec![[6; 20], [3; 20]]).await.unwrap();
+    fresh.commit(2).await.unwrap();
+    assert_eq!(index.index_root(2).unwrap(), fresh.index_root(2).unwrap());
+    assert_eq!(index.checkpoint(2).unwrap(), fresh.checkpoint(2).unwrap());
+    drop(index);
+    let index = open_index(&path).await;
+    index.check().unwrap();
+    assert_eq!(index.len().await, 4);
+}
+
+#[tokio::test]
+async fn queue_dedup() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("dedup.db")).await;
+    assert_eq!(
+        index.queue(1, vec![[1; 20], [2; 20]]).await.unwrap(),
+        vec![[1; 20], [2; 20]]
+    );
+    // duplicates within the block and with pending blocks
+    assert_eq!(
+        index
+            .queue(2, vec![[2; 20], [3; 20], [3; 20]])
+            .await
+            .unwrap(),
+        vec![[3; 20]]
+    );
+    index.commit(2).await.unwrap();
+    // duplicates with committed blocks
+    assert_eq!(
+        index.queue(3, vec![[1; 20], [4; 20]]).await.unwrap(),
+        vec![[4; 20]]
+    );
+    // a rolled back block releases its items
+    assert_eq!(index.queue(3, vec![[4; 20]]).await.unwrap(), vec![[4; 20]]);
+    assert_eq!(index.len().await, 4);
+}
+
+#[tokio::test]
+async fn subscribe() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("subscribe.db")).await;
+    let mut committed = index.subscribe();
+    index.queue(1, vec![[1; 20], [2; 20]]).await.unwrap();
+    index.queue(2, vec![[3; 20]]).await.unwrap();
+    assert!(committed.try_recv().is_err());
+    index.commit(2).await.unwrap();
+    assert_eq!(committed.recv().await.unwrap(), (0, [1; 20], 1));
+    assert_eq!(committed.recv().await.unwrap(), (1, [2; 20], 1));
+    assert_eq!(committed.recv().await.unwrap(), (2, [3; 20], 2));
+}
 
-        let t = std::time::Instant::now();
-        let mut items = Vec::new();
-        for _ in 0..GET_ITERATIONS {
-            let key = gen.gen::<u32>() % index.len().await as u32;
-            let v = index.get(key as usize).await.expect("get");
-            assert!(v.is_some());
-            items.push(v.unwrap());
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn reorg_during_commit() {
+    let temp_dir = tempdir().unwrap();
+    let item = |block: u64, i: u64| {
+        let mut item = [0; 20];
+        item[..8].copy_from_slice(&block.to_le_bytes());
+        item[8..16].copy_from_slice(&i.to_le_bytes());
+        item
+    };
+    // the reorg lands before, during or after the snapshot of the commit
+    for delay in 0..8 {
+        let index =
+            std::sync::Arc::new(open_index(temp_dir.path().join(format!("{}.db", delay))).await);
+        for block in 1..=20 {
+            let items = (0..50).map(|i| item(block, i)).collect();
+            index.queue(block, items).await.unwrap();
+        }
+        let committer = {
+            let index = index.clone();
+            tokio::spawn(async move { index.commit(20).await })
+        };
+        for _ in 0..delay {
+            tokio::task::yield_now().await;
+        }
+        let reorg = index.queue(10, vec![[0xee; 20]]).await;
+        committer.await.unwrap().unwrap();
+        let (_, block_10) = index.block_entries(10).unwrap().unwrap();
+        match reorg {
+            // queued before the snapshot: the new block is committed
+            Ok(_) => {
+                assert_eq!(block_10, vec![[0xee; 20]]);
+                assert_eq!(index.get_counters().await.last_committed_block, 10);
+            }
+            // refused once the blocks are being committed: the old one stays
+            Err(MoniqueError::Reorg { block: 10, .. }) => {
+                assert_eq!(block_10, (0..50).map(|i| item(10, i)).collect::<Vec<_>>());
+                assert_eq!(index.get_counters().await.last_committed_block, 20);
+            }
+            Err(e) => panic!("unexpected error {}", e),
         }
-        println!(
-            "get: {:?}",
-            t.elapsed().as_nanos() / (GET_ITERATIONS as u128)
+        assert_eq!(index.len().await, index.committed_len().await);
+    }
+}
+
+#[tokio::test]
+async fn subscribe_entries_lagging() {
+    let temp_dir = tempdir().unwrap();
+    let index = std::sync::Arc::new(open_index(temp_dir.path().join("entries.db")).await);
+    let mut entries = index.subscribe_entries();
+    let item = |n: usize| {
+        let mut item = [0xaa; 20];
+        item[..8].copy_from_slice(&(n as u64).to_le_bytes());
+        item
+    };
+    // more entries than the broadcast channel holds
+    let per_block = 40_000;
+    for block in 1..=3 {
+        let items = (0..per_block).map(|n| item((block - 1) * per_block + n));
+        index.queue(block as u64, items.collect()).await.unwrap();
+    }
+    index.commit(3).await.unwrap();
+    for n in 0..3 * per_block {
+        let (index, entry, block) = entries.recv().await.unwrap().unwrap();
+        assert_eq!(
+            (index, entry, block),
+            (n, item(n), (n / per_block + 1) as u64)
         );
+    }
+    index.queue(4, vec![[1; 20]]).await.unwrap();
+    index.commit(4).await.unwrap();
+    assert_eq!(
+        entries.recv().await.unwrap(),
+        Some((3 * per_block, [1; 20], 4))
+    );
+}
+
+#[tokio::test]
+async fn rollback_events() {
+    let temp_dir = tempdir().unwrap();
+    let index = std::sync::Arc::new(open_index(temp_dir.path().join("rollbacks.db")).await);
+    let mut rollbacks = index.subscribe_rollbacks();
+    let mut entries = index.subscribe_entries();
+    for block in 1..=3 {
+        index.queue(block, vec![[block as u8; 20]]).await.unwrap();
+    }
+    index.commit(3).await.unwrap();
+    assert_eq!(rollbacks.take(), None);
 
-        let t = std::time::Instant::now();
-        for i in &items {
-            let key = index.index(i.clone()).await.expect("index");
-            assert!(key.is_some());
+    // the lowest block of the rollbacks since the last take
+    index.rollback(2).await.unwrap();
+    index.rollback(1).await.unwrap();
+    assert_eq!(rollbacks.take(), Some(1));
+    assert_eq!(rollbacks.take(), None);
+
+    // the entries broadcast before the rollback are not delivered
+    index.queue(2, vec![[4; 20]]).await.unwrap();
+    index.commit(2).await.unwrap();
+    assert_eq!(entries.recv().await.unwrap(), Some((0, [1; 20], 1)));
+    assert_eq!(entries.recv().await.unwrap(), Some((1, [4; 20], 2)));
+    index.queue(3, vec![[5; 20]]).await.unwrap();
+    index.commit(3).await.unwrap();
+    assert_eq!(entries.recv().await.unwrap(), Some((2, [5; 20], 3)));
+}
+
+#[tokio::test]
+async fn batched_commit() {
+    let temp_dir = tempdir().unwrap();
+    let open = |name: &str| {
+        IndexTable::<20, [u8; 20]>::builder(temp_dir.path().join(name))
+            .cache_size(16)
+            .build()
+    };
+    let (batched, sequential) = (
+        open("batched.db").await.unwrap(),
+        open("sequential.db").await.unwrap(),
+    );
+    // blocks larger than a commit batch, pushed in several batches by a single commit
+    for number in 1..=3u64 {
+        let items: Vec<[u8; 20]> = (0..COMMIT_BATCH_SIZE as u32 * 2 / 3)
+            .map(|i| {
+                let mut item = [0; 20];
+                item[..8].copy_from_slice(&number.to_be_bytes());
+                item[8..12].copy_from_slice(&i.to_be_bytes());
+                item
+            })
+            .collect();
+        batched.queue(number, items.clone()).await.unwrap();
+        sequential.queue(number, items).await.unwrap();
+        sequential.commit(number).await.unwrap();
+    }
+    let mut commits = batched.subscribe_commits();
+    assert_eq!(
+        batched.commit(3).await.unwrap(),
+        3 * (COMMIT_BATCH_SIZE * 2 / 3)
+    );
+    assert_eq!(*commits.borrow_and_update(), 3);
+    assert_eq!(batched.len().await, sequential.len().await);
+    for number in 1..=3 {
+        assert_eq!(
+            batched.checkpoint(number).unwrap(),
+            sequential.checkpoint(number).unwrap()
+        );
+    }
+}
+
+#[tokio::test]
+async fn metrics() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("metrics.db")).await;
+    index
+        .queue(1, vec![[1; 20], [2; 20], [1; 20]])
+        .await
+        .unwrap();
+    index.queue(2, vec![[2; 20], [3; 20]]).await.unwrap();
+    let metrics = index.metrics().await;
+    assert_eq!(metrics.queued_blocks, 2);
+    assert_eq!(metrics.queued_items, 5);
+    assert_eq!(metrics.duplicate_items, 2);
+    assert_eq!(metrics.duplicate_ratio(), 0.4);
+    assert_eq!(metrics.pending_blocks, 2);
+    assert_eq!(metrics.pending_items, 3);
+    assert_eq!(metrics.pending_bytes, 3 * (2 * 20 + 8));
+    assert_eq!(index.pending_memory().await, metrics.pending_bytes);
+
+    index.commit(1).await.unwrap();
+    let metrics = index.metrics().await;
+    assert_eq!(metrics.commits, 1);
+    assert_eq!(metrics.committed_items, 2);
+    assert_eq!(metrics.pending_blocks, 1);
+    assert_eq!(metrics.pending_items, 1);
+    assert_eq!(metrics.pending_bytes, 2 * 20 + 8);
+}
+
+#[tokio::test]
+async fn spill() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("spill.db")).await;
+    index.queue(1, vec![[1; 20], [2; 20]]).await.unwrap();
+    index.queue(2, vec![[3; 20]]).await.unwrap();
+    index.queue(3, vec![[4; 20], [5; 20]]).await.unwrap();
+    // room for the items of the last two blocks
+    assert_eq!(index.spill(3 * (2 * 20 + 8)).await.unwrap(), 1);
+    let metrics = index.metrics().await;
+    assert_eq!((metrics.pending_blocks, metrics.spilled_blocks), (2, 1));
+    assert_eq!(metrics.pending_items, 3);
+    assert_eq!(index.len().await, 5);
+    for n in 1..=5u8 {
+        assert_eq!(index.get(n as usize - 1).await.unwrap(), Some([n; 20]));
+        assert_eq!(index.index([n; 20]).await.unwrap(), Some(n as usize - 1));
+    }
+
+    // duplicates with spilled blocks
+    assert_eq!(
+        index.queue(4, vec![[2; 20], [6; 20]]).await.unwrap(),
+        vec![[6; 20]]
+    );
+    assert_eq!(index.spill(0).await.unwrap(), 3);
+    assert_eq!(index.pending_memory().await, 0);
+    assert_eq!(index.index([6; 20]).await.unwrap(), Some(5));
+
+    // a reorg drops spilled blocks
+    index.queue(3, vec![[7; 20]]).await.unwrap();
+    assert_eq!(index.len().await, 4);
+    assert_eq!(index.index([4; 20]).await.unwrap(), None);
+    assert_eq!(index.index([7; 20]).await.unwrap(), Some(3));
+    let dropped: Vec<(u64, Vec<[u8; 20]>)> = index
+        .tombstones(0)
+        .unwrap()
+        .into_iter()
+        .map(|tombstone| (tombstone.block, tombstone.items))
+        .collect();
+    assert_eq!(
+        dropped,
+        vec![(3, vec![[4; 20], [5; 20]]), (4, vec![[6; 20]])]
+    );
+
+    // commits read the spilled blocks
+    index.commit(3).await.unwrap();
+    assert_eq!(index.metrics().await.spilled_blocks, 0);
+    assert_eq!(index.len().await, 4);
+    for (i, item) in [[1; 20], [2; 20], [3; 20], [7; 20]].iter().enumerate() {
+        assert_eq!(index.get(i).await.unwrap(), Some(*item));
+        assert_eq!(index.index(*item).await.unwrap(), Some(i));
+    }
+    assert!(index.queue(4, vec![[3; 20]]).await.unwrap().is_empty());
+    index.check().unwrap();
+}
+
+#[tokio::test]
+async fn checkpoints() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("checkpoints.db")).await;
+    index.queue(1, vec![[1; 20]]).await.unwrap();
+    index.queue(2, vec![]).await.unwrap();
+    index.commit(2).await.unwrap();
+    assert_eq!(index.checkpoint(3).unwrap(), None);
+    let first = index.checkpoint(1).unwrap().unwrap();
+    let second = index.checkpoint(2).unwrap().unwrap();
+    assert_eq!(first.block, 1);
+    assert_ne!(first.hash, second.hash);
+    assert_eq!(&second.message()[..8], &2u64.to_be_bytes());
+
+    assert_eq!(index.get_signature(1).unwrap(), None);
+    index.put_signatures(vec![(1, [7; 65])]).unwrap();
+    assert_eq!(index.get_signature(1).unwrap(), Some([7; 65]));
+}
+
+#[tokio::test]
+async fn multi_proofs() {
+    for persist in [false, true] {
+        let temp_dir = tempdir().unwrap();
+        let index = IndexTable::<20, [u8; 20]>::builder(temp_dir.path().join("multi.db"))
+            .cache_size(16)
+            .persist_tries(persist)
+            .build()
+            .await
+            .unwrap();
+        let items: Vec<[u8; 20]> = (0..30u8).map(|i| [i; 20]).collect();
+        index.queue(1, items).await.unwrap();
+        index.queue(2, vec![[100; 20], [101; 20]]).await.unwrap();
+        index.commit(2).await.unwrap();
+
+        let unlimited = ScanBudget {
+            entries: usize::MAX,
+            duration: Duration::from_secs(60),
+        };
+        let (proofs, missing, next) = index
+            .multi_proof(&[31, 3, 40, 7, 3, 30], unlimited)
+            .await
+            .unwrap();
+        assert_eq!((missing, next), (vec![40], None));
+        assert_eq!(proofs.len(), 2);
+        assert_eq!(proofs[0].block, 1);
+        assert_eq!(proofs[0].entries, vec![(3, [3; 20]), (7, [7; 20])]);
+        assert_eq!(Some(proofs[0].root), index.checkpoint_root(1).unwrap());
+        assert_eq!(proofs[1].entries, vec![(30, [100; 20]), (31, [101; 20])]);
+        // shared nodes are only included once
+        let (_, single) = index.proof(3).await.unwrap().unwrap();
+        let (_, other) = index.proof(7).await.unwrap().unwrap();
+        assert!(proofs[0].nodes.len() < single.nodes.len() + other.nodes.len());
+        assert!(single
+            .nodes
+            .iter()
+            .all(|node| proofs[0].nodes.contains(node)));
+
+        #[cfg(feature = "verify")]
+        {
+            use crate::verify::verify_multi_proof;
+            use crate::words::PIVOT;
+
+            let pivoted = |entries: &[(usize, [u8; 20])]| -> Vec<(usize, [u8; 20])> {
+                entries.iter().map(|(i, item)| (i + PIVOT, *item)).collect()
+            };
+            for proof in proofs.iter() {
+                let entries = pivoted(&proof.entries);
+                assert!(verify_multi_proof(
+                    proof.root,
+                    &entries,
+                    proof.nodes.clone()
+                ));
+            }
+            let swapped = vec![(PIVOT + 3, [7; 20]), (PIVOT + 7, [3; 20])];
+            assert!(!verify_multi_proof(
+                proofs[0].root,
+                &swapped,
+                proofs[0].nodes.clone()
+            ));
         }
-        println!(
-            "index: {:?}",
-            t.elapsed().as_nanos() / (items.len() as u128)
+
+        // the first block is proven whatever the budget, then it is spent
+        let budget = ScanBudget {
+            entries: 30,
+            ..unlimited
+        };
+        let (proofs, missing, next) = index.multi_proof(&[31, 3, 40], budget).await.unwrap();
+        assert_eq!(proofs.len(), 1);
+        assert_eq!(proofs[0].entries, vec![(3, [3; 20])]);
+        assert_eq!((missing, next), (vec![], Some(31)));
+    }
+}
+
+#[tokio::test]
+async fn proofs() {
+    let temp_dir = tempdir().unwrap();
+    let index = open_index(temp_dir.path().join("proofs.db")).await;
+    index.queue(1, vec![[1; 20], [2; 20]]).await.unwrap();
+    index.queue(2, vec![]).await.unwrap();
+    index.queue(3, vec![[3; 20]]).await.unwrap();
+    index.commit(3).await.unwrap();
+    assert_eq!(index.proof(3).await.unwrap(), None);
+
+    let (item, proof) = index.proof(1).await.unwrap().unwrap();
+    assert_eq!(item, [2; 20]);
+    assert_eq!(proof.block, 1);
+    assert_eq!(Some(proof.root), index.checkpoint_root(1).unwrap());
+    let (item, proof) = index.proof(2).await.unwrap().unwrap();
+    assert_eq!(item, [3; 20]);
+    assert_eq!(proof.block, 3);
+
+    #[cfg(feature = "verify")]
+    {
+        use crate::verify::{verify_proof, verify_resolution};
+        use crate::words::{self, PIVOT};
+
+        assert!(verify_proof(
+            proof.root,
+            PIVOT + 2,
+            item,
+            proof.nodes.clone()
+        ));
+        assert!(!verify_proof(
+            proof.root,
+            PIVOT + 1,
+            item,
+            proof.nodes.clone()
+        ));
+        assert!(!verify_proof(
+            proof.root,
+            PIVOT + 2,
+            [2; 20],
+            proof.nodes.clone()
+        ));
+        let monic = words::to_words((PIVOT + 2) as u64, words::checksum(item));
+        assert!(verify_resolution(&monic, item, proof.root, proof.nodes.clone()).unwrap());
+        let other = words::to_words((PIVOT + 1) as u64, words::checksum(item));
+        assert!(!verify_resolution(&other, item, proof.root, proof.nodes).unwrap());
+    }
+}
+
+#[tokio::test]
+async fn persisted_proofs() {
+    let temp_dir = tempdir().unwrap();
+    let persisted = IndexTable::<20, [u8; 20]>::builder(temp_dir.path().join("persisted.db"))
+        .cache_size(16)
+        .persist_tries(true)
+        .build()
+        .await
+        .unwrap();
+    let rebuilt = open_index(temp_dir.path().join("rebuilt.db")).await;
+    let items: Vec<[u8; 20]> = (0..50u8).map(|i| [i; 20]).collect();
+    for index in [&persisted, &rebuilt] {
+        index.queue(1, items.clone()).await.unwrap();
+        index.commit(1).await.unwrap();
+    }
+    let root = persisted.checkpoint_root(1).unwrap().unwrap();
+    assert!(persisted
+        .storage
+        .get_trie_node(root.as_bytes())
+        .unwrap()
+        .is_some());
+    assert_eq!(
+        rebuilt.storage.get_trie_node(root.as_bytes()).unwrap(),
+        None
+    );
+    for i in [0, 17, 49] {
+        assert_eq!(
+            persisted.proof(i).await.unwrap(),
+            rebuilt.proof(i).await.unwrap()
         );
     }
 }
+
+#[tokio::test]
+async fn index_roots() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("roots.db");
+    let mut accumulator = Accumulator::default();
+    {
+        let index = open_index(&path).await;
+        index.queue(1, vec![[1; 20], [2; 20]]).await.unwrap();
+        index.queue(2, vec![]).await.unwrap();
+        index.commit(2).await.unwrap();
+        accumulator.push(&[1; 20]);
+        accumulator.push(&[2; 20]);
+        assert_eq!(index.index_root(1).unwrap(), Some(accumulator.root()));
+        assert_eq!(index.index_root(2).unwrap(), Some(accumulator.root()));
+        assert_eq!(index.index_root(3).unwrap(), None);
+    }
+    // the accumulator is carried over when the database is reopened
+    let index = open_index(&path).await;
+    index.queue(3, vec![[3; 20]]).await.unwrap();
+    index.commit(3).await.unwrap();
+    accumulator.push(&[3; 20]);
+    assert_eq!(index.index_root(3).unwrap(), Some(accumulator.root()));
+}
+
+/// Writer process of `reader_refresh`, as mdbx opens a database once per process.
+#[tokio::test]
+#[ignore = "run by reader_refresh"]
+async fn reader_refresh_writer() {
+    let Ok(path) = std::env::var("MONIQUE_TEST_WRITER") else {
+        return;
+    };
+    let writer = open_index(path).await;
+    writer.queue(1, vec![[1; 20], [2; 20]]).await.unwrap();
+    writer.commit(1).await.unwrap();
+    println!("writer: ready");
+    for line in std::io::stdin().lines() {
+        match line.unwrap().as_str() {
+            "commit" => {
+                writer.queue(2, vec![[3; 20]]).await.unwrap();
+                writer.queue(3, vec![[4; 20], [5; 20]]).await.unwrap();
+                writer.commit(3).await.unwrap();
+            }
+            "rollback" => {
+                writer.rollback(2).await.unwrap();
+                writer.queue(3, vec![[5; 20], [4; 20]]).await.unwrap();
+                writer.commit(3).await.unwrap();
+            }
+            _ => break,
+        }
+        println!("writer: ready");
+    }
+}
+
+#[tokio::test]
+async fn reader_refresh() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::process::{Command, Stdio};
+
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("shared.db");
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "index::tests::reader_refresh_writer"])
+        .args(["--ignored", "--nocapture", "--test-threads=1"])
+        .env("MONIQUE_TEST_WRITER", &path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut send = |command: &str| {
+        writeln!(stdin, "{}", command).unwrap();
+    };
+    let mut ready = || {
+        stdout
+            .find(|line| line.as_ref().unwrap().ends_with("writer: ready"))
+            .expect("writer process failed")
+            .unwrap();
+    };
+    ready();
+
+    // a single writer
+    let err = IndexTable::<20, [u8; 20]>::builder(&path)
+        .cache_size(16)
+        .build()
+        .await
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("another process"));
+
+    let reader = IndexTable::<20, [u8; 20]>::builder(&path)
+        .cache_size(16)
+        .read_only(true)
+        .build()
+        .await
+        .unwrap();
+    let mut entries = reader.subscribe();
+    let mut commits = reader.subscribe_commits();
+    let mut rollbacks = reader.subscribe_rollbacks();
+    assert_eq!(reader.len().await, 2);
+    assert_eq!(reader.refresh().await.unwrap(), 1);
+    assert_eq!(reader.index([2; 20]).await.unwrap(), Some(1));
+    assert_eq!(reader.index([5; 20]).await.unwrap(), None);
+
+    send("commit");
+    ready();
+    assert_eq!(reader.len().await, 2);
+    assert_eq!(reader.refresh().await.unwrap(), 3);
+    assert_eq!(reader.len().await, 5);
+    assert_eq!(*commits.borrow_and_update(), 3);
+    assert_eq!(reader.index([5; 20]).await.unwrap(), Some(4));
+    for expected in [(2, [3; 20], 2), (3, [4; 20], 3), (4, [5; 20], 3)] {
+        assert_eq!(entries.try_recv().unwrap(), expected);
+    }
+    let hash = reader.block_hash(3).unwrap();
+
+    // the cached lookups of the rolled back entries are dropped
+    send("rollback");
+    ready();
+    assert_eq!(rollbacks.take(), None);
+    assert_eq!(reader.refresh().await.unwrap(), 3);
+    // the writer rolled back to block 2 before committing block 3 again
+    assert_eq!(rollbacks.take(), Some(2));
+    assert_eq!(reader.index([5; 20]).await.unwrap(), Some(3));
+    assert_eq!(reader.get(4).await.unwrap(), Some([4; 20]));
+    assert_ne!(reader.block_hash(3).unwrap(), hash);
+    reader.check().unwrap();
+
+    send("exit");
+    assert!(child.wait().unwrap().success());
+    drop(reader);
+    // the lock is released with the writer
+    let writer = open_index(&path).await;
+    assert!(writer.refresh().await.is_err());
+}
@@ -1,10 +1,14 @@
 use ethers::{
-    providers::{Middleware, Provider, Ws},
-    types::{Address, Block, TxHash},
+    providers::{JsonRpcClient, Middleware, Provider},
+    types::{Action, Address, Block, BlockNumber, Res, TxHash, H256},
+    utils::{hex, keccak256},
 };
 use hex_literal::hex;
-use indexmap::IndexSet;
-use log::{error, trace};
+use indexmap::IndexMap;
+use tracing::{error, trace};
+
+use crate::index::Appearance;
+use crate::{MoniqueError, Result};
 
 const TRANSFER_LOG: [u8; 32] =
     /* Transfer(address,address,uint256) */
@@ -16,17 +20,104 @@ const TRANSFERBATCH_LOG: [u8; 32] =
     /* TransferBatch(address,address,address,uint256[],uint256[]) */
     hex!("4a39dc06d4c0dbc64b70af90fd698a233a518aa5d07e595d983b8c0526c8f7fb");
 
-pub(crate) async fn process(
-    provider: &Provider<Ws>,
+/// Where an address was first seen in a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    Miner,
+    Sender,
+    /// Transaction recipient, or the created contract
+    Recipient,
+    /// ERC-20 and ERC-721 `Transfer` logs
+    Erc20,
+    /// ERC-1155 `TransferSingle` and `TransferBatch` logs
+    Erc1155,
+    /// Internal calls, contract creations and self-destructs, with `--traces`
+    Trace,
+    Withdrawal,
+    /// Account allocated in the genesis block, from the `--network` profile
+    Genesis,
+}
+
+impl Source {
+    pub const ALL: [Source; 8] = [
+        Source::Miner,
+        Source::Sender,
+        Source::Recipient,
+        Source::Erc20,
+        Source::Erc1155,
+        Source::Trace,
+        Source::Withdrawal,
+        Source::Genesis,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Source::Miner => "miner",
+            Source::Sender => "sender",
+            Source::Recipient => "recipient",
+            Source::Erc20 => "erc20",
+            Source::Erc1155 => "erc1155",
+            Source::Trace => "trace",
+            Source::Withdrawal => "withdrawal",
+            Source::Genesis => "genesis",
+        }
+    }
+}
+
+/// Version of the extraction rules, to bump whenever `process` changes the addresses
+/// it returns, or their order.
+const RULES_VERSION: u32 = 1;
+
+/// Identifier of the extraction rules: hash of their version, of the sources in the
+/// order `process` reads them, and of the log signatures it decodes. Genesis
+/// allocations, when indexed with the start block, and traces, when read, are part
+/// of the rules.
+pub fn ruleset(genesis: &[Address], traces: bool) -> H256 {
+    let mut rules = format!("monique/{}", RULES_VERSION);
+    for source in Source::ALL
+        .iter()
+        .filter(|source| **source != Source::Genesis)
+        .filter(|source| traces || **source != Source::Trace)
+    {
+        rules += &format!(":{}", source.name());
+    }
+    for signature in [TRANSFER_LOG, TRANSFERSINGLE_LOG, TRANSFERBATCH_LOG] {
+        rules += &format!(":{}", hex::encode(signature));
+    }
+    if !genesis.is_empty() {
+        let allocations: Vec<u8> = genesis.iter().flat_map(|a| a.to_fixed_bytes()).collect();
+        rules += &format!(":genesis:{}", hex::encode(keccak256(allocations)));
+    }
+    H256(keccak256(rules))
+}
+
+/// Ordered set of addresses referenced in a block, tagged with the source
+/// they were first seen in, and the transaction of that first appearance.
+#[derive(Default)]
+struct Appearances(IndexMap<Address, (Source, Option<Appearance>)>);
+
+impl Appearances {
+    fn insert(&mut self, address: Address, source: Source, appearance: Option<Appearance>) {
+        self.0.entry(address).or_insert((source, appearance));
+    }
+}
+
+/// Addresses referenced by a block, in the order they first appear: miner, senders
+/// and recipients of its transactions, transfer logs of their receipts, the calls of
+/// their traces with `traces`, then withdrawals. Addresses found in a transaction
+/// come with it, and with the log they were found in.
+pub async fn process<P: JsonRpcClient>(
+    provider: &Provider<P>,
     block: &Block<TxHash>,
-) -> Result<Vec<Address>, Box<dyn std::error::Error + Send + Sync>> {
+    traces: bool,
+) -> Result<Vec<(Address, Source, Option<Appearance>)>> {
     let number = block.number.unwrap().as_u64();
 
     // add the block miner
-    let mut list = IndexSet::with_capacity(500);
-    list.insert(block.author.unwrap());
+    let mut list = Appearances(IndexMap::with_capacity(500));
+    list.insert(block.author.unwrap(), Source::Miner, None);
 
-    if block.transactions.len() > 0 {
+    if !block.transactions.is_empty() {
         let receipts = provider.get_block_receipts(number).await?;
 
         if receipts.len() != block.transactions.len() {
@@ -34,36 +125,50 @@ pub(crate) async fn process(
                 "mismatched number of transactions and receipts in block {}",
                 number
             );
-            return Err("bad block received".into());
+            return Err(MoniqueError::BadBlock(number));
         }
 
         for tx in receipts {
+            let appearance = Appearance {
+                transaction: tx.transaction_hash,
+                log_index: None,
+            };
             // add the tx sender
-            list.insert(tx.from);
+            list.insert(tx.from, Source::Sender, Some(appearance));
             if let Some(to) = tx.to {
                 // add the tx recipient
-                list.insert(to);
+                list.insert(to, Source::Recipient, Some(appearance));
             } else if let Some(to) = tx.contract_address {
                 // ad the created contract address
-                list.insert(to);
+                list.insert(to, Source::Recipient, Some(appearance));
             }
             for log in tx.logs {
                 if log.topics.len() > 2 {
                     let signature = log.topics[0].to_fixed_bytes();
-                    let addrs = match signature {
-                        TRANSFER_LOG => vec![
-                            Address::from_slice(&log.topics[1].as_bytes()[12..]), // from
-                            Address::from_slice(&log.topics[2].as_bytes()[12..]), // to
-                        ],
-                        TRANSFERSINGLE_LOG | TRANSFERBATCH_LOG => vec![
-                            Address::from_slice(&log.topics[1].as_bytes()[12..]), // operator
-                            Address::from_slice(&log.topics[2].as_bytes()[12..]), // from
-                            Address::from_slice(&log.topics[3].as_bytes()[12..]), // to
-                        ],
-                        _ => vec![],
+                    let (addrs, source) = match signature {
+                        TRANSFER_LOG => (
+                            vec![
+                                Address::from_slice(&log.topics[1].as_bytes()[12..]), // from
+                                Address::from_slice(&log.topics[2].as_bytes()[12..]), // to
+                            ],
+                            Source::Erc20,
+                        ),
+                        TRANSFERSINGLE_LOG | TRANSFERBATCH_LOG => (
+                            vec![
+                                Address::from_slice(&log.topics[1].as_bytes()[12..]), // operator
+                                Address::from_slice(&log.topics[2].as_bytes()[12..]), // from
+                                Address::from_slice(&log.topics[3].as_bytes()[12..]), // to
+                            ],
+                            Source::Erc1155,
+                        ),
+                        _ => (vec![], Source::Erc20),
+                    };
+                    let appearance = Appearance {
+                        log_index: log.log_index.map(|index| index.as_u64()),
+                        ..appearance
                     };
                     for addr in addrs {
-                        list.insert(addr);
+                        list.insert(addr, source, Some(appearance));
                     }
                 }
             }
@@ -72,27 +177,58 @@ pub(crate) async fn process(
         trace!("no transactions in block {}", number);
     }
 
+    if traces && !block.transactions.is_empty() {
+        let traces = provider
+            .trace_block(BlockNumber::Number(number.into()))
+            .await?;
+        // reverted calls left no trace on the state
+        for trace in traces.into_iter().filter(|trace| trace.error.is_none()) {
+            let appearance = trace.transaction_hash.map(|transaction| Appearance {
+                transaction,
+                log_index: None,
+            });
+            let addresses = match (trace.action, trace.result) {
+                (Action::Call(call), _) => vec![call.from, call.to],
+                (Action::Create(create), Some(Res::Create(created))) => {
+                    vec![create.from, created.address]
+                }
+                (Action::Suicide(suicide), _) => vec![suicide.address, suicide.refund_address],
+                // the rewarded miner is already listed
+                _ => vec![],
+            };
+            for address in addresses {
+                list.insert(address, Source::Trace, appearance);
+            }
+        }
+    }
+
     if let Some(withdrawals) = &block.withdrawals {
         for withdrawal in withdrawals {
             // add the withdrawal recipient
-            list.insert(withdrawal.address);
+            list.insert(withdrawal.address, Source::Withdrawal, None);
         }
     }
 
-    Ok(list.into_iter().collect())
+    Ok(list
+        .0
+        .into_iter()
+        .map(|(address, (source, appearance))| (address, source, appearance))
+        .collect())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::indexer::mock::{fixtures_dir, Fixtures};
     use ethers::{
-        providers::{Middleware, Provider},
+        providers::Ws,
         types::{BlockId, BlockNumber},
     };
+    use serde_json::{json, Value};
     use std::env;
     use tiny_keccak::{Hasher, Keccak};
 
-    async fn provider() -> Result<Provider<Ws>, Box<dyn std::error::Error>> {
+    async fn provider() -> std::result::Result<Provider<Ws>, Box<dyn std::error::Error>> {
         let provider_env = env::var("PROVIDER_RPC_URL");
         let provider_url = match provider_env {
             Ok(provider_url) => provider_url,
@@ -106,27 +242,39 @@ mod tests {
         Ok(Provider::<Ws>::connect(provider_url).await?)
     }
 
+    #[test]
+    fn test_ruleset() {
+        // changing the rules must bump their version, and this hash
+        assert_eq!(
+            format!("{:?}", ruleset(&[], false)),
+            "0x7d774cb6205fcd8897ddd022ec89f32ba9f5e6927ec76fbf64652e599c4587e3"
+        );
+        let genesis = ruleset(&[Address::from_low_u64_be(1)], false);
+        assert_ne!(genesis, ruleset(&[], false));
+        assert_ne!(genesis, ruleset(&[Address::from_low_u64_be(2)], false));
+        assert_ne!(ruleset(&[], true), ruleset(&[], false));
+    }
+
     #[tokio::test]
     async fn test_genesis() {
-        let provider = provider().await.unwrap();
+        let provider = Fixtures::load(&fixtures_dir(), &[0]).unwrap().provider();
         let genesis = BlockId::Number(BlockNumber::Number(0.into()));
         let block = provider.get_block(genesis).await.unwrap().unwrap();
-        let addresses = process(&provider, &block).await.unwrap();
+        let addresses = process(&provider, &block, false).await.unwrap();
         assert_eq!(addresses.len(), 1);
-        assert_eq!(addresses[0], Address::zero());
+        assert_eq!(addresses[0], (Address::zero(), Source::Miner, None));
     }
 
-    async fn multi_test(blocks: Vec<(u64, &str)>) {
-        let provider = provider().await.unwrap();
+    async fn multi_test<P: JsonRpcClient>(provider: &Provider<P>, blocks: Vec<(u64, &str)>) {
         for (block, expected) in blocks {
             let block = provider
                 .get_block(BlockId::Number(block.into()))
                 .await
                 .unwrap()
                 .unwrap();
-            let set = process(&provider, &block).await.unwrap();
+            let set = process(provider, &block, false).await.unwrap();
             let mut h = Keccak::v256();
-            for addr in &set {
+            for (addr, ..) in &set {
                 h.update(addr.as_bytes());
             }
             let mut hash = [0u8; 32];
@@ -141,26 +289,230 @@ mod tests {
         }
     }
 
+    const RECORDED: [(u64, &str); 3] = [
+        (
+            0,
+            "5380c7b7ae81a58eb98d9c78de4a1fd7fd9535fc953ed2be602daaa41767312a",
+        ),
+        (
+            123,
+            "c627e342bba2807022514c2d522e22ba66f911653d1abcc74bd0a7868ad3cb36",
+        ),
+        (
+            46147,
+            "6f98c2dc68fbed0867534669f39046b52aae56b00b498efe5b7e7ee140cff127",
+        ),
+    ];
+
     #[tokio::test]
     async fn test_multi() {
-        multi_test(vec![
-            (
+        let provider = Fixtures::load(&fixtures_dir(), &RECORDED.map(|(number, _)| number))
+            .unwrap()
+            .provider();
+        multi_test(&provider, RECORDED.to_vec()).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an archive node at PROVIDER_RPC_URL"]
+    async fn test_multi_node() {
+        let mut blocks = RECORDED.to_vec();
+        blocks.push((
+            17464418,
+            "6ea4a6eb22f833b1c60059c48861a49b4d71baa4bff8ffc644a69d21e2129324",
+        ));
+        multi_test(&provider().await.unwrap(), blocks).await;
+    }
+
+    /// Records the blocks listed in `MONIQUE_RECORD_BLOCKS` (comma separated) from the
+    /// node at `PROVIDER_RPC_URL` into the fixtures.
+    #[tokio::test]
+    #[ignore = "needs an archive node at PROVIDER_RPC_URL"]
+    async fn record_fixtures() {
+        let provider = provider().await.unwrap();
+        let blocks = env::var("MONIQUE_RECORD_BLOCKS").unwrap_or_default();
+        for number in blocks.split(',').filter(|number| !number.is_empty()) {
+            let number: u64 = number.trim().parse().unwrap();
+            let id = format!("{:#x}", number);
+            let block: Value = provider
+                .request("eth_getBlockByNumber", (&id, false))
+                .await
+                .unwrap();
+            let receipts: Value = provider
+                .request("eth_getBlockReceipts", [&id])
+                .await
+                .unwrap();
+            let fixture = json!({ "block": block, "receipts": receipts });
+            let path = fixtures_dir().join(format!("{}.json", number));
+            std::fs::write(&path, serde_json::to_string_pretty(&fixture).unwrap()).unwrap();
+            println!("recorded block {} to {}", number, path.display());
+        }
+    }
+
+    fn topic(address: &str) -> String {
+        format!("0x{:0>64}", address.trim_start_matches("0x"))
+    }
+
+    #[tokio::test]
+    async fn test_sources() {
+        let bloom = format!("0x{}", "0".repeat(512));
+        let receipt = |index: u64, from: &str, to: Value, created: Value, logs: Value| {
+            json!({
+                "transactionHash": format!("0x{:064x}", index + 1),
+                "transactionIndex": format!("{:#x}", index),
+                "from": from,
+                "to": to,
+                "contractAddress": created,
+                "cumulativeGasUsed": "0x5208",
+                "logs": logs,
+                "logsBloom": bloom,
+            })
+        };
+        let log = |index: u64, signature: [u8; 32], topics: &[&str]| {
+            let mut all = vec![format!("0x{}", hex::encode(signature))];
+            all.extend(topics.iter().map(|address| topic(address)));
+            json!({
+                "address": "0x00000000000000000000000000000000000000f0",
+                "topics": all,
+                "data": "0x",
+                "logIndex": format!("{:#x}", index),
+            })
+        };
+        let block = json!({
+            "number": "0x1",
+            "miner": "0x0000000000000000000000000000000000000001",
+            "transactions": [format!("0x{:064x}", 1), format!("0x{:064x}", 2)],
+            "withdrawals": [{
+                "index": "0x0",
+                "validatorIndex": "0x0",
+                "address": "0x0000000000000000000000000000000000000009",
+                "amount": "0x1",
+            }],
+        });
+        let receipts = json!([
+            receipt(
                 0,
-                "5380c7b7ae81a58eb98d9c78de4a1fd7fd9535fc953ed2be602daaa41767312a",
+                "0x0000000000000000000000000000000000000002",
+                json!("0x0000000000000000000000000000000000000003"),
+                Value::Null,
+                json!([
+                    log(0, TRANSFER_LOG, &["0x02", "0x04"]),
+                    log(1, TRANSFERSINGLE_LOG, &["0x02", "0x05", "0x06"]),
+                    // not a transfer
+                    log(2, [0; 32], &["0x07", "0x08"]),
+                ]),
+            ),
+            receipt(
+                1,
+                "0x0000000000000000000000000000000000000001",
+                Value::Null,
+                json!("0x000000000000000000000000000000000000000a"),
+                json!([]),
             ),
-            (
-                123,
-                "c627e342bba2807022514c2d522e22ba66f911653d1abcc74bd0a7868ad3cb36",
+        ]);
+        let trace = |index: u64, action: Value, result: Value, error: Option<&str>| {
+            let kind = match result.get("address") {
+                Some(_) => "create",
+                None => "call",
+            };
+            let mut trace = json!({
+                "action": action,
+                "result": result,
+                "traceAddress": [0],
+                "subtraces": 0,
+                "transactionPosition": index,
+                "transactionHash": format!("0x{:064x}", index + 1),
+                "blockNumber": 1,
+                "blockHash": format!("0x{:064x}", 0),
+                "type": kind,
+            });
+            if let Some(error) = error {
+                trace["error"] = json!(error);
+            }
+            trace
+        };
+        let call = |from: &str, to: &str| json!({ "callType": "call", "from": from, "to": to, "gas": "0x0", "input": "0x", "value": "0x0" });
+        let traces = json!([
+            trace(
+                0,
+                call(
+                    "0x0000000000000000000000000000000000000003",
+                    "0x000000000000000000000000000000000000000b",
+                ),
+                json!({ "gasUsed": "0x0", "output": "0x" }),
+                None,
             ),
-            (
-                46147,
-                "6f98c2dc68fbed0867534669f39046b52aae56b00b498efe5b7e7ee140cff127",
+            // reverted
+            trace(
+                0,
+                call(
+                    "0x000000000000000000000000000000000000000b",
+                    "0x000000000000000000000000000000000000000c",
+                ),
+                Value::Null,
+                Some("Reverted"),
             ),
-            (
-                17464418,
-                "6ea4a6eb22f833b1c60059c48861a49b4d71baa4bff8ffc644a69d21e2129324",
+            trace(
+                1,
+                json!({ "from": "0x000000000000000000000000000000000000000a", "gas": "0x0", "init": "0x", "value": "0x0" }),
+                json!({ "gasUsed": "0x0", "code": "0x", "address": "0x000000000000000000000000000000000000000d" }),
+                None,
             ),
-        ])
-        .await;
+        ]);
+        let provider = Fixtures::default()
+            .with_block(1, block, receipts)
+            .with_traces(1, traces)
+            .provider();
+        let block = provider.get_block(1).await.unwrap().unwrap();
+        let addresses = process(&provider, &block, false).await.unwrap();
+        let seen = |transaction: u64, log_index: Option<u64>| {
+            Some(Appearance {
+                transaction: H256::from_low_u64_be(transaction),
+                log_index,
+            })
+        };
+        let expected = |traced: &[(u64, Source, Option<Appearance>)]| {
+            let mut expected = vec![
+                (1, Source::Miner, None),
+                (2, Source::Sender, seen(1, None)),
+                (3, Source::Recipient, seen(1, None)),
+                (4, Source::Erc20, seen(1, Some(0))),
+                (5, Source::Erc1155, seen(1, Some(1))),
+                (6, Source::Erc1155, seen(1, Some(1))),
+                (10, Source::Recipient, seen(2, None)),
+            ];
+            expected.extend_from_slice(traced);
+            expected.push((9, Source::Withdrawal, None));
+            expected
+                .into_iter()
+                .map(|(address, source, appearance)| {
+                    (Address::from_low_u64_be(address), source, appearance)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(addresses, expected(&[]));
+
+        let addresses = process(&provider, &block, true).await.unwrap();
+        let traced = [
+            (11, Source::Trace, seen(1, None)),
+            (13, Source::Trace, seen(2, None)),
+        ];
+        assert_eq!(addresses, expected(&traced));
+    }
+
+    #[tokio::test]
+    async fn test_missing_receipts() {
+        let block = json!({
+            "number": "0x1",
+            "miner": "0x0000000000000000000000000000000000000001",
+            "transactions": [format!("0x{:064x}", 1)],
+        });
+        let provider = Fixtures::default()
+            .with_block(1, block, json!([]))
+            .provider();
+        let block = provider.get_block(1).await.unwrap().unwrap();
+        assert!(matches!(
+            process(&provider, &block, false).await,
+            Err(MoniqueError::BadBlock(1))
+        ));
     }
 }
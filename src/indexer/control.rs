@@ -0,0 +1,93 @@
+use crate::Result;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+/// Commands queued before the indexer handles them, between two blocks.
+const CAPACITY: usize = 16;
+
+/// Operator commands sent to a running indexer, e.g. from the admin API.
+#[derive(Debug)]
+pub enum Command {
+    /// Stop indexing new blocks, keeping the pending queue.
+    Pause,
+    Resume,
+    /// Commit the pending blocks up to `block`, even if it is not safe yet.
+    Commit {
+        block: u64,
+        reply: oneshot::Sender<Result<usize>>,
+    },
+    /// Reconnect to the providers, e.g. when a healthier one is available.
+    Reconnect,
+    /// Unwind the committed blocks after `block`, replying with the number of
+    /// entries removed. The indexer then restarts from the block after it.
+    Rollback {
+        block: u64,
+        reply: oneshot::Sender<Result<usize>>,
+    },
+    /// Promote a standby instance to active indexer, once it has imported what its
+    /// primary committed. Ignored by an active indexer.
+    Promote,
+}
+
+pub type CommandSender = mpsc::Sender<Command>;
+
+/// Receiving end of the commands, shared by the successive indexers of a restart
+/// loop so that queued commands and the paused state outlive an indexer.
+#[derive(Clone)]
+pub struct CommandReceiver {
+    commands: Arc<Mutex<mpsc::Receiver<Command>>>,
+    paused: Arc<AtomicBool>,
+}
+
+impl CommandReceiver {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Next command, waiting for one only if `wait` is set. `None` if there is no
+    /// command or all the senders are gone.
+    pub(crate) async fn next(&self, wait: bool) -> Option<Command> {
+        let mut commands = self.commands.lock().await;
+        if wait {
+            commands.recv().await
+        } else {
+            commands.try_recv().ok()
+        }
+    }
+}
+
+pub fn channel() -> (CommandSender, CommandReceiver) {
+    let (tx, rx) = mpsc::channel(CAPACITY);
+    (
+        tx,
+        CommandReceiver {
+            commands: Arc::new(Mutex::new(rx)),
+            paused: Arc::new(AtomicBool::new(false)),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_commands() {
+        let (tx, rx) = channel();
+        assert!(rx.next(false).await.is_none());
+        tx.send(Command::Pause).await.unwrap();
+        let shared = rx.clone();
+        assert!(matches!(shared.next(false).await, Some(Command::Pause)));
+        shared.set_paused(true);
+        assert!(rx.is_paused());
+        drop(tx);
+        assert!(rx.next(true).await.is_none());
+    }
+}
@@ -0,0 +1,92 @@
+//! JSON-RPC client answering from recorded blocks and receipts, so that the block
+//! processing is tested, and benchmarked with the `mock` feature, without a node.
+//!
+//! A fixture is a `<number>.json` file, such as those of `src/indexer/fixtures`,
+//! holding the `eth_getBlockByNumber` result of a block under `block`, and the
+//! `eth_getBlockReceipts` result under `receipts`. Tests can add the `trace_block`
+//! result of a block.
+
+use async_trait::async_trait;
+use ethers::providers::{JsonRpcClient, JsonRpcError, MockError, Provider};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::Path;
+
+/// Fixtures recorded in the repository, for the tests.
+#[cfg(test)]
+pub(crate) fn fixtures_dir() -> std::path::PathBuf {
+    std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/indexer/fixtures")
+}
+
+#[derive(Debug, Default)]
+pub struct Fixtures {
+    blocks: HashMap<u64, (Value, Value)>,
+    traces: HashMap<u64, Value>,
+}
+
+impl Fixtures {
+    /// Loads the fixtures of the given blocks from `dir`.
+    pub fn load(dir: &Path, numbers: &[u64]) -> std::io::Result<Self> {
+        let mut fixtures = Self::default();
+        for number in numbers {
+            let path = dir.join(format!("{}.json", number));
+            let file = std::fs::File::open(&path).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("fixture {}: {}", path.display(), e))
+            })?;
+            let mut fixture: Value = serde_json::from_reader(std::io::BufReader::new(file))?;
+            fixtures =
+                fixtures.with_block(*number, fixture["block"].take(), fixture["receipts"].take());
+        }
+        Ok(fixtures)
+    }
+
+    /// Adds a block with its receipts, for the tests building their own.
+    pub fn with_block(mut self, number: u64, block: Value, receipts: Value) -> Self {
+        self.blocks.insert(number, (block, receipts));
+        self
+    }
+
+    /// Adds the traces of a block.
+    pub fn with_traces(mut self, number: u64, traces: Value) -> Self {
+        self.traces.insert(number, traces);
+        self
+    }
+
+    pub fn provider(self) -> Provider<Self> {
+        Provider::new(self)
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for Fixtures {
+    type Error = MockError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, MockError>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let params = serde_json::to_value(params)?;
+        let number = params[0]
+            .as_str()
+            .and_then(|number| u64::from_str_radix(number.trim_start_matches("0x"), 16).ok());
+        let fixture = number.and_then(|number| self.blocks.get(&number));
+        let traces = number.and_then(|number| self.traces.get(&number));
+        let result = match (method, fixture, traces) {
+            ("trace_block", _, Some(traces)) => traces.clone(),
+            ("eth_getBlockByNumber", Some((block, _)), _) => block.clone(),
+            ("eth_getBlockReceipts", Some((_, receipts)), _) => receipts.clone(),
+            ("eth_getBlockByNumber" | "eth_getBlockReceipts", None, _) => Value::Null,
+            _ => {
+                return Err(MockError::JsonRpcError(JsonRpcError {
+                    code: -32601,
+                    message: format!("no fixture for {} {}", method, params),
+                    data: Some(json!(null)),
+                }))
+            }
+        };
+        Ok(serde_json::from_value(result)?)
+    }
+}
@@ -1,17 +1,120 @@
-use crate::index::{Indexed, SharedIndex};
-use crate::Result;
+use crate::index::{Appearance, BlockData, IndexTable, Indexed, SharedIndex};
+use crate::{MoniqueError, Result};
 use ethers::{
-    providers::{Middleware, Provider, StreamExt, Ws},
-    types::{Address, BlockId, BlockNumber},
+    providers::{JsonRpcClient, Middleware, Provider, ProviderError, StreamExt, Ws},
+    signers::{LocalWallet, Signer},
+    types::{Address, Block, BlockId, BlockNumber, H256},
 };
-use log::{error, info, trace};
-use std::time;
+use std::time::{self, Duration};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{error, info, instrument, trace, trace_span, warn, Instrument};
 
 mod block;
+pub mod control;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod network;
+pub mod providers;
+pub mod sources;
+pub mod status;
+pub mod throttle;
+
+pub use block::{process, ruleset, Source};
+use control::{Command, CommandReceiver};
+use status::{IndexerState, IndexerStatus, StatusReceiver, StatusSender};
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use throttle::{CommitBatch, Throttle};
+
+/// Fetched blocks buffered ahead of the one being queued during catch-up.
+const PREFETCH_BLOCKS: usize = 8;
+
+/// Time spent on each stage of the indexing of a block.
+#[derive(Clone, Copy, Debug, Default)]
+struct BlockTimes {
+    get_block: Duration,
+    process: Duration,
+    queue: Duration,
+}
+
+impl std::ops::AddAssign for BlockTimes {
+    fn add_assign(&mut self, other: Self) {
+        self.get_block += other.get_block;
+        self.process += other.process;
+        self.queue += other.queue;
+    }
+}
+
+/// Handle of a spawned task, which is aborted when the handle is dropped: the tasks
+/// of a catch-up or a classification stopped by an error, a reconnection or the
+/// shutdown do not keep running detached.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Stats key of the last block whose checkpoint was signed, or skipped for lack of one.
+const SIGNED_STAT: &str = "signed";
+
+/// Calls to `eth_getCode` in flight while classifying the committed entries.
+const ENRICH_CONCURRENCY: usize = 16;
+
+/// Entries committed before the enrichment was enabled, or while it failed, classified
+/// at most along with each commit.
+const ENRICH_BACKFILL: usize = 10_000;
+
+/// Stats key of the first entry which may not be classified yet.
+const ENRICHED_STAT: &str = "enriched";
+
+/// Time without a new block, or while a fetched block is awaited during catch-up,
+/// after which the indexer is reported as stalled.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval at which the safe block is polled while the pending queue is over its cap.
+const PENDING_CAP_POLL: Duration = Duration::from_secs(12);
+
+/// What the indexer does when the pending queue is over its cap.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PendingPolicy {
+    /// Stop queueing blocks until commits make room.
+    #[default]
+    Pause,
+    /// Move the oldest pending blocks to disk and keep queueing.
+    Spill,
+}
+
+/// Addresses referenced by a block, in order, with where they were found.
+type SourcedAddresses = Vec<(Address, Source, Option<Appearance>)>;
 
 pub struct Indexer {
     db: SharedIndex<20, Address>,
+    transactions: Option<SharedIndex<32, H256>>,
     provider: Provider<Ws>,
+    /// Providers sharing the block fetches.
+    rotation: Rotation,
+    enrich: bool,
+    signer: Option<LocalWallet>,
+    status: StatusSender,
+    speed: f64,
+    commit_ms: Option<u64>,
+    commands: Option<CommandReceiver>,
+    commit_batch: CommitBatch,
+    /// Genesis allocations, queued with the start block.
+    genesis: Vec<Address>,
+    /// Whether the traces of the blocks are read, for their internal calls.
+    traces: bool,
+    record_appearances: bool,
+    /// Memory of the pending queue, in bytes, above which no block is queued until
+    /// commits make room.
+    pending_cap: Option<usize>,
+    pending_policy: PendingPolicy,
 }
 
 #[derive(Debug)]
@@ -24,7 +127,99 @@ pub struct Info {
 
 impl Indexer {
     pub fn new(db: SharedIndex<20, Address>, provider: Provider<Ws>) -> Self {
-        Self { db, provider }
+        Self {
+            db,
+            transactions: None,
+            rotation: Rotation::new(provider.clone(), vec![]),
+            provider,
+            enrich: false,
+            signer: None,
+            status: status::channel().0,
+            speed: 0.0,
+            commit_ms: None,
+            commands: None,
+            commit_batch: CommitBatch::default(),
+            genesis: vec![],
+            traces: false,
+            record_appearances: false,
+            pending_cap: None,
+            pending_policy: PendingPolicy::default(),
+        }
+    }
+
+    /// Publish progress through the given status channel instead of a private one.
+    pub fn with_status(mut self, status: StatusSender) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Also index transaction hashes, block by block, into the given table.
+    pub fn with_transactions(mut self, transactions: SharedIndex<32, H256>) -> Self {
+        self.transactions = Some(transactions);
+        self
+    }
+
+    /// Sign every committed checkpoint with the operator key.
+    pub fn with_signer(mut self, signer: LocalWallet) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Spread the block fetches over these other providers, up to their head block.
+    pub fn with_backfill(mut self, backfill: Vec<(Provider<Ws>, u64)>) -> Self {
+        self.rotation = Rotation::new(self.provider.clone(), backfill);
+        self
+    }
+
+    /// Index these genesis allocations with the start block, before its addresses.
+    pub fn with_genesis(mut self, genesis: Vec<Address>) -> Self {
+        self.genesis = genesis;
+        self
+    }
+
+    /// Index the addresses of the internal calls of the blocks, from their traces.
+    pub fn with_traces(mut self, traces: bool) -> Self {
+        self.traces = traces;
+        self
+    }
+
+    /// Record the transaction, and log, each newly committed address was first seen in.
+    pub fn with_appearances(mut self, record: bool) -> Self {
+        self.record_appearances = record;
+        self
+    }
+
+    /// Stop queueing blocks while the pending queue holds more than `cap` bytes, e.g.
+    /// when the safe block lags during a finality incident.
+    pub fn with_pending_cap(mut self, cap: Option<usize>) -> Self {
+        self.pending_cap = cap;
+        self
+    }
+
+    /// Spill the oldest pending blocks to disk over the cap, instead of pausing.
+    pub fn with_pending_policy(mut self, policy: PendingPolicy) -> Self {
+        self.pending_policy = policy;
+        self
+    }
+
+    /// Handle operator commands (pause, resume, commit) between blocks.
+    pub fn with_commands(mut self, commands: CommandReceiver) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+
+    pub fn status(&self) -> IndexerStatus {
+        self.status.borrow().current()
+    }
+
+    pub fn subscribe_status(&self) -> StatusReceiver {
+        self.status.subscribe()
+    }
+
+    /// Classify newly committed addresses as contracts or EOAs with `eth_getCode`.
+    pub fn with_enrichment(mut self, enrich: bool) -> Self {
+        self.enrich = enrich;
+        self
     }
 
     pub async fn info(&self) -> Result<Info> {
@@ -46,6 +241,23 @@ impl Indexer {
             "Indexing stats: [{last_db_block}/{last_node_block}] [{progress}%] [safe: {}] [index: {addr_count}]",
             safe_block,
         );
+        let state = if last_db_block >= last_node_block.as_u64() {
+            IndexerState::Live
+        } else {
+            IndexerState::CatchingUp
+        };
+        let mut status =
+            IndexerStatus::new(state, last_db_block, last_node_block.as_u64(), self.speed);
+        status.addresses = addr_count;
+        status.project(
+            &self
+                .db
+                .history(status::now().saturating_sub(status::GROWTH_WINDOW))?,
+        );
+        status.commit_ms = self.commit_ms;
+        status.cache_hit_rate = self.db.cache_hit_rate();
+        status.provider = self.status.borrow().provider.clone();
+        self.status.send_replace(status);
         Ok(Info {
             last_node_block: last_node_block.as_u64(),
             safe_block,
@@ -55,6 +267,22 @@ impl Indexer {
     }
 
     pub async fn run(&mut self) -> Result<()> {
+        // the provider may have changed since the last run
+        self.db
+            .check_chain_id(self.provider.get_chainid().await?.as_u64())?;
+        if let Some(transactions) = &self.transactions {
+            // both tables are queued block by block, so they must be in sync, but are
+            // committed one after the other
+            align_tables(&self.db, transactions).await?;
+            let addresses = self.db.get_counters().await.last_indexed_block;
+            let transactions = transactions.get_counters().await.last_indexed_block;
+            if addresses != transactions {
+                Err(MoniqueError::OutOfSync {
+                    addresses,
+                    transactions,
+                })?;
+            }
+        }
         let mut safe_block = loop {
             let info = self.catch_up().await?;
             if info.last_node_block == info.last_db_block {
@@ -63,17 +291,31 @@ impl Indexer {
         };
         let provider = self.provider.to_owned();
         let mut stream = provider.subscribe_blocks().await?.boxed();
-        while let Some(block) = stream.next().await {
-            let (queued, _, _, _) = self.index_block(block.number.unwrap().as_u64()).await?;
+        self.status
+            .send_modify(|status| status.provider.subscribed());
+        let mut block_time = time::Instant::now();
+        while let Some(block) = unless_stalled(
+            &self.status,
+            STALL_TIMEOUT,
+            IndexerState::Live,
+            stream.next(),
+        )
+        .await
+        {
+            self.handle_commands().await?;
+            self.wait_for_room().await?;
+            self.speed = 1.0 / block_time.elapsed().as_secs_f64();
+            block_time = time::Instant::now();
+            let queued = self.index_block(block.number.unwrap().as_u64()).await?;
             info!(
-                "Processed block {} [{}] [{} new addresses]",
-                block.number.unwrap(),
-                block.hash.unwrap(),
-                queued
+                block = block.number.unwrap().as_u64(),
+                hash = %block.hash.unwrap(),
+                addresses_added = queued,
+                "processed block"
             );
             let info = self.info().await?;
             if info.safe_block > safe_block {
-                let len = self.db.commit(info.safe_block).await?;
+                let len = self.commit(info.safe_block).await?;
                 info!(
                     "Committed up to block {} [{} addresses]",
                     info.safe_block, len
@@ -83,12 +325,13 @@ impl Indexer {
         }
 
         error!("run loop exited");
-        Err("run loop exited")?
+        Err(MoniqueError::SubscriptionEnded)
     }
 
     pub async fn catch_up(&mut self) -> Result<Info> {
         let mut log_time = time::Instant::now();
-        let mut times = (0usize, 0u128, 0u128, 0u128);
+        let mut added = 0usize;
+        let mut times = BlockTimes::default();
 
         let mut info = self.info().await?;
         info!(
@@ -96,52 +339,90 @@ impl Indexer {
             info.last_node_block - info.last_db_block
         );
 
-        let mut last_block = info.last_db_block + 1;
+        let first_block = info.last_db_block + 1;
+        let mut last_block = first_block;
         let mut last_count = self.db.len().await;
-        for block_number in last_block..=info.last_node_block {
-            let (count, get_block_time, process_time, queue_time) =
-                self.index_block(block_number).await?;
-            times.0 += count;
-            times.1 += get_block_time;
-            times.2 += process_time;
-            times.3 += queue_time;
+        // fetch the next blocks while the current one is queued; the fetch task is
+        // aborted when the catch-up returns, or is dropped
+        let (fetched_tx, mut fetched) = mpsc::channel(PREFETCH_BLOCKS);
+        let in_flight = Arc::new(AtomicUsize::new(throttle::MIN_IN_FLIGHT));
+        let _prefetch = AbortOnDrop(tokio::spawn(prefetch(
+            self.rotation.clone(),
+            first_block..=info.last_node_block,
+            fetched_tx,
+            in_flight.clone(),
+            self.traces,
+        )));
+        let mut committed = 0;
+        let mut pending_entries = 0;
+        for block_number in first_block..=info.last_node_block {
+            self.handle_commands().await?;
+            let freed = self.wait_for_room().await?;
+            if freed > 0 {
+                committed += freed;
+                pending_entries = 0;
+            }
+            let next = fetched.recv();
+            let (block, set, mut block_times) =
+                unless_stalled(&self.status, STALL_TIMEOUT, IndexerState::CatchingUp, next)
+                    .await
+                    .ok_or(MoniqueError::BlockNotFound(block_number))??;
+            let queued = self
+                .queue_block(block_number, block, set, &mut block_times)
+                .await?;
+            added += queued;
+            times += block_times;
+            pending_entries += queued;
 
-            let processed = block_number - last_block;
-            if times.0 > 0 && (log_time.elapsed().as_secs() > 15) {
+            let last_committed = self.db.get_counters().await.last_committed_block;
+            if self
+                .commit_batch
+                .is_due(block_number - last_committed, pending_entries)
+            {
                 info = self.info().await?;
-                let committed =
-                    if info.safe_block > self.db.get_counters().await.last_committed_block {
-                        self.db.commit(info.safe_block).await?
-                    } else {
-                        0
-                    };
+                let duration = if info.safe_block > last_committed {
+                    let time = time::Instant::now();
+                    committed += self.commit(info.safe_block).await?;
+                    pending_entries = 0;
+                    Some(time.elapsed())
+                } else {
+                    None
+                };
+                self.commit_batch.record(duration);
+            }
 
+            let processed = block_number - last_block;
+            if added > 0 && processed > 0 && (log_time.elapsed().as_secs() > 15) {
                 // blocks per second
                 let speed = processed as f64 / log_time.elapsed().as_secs_f64();
+                self.speed = speed;
+                self.info().await?;
+
                 let counter = self.db.len().await;
                 info!(
-                    "Block: {} [{} new addresses] [committed {}] [{} blk/s] [{} ms per block]",
-                    block_number,
-                    counter - last_count,
+                    block = block_number,
+                    addresses_added = counter - last_count,
                     committed,
-                    speed.round(),
-                    (log_time.elapsed().as_millis() as u64) / processed,
-                );
-                info!(
-                    "  get_block={}us process={}us queue={}us",
-                    times.1 / times.0 as u128,
-                    times.2 / times.0 as u128,
-                    times.3 / times.0 as u128
+                    blocks_per_sec = speed.round(),
+                    ms_per_block = (log_time.elapsed().as_millis() as u64) / processed,
+                    get_block_us = (times.get_block.as_micros() / processed as u128) as u64,
+                    process_us = (times.process.as_micros() / processed as u128) as u64,
+                    queue_us = (times.queue.as_micros() / processed as u128) as u64,
+                    in_flight = in_flight.load(Ordering::Relaxed),
+                    commit_blocks = self.commit_batch.blocks(),
+                    "catch up progress"
                 );
                 log_time = time::Instant::now();
                 last_count = counter;
                 last_block = block_number;
-                times = (0, 0, 0, 0);
+                added = 0;
+                times = BlockTimes::default();
+                committed = 0;
             }
         }
         info = self.info().await?;
         let committed = if info.safe_block > self.db.get_counters().await.last_committed_block {
-            self.db.commit(info.safe_block).await?
+            self.commit(info.safe_block).await?
         } else {
             0
         };
@@ -149,35 +430,620 @@ impl Indexer {
         Ok(info)
     }
 
-    async fn index_block(&mut self, number: u64) -> Result<(usize, u128, u128, u128)> {
-        trace!("indexing block {}", number);
-        let id = BlockId::Number(number.into());
+    async fn commit(&mut self, safe_block: u64) -> Result<usize> {
+        let start = self.db.committed_len().await;
+        let first_block = self.db.get_counters().await.last_committed_block + 1;
+        let time = time::Instant::now();
+        let len = match self.db.commit(safe_block).await {
+            Err(MoniqueError::MissedBlock(number)) => {
+                self.requeue(number).await?;
+                self.db.commit(safe_block).await?
+            }
+            result => result?,
+        };
+        if let Some(transactions) = self.transactions.clone() {
+            match transactions.commit(safe_block).await {
+                Err(MoniqueError::MissedBlock(number)) => {
+                    self.requeue(number).await?;
+                    transactions.commit(safe_block).await?;
+                }
+                Err(e) => Err(e)?,
+                Ok(_) => {}
+            }
+        }
+        self.commit_ms = Some(time.elapsed().as_millis() as u64);
+        if self.enrich {
+            // the entries left unclassified are backfilled with the next commits
+            if let Err(e) = self.enrich(start, len, safe_block).await {
+                warn!("enrichment failed: {}", e);
+            }
+        }
+        let last_committed = self.db.get_counters().await.last_committed_block;
+        if let Some(signer) = &self.signer {
+            // the blocks a failed signing left unsigned are signed first
+            let from = match self.db.get_stat(SIGNED_STAT)? {
+                Some(signed) => (signed + 1).min(first_block),
+                None => first_block,
+            };
+            let mut signatures = vec![];
+            let mut signed = from - 1;
+            for number in from..=last_committed {
+                if let Some(checkpoint) = self.db.checkpoint(number)? {
+                    match signer.sign_message(checkpoint.message()).await {
+                        Ok(signature) => signatures.push((number, signature.into())),
+                        Err(e) => {
+                            warn!(
+                                block = number,
+                                "signing failed, retrying on the next commit: {}", e
+                            );
+                            break;
+                        }
+                    }
+                }
+                signed = number;
+            }
+            self.db.put_signatures(signatures)?;
+            self.db.put_stats(vec![(SIGNED_STAT.to_string(), signed)])?;
+        }
+        Ok(len)
+    }
+
+    /// Unwinds the indexed tables to block `block`, dropping their pending blocks.
+    /// Returns the number of addresses removed.
+    pub async fn rollback(&mut self, block: u64) -> Result<usize> {
+        check_rollback(&self.db, self.transactions.as_deref(), block).await?;
+        let removed = self.db.rollback(block).await?;
+        if let Some(transactions) = &self.transactions {
+            transactions.rollback(block).await?;
+        }
+        Ok(removed)
+    }
 
-        // get block
-        let start = time::Instant::now();
-        let block = self.provider.get_block(id).await?.expect("block not found");
-        let get_block_time = start.elapsed().as_micros();
+    /// Applies the queued commands, and waits for a resume command while paused.
+    /// Fails with `RolledBack` after a rollback, for the indexer to be restarted from
+    /// the new head of the index.
+    async fn handle_commands(&mut self) -> Result<()> {
+        let Some(commands) = self.commands.clone() else {
+            return Ok(());
+        };
+        loop {
+            let paused = commands.is_paused();
+            if paused {
+                self.status
+                    .send_modify(|status| status.state = IndexerState::Paused);
+            }
+            let Some(command) = commands.next(paused).await else {
+                return Ok(());
+            };
+            match command {
+                Command::Pause => {
+                    info!("indexer paused");
+                    commands.set_paused(true);
+                }
+                Command::Resume => {
+                    info!("indexer resumed");
+                    commands.set_paused(false);
+                }
+                Command::Commit { block, reply } => {
+                    info!(block, "forced commit");
+                    let _ = reply.send(self.commit(block).await);
+                }
+                Command::Promote => info!("already the active indexer"),
+                Command::Reconnect => {
+                    info!("reconnecting to the providers");
+                    Err(MoniqueError::Reconnect)?
+                }
+                Command::Rollback { block, reply } => {
+                    warn!(block, "rollback");
+                    let result = self.rollback(block).await;
+                    let rolled_back = result.is_ok();
+                    let _ = reply.send(result);
+                    if rolled_back {
+                        Err(MoniqueError::RolledBack(block))?
+                    }
+                }
+            }
+        }
+    }
 
-        // process block
-        let start = time::Instant::now();
-        let set = block::process(&self.provider, &block).await?;
-        let set_len = set.len() as u128;
-        let process_time = start.elapsed().as_micros();
+    /// Waits while the pending queue is over its cap, committing the blocks that
+    /// become safe, and handling the operator commands meanwhile. With the spill policy,
+    /// the oldest pending blocks are moved to disk instead of waiting, down to half the
+    /// cap so that it is not done for every block. Returns the number of addresses
+    /// committed.
+    async fn wait_for_room(&mut self) -> Result<usize> {
+        let Some(cap) = self.pending_cap else {
+            return Ok(0);
+        };
+        let mut committed = 0;
+        let mut throttled = false;
+        loop {
+            let pending = self.db.pending_memory().await;
+            if pending < cap {
+                break;
+            }
+            if !throttled {
+                warn!(
+                    pending,
+                    cap, "pending queue over its cap, waiting for commits"
+                );
+                throttled = true;
+            }
+            let safe_block = self.info().await?.safe_block;
+            if safe_block > self.db.get_counters().await.last_committed_block {
+                committed += self.commit(safe_block).await?;
+                continue;
+            }
+            if self.pending_policy == PendingPolicy::Spill {
+                let spilled = self.db.spill(cap / 2).await?;
+                info!(spilled, "spilled pending blocks to disk");
+                if spilled > 0 {
+                    break;
+                }
+            }
+            self.status
+                .send_modify(|status| status.state = IndexerState::Throttled);
+            tokio::time::sleep(PENDING_CAP_POLL).await;
+            self.handle_commands().await?;
+        }
+        if throttled {
+            info!(committed, "pending queue back under its cap");
+        }
+        Ok(committed)
+    }
+
+    /// Classifies the `len` entries committed from `start`, then up to `ENRICH_BACKFILL`
+    /// earlier entries left unclassified, committed before the enrichment was enabled
+    /// or while it failed.
+    async fn enrich(&self, start: usize, len: usize, block: u64) -> Result<()> {
+        let time = time::Instant::now();
+        let end = start + len;
+        let mut indexes: Vec<usize> = (start..end).collect();
+        let from = (self.db.get_stat(ENRICHED_STAT)?.unwrap_or(0) as usize).min(start);
+        let mut next = from;
+        while next < start && indexes.len() < len + ENRICH_BACKFILL {
+            if self.db.is_contract(next)?.is_none() {
+                indexes.push(next);
+            }
+            next += 1;
+        }
+        let mut addresses = Vec::with_capacity(indexes.len());
+        for index in indexes {
+            if let Some(address) = self.db.get(index).await? {
+                addresses.push((index, address));
+            }
+        }
+        let flags = self.classify(addresses, block).await?;
+        let contracts = flags.iter().filter(|(_, c)| *c).count();
+        let enriched = flags.len();
+        self.db.set_contract_flags(flags)?;
+        let first_unclassified = if next < start { next } else { end };
+        self.db
+            .put_stats(vec![(ENRICHED_STAT.to_string(), first_unclassified as u64)])?;
+        if enriched > 0 {
+            info!(
+                "Enriched {} addresses [{} contracts, {} backfilled] in {}ms",
+                enriched,
+                contracts,
+                enriched.saturating_sub(len),
+                time.elapsed().as_millis()
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether each address holds code at `block`, with `ENRICH_CONCURRENCY` calls to
+    /// `eth_getCode` in flight.
+    async fn classify(
+        &self,
+        addresses: Vec<(usize, Address)>,
+        block: u64,
+    ) -> Result<Vec<(usize, bool)>> {
+        let at = Some(BlockId::Number(block.into()));
+        let mut flags = Vec::with_capacity(addresses.len());
+        let mut calls = VecDeque::new();
+        let mut addresses = addresses.into_iter();
+        loop {
+            while calls.len() < ENRICH_CONCURRENCY {
+                let Some((index, address)) = addresses.next() else {
+                    break;
+                };
+                let provider = self.provider.clone();
+                calls.push_back((
+                    index,
+                    AbortOnDrop(tokio::spawn(
+                        async move { provider.get_code(address, at).await },
+                    )),
+                ));
+            }
+            let Some((index, mut call)) = calls.pop_front() else {
+                return Ok(flags);
+            };
+            let code = (&mut call.0)
+                .await
+                .map_err(|e| ProviderError::CustomError(e.to_string()))??;
+            flags.push((index, !code.is_empty()));
+        }
+    }
+
+    /// Data queued with the addresses of a fetched block: the index counts the
+    /// addresses it adds per source, when it commits them.
+    fn block_data(&self, block: &Block<H256>, set: &SourcedAddresses) -> BlockData<Address> {
+        let mut appearances = HashMap::new();
+        let mut counters = HashMap::new();
+        for (address, source, appearance) in set {
+            counters.entry(*address).or_insert(sources::key(*source));
+            if let (true, Some(appearance)) = (self.record_appearances, appearance) {
+                appearances.entry(*address).or_insert(*appearance);
+            }
+        }
+        BlockData {
+            timestamp: Some(block.timestamp.as_u64()),
+            appearances,
+            counters,
+        }
+    }
 
-        // queue block
+    async fn fetch_block(
+        &mut self,
+        number: u64,
+    ) -> Result<(Block<H256>, SourcedAddresses, BlockTimes)> {
+        fetch_block(self.rotation.next(number), number, self.traces).await
+    }
+
+    /// Queues again the blocks from `from` on, fetched from the provider, in the
+    /// tables that have not committed them, after `from` went missing from a pending
+    /// queue.
+    async fn requeue(&mut self, from: u64) -> Result<()> {
+        let last = self.db.get_counters().await.last_indexed_block;
+        warn!(
+            from,
+            last, "blocks missing from the pending queue, queueing them again"
+        );
+        for number in from..=last {
+            let (block, set, _) = self.fetch_block(number).await?;
+            if number > self.db.get_counters().await.last_committed_block {
+                let addresses = set.iter().map(|(address, ..)| *address).collect();
+                let data = self.block_data(&block, &set);
+                self.db.queue_with(number, addresses, data).await?;
+            }
+            if let Some(transactions) = &self.transactions {
+                if number > transactions.get_counters().await.last_committed_block {
+                    transactions.queue(number, block.transactions).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn index_block(&mut self, number: u64) -> Result<usize> {
+        let (block, set, mut times) = self.fetch_block(number).await?;
+        self.queue_block(number, block, set, &mut times).await
+    }
+
+    /// Queues a fetched block in the tables, adding the time spent to its `times`.
+    async fn queue_block(
+        &mut self,
+        number: u64,
+        block: Block<H256>,
+        mut set: SourcedAddresses,
+        times: &mut BlockTimes,
+    ) -> Result<usize> {
         let start = time::Instant::now();
-        let result = self.db.queue(block.number.unwrap().as_u64(), set).await?;
-        let queue_time = start.elapsed().as_micros();
+        if number == self.db.start_block() && !self.genesis.is_empty() {
+            let genesis = self
+                .genesis
+                .iter()
+                .map(|address| (*address, Source::Genesis, None));
+            set.splice(0..0, genesis);
+        }
+        let addresses = set.len();
+        let data = self.block_data(&block, &set);
+        let queued = self
+            .db
+            .queue_with(
+                block.number.unwrap().as_u64(),
+                set.iter().map(|(address, ..)| *address).collect(),
+                data,
+            )
+            .await?;
+        if let Some(transactions) = &self.transactions {
+            transactions
+                .queue(block.number.unwrap().as_u64(), block.transactions.clone())
+                .await?;
+        }
 
+        times.queue = start.elapsed();
         trace!(
-            "index_block={} total={}us set={} get_block={}us process={}us queue={}us",
-            block.number.unwrap(),
-            get_block_time + process_time + queue_time,
-            set_len,
-            get_block_time,
-            process_time / set_len,
-            queue_time / set_len
+            addresses,
+            addresses_added = queued.len(),
+            get_block_us = times.get_block.as_micros() as u64,
+            process_us = times.process.as_micros() as u64,
+            queue_us = times.queue.as_micros() as u64,
+            elapsed_us = (times.get_block + times.process + times.queue).as_micros() as u64,
+            "indexed block"
+        );
+        Ok(queued.len())
+    }
+}
+
+/// Checks that the address table and the transaction table, if any, can both be
+/// rolled back to block `block`. They are separate databases: a rollback to a block
+/// that either has not committed is refused before any of them is unwound.
+pub async fn check_rollback(
+    db: &IndexTable<20, Address>,
+    transactions: Option<&IndexTable<32, H256>>,
+    block: u64,
+) -> Result<()> {
+    let mut tables = vec![("address", db.get_counters().await.last_committed_block)];
+    if let Some(transactions) = transactions {
+        let committed = transactions.get_counters().await.last_committed_block;
+        tables.push(("transaction", committed));
+    }
+    for (name, committed) in tables {
+        if block > committed {
+            Err(MoniqueError::Rollback(format!(
+                "block {} is not committed yet, the last committed block of the {} \
+                table is {}",
+                block, name, committed
+            )))?
+        }
+    }
+    Ok(())
+}
+
+/// Rolls the address or the transaction table back to the last block both committed,
+/// after a stop between their commits or the recovery of either, so that they are
+/// indexed again from the same block.
+pub async fn align_tables(
+    db: &IndexTable<20, Address>,
+    transactions: &IndexTable<32, H256>,
+) -> Result<()> {
+    let committed = db.get_counters().await.last_committed_block;
+    let tx_committed = transactions.get_counters().await.last_committed_block;
+    if committed > tx_committed {
+        warn!("rolling the address table back to block {}", tx_committed);
+        db.rollback(tx_committed).await?;
+    } else if tx_committed > committed {
+        warn!("rolling the transaction table back to block {}", committed);
+        transactions.rollback(committed).await?;
+    }
+    Ok(())
+}
+
+/// Rotation of the block fetches over the providers which have the block.
+#[derive(Clone)]
+struct Rotation {
+    /// The providers, with their head block when probed.
+    providers: Vec<(Provider<Ws>, u64)>,
+    fetches: usize,
+}
+
+impl Rotation {
+    fn new(primary: Provider<Ws>, backfill: Vec<(Provider<Ws>, u64)>) -> Self {
+        let mut providers = vec![(primary, u64::MAX)];
+        providers.extend(backfill);
+        Self {
+            providers,
+            fetches: 0,
+        }
+    }
+
+    /// Picks in turn the providers which have the block `number`.
+    fn next(&mut self, number: u64) -> &Provider<Ws> {
+        let count = self
+            .providers
+            .iter()
+            .filter(|(_, head)| number <= *head)
+            .count();
+        self.fetches = self.fetches.wrapping_add(1);
+        self.providers
+            .iter()
+            .filter(|(_, head)| number <= *head)
+            .map(|(provider, _)| provider)
+            .nth(self.fetches % count)
+            .unwrap()
+    }
+}
+
+/// Awaits `next`, reporting the indexer as stalled while it takes longer than
+/// `timeout`, and back in the `resumed` state once it completes.
+async fn unless_stalled<F: std::future::Future>(
+    status: &StatusSender,
+    timeout: Duration,
+    resumed: IndexerState,
+    next: F,
+) -> F::Output {
+    let mut next = std::pin::pin!(next);
+    let mut stalled = false;
+    loop {
+        match tokio::time::timeout(timeout, &mut next).await {
+            Ok(output) => {
+                if stalled {
+                    info!(state = ?resumed, "indexer resumed");
+                    status.send_modify(|status| status.state = resumed);
+                }
+                return output;
+            }
+            Err(_) => {
+                if !stalled {
+                    warn!(
+                        seconds = timeout.as_secs(),
+                        "indexer stalled, no block received"
+                    );
+                    status.send_modify(|status| status.state = IndexerState::Stalled);
+                }
+                stalled = true;
+            }
+        }
+    }
+}
+
+/// Retries of a failed block fetch during catch-up.
+const FETCH_RETRIES: usize = 3;
+
+/// Fetches the `blocks` in order into `fetched`, with a number of concurrent fetches
+/// tuned by a `Throttle` and published in `in_flight`. A failing fetch is retried
+/// before its error is sent, which ends the prefetch. The fetches in flight are
+/// aborted when it ends, or is aborted.
+async fn prefetch(
+    mut rotation: Rotation,
+    mut blocks: RangeInclusive<u64>,
+    fetched: mpsc::Sender<Result<(Block<H256>, SourcedAddresses, BlockTimes)>>,
+    in_flight: Arc<AtomicUsize>,
+    traces: bool,
+) {
+    let mut throttle = Throttle::default();
+    let mut fetches = VecDeque::new();
+    let mut retries = 0;
+    let spawn = |rotation: &mut Rotation, number: u64| {
+        let provider = rotation.next(number).clone();
+        AbortOnDrop(tokio::spawn(async move {
+            let time = time::Instant::now();
+            (fetch_block(&provider, number, traces).await, time.elapsed())
+        }))
+    };
+    loop {
+        while fetches.len() < throttle.in_flight() {
+            let Some(number) = blocks.next() else {
+                break;
+            };
+            fetches.push_back((number, spawn(&mut rotation, number)));
+        }
+        let Some((number, mut fetch)) = fetches.pop_front() else {
+            return;
+        };
+        let (result, latency) = (&mut fetch.0).await.unwrap_or_else(|e| {
+            let error = ProviderError::CustomError(e.to_string()).into();
+            (Err(error), Duration::ZERO)
+        });
+        throttle.record(latency, result.is_ok());
+        in_flight.store(throttle.in_flight(), Ordering::Relaxed);
+        match result {
+            Err(e) if retries < FETCH_RETRIES => {
+                retries += 1;
+                warn!(block = number, retries, "block fetch failed: {}", e);
+                fetches.push_front((number, spawn(&mut rotation, number)));
+            }
+            result => {
+                retries = 0;
+                let failed = result.is_err();
+                if fetched.send(result).await.is_err() || failed {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[instrument(skip(provider))]
+/// Fetches a block, with the addresses it references and the time spent on each.
+async fn fetch_block<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    number: u64,
+    traces: bool,
+) -> Result<(Block<H256>, SourcedAddresses, BlockTimes)> {
+    let id = BlockId::Number(number.into());
+    let start = time::Instant::now();
+    let block = provider
+        .get_block(id)
+        .instrument(trace_span!("get_block"))
+        .await?
+        .ok_or(MoniqueError::BlockNotFound(number))?;
+    let get_block = start.elapsed();
+    let set = block::process(provider, &block, traces)
+        .instrument(trace_span!("process"))
+        .await?;
+    let times = BlockTimes {
+        get_block,
+        process: start.elapsed() - get_block,
+        queue: Duration::ZERO,
+    };
+    Ok((block, set, times))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::Fixtures;
+
+    #[tokio::test]
+    async fn test_fetch_block() {
+        let provider = Fixtures::load(&mock::fixtures_dir(), &[46147])
+            .unwrap()
+            .provider();
+        let (block, set, _) = fetch_block(&provider, 46147, false).await.unwrap();
+        assert_eq!(block.transactions.len(), 1);
+        let sources: Vec<Source> = set.into_iter().map(|(_, source, _)| source).collect();
+        assert_eq!(sources, [Source::Miner, Source::Sender, Source::Recipient]);
+        assert!(matches!(
+            fetch_block(&provider, 46148, false).await,
+            Err(MoniqueError::BlockNotFound(46148))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_abort_on_drop() {
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let task = AbortOnDrop(tokio::spawn(async move {
+            let _tx = tx;
+            std::future::pending::<()>().await
+        }));
+        drop(task);
+        // the sender is dropped with the aborted task
+        assert!(rx.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unless_stalled() {
+        let (status, mut receiver) = status::channel();
+        let slow = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            1
+        };
+        let (output, state) = tokio::join!(
+            unless_stalled(&status, Duration::from_millis(10), IndexerState::Live, slow),
+            async {
+                receiver.changed().await.unwrap();
+                receiver.borrow_and_update().state
+            }
         );
-        Ok((result, get_block_time, process_time, queue_time))
+        assert_eq!((output, state), (1, IndexerState::Stalled));
+        assert_eq!(receiver.borrow_and_update().state, IndexerState::Live);
+
+        // a prompt block leaves the state alone
+        let fast = std::future::ready(2);
+        let output = unless_stalled(&status, STALL_TIMEOUT, IndexerState::CatchingUp, fast).await;
+        assert_eq!(output, 2);
+        assert!(!receiver.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_align_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = IndexTable::<20, Address>::builder(dir.path().join("db"))
+            .build()
+            .await
+            .unwrap();
+        let transactions = IndexTable::<32, H256>::builder(dir.path().join("tx"))
+            .build()
+            .await
+            .unwrap();
+        // the address table committed block 2, the transaction table did not
+        for number in 1..=2 {
+            let address = Address::from_low_u64_be(number);
+            db.queue(number, vec![address]).await.unwrap();
+            let hash = H256::from_low_u64_be(number);
+            transactions.queue(number, vec![hash]).await.unwrap();
+        }
+        db.commit(2).await.unwrap();
+        transactions.commit(1).await.unwrap();
+        transactions.rollback(1).await.unwrap();
+
+        align_tables(&db, &transactions).await.unwrap();
+        assert_eq!(db.get_counters().await.last_committed_block, 1);
+        assert_eq!(db.get_counters().await.last_indexed_block, 1);
+        assert_eq!(db.len().await, 1);
+        assert_eq!(transactions.get_counters().await.last_indexed_block, 1);
     }
 }
@@ -0,0 +1,138 @@
+//! Network profiles selected with `--network`: the chain id expected from the
+//! providers, the first block to index, and the accounts allocated in the genesis
+//! block, which no transaction references.
+//!
+//! Custom profiles are JSON files deserialized into a `Network`, e.g.
+//! `{"chain_id": 17000, "genesis": ["0x..."]}`. The allocations of any profile can
+//! also be read from the genesis file of the chain, as published with its clients.
+
+use ethers::types::Address;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Network {
+    pub chain_id: u64,
+    #[serde(default = "first_block")]
+    pub start_block: u64,
+    /// Accounts allocated in the genesis block, indexed with the start block, before
+    /// its own addresses and in this order.
+    #[serde(default)]
+    pub genesis: Vec<Address>,
+}
+
+fn first_block() -> u64 {
+    1
+}
+
+impl Network {
+    /// Names of the built-in profiles.
+    pub const NAMES: [&'static str; 3] = ["mainnet", "sepolia", "holesky"];
+
+    /// Built-in profile of that name. These do not list the genesis allocations,
+    /// which are read from the genesis file of the chain with [`Genesis::read`].
+    pub fn named(name: &str) -> Option<Self> {
+        let chain_id = match name {
+            "mainnet" => 1,
+            "sepolia" => 11_155_111,
+            "holesky" => 17_000,
+            _ => return None,
+        };
+        Some(Self {
+            chain_id,
+            start_block: first_block(),
+            genesis: vec![],
+        })
+    }
+}
+
+/// Allocations of a genesis file in the format of geth (`genesis.json`, with an
+/// `alloc` object keyed by address), as published for mainnet and the testnets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Genesis {
+    /// Chain id of the `config` of the file, if any.
+    pub chain_id: Option<u64>,
+    /// Allocated accounts, in ascending order of address, as the keys of `alloc`
+    /// are not ordered.
+    pub allocations: Vec<Address>,
+}
+
+#[derive(Deserialize)]
+struct GenesisFile {
+    #[serde(default)]
+    config: GenesisConfig,
+    alloc: BTreeMap<String, IgnoredAny>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenesisConfig {
+    chain_id: Option<u64>,
+}
+
+impl Genesis {
+    pub fn read(reader: impl Read) -> Result<Self, String> {
+        let file: GenesisFile = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+        let mut allocations = file
+            .alloc
+            .keys()
+            .map(|key| {
+                let hex = key.strip_prefix("0x").unwrap_or(key);
+                Address::from_str(hex).map_err(|e| format!("invalid allocation {}: {}", key, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        allocations.sort();
+        allocations.dedup();
+        Ok(Self {
+            chain_id: file.config.chain_id,
+            allocations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        assert_eq!(Network::named("sepolia").unwrap().chain_id, 11_155_111);
+        assert!(Network::NAMES
+            .iter()
+            .all(|name| Network::named(name).is_some()));
+        assert_eq!(Network::named("goerli"), None);
+
+        let custom: Network = serde_json::from_str(
+            r#"{"chain_id": 17000, "genesis": ["0x0000000000000000000000000000000000000001"]}"#,
+        )
+        .unwrap();
+        assert_eq!(custom.start_block, 1);
+        assert_eq!(custom.genesis, vec![Address::from_low_u64_be(1)]);
+    }
+
+    #[test]
+    fn test_genesis_file() {
+        let genesis = Genesis::read(
+            r#"{
+                "config": {"chainId": 17000, "londonBlock": 0},
+                "alloc": {
+                    "0000000000000000000000000000000000000002": {"balance": "0x1"},
+                    "0x0000000000000000000000000000000000000001": {"balance": "0x1", "code": "0x00"}
+                }
+            }"#
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(genesis.chain_id, Some(17_000));
+        assert_eq!(
+            genesis.allocations,
+            vec![Address::from_low_u64_be(1), Address::from_low_u64_be(2)]
+        );
+
+        assert!(Genesis::read(r#"{"alloc": {"0x01": {}}}"#.as_bytes()).is_err());
+        assert!(Genesis::read(r#"{"config": {}}"#.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! Selection of the node provider among several: the providers are probed for their
+//! head block and latency, the healthiest one carries the block subscription and the
+//! other healthy ones share the backfill.
+
+use crate::{MoniqueError, Result};
+use ethers::providers::{Middleware, Provider, ProviderError, Ws};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+
+/// Blocks a provider may lag behind the highest head and still be healthy.
+pub const HEAD_TOLERANCE: u64 = 2;
+
+/// Interval between two probes of the providers while indexing.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A healthy provider this many times slower than the fastest one is replaced.
+const LATENCY_FACTOR: u32 = 2;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Consecutive probes in which the current provider must be found unhealthy before
+/// it is replaced, so that a transient error does not switch providers.
+pub const SWITCH_AFTER: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Probe {
+    pub url: String,
+    pub head: u64,
+    pub latency: Duration,
+}
+
+/// Connects to `url` and times a head block request.
+pub async fn probe(url: &str) -> Result<(Provider<Ws>, Probe)> {
+    let probe = async {
+        let provider = Provider::<Ws>::connect(url).await?;
+        let start = Instant::now();
+        let head = provider.get_block_number().await?.as_u64();
+        let probe = Probe {
+            url: url.to_string(),
+            head,
+            latency: start.elapsed(),
+        };
+        Ok::<_, MoniqueError>((provider, probe))
+    };
+    tokio::time::timeout(PROBE_TIMEOUT, probe)
+        .await
+        .map_err(|_| {
+            ProviderError::CustomError(format!("no response within {}s", PROBE_TIMEOUT.as_secs()))
+        })?
+}
+
+/// Connects to the providers in parallel, returning the reachable ones from the
+/// healthiest (see `rank`), or the last error if none is.
+pub async fn connect(urls: &[String]) -> Result<Vec<(Provider<Ws>, Probe)>> {
+    let mut probes = JoinSet::new();
+    for url in urls {
+        let url = url.clone();
+        probes.spawn(async move { probe(&url).await });
+    }
+    let mut connected = vec![];
+    let mut error = None;
+    while let Some(result) = probes.join_next().await {
+        match result {
+            Ok(Ok((provider, probe))) => {
+                debug!(
+                    head = probe.head,
+                    latency_ms = probe.latency.as_millis() as u64,
+                    "provider probed"
+                );
+                connected.push((provider, probe));
+            }
+            Ok(Err(e)) => {
+                warn!("provider probe failed: {}", e);
+                error = Some(e);
+            }
+            Err(e) => warn!("provider probe panicked: {}", e),
+        }
+    }
+    if connected.is_empty() {
+        return Err(error.unwrap_or_else(|| {
+            ProviderError::CustomError("no provider configured".to_string()).into()
+        }));
+    }
+    let order = rank(connected.iter().map(|(_, probe)| probe.clone()).collect());
+    connected.sort_by_key(|(_, probe)| order.iter().position(|ranked| ranked == probe));
+    Ok(connected)
+}
+
+/// Orders the probes from the healthiest: the providers close to the highest head
+/// come first, fastest first, then the lagging ones, highest head first.
+pub fn rank(mut probes: Vec<Probe>) -> Vec<Probe> {
+    let highest = probes.iter().map(|probe| probe.head).max().unwrap_or(0);
+    probes.sort_by_key(|probe| {
+        let lagging = probe.head + HEAD_TOLERANCE < highest;
+        (
+            lagging,
+            if lagging { highest - probe.head } else { 0 },
+            probe.latency,
+        )
+    });
+    probes
+}
+
+/// Whether the provider at `current` should be replaced by the first of the `ranked`
+/// probes: it was not reachable, lags behind the highest head, or is much slower
+/// than the fastest healthy provider.
+pub fn should_switch(current: &str, ranked: &[Probe]) -> bool {
+    let Some(best) = ranked.first() else {
+        return false;
+    };
+    let Some(probe) = ranked.iter().find(|probe| probe.url == current) else {
+        return true;
+    };
+    probe.url != best.url
+        && (probe.head + HEAD_TOLERANCE < best.head
+            || probe.latency > best.latency * LATENCY_FACTOR)
+}
+
+/// Outcome of the successive probes of the current provider.
+#[derive(Debug, Default)]
+pub struct Health {
+    current: Option<String>,
+    /// Consecutive probes in which `current` should have been replaced.
+    strikes: u32,
+}
+
+impl Health {
+    /// Records a probe of the providers, `ranked` from the healthiest, returning
+    /// whether the provider at `current` should now be replaced: only after
+    /// `SWITCH_AFTER` consecutive probes found it unhealthy.
+    pub fn record(&mut self, current: &str, ranked: &[Probe]) -> bool {
+        if self.current.as_deref() != Some(current) {
+            self.current = Some(current.to_string());
+            self.strikes = 0;
+        }
+        if !should_switch(current, ranked) {
+            self.strikes = 0;
+            return false;
+        }
+        self.strikes += 1;
+        if self.strikes < SWITCH_AFTER {
+            debug!(strikes = self.strikes, "provider unhealthy");
+            return false;
+        }
+        self.strikes = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(url: &str, head: u64, latency_ms: u64) -> Probe {
+        Probe {
+            url: url.to_string(),
+            head,
+            latency: Duration::from_millis(latency_ms),
+        }
+    }
+
+    #[test]
+    fn test_rank() {
+        let ranked = rank(vec![
+            probe("slow", 100, 300),
+            probe("behind", 90, 10),
+            probe("fast", 99, 50),
+            probe("stale", 80, 5),
+        ]);
+        let urls: Vec<&str> = ranked.iter().map(|probe| probe.url.as_str()).collect();
+        assert_eq!(urls, vec!["fast", "slow", "behind", "stale"]);
+
+        assert!(!should_switch("fast", &ranked));
+        // slow but alive providers are replaced
+        assert!(should_switch("slow", &ranked));
+        assert!(should_switch("behind", &ranked));
+        assert!(should_switch("unreachable", &ranked));
+        let close = rank(vec![probe("a", 100, 50), probe("b", 100, 80)]);
+        assert!(!should_switch("b", &close));
+        assert!(!should_switch("a", &[]));
+    }
+
+    #[test]
+    fn test_health() {
+        let healthy = rank(vec![probe("a", 100, 50), probe("b", 100, 60)]);
+        let unreachable = rank(vec![probe("b", 100, 60)]);
+        let mut health = Health::default();
+        // a transient failure does not switch
+        assert!(!health.record("a", &unreachable));
+        assert!(!health.record("a", &healthy));
+        for _ in 1..SWITCH_AFTER {
+            assert!(!health.record("a", &unreachable));
+        }
+        assert!(health.record("a", &unreachable));
+        // counted again for the new provider
+        assert!(!health.record("b", &rank(vec![probe("a", 100, 10), probe("b", 100, 60)])));
+        assert!(!health.record("a", &unreachable));
+    }
+}
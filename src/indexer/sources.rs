@@ -0,0 +1,38 @@
+use super::block::Source;
+use crate::index::SharedIndex;
+use crate::Result;
+use ethers::types::Address;
+
+/// Cumulative count of new addresses per source, kept in the stats table by the
+/// commits of the index, in the transaction of their blocks, and undone by its
+/// rollbacks.
+pub struct SourceStats {
+    db: SharedIndex<20, Address>,
+}
+
+impl SourceStats {
+    pub fn new(db: SharedIndex<20, Address>) -> Self {
+        Self { db }
+    }
+
+    pub fn snapshot(&self) -> Result<Vec<(Source, u64)>> {
+        Source::ALL
+            .iter()
+            .map(|&source| Ok((source, self.db.get_stat(key(source))?.unwrap_or(0))))
+            .collect()
+    }
+}
+
+/// Stats counter of the addresses added from `source`.
+pub fn key(source: Source) -> &'static str {
+    match source {
+        Source::Miner => "source:miner",
+        Source::Sender => "source:sender",
+        Source::Recipient => "source:recipient",
+        Source::Erc20 => "source:erc20",
+        Source::Erc1155 => "source:erc1155",
+        Source::Trace => "source:trace",
+        Source::Withdrawal => "source:withdrawal",
+        Source::Genesis => "source:genesis",
+    }
+}
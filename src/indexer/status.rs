@@ -0,0 +1,270 @@
+use crate::index::HistorySample;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+pub type StatusSender = Arc<watch::Sender<IndexerStatus>>;
+pub type StatusReceiver = watch::Receiver<IndexerStatus>;
+
+/// Seconds of recent progress samples from which `IndexerStatus::project` estimates
+/// the growth of the index.
+pub const GROWTH_WINDOW: u64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexerState {
+    Starting,
+    CatchingUp,
+    Live,
+    Paused,
+    /// Waiting for commits to bring the pending queue back under its memory cap.
+    Throttled,
+    Stalled,
+    /// Following a primary instance, ready to be promoted.
+    Standby,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    #[default]
+    Connecting,
+    Connected,
+    Reconnecting,
+}
+
+/// Connectivity of the node provider, across the reconnections of the indexer.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProviderStatus {
+    pub state: ConnectionState,
+    /// Connection attempts after the first one.
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<u64>,
+    /// When the current connection was established.
+    pub connected_at: Option<u64>,
+    /// When the current block subscription started.
+    pub subscribed_at: Option<u64>,
+    /// Age of the block subscription when the status was read.
+    pub subscription_age_seconds: Option<u64>,
+}
+
+impl ProviderStatus {
+    /// A connection attempt is starting.
+    pub fn connecting(&mut self) {
+        if self.connected_at.is_some() || self.last_error.is_some() {
+            self.state = ConnectionState::Reconnecting;
+            self.reconnects += 1;
+        }
+        self.connected_at = None;
+        self.subscribed_at = None;
+    }
+
+    pub fn connected(&mut self) {
+        self.state = ConnectionState::Connected;
+        self.connected_at = Some(now());
+    }
+
+    pub fn subscribed(&mut self) {
+        self.subscribed_at = Some(now());
+    }
+
+    /// The connection failed, or the indexer stopped with `error`.
+    pub fn failed(&mut self, error: impl ToString) {
+        self.state = ConnectionState::Reconnecting;
+        self.last_error = Some(error.to_string());
+        self.last_error_at = Some(now());
+        self.connected_at = None;
+        self.subscribed_at = None;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexerStatus {
+    pub state: IndexerState,
+    pub current_block: u64,
+    pub head_block: u64,
+    pub blocks_per_second: f64,
+    pub eta_seconds: Option<u64>,
+    pub addresses: usize,
+    /// New addresses per block over the recent samples.
+    #[serde(default)]
+    pub addresses_per_block: Option<f64>,
+    /// Addresses expected once the head block is indexed, at the recent rate.
+    #[serde(default)]
+    pub projected_addresses: Option<u64>,
+    pub commit_ms: Option<u64>,
+    pub cache_hit_rate: f64,
+    pub updated_at: u64,
+    #[serde(default)]
+    pub provider: ProviderStatus,
+}
+
+impl Default for IndexerStatus {
+    fn default() -> Self {
+        Self {
+            state: IndexerState::Starting,
+            current_block: 0,
+            head_block: 0,
+            blocks_per_second: 0.0,
+            eta_seconds: None,
+            addresses: 0,
+            addresses_per_block: None,
+            projected_addresses: None,
+            commit_ms: None,
+            cache_hit_rate: 0.0,
+            updated_at: now(),
+            provider: ProviderStatus::default(),
+        }
+    }
+}
+
+impl IndexerStatus {
+    pub fn new(state: IndexerState, current_block: u64, head_block: u64, speed: f64) -> Self {
+        Self {
+            state,
+            current_block,
+            head_block,
+            blocks_per_second: speed,
+            eta_seconds: eta(current_block, head_block, speed),
+            updated_at: now(),
+            ..Default::default()
+        }
+    }
+
+    /// Estimates from recent samples of the progress, oldest first: the indexing rate,
+    /// with the ETA, when none was measured yet (as for `monique info`), and the new
+    /// addresses per block, with the number of addresses projected at the head block.
+    pub fn project(&mut self, samples: &[HistorySample]) {
+        let (Some(first), Some(last)) = (samples.first(), samples.last()) else {
+            return;
+        };
+        let blocks = last.block.saturating_sub(first.block);
+        let seconds = last.timestamp.saturating_sub(first.timestamp);
+        if self.blocks_per_second == 0.0 && seconds > 0 {
+            self.blocks_per_second = blocks as f64 / seconds as f64;
+            self.eta_seconds = eta(self.current_block, self.head_block, self.blocks_per_second);
+        }
+        if blocks > 0 {
+            let per_block = last.addresses.saturating_sub(first.addresses) as f64 / blocks as f64;
+            let remaining = self.head_block.saturating_sub(self.current_block) as f64;
+            self.addresses_per_block = Some(per_block);
+            self.projected_addresses =
+                Some(self.addresses as u64 + (per_block * remaining).round() as u64);
+        }
+    }
+}
+
+fn eta(current_block: u64, head_block: u64, speed: f64) -> Option<u64> {
+    let remaining = head_block.saturating_sub(current_block);
+    if remaining == 0 {
+        Some(0)
+    } else if speed > 0.0 {
+        Some((remaining as f64 / speed).round() as u64)
+    } else {
+        None
+    }
+}
+
+impl IndexerStatus {
+    /// The status as of now, with the current age of the block subscription.
+    pub fn current(&self) -> Self {
+        let mut status = self.clone();
+        status.provider.subscription_age_seconds = status
+            .provider
+            .subscribed_at
+            .map(|subscribed_at| now().saturating_sub(subscribed_at));
+        status
+    }
+}
+
+pub fn channel() -> (StatusSender, StatusReceiver) {
+    let (tx, rx) = watch::channel(IndexerStatus::default());
+    (Arc::new(tx), rx)
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eta() {
+        let status = IndexerStatus::new(IndexerState::CatchingUp, 100, 1100, 20.0);
+        assert_eq!(status.eta_seconds, Some(50));
+
+        let status = IndexerStatus::new(IndexerState::CatchingUp, 100, 1100, 0.0);
+        assert_eq!(status.eta_seconds, None);
+
+        let status = IndexerStatus::new(IndexerState::Live, 1100, 1100, 0.1);
+        assert_eq!(status.eta_seconds, Some(0));
+    }
+
+    #[test]
+    fn test_project() {
+        let sample = |timestamp, block, addresses| HistorySample {
+            timestamp,
+            block,
+            addresses,
+            blocks_per_second: 0.0,
+        };
+        let samples = [
+            sample(1000, 100, 500),
+            sample(1060, 400, 800),
+            sample(1100, 600, 1500),
+        ];
+        let mut status = IndexerStatus::new(IndexerState::CatchingUp, 600, 1100, 0.0);
+        status.addresses = 1500;
+        status.project(&samples);
+        assert_eq!(status.blocks_per_second, 5.0);
+        assert_eq!(status.eta_seconds, Some(100));
+        assert_eq!(status.addresses_per_block, Some(2.0));
+        assert_eq!(status.projected_addresses, Some(2500));
+
+        // a measured rate is kept
+        let mut status = IndexerStatus::new(IndexerState::CatchingUp, 600, 1100, 20.0);
+        status.project(&samples);
+        assert_eq!(status.eta_seconds, Some(25));
+
+        // a single sample gives no estimate
+        let mut status = IndexerStatus::new(IndexerState::CatchingUp, 600, 1100, 0.0);
+        status.project(&samples[..1]);
+        assert_eq!(status.eta_seconds, None);
+        assert_eq!(status.projected_addresses, None);
+    }
+
+    #[test]
+    fn test_provider() {
+        let mut provider = ProviderStatus::default();
+        provider.connecting();
+        assert_eq!(
+            (provider.state, provider.reconnects),
+            (ConnectionState::Connecting, 0)
+        );
+        provider.connected();
+        provider.subscribed();
+        assert_eq!(provider.state, ConnectionState::Connected);
+        let mut status = IndexerStatus {
+            provider: provider.clone(),
+            ..Default::default()
+        };
+        assert_eq!(status.current().provider.subscription_age_seconds, Some(0));
+
+        provider.failed("subscription ended");
+        assert_eq!(provider.state, ConnectionState::Reconnecting);
+        assert_eq!(provider.last_error.as_deref(), Some("subscription ended"));
+        assert_eq!(provider.subscribed_at, None);
+        provider.connecting();
+        provider.connecting();
+        assert_eq!(provider.reconnects, 2);
+        status.provider = provider;
+        assert_eq!(status.current().provider.subscription_age_seconds, None);
+    }
+}
@@ -0,0 +1,145 @@
+//! Self-tuning of the catch-up: the number of blocks fetched concurrently follows the
+//! observed RPC latency and errors, and the commits are sized after their duration and
+//! the number of entries left pending in memory.
+
+use std::time::{Duration, Instant};
+
+/// Bounds of the number of blocks fetched concurrently.
+pub const MIN_IN_FLIGHT: usize = 1;
+pub const MAX_IN_FLIGHT: usize = 32;
+
+/// Fetches slower than this reduce the number of concurrent ones.
+const TARGET_LATENCY: Duration = Duration::from_millis(500);
+
+/// Bounds of the number of blocks committed at once.
+pub const MIN_COMMIT_BLOCKS: u64 = 100;
+pub const MAX_COMMIT_BLOCKS: u64 = 20_000;
+
+/// Commits longer than this are made smaller, those under a quarter of it larger.
+const TARGET_COMMIT: Duration = Duration::from_secs(5);
+
+/// Uncommitted entries above which a commit is due, whatever the number of blocks.
+#[cfg(not(test))]
+const MAX_PENDING_ENTRIES: usize = 1_000_000;
+#[cfg(test)]
+const MAX_PENDING_ENTRIES: usize = 1_000;
+
+/// Minimum interval between two commit attempts, e.g. while the safe block lags.
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of block fetches in flight, increased by one after a fast fetch and
+/// halved after an error, a slow fetch only decreasing it by one.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    in_flight: usize,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self {
+            in_flight: MIN_IN_FLIGHT,
+        }
+    }
+}
+
+impl Throttle {
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Adjusts the number of fetches in flight after a fetch which took `latency`.
+    pub fn record(&mut self, latency: Duration, ok: bool) {
+        self.in_flight = if !ok {
+            self.in_flight / 2
+        } else if latency > TARGET_LATENCY {
+            self.in_flight - 1
+        } else {
+            self.in_flight + 1
+        }
+        .clamp(MIN_IN_FLIGHT, MAX_IN_FLIGHT);
+    }
+}
+
+/// Number of blocks committed at once during catch-up.
+#[derive(Debug, Clone)]
+pub struct CommitBatch {
+    blocks: u64,
+    last_attempt: Instant,
+}
+
+impl Default for CommitBatch {
+    fn default() -> Self {
+        Self {
+            blocks: 1_000,
+            last_attempt: Instant::now(),
+        }
+    }
+}
+
+impl CommitBatch {
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    /// Whether to commit with `blocks` uncommitted blocks holding `entries` new entries.
+    pub fn is_due(&self, blocks: u64, entries: usize) -> bool {
+        (blocks >= self.blocks || entries >= MAX_PENDING_ENTRIES)
+            && self.last_attempt.elapsed() >= COMMIT_INTERVAL
+    }
+
+    /// Records a commit attempt, and the duration of the commit if there was one.
+    pub fn record(&mut self, duration: Option<Duration>) {
+        self.last_attempt = Instant::now();
+        match duration {
+            Some(duration) if duration > TARGET_COMMIT => self.blocks /= 2,
+            Some(duration) if duration < TARGET_COMMIT / 4 => self.blocks *= 2,
+            _ => {}
+        }
+        self.blocks = self.blocks.clamp(MIN_COMMIT_BLOCKS, MAX_COMMIT_BLOCKS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let mut throttle = Throttle::default();
+        let fast = Duration::from_millis(20);
+        for _ in 0..100 {
+            throttle.record(fast, true);
+        }
+        assert_eq!(throttle.in_flight(), MAX_IN_FLIGHT);
+        throttle.record(Duration::from_secs(1), true);
+        assert_eq!(throttle.in_flight(), MAX_IN_FLIGHT - 1);
+        throttle.record(fast, false);
+        assert_eq!(throttle.in_flight(), (MAX_IN_FLIGHT - 1) / 2);
+        for _ in 0..10 {
+            throttle.record(fast, false);
+        }
+        assert_eq!(throttle.in_flight(), MIN_IN_FLIGHT);
+    }
+
+    #[test]
+    fn test_commit_batch() {
+        let mut batch = CommitBatch::default();
+        batch.last_attempt -= COMMIT_INTERVAL;
+        assert!(!batch.is_due(batch.blocks() - 1, 0));
+        assert!(batch.is_due(batch.blocks(), 0));
+        // too many entries in memory
+        assert!(batch.is_due(1, MAX_PENDING_ENTRIES));
+
+        batch.record(None);
+        assert!(!batch.is_due(batch.blocks(), 0));
+        assert_eq!(batch.blocks(), 1_000);
+        batch.record(Some(Duration::from_millis(100)));
+        assert_eq!(batch.blocks(), 2_000);
+        batch.record(Some(TARGET_COMMIT * 2));
+        assert_eq!(batch.blocks(), 1_000);
+        for _ in 0..10 {
+            batch.record(Some(TARGET_COMMIT * 2));
+        }
+        assert_eq!(batch.blocks(), MIN_COMMIT_BLOCKS);
+    }
+}
@@ -0,0 +1,204 @@
+//! Publisher of checkpoint manifests to IPFS, giving clients a decentralized source of
+//! verification data.
+
+use crate::index::SharedIndex;
+use crate::words;
+use crate::{MoniqueError, Result};
+use ethers::types::{Address, H256};
+use reqwest::multipart::{Form, Part};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Stats key of the last block published.
+const LAST_BLOCK_KEY: &str = "ipfs:last_block";
+
+/// Stats key of the last manifest published, `<block> <cid>`, linked by the next one.
+const MANIFEST_KEY: &str = "ipfs:manifest";
+
+/// Checkpoint of a block, pinned to IPFS as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub block: u64,
+    pub root: H256,
+    pub hash: H256,
+    pub index_root: Option<H256>,
+    /// Fingerprint of the vocabulary of the monics of the publisher, missing from the
+    /// manifests published before it.
+    pub wordlist: Option<H256>,
+    /// CID of the dump of the blocks since the previous manifest, `from..=block`.
+    pub snapshot: Option<String>,
+    pub from: u64,
+    /// CID of the previous manifest published by this instance, if known.
+    pub previous: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AddResponse {
+    #[serde(rename = "Hash")]
+    hash: String,
+}
+
+pub struct IpfsPublisher {
+    client: reqwest::Client,
+    /// Base URL of the Kubo RPC API, e.g. `http://localhost:5001`.
+    api: String,
+    /// Blocks between two manifests.
+    interval: u64,
+    /// Whether manifests link a dump of their blocks.
+    snapshots: bool,
+}
+
+impl IpfsPublisher {
+    pub fn new(api: &str, interval: u64, snapshots: bool) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            api: api.trim_end_matches('/').to_string(),
+            interval: interval.max(1),
+            snapshots,
+        }
+    }
+
+    /// Adds and pins `data`, returning its CID.
+    async fn add(&self, name: &str, data: Vec<u8>) -> Result<String> {
+        let error = |e: reqwest::Error| MoniqueError::Publisher(e.to_string());
+        let form = Form::new().part("file", Part::bytes(data).file_name(name.to_string()));
+        let response: AddResponse = self
+            .client
+            .post(format!("{}/api/v0/add?pin=true&cid-version=1", self.api))
+            .multipart(form)
+            .send()
+            .await
+            .map_err(error)?
+            .error_for_status()
+            .map_err(error)?
+            .json()
+            .await
+            .map_err(error)?;
+        Ok(response.hash)
+    }
+
+    /// Publishes the manifest of block `to`, covering the blocks `from..=to`.
+    pub async fn publish(
+        &self,
+        db: &SharedIndex<20, Address>,
+        from: u64,
+        to: u64,
+        previous: Option<String>,
+    ) -> Result<(String, Manifest)> {
+        let checkpoint = db.checkpoint(to)?.ok_or_else(|| {
+            MoniqueError::Publisher(format!("no checkpoint recorded for block {}", to))
+        })?;
+        let snapshot = match self.snapshots {
+            true => {
+                let mut dump = vec![];
+                db.export(from, to, &mut dump).await?;
+                Some(self.add(&format!("{}-{}.dump", from, to), dump).await?)
+            }
+            false => None,
+        };
+        let manifest = Manifest {
+            block: to,
+            root: checkpoint.root,
+            hash: checkpoint.hash,
+            index_root: db.index_root(to)?,
+            wordlist: Some(words::fingerprint()),
+            snapshot,
+            from,
+            previous,
+        };
+        let payload = serde_json::to_vec(&manifest).expect("serializable manifest");
+        let cid = self.add(&format!("{}.json", to), payload).await?;
+        Ok((cid, manifest))
+    }
+}
+
+/// Next range of blocks to publish once `committed` is known, every `interval` blocks.
+fn next_range(last_published: u64, committed: u64, interval: u64) -> Option<(u64, u64)> {
+    let to = last_published + interval;
+    (to <= committed).then_some((last_published + 1, to))
+}
+
+/// CID of the manifest of `block`, if it is the last one recorded.
+fn recorded_manifest(db: &SharedIndex<20, Address>, block: u64) -> Result<Option<String>> {
+    let Some(manifest) = db.get_stat_text(MANIFEST_KEY)? else {
+        return Ok(None);
+    };
+    Ok(match manifest.split_once(' ') {
+        Some((number, cid)) if number.parse::<u64>() == Ok(block) => Some(cid.to_string()),
+        _ => None,
+    })
+}
+
+/// Publishes a manifest every `interval` committed blocks, resuming after the last
+/// block published by this datadir and linking its manifest. After a rollback below
+/// the last published block, publishing resumes from the block rolled back to, the new
+/// manifests not linking those of the blocks removed.
+pub async fn run(db: SharedIndex<20, Address>, publisher: IpfsPublisher) -> Result<()> {
+    let mut last_published = match db.get_stat(LAST_BLOCK_KEY)? {
+        Some(block) => block,
+        None => db.start_block() - 1,
+    };
+    let mut previous = recorded_manifest(&db, last_published)?;
+    info!(
+        "publishing checkpoint manifests to {} every {} blocks",
+        publisher.api, publisher.interval
+    );
+    let mut rollbacks = db.subscribe_rollbacks();
+    let mut commits = db.subscribe_commits();
+    loop {
+        // taken first, the rollbacks being sent before the commits watch is updated
+        let rollback = rollbacks.take();
+        let committed = *commits.borrow_and_update();
+        let keep = rollback.unwrap_or(u64::MAX).min(committed);
+        if keep < last_published {
+            warn!(
+                block = keep,
+                last_published, "rolled back, the manifests after the block are superseded"
+            );
+            db.put_stats(vec![(LAST_BLOCK_KEY.to_string(), keep)])?;
+            (last_published, previous) = (keep, None);
+        }
+        while let Some((from, to)) = next_range(last_published, committed, publisher.interval) {
+            let (cid, _) = publisher.publish(&db, from, to, previous).await?;
+            info!(block = to, %cid, "checkpoint manifest published to IPFS");
+            db.put_stat_text(MANIFEST_KEY, &format!("{} {}", to, cid))?;
+            db.put_stats(vec![(LAST_BLOCK_KEY.to_string(), to)])?;
+            (last_published, previous) = (to, Some(cid));
+        }
+        if commits.changed().await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_range() {
+        assert_eq!(next_range(0, 99, 100), None);
+        assert_eq!(next_range(0, 250, 100), Some((1, 100)));
+        assert_eq!(next_range(100, 250, 100), Some((101, 200)));
+        assert_eq!(next_range(200, 250, 100), None);
+    }
+
+    #[tokio::test]
+    async fn test_recorded_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = std::sync::Arc::new(
+            crate::index::IndexTable::<20, Address>::builder(dir.path())
+                .build()
+                .await
+                .unwrap(),
+        );
+        assert_eq!(recorded_manifest(&db, 100).unwrap(), None);
+        db.put_stat_text(MANIFEST_KEY, "100 bafyexample").unwrap();
+        assert_eq!(
+            recorded_manifest(&db, 100).unwrap(),
+            Some("bafyexample".to_string())
+        );
+        // the manifest of another block, e.g. after a rollback, is not linked
+        assert_eq!(recorded_manifest(&db, 50).unwrap(), None);
+    }
+}
@@ -1,6 +1,45 @@
+#[cfg(feature = "api")]
 pub mod api;
+#[cfg(feature = "clickhouse")]
+pub mod clickhouse;
+#[cfg(feature = "dns")]
+pub mod dns;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+#[cfg(feature = "ens")]
+pub mod ens;
+pub mod error;
+#[cfg(feature = "follow")]
+pub mod follower;
+#[cfg(feature = "index")]
 pub mod index;
+#[cfg(feature = "indexer")]
 pub mod indexer;
+#[cfg(feature = "ipfs")]
+pub mod ipfs;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "api")]
+pub mod qr;
+#[cfg(feature = "api")]
+pub mod rpc;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(any(feature = "postgres", feature = "clickhouse"))]
+mod sink;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "verify")]
+pub mod verify;
+#[cfg(feature = "watchlist")]
+pub mod watchlist;
+#[cfg(feature = "webhooks")]
+pub mod webhooks;
+#[cfg(feature = "words")]
 pub mod words;
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+pub use error::MoniqueError;
+
+pub type Result<T> = std::result::Result<T, MoniqueError>;
@@ -0,0 +1,69 @@
+use crate::index::SharedIndex;
+use crate::watchlist::SharedWatchlists;
+use crate::words::{self, PIVOT};
+use crate::{MoniqueError, Result};
+use ethers::types::{Address, H256};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Message published for every committed address.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexedAddress {
+    pub index: usize,
+    pub address: Address,
+    pub monic: String,
+    pub block: u64,
+    pub checkpoint_root: Option<H256>,
+}
+
+/// Publishes the committed addresses to a NATS subject, as JSON messages. The events
+/// of `watchlists`, if given, go to `<subject>.watchlist.<name>`.
+pub async fn publish(
+    db: SharedIndex<20, Address>,
+    url: &str,
+    subject: String,
+    watchlists: Option<SharedWatchlists>,
+) -> Result<()> {
+    let client = async_nats::connect(url)
+        .await
+        .map_err(|e| MoniqueError::Publisher(e.to_string()))?;
+    info!("publishing indexed addresses to {} on {}", subject, url);
+    let mut entries = db.subscribe_entries();
+    let mut root: Option<(u64, Option<H256>)> = None;
+    // the entries missed by a slow publisher are read back from the index
+    while let Some((index, address, block)) = entries.recv().await? {
+        if let Some(watchlists) = &watchlists {
+            for event in watchlists.events(index, address, block).await {
+                let payload = serde_json::to_vec(&event).expect("serializable message");
+                client
+                    .publish(
+                        format!("{}.watchlist.{}", subject, event.watchlist),
+                        payload.into(),
+                    )
+                    .await
+                    .map_err(|e| MoniqueError::Publisher(e.to_string()))?;
+            }
+        }
+        let checkpoint_root = match root {
+            Some((number, root)) if number == block => root,
+            _ => {
+                let checkpoint_root = db.checkpoint_root(block)?;
+                root = Some((block, checkpoint_root));
+                checkpoint_root
+            }
+        };
+        let message = IndexedAddress {
+            index: index + PIVOT,
+            address,
+            monic: words::to_words((index + PIVOT) as u64, words::checksum(address)),
+            block,
+            checkpoint_root,
+        };
+        let payload = serde_json::to_vec(&message).expect("serializable message");
+        client
+            .publish(subject.clone(), payload.into())
+            .await
+            .map_err(|e| MoniqueError::Publisher(e.to_string()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,279 @@
+//! Writer of the committed entries and checkpoints into PostgreSQL tables, for
+//! analytics warehouses.
+
+use crate::index::SharedIndex;
+use crate::sink::{follow, validate_table, Sink};
+use crate::words::{self, PIVOT};
+use crate::{MoniqueError, Result};
+use async_trait::async_trait;
+use ethers::types::Address;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::{Postgres, QueryBuilder};
+use tracing::info;
+
+/// Blocks written per PostgreSQL transaction while catching up.
+const BATCH_BLOCKS: u64 = 1_000;
+
+/// Rows per `INSERT` statement, below the limit of 65535 bind parameters.
+const BATCH_ROWS: usize = 10_000;
+
+/// Tables written by the sink, created if they do not exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tables {
+    /// `(idx, address, monic, block)` rows, keyed by the pivoted index.
+    pub entries: String,
+    /// `(block, root, hash, index_root)` rows, keyed by the block number.
+    pub checkpoints: String,
+}
+
+impl Default for Tables {
+    fn default() -> Self {
+        Self {
+            entries: "monique_entries".to_string(),
+            checkpoints: "monique_checkpoints".to_string(),
+        }
+    }
+}
+
+impl Tables {
+    fn validate(&self) -> Result<()> {
+        validate_table(&self.entries)?;
+        validate_table(&self.checkpoints)
+    }
+}
+
+pub struct PostgresSink {
+    pool: PgPool,
+    tables: Tables,
+}
+
+impl PostgresSink {
+    pub async fn connect(url: &str, tables: Tables) -> Result<Self> {
+        tables.validate()?;
+        let pool = PgPoolOptions::new().max_connections(2).connect(url).await?;
+        let sink = Self { pool, tables };
+        sink.create_tables().await?;
+        Ok(sink)
+    }
+
+    async fn create_tables(&self) -> Result<()> {
+        sqlx::raw_sql(&format!(
+            "CREATE TABLE IF NOT EXISTS {entries} (
+                idx BIGINT PRIMARY KEY,
+                address TEXT NOT NULL,
+                monic TEXT NOT NULL,
+                block BIGINT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {checkpoints} (
+                block BIGINT PRIMARY KEY,
+                root TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                index_root TEXT
+            );",
+            entries = self.tables.entries,
+            checkpoints = self.tables.checkpoints,
+        ))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Last block whose checkpoint was written, 0 if none.
+    pub async fn last_block(&self) -> Result<u64> {
+        let last_block: Option<i64> = sqlx::query_scalar(&format!(
+            "SELECT MAX(block) FROM {}",
+            self.tables.checkpoints
+        ))
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(last_block.unwrap_or(0) as u64)
+    }
+
+    /// Deletes the entries and checkpoints of the blocks after `to`, e.g. after a
+    /// rollback of the index.
+    pub async fn rewind(&self, to: u64) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for table in [&self.tables.entries, &self.tables.checkpoints] {
+            sqlx::query(&format!("DELETE FROM {} WHERE block > $1", table))
+                .bind(to as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Writes the committed blocks `from..=to`, from their recorded ranges. Rows are
+    /// upserted, so that blocks can be written again. Returns the number of entries.
+    pub async fn write(&self, db: &SharedIndex<20, Address>, from: u64, to: u64) -> Result<usize> {
+        let mut rows = 0;
+        let mut from = from.max(db.start_block());
+        while from <= to {
+            let last = to.min(from + BATCH_BLOCKS - 1);
+            let mut entries = vec![];
+            let mut checkpoints = vec![];
+            for number in from..=last {
+                let (start, items) = db.block_entries(number)?.ok_or_else(|| {
+                    MoniqueError::Sink(format!("no range recorded for block {}", number))
+                })?;
+                for (offset, address) in items.into_iter().enumerate() {
+                    let index = start + offset + PIVOT;
+                    let monic = words::to_words(index as u64, words::checksum(address));
+                    entries.push((
+                        index as i64,
+                        format!("{:#x}", address),
+                        monic,
+                        number as i64,
+                    ));
+                }
+                let checkpoint = db.checkpoint(number)?.ok_or_else(|| {
+                    MoniqueError::Sink(format!("no checkpoint recorded for block {}", number))
+                })?;
+                checkpoints.push((
+                    number as i64,
+                    format!("{:#x}", checkpoint.root),
+                    format!("{:#x}", checkpoint.hash),
+                    db.index_root(number)?.map(|root| format!("{:#x}", root)),
+                ));
+            }
+            rows += entries.len();
+
+            let mut tx = self.pool.begin().await?;
+            for chunk in entries.chunks(BATCH_ROWS) {
+                let mut query: QueryBuilder<Postgres> = QueryBuilder::new(format!(
+                    "INSERT INTO {} (idx, address, monic, block) ",
+                    self.tables.entries
+                ));
+                query.push_values(chunk, |mut row, (index, address, monic, block)| {
+                    row.push_bind(index)
+                        .push_bind(address)
+                        .push_bind(monic)
+                        .push_bind(block);
+                });
+                query.push(
+                    " ON CONFLICT (idx) DO UPDATE SET address = EXCLUDED.address, \
+                    monic = EXCLUDED.monic, block = EXCLUDED.block",
+                );
+                query.build().execute(&mut *tx).await?;
+            }
+            for chunk in checkpoints.chunks(BATCH_ROWS) {
+                let mut query: QueryBuilder<Postgres> = QueryBuilder::new(format!(
+                    "INSERT INTO {} (block, root, hash, index_root) ",
+                    self.tables.checkpoints
+                ));
+                query.push_values(chunk, |mut row, (block, root, hash, index_root)| {
+                    row.push_bind(block)
+                        .push_bind(root)
+                        .push_bind(hash)
+                        .push_bind(index_root);
+                });
+                query.push(
+                    " ON CONFLICT (block) DO UPDATE SET root = EXCLUDED.root, \
+                    hash = EXCLUDED.hash, index_root = EXCLUDED.index_root",
+                );
+                query.build().execute(&mut *tx).await?;
+            }
+            tx.commit().await?;
+            from = last + 1;
+        }
+        Ok(rows)
+    }
+}
+
+#[async_trait]
+impl Sink for PostgresSink {
+    const NAME: &'static str = "PostgreSQL";
+
+    async fn rewind(&self, to: u64) -> Result<()> {
+        PostgresSink::rewind(self, to).await
+    }
+
+    async fn write(&self, db: &SharedIndex<20, Address>, from: u64, to: u64) -> Result<usize> {
+        PostgresSink::write(self, db, from, to).await
+    }
+}
+
+/// Streams the committed entries and checkpoints into PostgreSQL, first catching up
+/// from the last written checkpoint, or from `from` if given, then following the
+/// commits. The rows of the blocks rolled back are deleted, as are those of blocks
+/// written past the last committed one. Failures are retried with a backoff once
+/// connected.
+pub async fn stream(
+    db: SharedIndex<20, Address>,
+    url: &str,
+    tables: Tables,
+    from: Option<u64>,
+) -> Result<()> {
+    let sink = PostgresSink::connect(url, tables).await?;
+    let written = sink.last_block().await?;
+    let next = match from {
+        Some(from) => from.max(db.start_block()),
+        None => (written + 1).max(db.start_block()),
+    };
+    info!(
+        "writing committed entries to {} from block {}",
+        sink.tables.entries, next
+    );
+    follow(&sink, db, next, written).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexTable;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_table_names() {
+        assert!(Tables::default().validate().is_ok());
+        let tables = |entries: &str| Tables {
+            entries: entries.to_string(),
+            ..Default::default()
+        };
+        assert!(tables("analytics.monique_entries").validate().is_ok());
+        assert!(tables("entries; DROP TABLE users").validate().is_err());
+        assert!(tables("1entries").validate().is_err());
+        assert!(tables("a.b.c").validate().is_err());
+        assert!(tables("").validate().is_err());
+    }
+
+    /// Needs a PostgreSQL server (set POSTGRES_URL to override the default one).
+    #[tokio::test]
+    #[ignore]
+    async fn test_write() {
+        let url = std::env::var("POSTGRES_URL")
+            .unwrap_or("postgres://postgres@localhost/postgres".to_string());
+        let dir = tempfile::tempdir().unwrap();
+        let db = IndexTable::<20, Address>::builder(dir.path())
+            .build()
+            .await
+            .unwrap();
+        db.queue(1, vec![Address::repeat_byte(1), Address::repeat_byte(2)])
+            .await
+            .unwrap();
+        db.queue(2, vec![Address::repeat_byte(3)]).await.unwrap();
+        db.commit(2).await.unwrap();
+        let db = Arc::new(db);
+
+        let tables = Tables {
+            entries: "monique_test_entries".to_string(),
+            checkpoints: "monique_test_checkpoints".to_string(),
+        };
+        let sink = PostgresSink::connect(&url, tables).await.unwrap();
+        sqlx::raw_sql("TRUNCATE monique_test_entries, monique_test_checkpoints")
+            .execute(&sink.pool)
+            .await
+            .unwrap();
+        assert_eq!(sink.last_block().await.unwrap(), 0);
+        assert_eq!(sink.write(&db, 1, 2).await.unwrap(), 3);
+        // upserts
+        assert_eq!(sink.write(&db, 2, 2).await.unwrap(), 1);
+        assert_eq!(sink.last_block().await.unwrap(), 2);
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM monique_test_entries")
+            .fetch_one(&sink.pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+}
@@ -0,0 +1,569 @@
+//! QR codes of short texts (byte mode, error correction level M, versions 1 to 10),
+//! rendered as SVG, e.g. for deposit slips showing an address with its monic.
+
+use std::fmt::Write;
+
+/// Codewords of each version at level M: total, error correction per block, then
+/// the blocks of the two groups and their data codewords.
+const VERSIONS: [(usize, usize, usize, usize, usize, usize); 10] = [
+    (26, 10, 1, 16, 0, 0),
+    (44, 16, 1, 28, 0, 0),
+    (70, 26, 1, 44, 0, 0),
+    (100, 18, 2, 32, 0, 0),
+    (134, 24, 2, 43, 0, 0),
+    (172, 16, 4, 27, 0, 0),
+    (196, 18, 4, 31, 0, 0),
+    (242, 22, 2, 38, 2, 39),
+    (292, 22, 3, 36, 2, 37),
+    (346, 26, 4, 43, 1, 44),
+];
+
+/// Centers of the alignment patterns of each version, on both axes.
+const ALIGNMENT: [&[usize]; 10] = [
+    &[],
+    &[6, 18],
+    &[6, 22],
+    &[6, 26],
+    &[6, 30],
+    &[6, 34],
+    &[6, 22, 38],
+    &[6, 24, 42],
+    &[6, 26, 46],
+    &[6, 28, 50],
+];
+
+/// Longest text encoded, the byte capacity of version 10.
+pub const MAX_LEN: usize = 213;
+
+/// Square matrix of modules, `true` being dark.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encodes `text` in the smallest version which holds it, or `None` over
+    /// [`MAX_LEN`] bytes.
+    pub fn encode(text: &str) -> Option<Self> {
+        Self::encode_masked(text, None)
+    }
+
+    /// Encodes `text` with `mask`, or with the mask of the lowest penalty.
+    fn encode_masked(text: &str, mask: Option<u8>) -> Option<Self> {
+        let bytes = text.as_bytes();
+        let version = (1..=10).find(|v| bytes.len() <= capacity(*v))?;
+        let data = data_codewords(bytes, version);
+        let codewords = interleave(&data, version);
+
+        let mut code = Builder::new(version);
+        code.function_patterns();
+        code.codewords(&codewords);
+        // the mask with the lowest penalty
+        let mask = mask.unwrap_or_else(|| {
+            (0..8)
+                .min_by_key(|mask| {
+                    let mut masked = code.clone();
+                    masked.mask(*mask);
+                    masked.format(*mask);
+                    masked.penalty()
+                })
+                .unwrap()
+        });
+        code.mask(mask);
+        code.format(mask);
+        Some(Self {
+            size: code.size,
+            modules: code.modules,
+        })
+    }
+
+    /// Modules per side.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    /// SVG image of the code, `scale` pixels per module, with a quiet zone of 4
+    /// modules.
+    pub fn to_svg(&self, scale: usize) -> String {
+        let side = self.size + 8;
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.is_dark(x, y) {
+                    let _ = write!(path, "M{},{}h1v1h-1z", x + 4, y + 4);
+                }
+            }
+        }
+        format!(
+            concat!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {side} {side}\" ",
+                "width=\"{px}\" height=\"{px}\" shape-rendering=\"crispEdges\">",
+                "<rect width=\"{side}\" height=\"{side}\" fill=\"#fff\"/>",
+                "<path d=\"{path}\" fill=\"#000\"/></svg>"
+            ),
+            side = side,
+            px = side * scale,
+            path = path
+        )
+    }
+}
+
+/// Bytes held by a version: its data codewords, less the mode and the length.
+fn capacity(version: usize) -> usize {
+    (data_len(version) * 8 - 4 - count_bits(version)) / 8
+}
+
+fn data_len(version: usize) -> usize {
+    let (_, _, blocks1, data1, blocks2, data2) = VERSIONS[version - 1];
+    blocks1 * data1 + blocks2 * data2
+}
+
+/// Bits of the length of the text.
+fn count_bits(version: usize) -> usize {
+    if version < 10 {
+        8
+    } else {
+        16
+    }
+}
+
+/// Mode, length and bytes of the text, then the terminator and the padding.
+fn data_codewords(bytes: &[u8], version: usize) -> Vec<u8> {
+    let mut bits = Bits::default();
+    bits.push(0b0100, 4);
+    bits.push(bytes.len() as u32, count_bits(version));
+    for byte in bytes {
+        bits.push(*byte as u32, 8);
+    }
+    let capacity = data_len(version) * 8;
+    bits.push(0, std::cmp::min(4, capacity - bits.len));
+    bits.push(0, (8 - bits.len % 8) % 8);
+    let mut data = bits.bytes;
+    for pad in [0xec, 0x11].into_iter().cycle() {
+        if data.len() == data_len(version) {
+            break;
+        }
+        data.push(pad);
+    }
+    data
+}
+
+#[derive(Default)]
+struct Bits {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bits {
+    /// Appends the `count` low bits of `value`, most significant first.
+    fn push(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if value >> i & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+/// Splits the data into the blocks of the version, each followed by its error
+/// correction, and interleaves them.
+fn interleave(data: &[u8], version: usize) -> Vec<u8> {
+    let (total, ec, blocks1, data1, blocks2, data2) = VERSIONS[version - 1];
+    let divisor = rs_divisor(ec);
+    let mut blocks = vec![];
+    let mut rest = data;
+    for len in std::iter::repeat_n(data1, blocks1).chain(std::iter::repeat_n(data2, blocks2)) {
+        let (block, next) = rest.split_at(len);
+        blocks.push((block, rs_remainder(block, &divisor)));
+        rest = next;
+    }
+    let mut codewords = Vec::with_capacity(total);
+    for i in 0..data1.max(data2) {
+        codewords.extend(blocks.iter().filter_map(|(block, _)| block.get(i)));
+    }
+    for i in 0..ec {
+        codewords.extend(blocks.iter().map(|(_, ecc)| ecc[i]));
+    }
+    codewords
+}
+
+/// Product of two elements of GF(2^8), modulo x^8 + x^4 + x^3 + x^2 + 1.
+fn gf_mul(x: u8, y: u8) -> u8 {
+    let mut z: u16 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11d);
+        z ^= ((y as u16 >> i) & 1) * x as u16;
+    }
+    z as u8
+}
+
+/// Coefficients of the Reed-Solomon generator polynomial of `degree`, highest
+/// first, the leading 1 left out.
+fn rs_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_mul(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_mul(root, 2);
+    }
+    result
+}
+
+/// Error correction codewords of a block.
+fn rs_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (r, d) in result.iter_mut().zip(divisor) {
+            *r ^= gf_mul(*d, factor);
+        }
+    }
+    result
+}
+
+/// Format bits of level M with a mask: BCH(15, 5) code, then XOR-masked.
+fn format_bits(mask: u8) -> u32 {
+    // level M is 00
+    let data = mask as u32;
+    let mut rem = data;
+    for _ in 0..10 {
+        rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+    ((data << 10) | rem) ^ 0x5412
+}
+
+/// Version bits of versions 7 and up: BCH(18, 6) code.
+fn version_bits(version: usize) -> u32 {
+    let mut rem = version as u32;
+    for _ in 0..12 {
+        rem = (rem << 1) ^ ((rem >> 11) * 0x1f25);
+    }
+    (version as u32) << 12 | rem
+}
+
+/// Matrix being drawn, with the modules of the function patterns, which the data
+/// and the mask skip.
+#[derive(Clone)]
+struct Builder {
+    version: usize,
+    size: usize,
+    modules: Vec<bool>,
+    function: Vec<bool>,
+}
+
+impl Builder {
+    fn new(version: usize) -> Self {
+        let size = 17 + 4 * version;
+        Self {
+            version,
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        }
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn function_patterns(&mut self) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            self.finder(x, y);
+        }
+        let centers = ALIGNMENT[self.version - 1];
+        for (i, y) in centers.iter().enumerate() {
+            for (j, x) in centers.iter().enumerate() {
+                let last = centers.len() - 1;
+                // over the finders
+                if (i, j) == (0, 0) || (i, j) == (0, last) || (i, j) == (last, 0) {
+                    continue;
+                }
+                self.alignment(*x, *y);
+            }
+        }
+        // reserved until the mask is chosen
+        self.format(0);
+        if self.version >= 7 {
+            let bits = version_bits(self.version);
+            for i in 0..18 {
+                let dark = bits >> i & 1 == 1;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set_function(a, b, dark);
+                self.set_function(b, a, dark);
+            }
+        }
+    }
+
+    /// Finder pattern centered on `(x, y)`, with its separator.
+    fn finder(&mut self, x: usize, y: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (xx, yy) = (x as i32 + dx, y as i32 + dy);
+                if (0..self.size as i32).contains(&xx) && (0..self.size as i32).contains(&yy) {
+                    let distance = dx.abs().max(dy.abs());
+                    self.set_function(xx as usize, yy as usize, distance != 2 && distance != 4);
+                }
+            }
+        }
+    }
+
+    fn alignment(&mut self, x: usize, y: usize) {
+        for dy in -2i32..=2 {
+            for dx in -2i32..=2 {
+                let distance = dx.abs().max(dy.abs());
+                let (xx, yy) = ((x as i32 + dx) as usize, (y as i32 + dy) as usize);
+                self.set_function(xx, yy, distance != 1);
+            }
+        }
+    }
+
+    /// Both copies of the format bits, with the dark module.
+    fn format(&mut self, mask: u8) {
+        let bits = format_bits(mask);
+        let bit = |i: usize| bits >> i & 1 == 1;
+        let size = self.size;
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Places the codewords in the zigzag of two-module columns, from the bottom
+    /// right corner.
+    fn codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        loop {
+            // the vertical timing pattern is skipped
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward {
+                        size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+                    if !self.function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = codewords[i / 8] >> (7 - i % 8) & 1 == 1;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn mask(&mut self, mask: u8) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if invert && !self.function[y * self.size + x] {
+                    self.modules[y * self.size + x] ^= true;
+                }
+            }
+        }
+    }
+
+    /// Penalty of the masked matrix: runs of five or more modules, 2x2 blocks, finder
+    /// like patterns, and imbalance of dark and light modules.
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let at = |x: usize, y: usize| self.modules[y * size + x];
+        let mut penalty = 0;
+        const FINDER: [bool; 11] = [
+            true, false, true, true, true, false, true, false, false, false, false,
+        ];
+        for horizontal in [true, false] {
+            for a in 0..size {
+                let line: Vec<bool> = (0..size)
+                    .map(|b| if horizontal { at(b, a) } else { at(a, b) })
+                    .collect();
+                let mut run = 1;
+                for b in 1..=size {
+                    if b < size && line[b] == line[b - 1] {
+                        run += 1;
+                        continue;
+                    }
+                    if run >= 5 {
+                        penalty += run - 2;
+                    }
+                    run = 1;
+                }
+                for window in line.windows(11) {
+                    if window == FINDER || window.iter().rev().eq(FINDER.iter()) {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = at(x, y);
+                if color == at(x + 1, y) && color == at(x, y + 1) && color == at(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+        let dark = self.modules.iter().filter(|dark| **dark).count();
+        let total = size * size;
+        // 10 per 5% away from half dark
+        let k = (dark * 20)
+            .abs_diff(total * 10)
+            .div_ceil(total)
+            .saturating_sub(1);
+        penalty + k * 10
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_correction() {
+        // "HELLO WORLD" at 1-M, in alphanumeric mode
+        let data = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17,
+        ];
+        assert_eq!(
+            rs_remainder(&data, &rs_divisor(10)),
+            vec![196, 35, 39, 119, 235, 215, 231, 226, 93, 23]
+        );
+    }
+
+    #[test]
+    fn format_and_version() {
+        assert_eq!(format_bits(0), 0b101010000010010);
+        assert_eq!(format_bits(5), 0b100000011001110);
+        assert_eq!(version_bits(7), 0b000111110010010100);
+    }
+
+    #[test]
+    fn versions() {
+        let capacities: Vec<usize> = (1..=10).map(capacity).collect();
+        assert_eq!(
+            capacities,
+            vec![14, 26, 42, 62, 84, 106, 122, 152, 180, 213]
+        );
+        for version in 1..=10 {
+            let (total, ec, blocks1, _, blocks2, _) = VERSIONS[version - 1];
+            assert_eq!(data_len(version) + ec * (blocks1 + blocks2), total);
+        }
+        let text = "ethereum:0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed?monic=abandon+ability";
+        let code = QrCode::encode(text).unwrap();
+        assert_eq!(code.size(), 17 + 4 * 5);
+        assert!(QrCode::encode(&"a".repeat(MAX_LEN)).is_some());
+        assert!(QrCode::encode(&"a".repeat(MAX_LEN + 1)).is_none());
+    }
+
+    #[test]
+    fn golden() {
+        // "monique" at 1-M with mask 2, from an independent implementation of the
+        // specification; its format bits are those of the table of ISO/IEC 18004
+        let expected = [
+            "#######...###.#######",
+            "#.....#..####.#.....#",
+            "#.###.#.#...#.#.###.#",
+            "#.###.#.###...#.###.#",
+            "#.###.#.##..#.#.###.#",
+            "#.....#.####..#.....#",
+            "#######.#.#.#.#######",
+            "........#.#..........",
+            "#.#####....#..#####..",
+            "#...#..#.#######.#..#",
+            "#....##.#...#.##.#.#.",
+            "...#.#..#..####..###.",
+            "#...###.#.#.#......##",
+            "........#.#.#..###..#",
+            "#######...##.#.....#.",
+            "#.....#.###......####",
+            "#.###.#.####.#.....#.",
+            "#.###.#.##.####.#....",
+            "#.###.#.##..#.#..##..",
+            "#.....#..######..##..",
+            "#######.##..#..#.#.#.",
+        ];
+        let code = QrCode::encode_masked("monique", Some(2)).unwrap();
+        let rows: Vec<String> = (0..code.size())
+            .map(|y| {
+                (0..code.size())
+                    .map(|x| if code.is_dark(x, y) { '#' } else { '.' })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(rows, expected);
+        // the encoder picks one of the eight masks
+        let best = QrCode::encode("monique").unwrap();
+        assert_eq!(best.size(), code.size());
+        assert!(
+            (0..8).any(|mask| QrCode::encode_masked("monique", Some(mask)) == Some(best.clone()))
+        );
+    }
+
+    #[test]
+    fn patterns() {
+        let code = QrCode::encode("monique").unwrap();
+        assert_eq!(code.size(), 21);
+        // finders, with their separators, and the dark module
+        let row: Vec<bool> = (0..9).map(|x| code.is_dark(x, 0)).collect();
+        assert_eq!(
+            row,
+            [true, true, true, true, true, true, true, false, row[8]]
+        );
+        assert!(code.is_dark(20, 0) && !code.is_dark(13, 0));
+        assert!(code.is_dark(8, 13));
+        let svg = code.to_svg(4);
+        assert!(svg.starts_with("<svg") && svg.contains("width=\"116\""));
+    }
+}
@@ -0,0 +1,354 @@
+//! JSON-RPC 2.0 facade of the lookup routes, for wallets and dapps that already
+//! speak JSON-RPC.
+//!
+//! Methods take their parameters by position or by name:
+//! - `monique_resolve(monic, pending?)`
+//! - `monique_alias(address, pending?)`
+//! - `monique_index(index, pending?)`
+//! - `monique_stats()`
+
+use crate::api::{self, ErrorCode, RequestId, ResolveError, SharedResponseCache};
+use crate::ens::SharedEns;
+use crate::index::SharedIndex;
+use ethers::types::Address;
+use rocket::{
+    http::Status,
+    post,
+    serde::{
+        de::DeserializeOwned,
+        json::{self, json, Json, Value},
+        Serialize,
+    },
+    Responder, State,
+};
+
+/// Maximum number of calls in a batch.
+const MAX_BATCH: usize = 100;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// Server error: the index or the indexer is unavailable.
+const UNAVAILABLE: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+    /// Code of the API error, returned in the `data` of the error.
+    api_code: Option<ErrorCode>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            api_code: None,
+        }
+    }
+}
+
+impl From<ResolveError> for RpcError {
+    fn from(value: ResolveError) -> Self {
+        let code = match value {
+            ResolveError::InvalidAlias(_)
+            | ResolveError::BadAddress(_)
+            | ResolveError::WrongChecksum(_)
+            | ResolveError::BadRequest(_)
+            | ResolveError::NotFound(_) => INVALID_PARAMS,
+            ResolveError::Internal(_) => INTERNAL_ERROR,
+            ResolveError::Unavailable(_) => UNAVAILABLE,
+        };
+        Self {
+            api_code: Some(value.code()),
+            ..Self::new(code, value.to_string())
+        }
+    }
+}
+
+/// Entries missing for a known reason, such as pending ones looked up with
+/// `pending=false`, are `null` results like unknown ones.
+fn found<T>(result: Result<Option<T>, ResolveError>) -> Result<Option<T>, ResolveError> {
+    match result {
+        Err(ResolveError::NotFound(_)) => Ok(None),
+        result => result,
+    }
+}
+
+#[derive(Responder)]
+pub enum RpcResponse {
+    Reply(Json<Value>),
+    /// Only notifications were received.
+    Empty(Status),
+}
+
+struct Context<'a> {
+    set: &'a SharedIndex<20, Address>,
+    ens: Option<&'a SharedEns>,
+    cache: Option<&'a SharedResponseCache>,
+}
+
+/// Parameter at `position`, or named `name`, if present.
+fn param<T: DeserializeOwned>(
+    params: &Value,
+    position: usize,
+    name: &str,
+) -> Result<Option<T>, RpcError> {
+    let value = match params {
+        Value::Array(values) => values.get(position),
+        Value::Object(values) => values.get(name),
+        Value::Null => None,
+        _ => {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                "params must be an array or an object",
+            ))
+        }
+    };
+    match value {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => json::from_value(value.clone())
+            .map(Some)
+            .map_err(|e| RpcError::new(INVALID_PARAMS, format!("{}: {}", name, e))),
+    }
+}
+
+fn required<T: DeserializeOwned>(
+    params: &Value,
+    position: usize,
+    name: &str,
+) -> Result<T, RpcError> {
+    param(params, position, name)?
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing parameter: {}", name)))
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    json::to_value(value).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))
+}
+
+async fn call(context: &Context<'_>, method: &str, params: &Value) -> Result<Value, RpcError> {
+    let pending = || param::<bool>(params, 1, "pending").map(|pending| pending.unwrap_or(true));
+    match method {
+        "monique_resolve" => {
+            let monic: String = required(params, 0, "monic")?;
+            let info = found(
+                api::resolve_info(&monic, pending()?, context.set, context.ens, context.cache)
+                    .await,
+            )?;
+            to_value(info)
+        }
+        "monique_alias" => {
+            let address: String = required(params, 0, "address")?;
+            let info = found(
+                api::alias_info(
+                    &address,
+                    pending()?,
+                    context.set,
+                    context.ens,
+                    context.cache,
+                )
+                .await,
+            )?;
+            to_value(info)
+        }
+        "monique_index" => {
+            let index: usize = required(params, 0, "index")?;
+            to_value(found(
+                api::lookup_index(index, context.set, pending()?).await,
+            )?)
+        }
+        "monique_stats" => to_value(api::get_stats(context.set).await?),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("method not found: {}", method),
+        )),
+    }
+}
+
+fn error_reply(id: Value, error: RpcError) -> Value {
+    let mut reply = json!({
+        "jsonrpc": "2.0",
+        "error": { "code": error.code, "message": error.message },
+        "id": id,
+    });
+    if let Some(code) = error.api_code {
+        reply["error"]["data"] = json!({ "code": code });
+    }
+    reply
+}
+
+/// Handles a single call, returning `None` for notifications.
+async fn handle(context: &Context<'_>, request: Value) -> Option<Value> {
+    let Value::Object(request) = request else {
+        return Some(error_reply(
+            Value::Null,
+            RpcError::new(INVALID_REQUEST, "invalid request"),
+        ));
+    };
+    // calls without an id are notifications, which get no reply
+    let id = request.get("id").cloned();
+    let valid_id = matches!(
+        id,
+        None | Some(Value::Null | Value::Number(_) | Value::String(_))
+    );
+    let method = match (request.get("jsonrpc"), request.get("method")) {
+        (Some(Value::String(version)), Some(Value::String(method)))
+            if version == "2.0" && valid_id =>
+        {
+            method
+        }
+        _ => {
+            let id = id.filter(|_| valid_id).unwrap_or(Value::Null);
+            return Some(error_reply(
+                id,
+                RpcError::new(INVALID_REQUEST, "invalid request"),
+            ));
+        }
+    };
+    let params = request.get("params").unwrap_or(&Value::Null);
+    let result = call(context, method, params).await;
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => error_reply(id, error),
+    })
+}
+
+/// JSON-RPC 2.0 endpoint, accepting single calls and batches.
+#[post("/rpc", data = "<body>")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id))]
+pub async fn rpc(
+    request_id: &RequestId,
+    body: String,
+    set: &State<SharedIndex<20, Address>>,
+    ens: &State<Option<SharedEns>>,
+    cache: &State<Option<SharedResponseCache>>,
+) -> RpcResponse {
+    let context = Context {
+        set,
+        ens: ens.as_ref(),
+        cache: cache.as_ref(),
+    };
+    let request: Value = match json::from_str(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = RpcError::new(PARSE_ERROR, format!("parse error: {}", e));
+            return RpcResponse::Reply(Json(error_reply(Value::Null, error)));
+        }
+    };
+    let Value::Array(calls) = request else {
+        return match handle(&context, request).await {
+            Some(reply) => RpcResponse::Reply(Json(reply)),
+            None => RpcResponse::Empty(Status::NoContent),
+        };
+    };
+    if calls.is_empty() || calls.len() > MAX_BATCH {
+        let error = RpcError::new(
+            INVALID_REQUEST,
+            format!("batches must hold 1 to {} calls", MAX_BATCH),
+        );
+        return RpcResponse::Reply(Json(error_reply(Value::Null, error)));
+    }
+    let mut replies = vec![];
+    for call in calls {
+        replies.extend(handle(&context, call).await);
+    }
+    if replies.is_empty() {
+        return RpcResponse::Empty(Status::NoContent);
+    }
+    RpcResponse::Reply(Json(Value::Array(replies)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexTable;
+    use crate::words::PIVOT;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_calls() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = IndexTable::<20, Address>::builder(dir.path().join("index"))
+            .build()
+            .await
+            .unwrap();
+        let (a, b) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        db.queue(1, vec![a]).await.unwrap();
+        db.commit(1).await.unwrap();
+        db.queue(2, vec![b]).await.unwrap();
+        let db = Arc::new(db);
+        let context = Context {
+            set: &db,
+            ens: None,
+            cache: None,
+        };
+
+        let reply = handle(
+            &context,
+            json!({"jsonrpc": "2.0", "method": "monique_index", "params": [PIVOT], "id": 1}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply["id"], 1);
+        assert_eq!(reply["result"]["address"], format!("{:#x}", a));
+        assert_eq!(reply["result"]["pending"], false);
+
+        // pending entries are only returned when asked for
+        let alias = |pending| {
+            json!({
+                "jsonrpc": "2.0",
+                "method": "monique_alias",
+                "params": {"address": format!("{:#x}", b), "pending": pending},
+                "id": "b",
+            })
+        };
+        let reply = handle(&context, alias(true)).await.unwrap();
+        assert_eq!(reply["result"]["index"], PIVOT + 1);
+        assert_eq!(reply["result"]["pending"], true);
+        let reply = handle(&context, alias(false)).await.unwrap();
+        assert_eq!(reply["result"], Value::Null);
+
+        let reply = handle(
+            &context,
+            json!({"jsonrpc": "2.0", "method": "nope", "id": 2}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply["error"]["code"], METHOD_NOT_FOUND);
+        let reply = handle(
+            &context,
+            json!({"jsonrpc": "2.0", "method": "monique_resolve", "params": [], "id": 3}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply["error"]["code"], INVALID_PARAMS);
+        assert!(reply["error"].get("data").is_none());
+        let reply = handle(
+            &context,
+            json!({"jsonrpc": "2.0", "method": "monique_resolve", "params": ["source nope"], "id": 3}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply["error"]["code"], INVALID_PARAMS);
+        assert_eq!(reply["error"]["data"]["code"], "UNKNOWN_WORD");
+        // reserved indexes are null, as unknown ones
+        let reply = handle(
+            &context,
+            json!({"jsonrpc": "2.0", "method": "monique_index", "params": [1], "id": 3}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply["result"], Value::Null);
+        let reply = handle(&context, json!({"method": "monique_stats", "id": 4}))
+            .await
+            .unwrap();
+        assert_eq!(reply["error"]["code"], INVALID_REQUEST);
+
+        // notifications get no reply
+        let notification = json!({"jsonrpc": "2.0", "method": "monique_stats"});
+        assert!(handle(&context, notification).await.is_none());
+    }
+}
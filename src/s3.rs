@@ -0,0 +1,66 @@
+//! Upload of dump files to S3-compatible object storage, to distribute trusted
+//! snapshots to new deployments.
+
+use crate::{MoniqueError, Result};
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+use std::path::Path;
+use tracing::info;
+
+/// Destination of an upload. Without an `endpoint`, `region` is an AWS region;
+/// with one (e.g. MinIO), buckets are addressed by path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Target {
+    pub bucket: String,
+    pub key: String,
+    pub region: String,
+    pub endpoint: Option<String>,
+}
+
+impl S3Target {
+    fn bucket(&self) -> Result<Box<Bucket>> {
+        let error = |e: &dyn std::fmt::Display| MoniqueError::Dump(format!("s3: {}", e));
+        let credentials = Credentials::default().map_err(|e| error(&e))?;
+        let bucket = match &self.endpoint {
+            Some(endpoint) => {
+                let region = Region::Custom {
+                    region: self.region.clone(),
+                    endpoint: endpoint.clone(),
+                };
+                Bucket::new(&self.bucket, region, credentials)
+                    .map_err(|e| error(&e))?
+                    .with_path_style()
+            }
+            None => {
+                let region = self.region.parse().map_err(|e| error(&e))?;
+                Bucket::new(&self.bucket, region, credentials).map_err(|e| error(&e))?
+            }
+        };
+        Ok(bucket)
+    }
+}
+
+/// Uploads the file at `path`, in parts for large dumps. Credentials are read from the
+/// `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` environment variables, or from the
+/// AWS profile.
+pub async fn upload(path: &Path, target: &S3Target) -> Result<()> {
+    let bucket = target.bucket()?;
+    let mut file = tokio::fs::File::open(path).await?;
+    let response = bucket
+        .put_object_stream(&mut file, &target.key)
+        .await
+        .map_err(|e| MoniqueError::Dump(format!("s3: {}", e)))?;
+    if !(200..300).contains(&response.status_code()) {
+        Err(MoniqueError::Dump(format!(
+            "s3: upload failed with status {}",
+            response.status_code()
+        )))?
+    }
+    info!(
+        "uploaded {} to s3://{}/{}",
+        path.display(),
+        target.bucket,
+        target.key
+    );
+    Ok(())
+}
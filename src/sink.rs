@@ -0,0 +1,185 @@
+//! Helpers shared by the sinks writing the committed entries into external databases.
+
+use crate::index::SharedIndex;
+use crate::{MoniqueError, Result};
+use async_trait::async_trait;
+use ethers::types::Address;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Longest wait before writing again after a failure, the wait doubling from a
+/// second with each failure in a row.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// External database the committed blocks are written to, in order.
+#[async_trait]
+pub(crate) trait Sink {
+    /// Prefix of the logs of the sink.
+    const NAME: &'static str;
+
+    /// Deletes the rows of the blocks after `to`.
+    async fn rewind(&self, to: u64) -> Result<()>;
+
+    /// Writes the committed blocks `from..=to`, returning the number of rows.
+    async fn write(&self, db: &SharedIndex<20, Address>, from: u64, to: u64) -> Result<usize>;
+}
+
+/// Writes the committed blocks to `sink` from `next` on, `written` being the last
+/// block it holds, then follows the commits. The rows of the blocks rolled back are
+/// deleted, as are those of blocks written past the last committed one. Failures are
+/// logged and retried with a backoff, from the last written block.
+pub(crate) async fn follow<S: Sink>(
+    sink: &S,
+    db: SharedIndex<20, Address>,
+    mut next: u64,
+    mut written: u64,
+) {
+    let mut rollbacks = db.subscribe_rollbacks();
+    let mut commits = db.subscribe_commits();
+    // lowest rollback not applied yet, kept across failed attempts
+    let mut rollback: Option<u64> = None;
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        // taken first, the rollbacks being sent before the commits watch is updated
+        rollback = match (rollback, rollbacks.take()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let to = *commits.borrow_and_update();
+        let keep = rollback.unwrap_or(u64::MAX).min(to);
+        let result: Result<()> = async {
+            if keep < written {
+                warn!("{}: deleting the rows after block {}", S::NAME, keep);
+                sink.rewind(keep).await?;
+                written = keep;
+                next = next.min(keep + 1);
+            }
+            if to >= next {
+                let rows = sink.write(&db, next, to).await?;
+                info!("{}: wrote {} rows up to block {}", S::NAME, rows, to);
+                written = to;
+                next = to + 1;
+            }
+            Ok(())
+        }
+        .await;
+        match result {
+            Ok(()) => {
+                rollback = None;
+                backoff = Duration::from_secs(1);
+            }
+            Err(e) => {
+                warn!("{}: {}, retrying in {}s", S::NAME, e, backoff.as_secs());
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        }
+        if commits.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Table names are interpolated in the queries of the sinks: only (optionally schema-
+/// or database-qualified) identifiers made of ASCII letters, digits and `_` are
+/// accepted.
+pub(crate) fn validate_table(name: &str) -> Result<()> {
+    let valid = name.split('.').count() <= 2
+        && name.split('.').all(|part| {
+            part.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+    if !valid {
+        Err(MoniqueError::Sink(format!("invalid table name {:?}", name)))?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexTable;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Addresses of each written block, failing the next rewind when asked to.
+    #[derive(Default)]
+    struct Blocks {
+        rows: Mutex<BTreeMap<u64, Vec<Address>>>,
+        fail_rewind: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Sink for Blocks {
+        const NAME: &'static str = "test";
+
+        async fn rewind(&self, to: u64) -> Result<()> {
+            if self.fail_rewind.swap(false, Ordering::Relaxed) {
+                Err(MoniqueError::Sink("unreachable".to_string()))?
+            }
+            self.rows.lock().unwrap().retain(|block, _| *block <= to);
+            Ok(())
+        }
+
+        async fn write(&self, db: &SharedIndex<20, Address>, from: u64, to: u64) -> Result<usize> {
+            let mut rows = 0;
+            for number in from..=to {
+                let (_, items) = db.block_entries(number)?.unwrap();
+                rows += items.len();
+                self.rows.lock().unwrap().insert(number, items);
+            }
+            Ok(rows)
+        }
+    }
+
+    #[test]
+    fn test_table_names() {
+        assert!(validate_table("monique_addresses").is_ok());
+        assert!(validate_table("analytics.monique_addresses").is_ok());
+        assert!(validate_table("_private").is_ok());
+        assert!(validate_table("addresses FORMAT Null").is_err());
+        assert!(validate_table("entries; DROP TABLE users").is_err());
+        assert!(validate_table("1entries").is_err());
+        assert!(validate_table("a.b.c").is_err());
+        assert!(validate_table("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_follow_retries_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = IndexTable::<20, Address>::builder(dir.path())
+            .build()
+            .await
+            .unwrap();
+        for block in 1..=3 {
+            db.queue(block, vec![Address::repeat_byte(block as u8)])
+                .await
+                .unwrap();
+        }
+        db.commit(3).await.unwrap();
+        let db = Arc::new(db);
+        let sink = Arc::new(Blocks::default());
+        let (index, blocks) = (db.clone(), sink.clone());
+        tokio::spawn(async move { follow(&*blocks, index, 1, 0).await });
+        let written = |blocks: Vec<(u64, u8)>| {
+            let rows = sink.rows.lock().unwrap();
+            rows.iter()
+                .map(|(block, items)| (*block, items[0].0[0]))
+                .eq(blocks)
+        };
+        while !written(vec![(1, 1), (2, 2), (3, 3)]) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // the failed rollback is applied on the retry, though block 2 is committed again
+        sink.fail_rewind.store(true, Ordering::Relaxed);
+        db.rollback(1).await.unwrap();
+        db.queue(2, vec![Address::repeat_byte(4)]).await.unwrap();
+        db.commit(2).await.unwrap();
+        while !written(vec![(1, 1), (2, 4)]) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Mirror of the committed addresses into a SQLite file, for downstream tools that
+//! query with SQL.
+
+use crate::index::SharedIndex;
+use crate::words::PIVOT;
+use crate::{MoniqueError, Result};
+use ethers::types::Address;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Blocks written per SQLite transaction while catching up.
+const BATCH_BLOCKS: u64 = 1_000;
+
+/// Longest wait before mirroring again after a failure, the wait doubling from a
+/// second with each failure in a row.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS addresses (
+        idx INTEGER PRIMARY KEY,
+        address TEXT NOT NULL UNIQUE,
+        block INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS addresses_block ON addresses (block);
+    CREATE TABLE IF NOT EXISTS mirror (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        last_block INTEGER NOT NULL
+    );
+";
+
+/// `addresses (idx, address, block)` rows, `idx` being the pivoted index of the monic
+/// and `address` its lowercase hex, with the last mirrored block in `mirror`.
+pub struct Mirror {
+    conn: Connection,
+}
+
+impl Mirror {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    pub fn last_block(&self) -> Result<u64> {
+        let last_block: Option<i64> = self
+            .conn
+            .query_row("SELECT last_block FROM mirror WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(last_block.unwrap_or(0) as u64)
+    }
+
+    /// Drops the rows of the blocks from `block` on, so that they are mirrored again.
+    pub fn rewind(&mut self, block: u64) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM addresses WHERE block >= ?1", [block as i64])?;
+        set_last_block(&tx, block.saturating_sub(1))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Mirrors the committed blocks after the last mirrored one, up to `to`, from their
+    /// recorded ranges. Returns the number of rows written.
+    pub fn sync(&mut self, db: &SharedIndex<20, Address>, to: u64) -> Result<usize> {
+        let mut rows = 0;
+        let mut from = (self.last_block()? + 1).max(db.start_block());
+        while from <= to {
+            let last = to.min(from + BATCH_BLOCKS - 1);
+            let tx = self.conn.transaction()?;
+            {
+                let mut insert = tx.prepare_cached(
+                    "INSERT OR REPLACE INTO addresses (idx, address, block) VALUES (?1, ?2, ?3)",
+                )?;
+                for number in from..=last {
+                    let (start, items) = db.block_entries(number)?.ok_or_else(|| {
+                        MoniqueError::Storage(format!(
+                            "mirror: no range recorded for block {}",
+                            number
+                        ))
+                    })?;
+                    for (offset, address) in items.into_iter().enumerate() {
+                        insert.execute(params![
+                            (start + offset + PIVOT) as i64,
+                            format!("{:#x}", address),
+                            number as i64
+                        ])?;
+                        rows += 1;
+                    }
+                }
+            }
+            set_last_block(&tx, last)?;
+            tx.commit()?;
+            from = last + 1;
+        }
+        Ok(rows)
+    }
+
+    /// Drops the rows of the blocks after `keep`, then mirrors the blocks up to `to`.
+    fn follow(&mut self, db: &SharedIndex<20, Address>, keep: u64, to: u64) -> Result<usize> {
+        if keep < self.last_block()? {
+            warn!("mirror: dropping the addresses after block {}", keep);
+            self.rewind(keep + 1)?;
+        }
+        self.sync(db, to)
+    }
+}
+
+/// Runs `f` off the runtime threads, SQLite calls being blocking.
+async fn blocking<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> Result<R> {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(output) => Ok(output),
+        Err(e) => match e.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(_) => Err(MoniqueError::Storage("mirror: task cancelled".to_string())),
+        },
+    }
+}
+
+fn set_last_block(conn: &Connection, block: u64) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO mirror (id, last_block) VALUES (0, ?1)",
+        [block as i64],
+    )?;
+    Ok(())
+}
+
+/// Mirrors the committed blocks into the SQLite file at `path`, first catching up
+/// from the last mirrored block, or from `from` if given, then following the commits.
+/// The rows of the blocks rolled back are dropped, as are those of blocks mirrored
+/// past the last committed one, e.g. before a rollback while the mirror was stopped.
+/// Failures are logged and retried with a backoff, from the last mirrored block.
+pub async fn mirror(db: SharedIndex<20, Address>, path: &Path, from: Option<u64>) -> Result<()> {
+    let file = path.to_path_buf();
+    let (mut mirror, last_block) = blocking(move || -> Result<_> {
+        let mut mirror = Mirror::open(file)?;
+        if let Some(from) = from {
+            mirror.rewind(from.max(1))?;
+        }
+        let last_block = mirror.last_block()?;
+        Ok((mirror, last_block))
+    })
+    .await??;
+    info!(
+        "mirroring committed addresses to {} from block {}",
+        path.display(),
+        last_block + 1
+    );
+    let mut rollbacks = db.subscribe_rollbacks();
+    let mut commits = db.subscribe_commits();
+    // lowest rollback not mirrored yet, kept across failed attempts
+    let mut rollback: Option<u64> = None;
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        // taken first, the rollbacks being sent before the commits watch is updated
+        rollback = match (rollback, rollbacks.take()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let to = *commits.borrow_and_update();
+        let keep = rollback.unwrap_or(u64::MAX).min(to);
+        let index = db.clone();
+        let result;
+        (mirror, result) = blocking(move || {
+            let result = mirror.follow(&index, keep, to);
+            (mirror, result)
+        })
+        .await?;
+        match result {
+            Ok(rows) => {
+                rollback = None;
+                backoff = Duration::from_secs(1);
+                if rows > 0 {
+                    info!("mirrored {} addresses up to block {}", rows, to);
+                }
+            }
+            Err(e) => {
+                warn!("mirror: {}, retrying in {}s", e, backoff.as_secs());
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        }
+        if commits.changed().await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexTable;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = IndexTable::<20, Address>::builder(dir.path().join("index"))
+            .build()
+            .await
+            .unwrap();
+        let (a, b, c) = (
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+            Address::repeat_byte(3),
+        );
+        db.queue(1, vec![a, b]).await.unwrap();
+        db.queue(2, vec![]).await.unwrap();
+        db.queue(3, vec![b, c]).await.unwrap();
+        db.commit(3).await.unwrap();
+        let db = Arc::new(db);
+
+        let mut mirror = Mirror::open(dir.path().join("mirror.sqlite")).unwrap();
+        assert_eq!(mirror.sync(&db, 3).unwrap(), 3);
+        assert_eq!(mirror.last_block().unwrap(), 3);
+        assert_eq!(mirror.sync(&db, 3).unwrap(), 0);
+        let row: (i64, String, i64) = mirror
+            .conn
+            .query_row(
+                "SELECT idx, address, block FROM addresses WHERE address = ?1",
+                [format!("{:#x}", c)],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(row, (PIVOT as i64 + 2, format!("{:#x}", c), 3));
+
+        mirror.rewind(2).unwrap();
+        assert_eq!(mirror.last_block().unwrap(), 1);
+        assert_eq!(mirror.sync(&db, 3).unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mirror_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = IndexTable::<20, Address>::builder(dir.path().join("index"))
+            .build()
+            .await
+            .unwrap();
+        for block in 1..=3 {
+            db.queue(block, vec![Address::repeat_byte(block as u8)])
+                .await
+                .unwrap();
+        }
+        db.commit(3).await.unwrap();
+        let db = Arc::new(db);
+        let path = dir.path().join("mirror.sqlite");
+        let (index, mirror_path) = (db.clone(), path.clone());
+        tokio::spawn(async move { mirror(index, &mirror_path, None).await });
+        let reader = Mirror::open(&path).unwrap();
+        let mirrored = |last_block: u64| {
+            let rows: i64 = reader
+                .conn
+                .query_row("SELECT COUNT(*) FROM addresses", [], |row| row.get(0))
+                .unwrap();
+            reader.last_block().unwrap() == last_block && rows == last_block as i64
+        };
+        while !mirrored(3) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        db.rollback(1).await.unwrap();
+        while !mirrored(1) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        db.queue(2, vec![Address::repeat_byte(4)]).await.unwrap();
+        db.commit(2).await.unwrap();
+        while !mirrored(2) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let address: String = reader
+            .conn
+            .query_row("SELECT address FROM addresses WHERE block = 2", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(address, format!("{:#x}", Address::repeat_byte(4)));
+    }
+}
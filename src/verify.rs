@@ -0,0 +1,176 @@
+//! Light client checks, for wallets and other clients that do not hold the index:
+//! a proof served by `/proof/<index>` is checked against a checkpoint root obtained
+//! from a trusted source (a signed checkpoint or a chain). Proofs of several entries
+//! of a block served by `/proofs` share their nodes.
+
+use crate::words::{self, PIVOT};
+use crate::Result;
+use eth_trie::{EthTrie, MemoryDB, Trie};
+use ethers_core::types::H256;
+use std::sync::Arc;
+
+/// Checks that `proof` binds `item` to the (pivoted) `index` in the checkpoint trie
+/// of root `root`.
+pub fn verify_proof(root: H256, index: usize, item: impl AsRef<[u8]>, proof: Vec<Vec<u8>>) -> bool {
+    let Some(stored_index) = index.checked_sub(PIVOT) else {
+        return false;
+    };
+    let trie = EthTrie::new(Arc::new(MemoryDB::new(true)));
+    match trie.verify_proof(root, item.as_ref(), proof) {
+        Ok(Some(value)) => value == (stored_index as u64).to_be_bytes(),
+        _ => false,
+    }
+}
+
+/// Checks that the multi-proof `nodes`, as served by `/proofs`, binds each item to
+/// its (pivoted) index in the checkpoint trie of root `root`.
+pub fn verify_multi_proof<I: AsRef<[u8]>>(
+    root: H256,
+    entries: &[(usize, I)],
+    nodes: Vec<Vec<u8>>,
+) -> bool {
+    !entries.is_empty()
+        && entries
+            .iter()
+            .all(|(index, item)| verify_proof(root, *index, item, nodes.clone()))
+}
+
+/// Checks that `monic` resolves to `item`: the checksum of the monic must match the
+/// item, and the proof must bind the item to the index encoded by the monic.
+pub fn verify_resolution(
+    monic: &str,
+    item: impl AsRef<[u8]>,
+    root: H256,
+    proof: Vec<Vec<u8>>,
+) -> Result<bool> {
+    let (index, checksum) = words::to_index(monic.to_string())?;
+    Ok(words::checksum(item.as_ref()) == checksum && verify_proof(root, index, item, proof))
+}
+
+/// Outcome of the comparison of the checkpoints of two instances.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    /// Blocks whose checkpoints were compared.
+    pub compared: u64,
+    pub last_matching: Option<u64>,
+    pub first_divergent: Option<u64>,
+}
+
+/// Compares the checkpoints of the blocks `first..=last` with those of a peer: one
+/// every `every` blocks and the last one, until the peer has none or they differ,
+/// then by bisection between the last matching block and the divergent one.
+/// `matches` tells whether the peer has the checkpoint of a block, `None` if it has
+/// none.
+pub async fn bisect_checkpoints<F, Fut, E>(
+    first: u64,
+    last: u64,
+    every: u64,
+    mut matches: F,
+) -> std::result::Result<Comparison, E>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: std::future::Future<Output = std::result::Result<Option<bool>, E>>,
+    E: From<String>,
+{
+    let mut samples: Vec<u64> = (first..=last).step_by(every.max(1) as usize).collect();
+    if samples.last() != Some(&last) {
+        samples.push(last);
+    }
+    let (mut matching, mut divergent, mut compared) = (None, None, 0);
+    for block in samples {
+        // the peer has not committed it yet
+        let Some(matched) = matches(block).await? else {
+            break;
+        };
+        compared += 1;
+        if !matched {
+            divergent = Some(block);
+            break;
+        }
+        matching = Some(block);
+    }
+    let Some(mut divergent) = divergent else {
+        return Ok(Comparison {
+            compared,
+            last_matching: matching,
+            first_divergent: None,
+        });
+    };
+    // the first divergent block is after the last matching one, and up to this one
+    let mut low = matching.map_or(first, |block| block + 1);
+    while low < divergent {
+        let middle = low + (divergent - low) / 2;
+        let matched = matches(middle)
+            .await?
+            .ok_or_else(|| format!("the peer has no checkpoint for block {}", middle))?;
+        compared += 1;
+        if matched {
+            matching = Some(middle);
+            low = middle + 1;
+        } else {
+            divergent = middle;
+        }
+    }
+    Ok(Comparison {
+        compared,
+        last_matching: matching,
+        first_divergent: Some(divergent),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compares the blocks `1..=100`, every 10 blocks, with a peer diverging from
+    /// block `from`, which has no checkpoint after block `peer_last`.
+    async fn compare(from: u64, peer_last: u64) -> Comparison {
+        bisect_checkpoints(1, 100, 10, |block| {
+            let matched = (block <= peer_last).then_some(block < from);
+            std::future::ready(Ok::<_, String>(matched))
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn bisection() {
+        for from in [1, 50, 100] {
+            let comparison = compare(from, 100).await;
+            assert_eq!(comparison.first_divergent, Some(from), "{}", from);
+            assert_eq!(
+                comparison.last_matching,
+                (from > 1).then_some(from - 1),
+                "{}",
+                from
+            );
+        }
+        // samples 1, 11, ..., 41 match, 51 does not, then 46, 49 and 50
+        assert_eq!(compare(50, 100).await.compared, 9);
+
+        assert_eq!(
+            compare(101, 100).await,
+            Comparison {
+                compared: 11,
+                last_matching: Some(100),
+                first_divergent: None,
+            }
+        );
+        // the peer is behind
+        assert_eq!(
+            compare(101, 45).await,
+            Comparison {
+                compared: 5,
+                last_matching: Some(41),
+                first_divergent: None,
+            }
+        );
+        // but has the checkpoints of the bisected blocks
+        let error = bisect_checkpoints(1, 100, 10, |block| {
+            std::future::ready(Ok::<_, String>((block != 46).then_some(block < 50)))
+        })
+        .await
+        .unwrap_err();
+        assert!(error.contains("block 46"), "{}", error);
+    }
+}
@@ -0,0 +1,252 @@
+//! Named sets of addresses tracked by operators (exchanges, auditors...), with an
+//! event each time one of their addresses is committed to the index.
+
+use crate::index::{Indexed, SharedIndex};
+use crate::words::{self, PIVOT};
+use crate::{MoniqueError, Result};
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// A watched address committed to the index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchEvent {
+    pub watchlist: String,
+    pub address: Address,
+    pub index: usize,
+    pub monic: String,
+    pub block: u64,
+}
+
+/// Indexing status of a watched address.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchedEntry {
+    pub address: Address,
+    pub index: Option<usize>,
+    pub monic: Option<String>,
+}
+
+#[derive(Default)]
+struct Lists {
+    by_name: BTreeMap<String, HashSet<Address>>,
+    /// Names of the watchlists containing each address.
+    by_address: HashMap<Address, Vec<String>>,
+}
+
+impl Lists {
+    fn reindex(&mut self) {
+        self.by_address.clear();
+        for (name, addresses) in &self.by_name {
+            for address in addresses {
+                self.by_address
+                    .entry(*address)
+                    .or_default()
+                    .push(name.clone());
+            }
+        }
+    }
+}
+
+pub struct Watchlists {
+    lists: RwLock<Lists>,
+    /// File the watchlists are read from and saved to, as `{ "name": [addresses] }`.
+    path: Option<PathBuf>,
+}
+
+pub type SharedWatchlists = Arc<Watchlists>;
+
+impl Default for Watchlists {
+    fn default() -> Self {
+        Self {
+            lists: RwLock::new(Lists::default()),
+            path: None,
+        }
+    }
+}
+
+impl Watchlists {
+    /// Loads the watchlists of `path`, which is created on the first registration
+    /// if it does not exist.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut lists = Lists::default();
+        if path.exists() {
+            lists.by_name = serde_json::from_reader(BufReader::new(File::open(&path)?))
+                .map_err(std::io::Error::from)?;
+            lists.reindex();
+        }
+        info!(
+            "loaded {} watchlists from {}",
+            lists.by_name.len(),
+            path.display()
+        );
+        Ok(Self {
+            lists: RwLock::new(lists),
+            path: Some(path),
+        })
+    }
+
+    /// Creates or replaces a watchlist. Names are made of ASCII letters, digits, `-`
+    /// and `_`, so that they can be used in URLs and NATS subjects.
+    pub async fn register(&self, name: &str, addresses: HashSet<Address>) -> Result<()> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || !name.chars().all(valid) {
+            Err(MoniqueError::Watchlist(format!("invalid name {:?}", name)))?
+        }
+        let mut lists = self.lists.write().await;
+        lists.by_name.insert(name.to_string(), addresses);
+        lists.reindex();
+        self.save(&lists)
+    }
+
+    /// Removes a watchlist, returning whether it existed.
+    pub async fn remove(&self, name: &str) -> Result<bool> {
+        let mut lists = self.lists.write().await;
+        if lists.by_name.remove(name).is_none() {
+            return Ok(false);
+        }
+        lists.reindex();
+        self.save(&lists)?;
+        Ok(true)
+    }
+
+    pub async fn names(&self) -> Vec<String> {
+        self.lists.read().await.by_name.keys().cloned().collect()
+    }
+
+    /// Index and monic of each address of a watchlist, `None` if it does not exist.
+    pub async fn status(
+        &self,
+        name: &str,
+        db: &SharedIndex<20, Address>,
+    ) -> Result<Option<Vec<WatchedEntry>>> {
+        let Some(addresses) = self.lists.read().await.by_name.get(name).cloned() else {
+            return Ok(None);
+        };
+        let mut addresses: Vec<Address> = addresses.into_iter().collect();
+        addresses.sort();
+        let mut entries = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let index = db.index(address).await?.map(|index| index + PIVOT);
+            entries.push(WatchedEntry {
+                address,
+                index,
+                monic: index.map(|index| words::to_words(index as u64, words::checksum(address))),
+            });
+        }
+        Ok(Some(entries))
+    }
+
+    /// Events of a committed `(index, address, block)` entry, one per watchlist
+    /// containing the address. Consumers match their own committed entries, so that
+    /// none of them is skipped.
+    pub async fn events(&self, index: usize, address: Address, block: u64) -> Vec<WatchEvent> {
+        let lists = self.lists.read().await;
+        let Some(names) = lists.by_address.get(&address) else {
+            return vec![];
+        };
+        let index = index + PIVOT;
+        let monic = words::to_words(index as u64, words::checksum(address));
+        names
+            .iter()
+            .map(|name| WatchEvent {
+                watchlist: name.clone(),
+                address,
+                index,
+                monic: monic.clone(),
+                block,
+            })
+            .collect()
+    }
+
+    /// Spawns the task logging the committed addresses of the watchlists.
+    pub fn spawn(self: &Arc<Self>, db: SharedIndex<20, Address>) -> JoinHandle<()> {
+        let watchlists = self.clone();
+        let mut entries = db.subscribe_entries();
+        tokio::spawn(async move {
+            loop {
+                let (index, address, block) = match entries.recv().await {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("watchlists: cannot read the committed entries: {}", e);
+                        break;
+                    }
+                };
+                for event in watchlists.events(index, address, block).await {
+                    info!(
+                        watchlist = event.watchlist,
+                        %address,
+                        index = event.index,
+                        "watched address indexed"
+                    );
+                }
+            }
+        })
+    }
+
+    fn save(&self, lists: &Lists) -> Result<()> {
+        if let Some(path) = &self.path {
+            // written aside and renamed, so that a crash keeps either the old or the
+            // new watchlists, never a partial file
+            let tmp = path.with_extension("tmp");
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            serde_json::to_writer_pretty(&mut writer, &lists.by_name)
+                .map_err(std::io::Error::from)?;
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+            std::fs::rename(tmp, path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_register() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watchlists.json");
+        let watchlists = Watchlists::load(&path).unwrap();
+        let (a, b) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        watchlists
+            .register("hot", HashSet::from([a, b]))
+            .await
+            .unwrap();
+        watchlists
+            .register("cold", HashSet::from([a]))
+            .await
+            .unwrap();
+        assert!(watchlists.register("a.b", HashSet::new()).await.is_err());
+        assert_eq!(watchlists.lists.read().await.by_address[&a].len(), 2);
+        let mut events = watchlists.events(7, a, 3).await;
+        events.sort_by(|x, y| x.watchlist.cmp(&y.watchlist));
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            (events[0].watchlist.as_str(), events[0].index),
+            ("cold", 7 + PIVOT)
+        );
+        assert!(watchlists
+            .events(7, Address::repeat_byte(3), 3)
+            .await
+            .is_empty());
+
+        let reloaded = Watchlists::load(&path).unwrap();
+        assert_eq!(reloaded.names().await, vec!["cold", "hot"]);
+        assert!(reloaded.remove("hot").await.unwrap());
+        assert!(!reloaded.remove("hot").await.unwrap());
+        assert_eq!(reloaded.lists.read().await.by_address.get(&b), None);
+        assert_eq!(Watchlists::load(&path).unwrap().names().await, vec!["cold"]);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::index::SharedIndex;
+use crate::watchlist::{SharedWatchlists, WatchEvent};
+use crate::words::{self, PIVOT};
+use ethers::types::{Address, H256};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Events queued for each webhook before the others wait for it to catch up.
+const QUEUE_SIZE: usize = 10_000;
+const MAX_ATTEMPTS: u32 = 6;
+
+/// A webhook endpoint, as read from the webhooks file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Only notify these addresses; every new address is notified when absent.
+    #[serde(default)]
+    pub watchlist: Option<HashSet<Address>>,
+    /// Also notify each commit (checkpoint).
+    #[serde(default)]
+    pub checkpoints: bool,
+    /// Notify the addresses of these registered watchlists.
+    #[serde(default)]
+    pub watchlists: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// An address was committed to the index for the first time.
+    Address {
+        address: Address,
+        index: usize,
+        monic: String,
+        block: u64,
+    },
+    /// The index was committed up to `block`.
+    Checkpoint {
+        block: u64,
+        hash: H256,
+        addresses: usize,
+    },
+    /// An address of a registered watchlist was committed to the index.
+    Watched(WatchEvent),
+}
+
+impl WebhookConfig {
+    fn accepts(&self, event: &Event) -> bool {
+        match event {
+            Event::Address { address, .. } => self
+                .watchlist
+                .as_ref()
+                .is_none_or(|watchlist| watchlist.contains(address)),
+            Event::Checkpoint { .. } => self.checkpoints,
+            Event::Watched(event) => self.watchlists.contains(&event.watchlist),
+        }
+    }
+}
+
+/// Spawns the tasks posting the index events to the configured webhooks, including
+/// the events of `watchlists` if given. No event is dropped: a webhook whose queue is
+/// full holds the others back, and the entries committed meanwhile are read back from
+/// the index.
+pub fn spawn(
+    db: SharedIndex<20, Address>,
+    hooks: Vec<WebhookConfig>,
+    watchlists: Option<SharedWatchlists>,
+) -> JoinHandle<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("http client");
+    let senders: Vec<(WebhookConfig, mpsc::Sender<Event>)> = hooks
+        .into_iter()
+        .map(|hook| {
+            let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+            tokio::spawn(deliver(client.clone(), hook.url.clone(), rx));
+            (hook, tx)
+        })
+        .collect();
+    info!("sending index events to {} webhooks", senders.len());
+
+    let mut entries = db.subscribe_entries();
+    let mut commits = db.subscribe_commits();
+    tokio::spawn(async move {
+        loop {
+            let events = tokio::select! {
+                entry = entries.recv() => {
+                    let (index, address, block) = match entry {
+                        Ok(Some(entry)) => entry,
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!("webhooks: cannot read the committed entries: {}", e);
+                            break;
+                        }
+                    };
+                    let mut events = vec![Event::Address {
+                        address,
+                        index: index + PIVOT,
+                        monic: words::to_words((index + PIVOT) as u64, words::checksum(address)),
+                        block,
+                    }];
+                    if let Some(watchlists) = &watchlists {
+                        events.extend(
+                            watchlists
+                                .events(index, address, block)
+                                .await
+                                .into_iter()
+                                .map(Event::Watched),
+                        );
+                    }
+                    events
+                },
+                changed = commits.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let block = *commits.borrow_and_update();
+                    let hash = match db.block_hash(block) {
+                        Ok(hash) => hash,
+                        Err(e) => {
+                            warn!("webhooks: no hash for block {}: {}", block, e);
+                            continue;
+                        }
+                    };
+                    vec![Event::Checkpoint {
+                        block,
+                        hash,
+                        addresses: db.committed_len().await,
+                    }]
+                }
+            };
+            for event in events {
+                for (hook, sender) in &senders {
+                    if hook.accepts(&event) && sender.send(event.clone()).await.is_err() {
+                        warn!("webhook {}: delivery task stopped", hook.url);
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Posts the events of one webhook in order, retrying with exponential backoff.
+async fn deliver(client: reqwest::Client, url: String, mut events: mpsc::Receiver<Event>) {
+    while let Some(event) = events.recv().await {
+        let mut backoff = Duration::from_secs(1);
+        for attempt in 1..=MAX_ATTEMPTS {
+            let res = client.post(&url).json(&event).send().await;
+            match res.and_then(|r| r.error_for_status()) {
+                Ok(_) => break,
+                Err(e) if attempt == MAX_ATTEMPTS => {
+                    warn!(
+                        "webhook {}: giving up after {} attempts: {}",
+                        url, attempt, e
+                    );
+                }
+                Err(e) => {
+                    warn!("webhook {}: attempt {} failed: {}", url, attempt, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts() {
+        let watched = Address::repeat_byte(1);
+        let hook: WebhookConfig = serde_json::from_value(serde_json::json!({
+            "url": "http://localhost",
+            "watchlist": [watched],
+        }))
+        .unwrap();
+        let address = |address| Event::Address {
+            address,
+            index: PIVOT,
+            monic: String::new(),
+            block: 1,
+        };
+        let checkpoint = Event::Checkpoint {
+            block: 1,
+            hash: H256::zero(),
+            addresses: 0,
+        };
+        assert!(hook.accepts(&address(watched)));
+        assert!(!hook.accepts(&address(Address::repeat_byte(2))));
+        assert!(!hook.accepts(&checkpoint));
+
+        let watched = |watchlist: &str| {
+            Event::Watched(WatchEvent {
+                watchlist: watchlist.to_string(),
+                address: Address::repeat_byte(2),
+                index: PIVOT,
+                monic: String::new(),
+                block: 1,
+            })
+        };
+        assert!(!hook.accepts(&watched("hot")));
+
+        let hook = WebhookConfig {
+            watchlist: None,
+            checkpoints: true,
+            watchlists: vec!["hot".to_string()],
+            ..hook
+        };
+        assert!(hook.accepts(&address(Address::repeat_byte(2))));
+        assert!(hook.accepts(&checkpoint));
+        assert!(hook.accepts(&watched("hot")));
+        assert!(!hook.accepts(&watched("cold")));
+    }
+}
@@ -3,8 +3,16 @@ mod list;
 use crate::words::list::ENGLISH;
 use crate::Result;
 use bitvec::{field::BitField, order::Msb0, view::BitView};
-use ethers::{types::Address, utils::keccak256};
+use ethers_core::types::H256;
+use ethers_core::utils::keccak256;
 use std::error::Error;
+use std::sync::OnceLock;
+
+/// Monics below this index are reserved for mutable monics; stored entries start here.
+pub const PIVOT: usize = 0x40000;
+
+/// Version of the encoding of indexes as words, bumped whenever it changes.
+pub const ENCODING_VERSION: u32 = 1;
 
 #[derive(Debug)]
 pub struct WordError;
@@ -17,16 +25,35 @@ impl std::fmt::Display for WordError {
 
 impl Error for WordError {}
 
-pub fn checksum(address: Address) -> u8 {
-    // address checksum is the first 4 bits of the address hash
-    let hash = keccak256(&address.as_bytes());
+pub fn checksum(item: impl AsRef<[u8]>) -> u8 {
+    // checksum is the first 4 bits of the item (address or hash) hash
+    let hash = keccak256(item.as_ref());
     hash[0] >> 4
 }
 
+/// Fingerprint of the vocabulary: hash of the encoding version and of the words of
+/// the list, in order. Resolvers agreeing on it encode the same monics.
+pub fn fingerprint() -> H256 {
+    static FINGERPRINT: OnceLock<H256> = OnceLock::new();
+    *FINGERPRINT.get_or_init(|| {
+        let words = format!("monique-words/{}:{}", ENCODING_VERSION, ENGLISH.join(" "));
+        H256(keccak256(words))
+    })
+}
+
+/// Maximum number of words of a monic.
+pub const MAX_WORDS: usize = 6;
+
+/// Encodes an index and its 4-bit checksum as words.
+///
+/// # Panics
+///
+/// If the index is 2^62 or more, or the checksum 16 or more.
 pub fn to_words(index: u64, checksum: u8) -> String {
     // a 6-word index needs 66 bits, minus 4 bits for the checksum
     // so the maximum index for u64 is 2^62 - 1
     assert!(index < 4611686018427387904);
+    assert!(checksum < 16);
 
     let mut chunks = index
         .view_bits::<Msb0>()
@@ -39,7 +66,7 @@ pub fn to_words(index: u64, checksum: u8) -> String {
         0
     };
     let last = if chunks[pos] > 127 { pos + 1 } else { pos };
-    chunks[last] = chunks[last] | (checksum as u16) << 7;
+    chunks[last] |= (checksum as u16) << 7;
     let mut words = Vec::new();
     for i in 0..last + 1 {
         words.push(ENGLISH[chunks[last - i] as usize].to_string());
@@ -47,6 +74,8 @@ pub fn to_words(index: u64, checksum: u8) -> String {
     words.join(" ")
 }
 
+/// Decodes words into an index and its checksum. Fails on unknown words, and on
+/// more words than an index can span.
 pub fn to_index(words: String) -> Result<(usize, u8)> {
     let mut index = 0usize;
     let mut checksum = 0u8;
@@ -54,7 +83,7 @@ pub fn to_index(words: String) -> Result<(usize, u8)> {
         .split(" ")
         .map(|w| list::ENGLISH.iter().position(|&r| r == w))
         .collect();
-    if val.iter().any(|&v| v.is_none()) {
+    if val.len() > MAX_WORDS || val.iter().any(|&v| v.is_none()) {
         return Err(WordError.into());
     }
 
@@ -62,7 +91,7 @@ pub fn to_index(words: String) -> Result<(usize, u8)> {
         let mut value = value.unwrap();
         if p == val.len() - 1 {
             checksum = (value >> 7) as u8;
-            value = value & 0x7f;
+            value &= 0x7f;
         }
         index += value << (11 * p);
     }
@@ -72,6 +101,8 @@ pub fn to_index(words: String) -> Result<(usize, u8)> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ethers_core::types::Address;
+    use proptest::prelude::*;
 
     #[test]
     fn test_max() {
@@ -106,4 +137,40 @@ mod tests {
         assert_eq!(to_i.0, 127);
         assert_eq!(to_i.1, checksum(address));
     }
+
+    #[test]
+    fn test_fingerprint() {
+        // a change of the list or of the encoding must bump the version
+        assert_eq!(
+            format!("{:?}", fingerprint()),
+            "0xda5399a2f240243b356ae35a013e3c8f53e8d64cf13927bd9f828daf053e2788"
+        );
+    }
+
+    #[test]
+    fn test_too_many_words() {
+        assert!(to_index("zoo zoo zoo zoo zoo zoo zoo".to_string()).is_err());
+        assert!(to_index(String::new()).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(index in 0u64..1 << 62, checksum in 0u8..16) {
+            let words = to_words(index, checksum);
+            prop_assert!(words.split(' ').count() <= MAX_WORDS);
+            prop_assert_eq!(to_index(words).unwrap(), (index as usize, checksum));
+        }
+
+        #[test]
+        fn prop_to_index_strings(words in "\\PC*") {
+            let _ = to_index(words);
+        }
+
+        #[test]
+        fn prop_to_index_words(
+            words in prop::collection::vec(prop::sample::select(&ENGLISH[..]), 0..10)
+        ) {
+            let _ = to_index(words.join(" "));
+        }
+    }
 }
@@ -0,0 +1,259 @@
+//! Deterministic end-to-end simulation: a scripted chain, generated from a seed,
+//! drives an `IndexTable` through queue, commit and rollback with reorgs, gaps and
+//! duplicate addresses. After every step, the index is compared to a model of the
+//! expected entries, the checkpoint chain and the proofs are verified, and the API
+//! lookups are checked. The final checkpoints must match an index built from the
+//! final chain alone.
+
+#![cfg(feature = "api")]
+
+use ethers::core::rand::{rngs::StdRng, Rng, SeedableRng};
+use ethers::types::{Address, H256};
+use ethers::utils::keccak256;
+use monique::api;
+use monique::index::{IndexTable, Indexed, SharedIndex};
+use monique::verify;
+use monique::words::{self, PIVOT};
+use serde_json::json;
+use std::collections::HashSet;
+use tempfile::tempdir;
+
+/// Addresses the blocks draw from, few enough for them to repeat often.
+const POOL: u64 = 96;
+const STEPS: usize = 100;
+
+/// Step of the script.
+#[derive(Debug)]
+enum Event {
+    /// Next block of the chain.
+    Block,
+    /// The last `depth` uncommitted blocks are replaced and queued again.
+    Reorg(u64),
+    /// The next two blocks are queued in reverse order.
+    Gap,
+    /// The blocks up to `lag` blocks behind the head are committed.
+    Commit(u64),
+    /// The committed blocks after `depth` blocks behind the last committed one are
+    /// rolled back, and the chain continues from there.
+    Rollback(u64),
+}
+
+/// Chain being simulated, and the expected state of the index.
+struct Simulation {
+    rng: StdRng,
+    index: SharedIndex<20, Address>,
+    /// Addresses of the blocks of the chain, block `n` at `n - 1`.
+    chain: Vec<Vec<Address>>,
+    committed: u64,
+}
+
+impl Simulation {
+    async fn new(seed: u64, path: &std::path::Path) -> Self {
+        let index = IndexTable::<20, Address>::builder(path)
+            .persist_tries(true)
+            .build()
+            .await
+            .unwrap();
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            index: SharedIndex::new(index),
+            chain: vec![],
+            committed: 0,
+        }
+    }
+
+    fn head(&self) -> u64 {
+        self.chain.len() as u64
+    }
+
+    /// Addresses of a new block, repeated within and across blocks.
+    fn block(&mut self) -> Vec<Address> {
+        let count = self.rng.gen_range(0..8);
+        (0..count)
+            .map(|_| Address::from_low_u64_be(1 + self.rng.gen_range(0..POOL)))
+            .collect()
+    }
+
+    fn event(&mut self) -> Event {
+        let uncommitted = self.head() - self.committed;
+        match self.rng.gen_range(0..20) {
+            0..=9 => Event::Block,
+            10..=11 if uncommitted > 0 => Event::Reorg(self.rng.gen_range(1..=uncommitted)),
+            12..=13 => Event::Gap,
+            14..=17 => Event::Commit(self.rng.gen_range(0..4)),
+            18 if self.committed > 0 => {
+                Event::Rollback(self.rng.gen_range(0..self.committed.min(4)))
+            }
+            _ => Event::Block,
+        }
+    }
+
+    async fn queue(&self, number: u64) {
+        let addresses = self.chain[number as usize - 1].clone();
+        self.index.queue(number, addresses).await.unwrap();
+    }
+
+    async fn step(&mut self, event: &Event) {
+        match *event {
+            Event::Block => {
+                let block = self.block();
+                self.chain.push(block);
+                self.queue(self.head()).await;
+            }
+            Event::Reorg(depth) => {
+                let from = self.head() - depth + 1;
+                for number in from..=self.head() {
+                    self.chain[number as usize - 1] = self.block();
+                }
+                for number in from..=self.head() {
+                    self.queue(number).await;
+                }
+            }
+            Event::Gap => {
+                let (first, second) = (self.block(), self.block());
+                self.chain.extend([first, second]);
+                self.queue(self.head()).await;
+                // held until the block before it is queued
+                assert_eq!(
+                    self.index.get_counters().await.last_indexed_block,
+                    self.head() - 2
+                );
+                self.queue(self.head() - 1).await;
+            }
+            Event::Commit(lag) => {
+                let safe = self.head().saturating_sub(lag);
+                self.index.commit(safe).await.unwrap();
+                self.committed = self.committed.max(safe);
+            }
+            Event::Rollback(depth) => {
+                let to = self.committed - depth;
+                self.index.rollback(to).await.unwrap();
+                self.chain.truncate(to as usize);
+                self.committed = to;
+            }
+        }
+    }
+
+    /// First appearances of the addresses in the blocks up to `last`, in order.
+    fn expected(&self, last: u64) -> Vec<Address> {
+        let mut seen = HashSet::new();
+        self.chain[..last as usize]
+            .iter()
+            .flatten()
+            .filter(|address| seen.insert(**address))
+            .cloned()
+            .collect()
+    }
+
+    async fn assert_consistent(&self) {
+        let index = &self.index;
+        let expected = self.expected(self.head());
+        let committed = self.expected(self.committed);
+        let counters = index.get_counters().await;
+        assert_eq!(counters.last_indexed_block, self.head());
+        assert_eq!(counters.last_committed_block, self.committed);
+        drop(counters);
+        assert_eq!(index.len().await, expected.len());
+        assert_eq!(index.committed_len().await, committed.len());
+        index.check().unwrap();
+
+        for (i, address) in expected.iter().enumerate() {
+            assert_eq!(index.get(i).await.unwrap(), Some(*address));
+            assert_eq!(index.index(*address).await.unwrap(), Some(i));
+            let is_committed = i < committed.len();
+            let committed_item = index.get_committed(i).await.unwrap();
+            assert_eq!(committed_item, is_committed.then_some(*address));
+
+            let monic = words::to_words((i + PIVOT) as u64, words::checksum(address));
+            let response = json!({
+                "address": address,
+                "index": i + PIVOT,
+                "monic": monic,
+                "pending": !is_committed,
+            });
+            let info = api::lookup_index(i + PIVOT, index, true)
+                .await
+                .ok()
+                .unwrap();
+            assert_eq!(serde_json::to_value(info).unwrap(), response);
+            let info = api::lookup_monic(&monic, index, true).await.ok().unwrap();
+            assert_eq!(serde_json::to_value(info).unwrap(), response);
+            let info = api::lookup_address(&format!("{:?}", address), index, true)
+                .await
+                .ok()
+                .unwrap();
+            assert_eq!(serde_json::to_value(info).unwrap(), response);
+            // pending entries are an error without the pending flag
+            let info = api::lookup_index(i + PIVOT, index, false).await;
+            assert_eq!(info.is_ok_and(|info| info.is_some()), is_committed);
+        }
+        let unknown = Address::from_low_u64_be(POOL + 1);
+        assert_eq!(index.index(unknown).await.unwrap(), None);
+        assert!(api::lookup_index(expected.len() + PIVOT, index, true)
+            .await
+            .ok()
+            .unwrap()
+            .is_none());
+
+        // each checkpoint chains from the previous one, and proves its entries
+        let mut previous = H256::zero();
+        let mut roots = vec![];
+        for number in 1..=self.committed {
+            let checkpoint = index.checkpoint(number).unwrap().unwrap();
+            let hash = keccak256([previous.as_bytes(), checkpoint.root.as_bytes()].concat());
+            assert_eq!(checkpoint.hash, H256(hash), "block {}", number);
+            previous = checkpoint.hash;
+            roots.push(checkpoint.root);
+        }
+        for (i, address) in committed.iter().enumerate() {
+            let (item, proof) = index.proof(i).await.unwrap().unwrap();
+            assert_eq!(item, *address);
+            assert_eq!(roots[proof.block as usize - 1], proof.root);
+            assert!(verify::verify_proof(
+                proof.root,
+                i + PIVOT,
+                item,
+                proof.nodes
+            ));
+        }
+    }
+}
+
+async fn simulate(seed: u64) {
+    let temp_dir = tempdir().unwrap();
+    let mut simulation = Simulation::new(seed, &temp_dir.path().join("simulated")).await;
+    for _ in 0..STEPS {
+        let event = simulation.event();
+        simulation.step(&event).await;
+        simulation.assert_consistent().await;
+    }
+    let head = simulation.head();
+    simulation.step(&Event::Commit(0)).await;
+    simulation.assert_consistent().await;
+
+    // the history of reorgs, gaps and rollbacks leaves no trace
+    let replayed = IndexTable::<20, Address>::builder(temp_dir.path().join("replayed"))
+        .build()
+        .await
+        .unwrap();
+    for (number, addresses) in (1..).zip(&simulation.chain) {
+        replayed.queue(number, addresses.clone()).await.unwrap();
+    }
+    replayed.commit(head).await.unwrap();
+    for number in 1..=head {
+        assert_eq!(
+            replayed.checkpoint(number).unwrap(),
+            simulation.index.checkpoint(number).unwrap(),
+            "seed {}, block {}",
+            seed,
+            number
+        );
+    }
+}
+
+#[tokio::test]
+async fn simulation() {
+    for seed in 0..4 {
+        simulate(seed).await;
+    }
+}